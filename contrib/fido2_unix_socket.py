@@ -0,0 +1,65 @@
+"""A python-fido2 (>= 1.0) connection to softauth's Unix socket transport.
+
+Run the authenticator with ``softauth --no-uhid --unix-socket /tmp/softauth.sock`` and then::
+
+    from fido2_unix_socket import open_device
+    from fido2.ctap2 import Ctap2
+
+    print(Ctap2(open_device("/tmp/softauth.sock")).get_info())
+
+Reports are exchanged as raw 64 byte CTAPHID packets, without report numbers or
+any other framing.
+"""
+
+import socket
+
+from fido2.hid import CtapHidDevice
+from fido2.hid.base import CtapHidConnection, HidDescriptor
+
+REPORT_SIZE = 64
+
+# Matches the vendor/product IDs of the UHID device created by softauth
+VENDOR_ID = 1337
+PRODUCT_ID = 1337
+
+
+class UnixSocketCtapHidConnection(CtapHidConnection):
+    """CTAPHID connection over a Unix stream socket"""
+
+    def __init__(self, path):
+        self.sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        self.sock.connect(path)
+
+    def close(self):
+        self.sock.close()
+
+    def write_packet(self, data):
+        if len(data) != REPORT_SIZE:
+            raise ValueError("Packet must be %d bytes" % REPORT_SIZE)
+        self.sock.sendall(data)
+
+    def read_packet(self):
+        packet = b""
+        while len(packet) < REPORT_SIZE:
+            chunk = self.sock.recv(REPORT_SIZE - len(packet))
+            if not chunk:
+                raise OSError("Authenticator closed the connection")
+            packet += chunk
+        return packet
+
+
+def get_descriptor(path):
+    return HidDescriptor(
+        path,
+        VENDOR_ID,
+        PRODUCT_ID,
+        REPORT_SIZE,
+        REPORT_SIZE,
+        "Software CTAP2",
+        None,
+    )
+
+
+def open_device(path):
+    """Opens a CtapHidDevice talking to softauth over the Unix socket at the given path"""
+    return CtapHidDevice(get_descriptor(path), UnixSocketCtapHidConnection(path))
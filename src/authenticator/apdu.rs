@@ -0,0 +1,279 @@
+//! ISO 7816-4 APDUs, used by U2F raw messages (over CTAPHID_MSG) and by NFC.
+//! [See more](https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#u2f-message-framing)
+
+use num_enum::IntoPrimitive;
+use thiserror::Error;
+
+/// Length of the CLA, INS, P1 and P2 header bytes
+const HEADER_LENGTH: usize = 4;
+
+/// Status words sent at the end of every response APDU
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive)]
+pub enum StatusWord {
+    NoError = 0x9000,
+    WrongLength = 0x6700,
+    ConditionsNotSatisfied = 0x6985,
+    WrongData = 0x6A80,
+    FileNotFound = 0x6A82,
+    WrongP1P2 = 0x6B00,
+    InsNotSupported = 0x6D00,
+    ClaNotSupported = 0x6E00,
+    NoPreciseDiagnosis = 0x6F00,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ApduError {
+    #[error("APDU of {len} bytes is shorter than its header")]
+    TooShort { len: usize },
+
+    #[error("APDU of {len} bytes has an invalid length encoding")]
+    InvalidLength { len: usize },
+}
+
+impl From<ApduError> for StatusWord {
+    fn from(_: ApduError) -> Self {
+        StatusWord::WrongLength
+    }
+}
+
+/// A command APDU, encoded with either short or extended lengths
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandApdu {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: Vec<u8>,
+
+    /// Maximal number of response bytes expected (`Ne`), 0 if the command expects no response data
+    pub ne: usize,
+}
+
+impl CommandApdu {
+    /// Parses an APDU of any of the ISO 7816-4 cases, in either short or extended form.
+    pub fn parse(bytes: &[u8]) -> Result<Self, ApduError> {
+        let len = bytes.len();
+        if len < HEADER_LENGTH {
+            return Err(ApduError::TooShort { len });
+        }
+        let (header, body) = bytes.split_at(HEADER_LENGTH);
+        let (data, ne) = match body {
+            // case 1
+            [] => (&[][..], 0),
+            // case 2S
+            [le] => (&[][..], short_ne(*le)),
+            // case 2E
+            [0, le @ ..] if le.len() == 2 => (&[][..], extended_ne(le)),
+            // cases 3E and 4E
+            [0, lc_1, lc_2, rest @ ..] => {
+                let lc = u16::from_be_bytes([*lc_1, *lc_2]) as usize;
+                match rest.len().checked_sub(lc) {
+                    Some(0) => (rest, 0),
+                    // U2F clients send an extended Le even when there's no command data
+                    Some(2) => (&rest[..lc], extended_ne(&rest[lc..])),
+                    _ => return Err(ApduError::InvalidLength { len }),
+                }
+            }
+            // cases 3S and 4S
+            [lc, rest @ ..] => {
+                let lc = *lc as usize;
+                match rest.len().checked_sub(lc) {
+                    Some(0) => (rest, 0),
+                    Some(1) => (&rest[..lc], short_ne(rest[lc])),
+                    _ => return Err(ApduError::InvalidLength { len }),
+                }
+            }
+        };
+        Ok(CommandApdu {
+            cla: header[0],
+            ins: header[1],
+            p1: header[2],
+            p2: header[3],
+            data: data.to_owned(),
+            ne,
+        })
+    }
+}
+
+/// Encodes a command APDU with extended lengths, which fit any data length and `Ne`
+impl From<CommandApdu> for Vec<u8> {
+    fn from(apdu: CommandApdu) -> Self {
+        let mut bytes = vec![apdu.cla, apdu.ins, apdu.p1, apdu.p2];
+        if apdu.data.is_empty() && apdu.ne == 0 {
+            return bytes;
+        }
+        bytes.push(0);
+        if !apdu.data.is_empty() {
+            bytes.extend_from_slice(&(apdu.data.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(&apdu.data);
+        }
+        if apdu.ne > 0 {
+            // an Le of 0 stands for the maximal Ne of 65536
+            bytes.extend_from_slice(&(apdu.ne as u16).to_be_bytes());
+        }
+        bytes
+    }
+}
+
+fn short_ne(le: u8) -> usize {
+    if le == 0 {
+        256
+    } else {
+        le as usize
+    }
+}
+
+fn extended_ne(le: &[u8]) -> usize {
+    match u16::from_be_bytes([le[0], le[1]]) {
+        0 => 65536,
+        le => le as usize,
+    }
+}
+
+/// SW1 of a response whose remaining bytes are to be fetched via GET RESPONSE, SW2 being
+/// their count (0 meaning 256 or more)
+const SW1_BYTES_REMAINING: u8 = 0x61;
+
+/// A response APDU, consisting of response data followed by a status word. The status word
+/// is usually one of [StatusWord], but may also carry a parameter in its second byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseApdu {
+    pub data: Vec<u8>,
+    pub status: u16,
+}
+
+impl ResponseApdu {
+    pub fn success(data: Vec<u8>) -> Self {
+        ResponseApdu {
+            data,
+            status: StatusWord::NoError.into(),
+        }
+    }
+
+    /// A part of a longer response, with `remaining` bytes left to be fetched via GET RESPONSE
+    pub fn bytes_remaining(data: Vec<u8>, remaining: usize) -> Self {
+        let sw2 = if remaining > 0xff { 0 } else { remaining as u8 };
+        ResponseApdu {
+            data,
+            status: u16::from_be_bytes([SW1_BYTES_REMAINING, sw2]),
+        }
+    }
+
+    /// Splits a response APDU into its data and status word, returning `None` if it's too
+    /// short to have a status word.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let split = bytes.len().checked_sub(2)?;
+        let (data, status) = bytes.split_at(split);
+        Some(ResponseApdu {
+            data: data.to_owned(),
+            status: u16::from_be_bytes([status[0], status[1]]),
+        })
+    }
+}
+
+impl From<StatusWord> for ResponseApdu {
+    fn from(status: StatusWord) -> Self {
+        ResponseApdu {
+            data: Vec::new(),
+            status: status.into(),
+        }
+    }
+}
+
+impl From<ResponseApdu> for Vec<u8> {
+    fn from(res: ResponseApdu) -> Self {
+        let mut bytes = res.data;
+        bytes.extend_from_slice(&res.status.to_be_bytes());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_short_apdus() {
+        let case_1 = CommandApdu::parse(&[0, 3, 0, 0]).unwrap();
+        assert_eq!((case_1.ins, case_1.data.len(), case_1.ne), (3, 0, 0));
+
+        let case_2 = CommandApdu::parse(&[0, 3, 0, 0, 0]).unwrap();
+        assert_eq!((case_2.data.len(), case_2.ne), (0, 256));
+
+        let case_3 = CommandApdu::parse(&[0, 1, 2, 3, 2, 0xaa, 0xbb]).unwrap();
+        assert_eq!((case_3.p1, case_3.p2), (2, 3));
+        assert_eq!((case_3.data, case_3.ne), (vec![0xaa, 0xbb], 0));
+
+        let case_4 = CommandApdu::parse(&[0, 1, 0, 0, 1, 0xaa, 0x10]).unwrap();
+        assert_eq!((case_4.data, case_4.ne), (vec![0xaa], 0x10));
+    }
+
+    #[test]
+    fn test_parse_extended_apdus() {
+        let case_2 = CommandApdu::parse(&[0, 3, 0, 0, 0, 0x01, 0x00]).unwrap();
+        assert_eq!((case_2.data.len(), case_2.ne), (0, 256));
+
+        let mut case_3 = vec![0, 1, 0, 0, 0, 0x01, 0x00];
+        case_3.extend_from_slice(&[7; 256]);
+        let case_3 = CommandApdu::parse(&case_3).unwrap();
+        assert_eq!((case_3.data, case_3.ne), (vec![7; 256], 0));
+
+        let case_4 = CommandApdu::parse(&[0, 2, 0, 0, 0, 0, 2, 0xaa, 0xbb, 0, 0]).unwrap();
+        assert_eq!((case_4.data, case_4.ne), (vec![0xaa, 0xbb], 65536));
+
+        // U2F_VERSION as sent by common U2F clients, with an empty Lc
+        let version = CommandApdu::parse(&[0, 3, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!((version.data.len(), version.ne), (0, 65536));
+    }
+
+    #[test]
+    fn test_encode_apdus() {
+        for bytes in [
+            vec![0, 3, 0, 0],
+            vec![0, 3, 0, 0, 0, 0, 0],
+            vec![0, 1, 0, 0, 0, 0, 2, 0xaa, 0xbb],
+            vec![0, 2, 7, 0, 0, 0, 1, 0xaa, 0x01, 0x00],
+        ] {
+            let apdu = CommandApdu::parse(&bytes).unwrap();
+            assert_eq!(Vec::from(apdu), bytes);
+        }
+        let short = CommandApdu::parse(&[0, 1, 0, 0, 1, 0xaa, 0x00]).unwrap();
+        assert_eq!(
+            Vec::from(short),
+            vec![0, 1, 0, 0, 0, 0, 1, 0xaa, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_apdus() {
+        assert_eq!(
+            CommandApdu::parse(&[0, 1, 0]),
+            Err(ApduError::TooShort { len: 3 })
+        );
+        assert_eq!(
+            CommandApdu::parse(&[0, 1, 0, 0, 3, 0xaa]),
+            Err(ApduError::InvalidLength { len: 6 })
+        );
+        assert_eq!(
+            CommandApdu::parse(&[0, 1, 0, 0, 0, 0, 3, 0xaa, 0xbb, 0xcc, 0xdd]),
+            Err(ApduError::InvalidLength { len: 11 })
+        );
+    }
+
+    #[test]
+    fn test_response_bytes() {
+        let bytes: Vec<u8> = ResponseApdu::success(vec![1, 2]).into();
+        assert_eq!(bytes, vec![1, 2, 0x90, 0x00]);
+        let bytes: Vec<u8> = ResponseApdu::from(StatusWord::WrongData).into();
+        assert_eq!(bytes, vec![0x6A, 0x80]);
+        let bytes: Vec<u8> = ResponseApdu::bytes_remaining(vec![3], 0x20).into();
+        assert_eq!(bytes, vec![3, 0x61, 0x20]);
+        let bytes: Vec<u8> = ResponseApdu::bytes_remaining(vec![], 300).into();
+        assert_eq!(bytes, vec![0x61, 0x00]);
+
+        let res = ResponseApdu::parse(&[1, 2, 0x90, 0x00]).unwrap();
+        assert_eq!(res, ResponseApdu::success(vec![1, 2]));
+        assert_eq!(ResponseApdu::parse(&[0x90]), None);
+    }
+}
@@ -1,21 +1,39 @@
-use std::{pin::Pin, sync::Arc, task::Poll};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    task::Poll,
+};
 
 use futures::Future;
 
 use thiserror::Error;
-use tokio::sync::Mutex;
 use tower::Service;
 use tracing::trace;
 
 use crate::{
     cbor::{key_mapped::KeymappedStruct, ordered_ser::make_ordered},
-    hid::{command::CommandType, packet::Message},
+    hid::{
+        command::CommandType,
+        packet::Message,
+        vendor::{VendorCommandError, VendorCommandHandler, VendorCommands, VendorContext},
+    },
 };
 
 use super::{
-    auth_impl::CTAP2ServiceImpl,
+    arbiter::TransactionArbiter,
+    auth_impl::{AuthenticatorStats, CTAP2ServiceImpl},
     command::{CTAPCommand, StatusCode},
+    crypto::AttestationKey,
+    presence::PresenceFrontend,
+    storage::{
+        state::{PersistentState, StateError},
+        store::{Storage, StorageError},
+    },
     types::{
+        AuthenticatorGetAssertionParams, AuthenticatorGetAssertionResponse,
         AuthenticatorGetInfoResponse, AuthenticatorMakeCredentialParams,
         AuthenticatorMakeCredentialResponse,
     },
@@ -31,6 +49,12 @@ pub enum AuthenticatorError {
 
     #[error("Cannot send response (response sink is closed)")]
     CannotSendResponse,
+
+    #[error(transparent)]
+    StorageError(#[from] StorageError),
+
+    #[error(transparent)]
+    StateError(#[from] StateError),
 }
 
 /// Error message type retuned from the Service
@@ -56,7 +80,9 @@ impl From<&AuthServiceError> for Message {
         let status_code = match err.inner {
             AuthenticatorError::CTAPErrorStatus(status) => status,
             AuthenticatorError::DeserializationError(_) => StatusCode::Ctap2ErrInvalidCbor,
-            AuthenticatorError::CannotSendResponse => StatusCode::Ctap1ErrOther,
+            AuthenticatorError::CannotSendResponse
+            | AuthenticatorError::StorageError(_)
+            | AuthenticatorError::StateError(_) => StatusCode::Ctap1ErrOther,
         };
         Message {
             channel_identifier: err.channel_identifier,
@@ -76,7 +102,12 @@ pub struct CTAP2Request {
 pub enum CTAP2Command {
     GetInfo,
     MakeCredential(Box<AuthenticatorMakeCredentialParams>),
+    GetAssertion(Box<AuthenticatorGetAssertionParams>),
+    GetNextAssertion,
     Reset,
+    /// A U2F (CTAP1) request APDU, which is parsed by the authenticator so that malformed
+    /// requests can be answered with the appropriate status word.
+    U2F(Vec<u8>),
 }
 
 impl CTAP2Command {
@@ -90,15 +121,20 @@ impl CTAP2Command {
                     .map_err(AuthenticatorError::DeserializationError)?;
                 CTAP2Command::MakeCredential(Box::new(data.into_inner()))
             }
-            CTAPCommand::GetAssertion => todo!(),
-            CTAPCommand::GetNextAssertion => todo!(),
+            CTAPCommand::GetAssertion => {
+                let data: KeymappedStruct<_, u8> = ciborium::de::from_reader(payload)
+                    .map_err(AuthenticatorError::DeserializationError)?;
+                CTAP2Command::GetAssertion(Box::new(data.into_inner()))
+            }
+            CTAPCommand::GetNextAssertion => CTAP2Command::GetNextAssertion,
             CTAPCommand::GetInfo => CTAP2Command::GetInfo,
-            CTAPCommand::GetClientPin => todo!(),
             CTAPCommand::Reset => CTAP2Command::Reset,
-            CTAPCommand::BioEnrollment => todo!(),
-            CTAPCommand::Selection => todo!(),
-            CTAPCommand::LargeBlobs => todo!(),
-            CTAPCommand::Config => todo!(),
+            // not implemented yet
+            CTAPCommand::GetClientPin
+            | CTAPCommand::BioEnrollment
+            | CTAPCommand::Selection
+            | CTAPCommand::LargeBlobs
+            | CTAPCommand::Config => return Err(StatusCode::Ctap1ErrInvalidCommand.into()),
         })
     }
 }
@@ -107,10 +143,16 @@ impl TryFrom<&Message> for CTAP2Request {
     type Error = AuthenticatorError;
 
     fn try_from(value: &Message) -> Result<Self, Self::Error> {
+        if value.command == Ok(CommandType::Msg) {
+            return Ok(CTAP2Request {
+                command: CTAP2Command::U2F(value.payload.clone()),
+                channel_identifier: value.channel_identifier,
+            });
+        }
         assert_eq!(
             value.command,
             Ok(CommandType::Cbor),
-            "Message passed must be a CBOR message"
+            "Message passed must be a CBOR or U2F message"
         );
         if value.payload.is_empty() {
             return Err(StatusCode::Ctap1ErrInvalidLength.into());
@@ -135,7 +177,10 @@ pub struct CTAP2Response {
 impl From<CTAP2Response> for Message {
     fn from(res: CTAP2Response) -> Self {
         let channel_identifier = res.channel_identifier;
-        let command = Ok(CommandType::Cbor);
+        let command = match res.data {
+            CTAP2ResponseData::U2F(_) => Ok(CommandType::Msg),
+            _ => Ok(CommandType::Cbor),
+        };
         let payload: Vec<u8> = res.data.into();
         Message {
             channel_identifier,
@@ -149,7 +194,10 @@ impl From<CTAP2Response> for Message {
 pub enum CTAP2ResponseData {
     GetInfo(AuthenticatorGetInfoResponse),
     MakeCredential(AuthenticatorMakeCredentialResponse),
+    GetAssertion(AuthenticatorGetAssertionResponse),
     ResetOK,
+    /// A U2F response APDU, including its status word
+    U2F(Vec<u8>),
 }
 
 impl From<CTAP2ResponseData> for Vec<u8> {
@@ -164,7 +212,12 @@ impl From<CTAP2ResponseData> for Vec<u8> {
                 let km = KeymappedStruct::from(res);
                 ciborium::value::Value::serialized(&km).unwrap()
             }
+            CTAP2ResponseData::GetAssertion(res) => {
+                let km = KeymappedStruct::from(res);
+                ciborium::value::Value::serialized(&km).unwrap()
+            }
             CTAP2ResponseData::ResetOK => return buf,
+            CTAP2ResponseData::U2F(apdu) => return apdu,
         };
         make_ordered(&mut value);
         ciborium::ser::into_writer(&value, &mut buf).unwrap();
@@ -173,8 +226,18 @@ impl From<CTAP2ResponseData> for Vec<u8> {
     }
 }
 
+/// The authenticator as a [Service] of CTAP2 requests. Clones of the service share the
+/// same authenticator, and may be used by different transports at once.
+#[derive(Clone)]
 pub struct CTAP2Service {
-    imp: Arc<Mutex<CTAP2ServiceImpl>>,
+    imp: TransactionArbiter<CTAP2ServiceImpl>,
+    /// The name of the transport this service is used by, for arbitration and logging
+    transport: Arc<str>,
+    frontend: Arc<dyn PresenceFrontend>,
+    /// Set while the authenticator waits for the user to confirm their presence
+    awaiting_presence: Arc<AtomicBool>,
+    /// Shared by all transports, so that handlers are registered only once
+    vendor_commands: Arc<RwLock<VendorCommands>>,
 }
 
 impl Service<CTAP2Request> for CTAP2Service {
@@ -190,9 +253,10 @@ impl Service<CTAP2Request> for CTAP2Service {
 
     fn call(&mut self, req: CTAP2Request) -> Self::Future {
         let imp = self.imp.clone();
+        let transport = self.transport.clone();
         Box::pin(async move {
             let channel_identifier = req.channel_identifier;
-            let mut imp = imp.lock().await;
+            let mut imp = imp.begin(&transport).await;
             let data = imp
                 .handle_command(req.command)
                 .await
@@ -209,9 +273,108 @@ impl Service<CTAP2Request> for CTAP2Service {
 }
 
 impl CTAP2Service {
-    pub fn new() -> Self {
+    /// An authenticator with the default identity, the daemon rather uses a
+    /// [Profile](crate::profile::Profile)
+    #[cfg(test)]
+    pub fn new(u2f_enabled: bool) -> Self {
+        Self::with_frontend(
+            u2f_enabled,
+            Arc::new(super::presence::TestFrontend::default()),
+        )
+    }
+
+    /// An authenticator with the default identity, interacting with its user via `frontend`
+    #[cfg(test)]
+    pub fn with_frontend(u2f_enabled: bool, frontend: Arc<dyn PresenceFrontend>) -> Self {
+        let info = AuthenticatorGetInfoResponse::default();
+        let info = if u2f_enabled { info.with_u2f() } else { info };
+        Self::with_identity(
+            u2f_enabled,
+            info,
+            AttestationKey::builtin(),
+            Box::new(super::storage::memory::InMemoryStorage::new()),
+            PersistentState::in_memory(),
+            frontend,
+        )
+    }
+
+    /// Creates an authenticator identifying itself via `info` and `attestation_key`, e.g,
+    /// to emulate another device, and keeping its credentials in `storage` and the rest of
+    /// its `state` alongside. `info` should advertise U2F if it is enabled. The authenticator
+    /// and its transports interact with the user via `frontend`.
+    pub fn with_identity(
+        u2f_enabled: bool,
+        info: AuthenticatorGetInfoResponse,
+        attestation_key: AttestationKey,
+        storage: Box<dyn Storage>,
+        state: PersistentState,
+        frontend: Arc<dyn PresenceFrontend>,
+    ) -> Self {
+        let imp = CTAP2ServiceImpl::new(
+            u2f_enabled,
+            info,
+            attestation_key,
+            storage,
+            state,
+            frontend.clone(),
+        );
+        CTAP2Service {
+            awaiting_presence: imp.awaiting_presence(),
+            imp: TransactionArbiter::new(imp),
+            transport: "default".into(),
+            frontend,
+            vendor_commands: Arc::new(RwLock::new(VendorCommands::builtin())),
+        }
+    }
+
+    /// The frontend interacting with the user of the authenticator
+    pub fn frontend(&self) -> Arc<dyn PresenceFrontend> {
+        self.frontend.clone()
+    }
+
+    /// Whether the transaction of this transport waits for the user to confirm their presence
+    pub fn awaiting_presence(&self) -> bool {
+        self.awaiting_presence.load(Ordering::SeqCst)
+            && self.imp.owner().as_ref() == Some(&self.transport)
+    }
+
+    /// Registers a handler for a CTAP-HID vendor command, whose identifier (without the MSB)
+    /// must lie within the vendor command range. The command is handled over every transport
+    /// the authenticator is served over.
+    pub fn register_vendor_command(
+        &self,
+        command: u8,
+        handler: impl VendorCommandHandler + 'static,
+    ) -> Result<(), VendorCommandError> {
+        self.vendor_commands
+            .write()
+            .unwrap()
+            .register(command, handler)
+    }
+
+    /// Handles a CTAP-HID vendor command message, returning the response message
+    pub async fn handle_vendor_command(&self, request: Message) -> Message {
+        // not locked while handling, as handlers may take a while
+        let commands = self.vendor_commands.read().unwrap().clone();
+        let context = VendorContext {
+            authenticator: self.clone(),
+        };
+        commands.handle(&context, request).await
+    }
+
+    /// Gathers statistics of the authenticator, once no other transaction is in progress
+    pub async fn stats(&self) -> Result<AuthenticatorStats, StorageError> {
+        self.imp.begin(&self.transport).await.stats().await
+    }
+
+    /// A clone of the service sharing the same authenticator, for use by another transport
+    pub fn for_transport(&self, transport: &str) -> Self {
         CTAP2Service {
-            imp: Arc::new(Mutex::new(CTAP2ServiceImpl::new())),
+            imp: self.imp.clone(),
+            transport: transport.into(),
+            frontend: self.frontend.clone(),
+            awaiting_presence: self.awaiting_presence.clone(),
+            vendor_commands: self.vendor_commands.clone(),
         }
     }
 }
@@ -0,0 +1,123 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::debug;
+
+/// Grants exclusive access to the authenticator state for the duration of a single
+/// transaction (a CTAP2/U2F command), across all transports the authenticator is served over.
+///
+/// Transactions are granted in the order they were requested, so that a transport can't
+/// starve the others, and so that state which spans a transaction (e.g, a pending user
+/// presence prompt) is never observed half-way by another transport.
+pub struct TransactionArbiter<T> {
+    state: Arc<Mutex<T>>,
+    /// The transport whose transaction is in progress, if any
+    owner: Arc<std::sync::Mutex<Option<Arc<str>>>>,
+}
+
+// not derived, as `T` itself needn't be Clone
+impl<T> Clone for TransactionArbiter<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            owner: self.owner.clone(),
+        }
+    }
+}
+
+impl<T> TransactionArbiter<T> {
+    pub fn new(state: T) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            owner: Arc::new(std::sync::Mutex::new(None)),
+        }
+    }
+
+    /// Waits until no other transaction is in progress, and begins one on behalf of
+    /// the given transport. The transaction ends once the returned guard is dropped.
+    pub async fn begin(&self, transport: &Arc<str>) -> Transaction<T> {
+        let guard = match self.state.clone().try_lock_owned() {
+            Ok(guard) => guard,
+            Err(_) => {
+                let owner = self.owner();
+                debug!(%transport, ?owner, "Waiting for another transaction to end");
+                self.state.clone().lock_owned().await
+            }
+        };
+        *self.owner.lock().unwrap() = Some(transport.clone());
+        Transaction {
+            guard,
+            owner: self.owner.clone(),
+        }
+    }
+
+    /// The transport whose transaction is in progress, if any
+    pub fn owner(&self) -> Option<Arc<str>> {
+        self.owner.lock().unwrap().clone()
+    }
+}
+
+/// Exclusive access to the authenticator state, see [TransactionArbiter::begin]
+pub struct Transaction<T> {
+    guard: OwnedMutexGuard<T>,
+    owner: Arc<std::sync::Mutex<Option<Arc<str>>>>,
+}
+
+impl<T> Deref for Transaction<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for Transaction<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for Transaction<T> {
+    fn drop(&mut self) {
+        // cleared before the state is unlocked, as the guard is dropped after this
+        *self.owner.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_transactions_are_exclusive() {
+        let arbiter = TransactionArbiter::new(Vec::new());
+        let (uhid, socket): (Arc<str>, Arc<str>) = ("uhid".into(), "socket".into());
+
+        let mut first = arbiter.begin(&uhid).await;
+        assert_eq!(arbiter.owner(), Some(uhid.clone()));
+
+        let mut waiting = {
+            let arbiter = arbiter.clone();
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                arbiter.begin(&socket).await.push("socket");
+            })
+        };
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), &mut waiting)
+                .await
+                .is_err()
+        );
+
+        first.push("uhid");
+        drop(first);
+        waiting.await.unwrap();
+        assert_eq!(arbiter.owner(), None);
+        assert_eq!(*arbiter.begin(&uhid).await, vec!["uhid", "socket"]);
+    }
+}
@@ -1,30 +1,389 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use tracing::{debug, error};
+use zeroize::Zeroizing;
+
 use crate::authenticator::{
     api::{AuthenticatorError, CTAP2Command, CTAP2ResponseData},
-    types::AuthenticatorGetInfoResponse,
+    command::StatusCode,
+    crypto::{
+        AttestationKey, COSEAlgorithmIdentifier, CredentialSeed, CredentialWrapper, CryptoKeyPair,
+        CryptoSystem, RingCryptoSystem, RingKeyPair, CREDENTIAL_KEY_LENGTH, ES256,
+    },
+    presence::{PresenceFrontend, PresenceToken},
+    storage::{
+        state::{AuthenticatorState, PersistentState},
+        store::{Storage, StorageError, StorageTransaction},
+    },
+    types::{
+        AuthenticatorGetInfoResponse, CredentialId, CredentialPrivateKey,
+        PublicKeyCredentialSource, PublicKeyType, RpId, RpIdHash, UserHandle,
+    },
 };
 
-pub struct CTAP2ServiceImpl {}
+use super::get_assertion_impl::NextAssertions;
+
+/// How long to wait for the user to confirm their presence, like the timeout of clients
+const PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to check whether the user confirmed their presence
+const PRESENCE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The length of the random IDs of discoverable credentials
+const DISCOVERABLE_CREDENTIAL_ID_LENGTH: usize = 32;
+
+/// A credential found by [CTAP2ServiceImpl::find_credential]
+pub(super) struct FoundCredential {
+    pub source: PublicKeyCredentialSource,
+    /// Whether the credential was unwrapped from its ID rather than stored
+    pub stateless: bool,
+}
+
+fn stateless_credential(
+    id: CredentialId,
+    rp_id_hash: RpIdHash,
+    private_key: CredentialPrivateKey,
+    sign_count: u32,
+) -> PublicKeyCredentialSource {
+    PublicKeyCredentialSource {
+        _type: PublicKeyType::PublicKey,
+        id,
+        rp_id: None,
+        rp_id_hash,
+        private_key,
+        user_handle: None,
+        discoverable: false,
+        sign_count,
+    }
+}
+
+/// Statistics of the authenticator, e.g, for monitoring it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorStats {
+    /// The number of stored credentials, which excludes stateless ones
+    pub credentials: usize,
+
+    /// Signature counter of stateless credentials
+    pub global_sign_count: u32,
+}
+
+pub struct CTAP2ServiceImpl {
+    pub(super) u2f_enabled: bool,
+    pub(super) info: AuthenticatorGetInfoResponse,
+    pub(super) crypto: RingCryptoSystem,
+    pub(super) attestation_key: AttestationKey,
+    pub(super) storage: Box<dyn Storage>,
+    state: PersistentState,
+    rng: SystemRandom,
+    pub(super) frontend: Arc<dyn PresenceFrontend>,
+    /// Set while waiting for the user to confirm their presence, see
+    /// [CTAP2ServiceImpl::awaiting_presence]
+    awaiting_presence: Arc<AtomicBool>,
+    /// Left by the last command if it was an authenticatorGetAssertion request which found
+    /// several credentials, discarded by any other command
+    pub(super) next_assertions: Option<NextAssertions>,
+}
+
+/// Ends a wait for the user's presence once dropped, including when the wait is cancelled,
+/// clearing its flag and dropping a confirmation which came too late
+struct AwaitingPresence<'a> {
+    flag: &'a AtomicBool,
+    frontend: &'a dyn PresenceFrontend,
+    token: PresenceToken,
+}
+
+impl Drop for AwaitingPresence<'_> {
+    fn drop(&mut self) {
+        self.flag.store(false, Ordering::SeqCst);
+        self.frontend.end_presence_request(self.token);
+    }
+}
 
 impl CTAP2ServiceImpl {
-    pub fn new() -> Self {
-        Self {}
+    /// Creates an authenticator identifying itself via `info`, which should advertise U2F
+    /// if it is enabled, and asking its user to confirm their presence via `frontend`
+    pub fn new(
+        u2f_enabled: bool,
+        info: AuthenticatorGetInfoResponse,
+        attestation_key: AttestationKey,
+        storage: Box<dyn Storage>,
+        state: PersistentState,
+        frontend: Arc<dyn PresenceFrontend>,
+    ) -> Self {
+        Self {
+            u2f_enabled,
+            info,
+            crypto: RingCryptoSystem,
+            attestation_key,
+            storage,
+            state,
+            rng: SystemRandom::new(),
+            frontend,
+            awaiting_presence: Arc::new(AtomicBool::new(false)),
+            next_assertions: None,
+        }
+    }
+
+    /// A flag which is set while the authenticator waits for the user to confirm their
+    /// presence, e.g, for transports to tell the client the user is needed. It is shared,
+    /// as it's read while a transaction is in progress.
+    pub fn awaiting_presence(&self) -> Arc<AtomicBool> {
+        self.awaiting_presence.clone()
     }
 
     pub async fn handle_command(
         &mut self,
         command: CTAP2Command,
     ) -> Result<CTAP2ResponseData, AuthenticatorError> {
+        let next_assertions = self.next_assertions.take();
         match command {
-            CTAP2Command::GetInfo => Ok(CTAP2ResponseData::GetInfo(
-                AuthenticatorGetInfoResponse::default(),
-            )),
+            CTAP2Command::GetInfo => Ok(CTAP2ResponseData::GetInfo(self.info.clone())),
             CTAP2Command::MakeCredential(params) => self.handle_make_credential(*params).await,
+            CTAP2Command::GetAssertion(params) => self.handle_get_assertion(*params).await,
+            CTAP2Command::GetNextAssertion => self.handle_get_next_assertion(next_assertions).await,
             CTAP2Command::Reset => self.reset_device().await,
+            CTAP2Command::U2F(apdu) => Ok(self.handle_u2f(&apdu).await),
         }
     }
 
     pub async fn reset_device(&mut self) -> Result<CTAP2ResponseData, AuthenticatorError> {
-        // TODO: resetting a device
+        let count = self.storage.count_credentials().await?;
+        debug!(count, "Deleting all credentials");
+        self.storage.delete_all_credentials().await?;
+        self.state.update(AuthenticatorState::reset).await?;
         Ok(CTAP2ResponseData::ResetOK)
     }
+
+    pub async fn stats(&self) -> Result<AuthenticatorStats, StorageError> {
+        Ok(AuthenticatorStats {
+            credentials: self.storage.count_credentials().await?,
+            global_sign_count: self.state.get().global_sign_count,
+        })
+    }
+
+    /// The wrapper of non-discoverable credentials, once its key was created
+    async fn credential_wrapper(&self) -> Result<Option<CredentialWrapper>, StorageError> {
+        let key = self.storage.credential_key().await?;
+        Ok(key.map(|key| CredentialWrapper::new(&key)))
+    }
+
+    fn credential_seed(&self) -> Option<CredentialSeed> {
+        let seed = self.state.get().seed.as_ref()?;
+        match seed[..].try_into() {
+            Ok(seed) => Some(CredentialSeed::new(seed)),
+            Err(_) => {
+                error!(len = seed.len(), "The seed has an invalid length");
+                None
+            }
+        }
+    }
+
+    /// Derives the key pair of a credential created from the seed for the given RP
+    fn derive_keypair(
+        &self,
+        seed: &CredentialSeed,
+        id: &CredentialId,
+        rp_id_hash: &RpIdHash,
+    ) -> Option<RingKeyPair> {
+        let material = seed.key_material(id, rp_id_hash)?;
+        self.crypto
+            .derive_credential_keypair(ES256, &material)
+            .map_err(|e| error!(?e, "Couldn't derive the key pair of a credential"))
+            .ok()
+    }
+
+    /// Creates a non-discoverable credential which isn't stored, returning it along with its
+    /// key pair. If a seed is set up, ES256 keys are derived from it, such that restoring the
+    /// seed restores the credential. Otherwise the private key is wrapped into the ID by the
+    /// credential key, which is created upon the first use and kept along with the stored
+    /// credentials.
+    pub(super) async fn new_stateless_credential(
+        &mut self,
+        rp_id_hash: &RpIdHash,
+        alg: COSEAlgorithmIdentifier,
+    ) -> Result<(PublicKeyCredentialSource, RingKeyPair), AuthenticatorError> {
+        if let Some(seed) = self.credential_seed().filter(|_| alg == ES256) {
+            let id = seed.new_credential_id(rp_id_hash, &self.rng).map_err(|e| {
+                error!(?e, "Couldn't create a credential ID");
+                StatusCode::Ctap1ErrOther
+            })?;
+            let key_pair = self
+                .derive_keypair(&seed, &id, rp_id_hash)
+                .ok_or(StatusCode::Ctap1ErrOther)?;
+            let private_key = key_pair.to_private_key();
+            return Ok((
+                stateless_credential(id, *rp_id_hash, private_key, 0),
+                key_pair,
+            ));
+        }
+
+        let key_pair = self.crypto.generate_credential_keypair(alg).map_err(|e| {
+            error!(?e, "Couldn't generate a key pair");
+            StatusCode::Ctap1ErrOther
+        })?;
+        let key = match self.storage.credential_key().await? {
+            Some(key) => key,
+            None => {
+                let mut key = Zeroizing::new([0; CREDENTIAL_KEY_LENGTH]);
+                self.rng.fill(key.as_mut()).map_err(|e| {
+                    error!(?e, "Couldn't generate the credential key");
+                    StatusCode::Ctap1ErrOther
+                })?;
+                self.storage.set_credential_key(&key).await?;
+                key
+            }
+        };
+        let private_key = key_pair.to_private_key();
+        let id = CredentialWrapper::new(&key)
+            .wrap(rp_id_hash, &private_key, &self.rng)
+            .map_err(|e| {
+                error!(?e, "Couldn't wrap a credential");
+                StatusCode::Ctap1ErrOther
+            })?;
+        Ok((
+            stateless_credential(id, *rp_id_hash, private_key, 0),
+            key_pair,
+        ))
+    }
+
+    /// Creates a discoverable credential and stores it, replacing the discoverable
+    /// credentials of the same user at the RP. Returns it along with its key pair.
+    pub(super) async fn new_discoverable_credential(
+        &mut self,
+        rp_id: &RpId,
+        user_handle: &UserHandle,
+        alg: COSEAlgorithmIdentifier,
+    ) -> Result<(PublicKeyCredentialSource, RingKeyPair), AuthenticatorError> {
+        let key_pair = self.crypto.generate_credential_keypair(alg).map_err(|e| {
+            error!(?e, "Couldn't generate a key pair");
+            StatusCode::Ctap1ErrOther
+        })?;
+        let mut id = vec![0; DISCOVERABLE_CREDENTIAL_ID_LENGTH];
+        self.rng.fill(&mut id).map_err(|e| {
+            error!(?e, "Couldn't create a credential ID");
+            StatusCode::Ctap1ErrOther
+        })?;
+        let rp_id_hash = RpIdHash::from(rp_id);
+        let credential = PublicKeyCredentialSource {
+            _type: PublicKeyType::PublicKey,
+            id: CredentialId(id),
+            rp_id: Some(rp_id.clone()),
+            rp_id_hash,
+            private_key: key_pair.to_private_key(),
+            user_handle: Some(user_handle.clone()),
+            discoverable: true,
+            sign_count: 0,
+        };
+
+        let replaced = self.storage.get_credentials_for_rp(&rp_id_hash).await?;
+        let transaction = replaced
+            .into_iter()
+            .filter(|cred| cred.discoverable && cred.user_handle.as_ref() == Some(user_handle))
+            .fold(StorageTransaction::new(), |transaction, cred| {
+                debug!(id = ?cred.id, "Replacing credential");
+                transaction.delete(cred.id)
+            })
+            .create(credential.clone());
+        self.storage.apply(transaction).await?;
+        Ok((credential, key_pair))
+    }
+
+    /// Waits for the user to confirm their presence for `purpose`, up to [PRESENCE_TIMEOUT].
+    /// The wait is cancelled by dropping the returned future.
+    pub(super) async fn wait_for_presence(&self, purpose: &str) -> Result<(), AuthenticatorError> {
+        let deadline = tokio::time::Instant::now() + PRESENCE_TIMEOUT;
+        let token = PresenceToken::unique();
+        self.awaiting_presence.store(true, Ordering::SeqCst);
+        let _awaiting = AwaitingPresence {
+            flag: &self.awaiting_presence,
+            frontend: &*self.frontend,
+            token,
+        };
+        while !self.frontend.check_presence(token, purpose) {
+            if tokio::time::Instant::now() >= deadline {
+                debug!(purpose, "The user didn't confirm their presence in time");
+                return Err(StatusCode::Ctap2ErrUserActionTimeout.into());
+            }
+            tokio::time::sleep(PRESENCE_POLL_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Finds a credential by its ID, as long as it is scoped to the given RP, either by
+    /// deriving or unwrapping the key of a stateless credential, or among the stored ones
+    pub(super) async fn find_credential(
+        &self,
+        id: &CredentialId,
+        rp_id_hash: &RpIdHash,
+    ) -> Result<Option<FoundCredential>, StorageError> {
+        let private_key = match self
+            .credential_seed()
+            .and_then(|seed| self.derive_keypair(&seed, id, rp_id_hash))
+        {
+            Some(key_pair) => Some(key_pair.to_private_key()),
+            None => self
+                .credential_wrapper()
+                .await?
+                .and_then(|wrapper| wrapper.unwrap(id, rp_id_hash)),
+        };
+        if let Some(private_key) = private_key {
+            let sign_count = self.state.get().global_sign_count;
+            return Ok(Some(FoundCredential {
+                source: stateless_credential(id.clone(), *rp_id_hash, private_key, sign_count),
+                stateless: true,
+            }));
+        }
+        let credential = self.storage.get_credential(id).await?;
+        Ok(credential
+            .filter(|cred| &cred.rp_id_hash == rp_id_hash)
+            .map(|source| FoundCredential {
+                source,
+                stateless: false,
+            }))
+    }
+
+    /// Increments the signature counter of a credential about to be used, returning the
+    /// credential along with its new counter. Stateless credentials share a global counter.
+    pub(super) async fn increment_sign_count(
+        &mut self,
+        credential: FoundCredential,
+    ) -> Result<PublicKeyCredentialSource, AuthenticatorError> {
+        let mut source = credential.source;
+        source.sign_count = if credential.stateless {
+            self.state
+                .update(|state| {
+                    state.global_sign_count = state.global_sign_count.saturating_add(1);
+                    state.global_sign_count
+                })
+                .await?
+        } else {
+            self.storage.increment_sign_count(&source.id).await?
+        };
+        Ok(source)
+    }
+
+    /// Signs data with the private key of the given credential
+    pub(super) fn sign_with_credential(
+        &self,
+        credential: &PublicKeyCredentialSource,
+        data: &[u8],
+    ) -> Result<Vec<u8>, AuthenticatorError> {
+        let key_pair = RingKeyPair::from_private_key(&credential.private_key).ok_or_else(|| {
+            error!(id = ?credential.id, "Couldn't decode the private key of a credential");
+            StatusCode::Ctap2ErrInvalidCredential
+        })?;
+        Ok(self.crypto.sign_data(&key_pair, data).map_err(|e| {
+            error!(?e, "Couldn't sign with a credential");
+            StatusCode::Ctap1ErrOther
+        })?)
+    }
 }
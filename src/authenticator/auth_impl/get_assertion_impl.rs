@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+use crate::authenticator::{
+    api::{AuthenticatorError, CTAP2ResponseData},
+    command::StatusCode,
+    types::{
+        AuthenticatorData, AuthenticatorDataFlags, AuthenticatorGetAssertionParams,
+        AuthenticatorGetAssertionResponse, ClientDataHash, PublicKeyCredentialDescriptor,
+        PublicKeyCredentialSource, RpIdHash,
+    },
+};
+
+use super::{ctap2_impl::FoundCredential, CTAP2ServiceImpl};
+
+/// How long after the last assertion the remaining credentials may still be asserted by
+/// authenticatorGetNextAssertion
+pub(super) const NEXT_ASSERTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The discoverable credentials an authenticatorGetAssertion request found besides the one
+/// it asserted, to be asserted by authenticatorGetNextAssertion without asking the user again
+pub(super) struct NextAssertions {
+    rp_id_hash: RpIdHash,
+    client_data_hash: ClientDataHash,
+    user_present: bool,
+    /// The credentials yet to be asserted, the next one last
+    credentials: Vec<PublicKeyCredentialSource>,
+    deadline: Instant,
+}
+
+impl CTAP2ServiceImpl {
+    /// Finds the first credential of the list scoped to the RP, e.g, of an allowList
+    pub(super) async fn find_allowed_credential(
+        &self,
+        list: &[PublicKeyCredentialDescriptor],
+        rp_id_hash: &RpIdHash,
+    ) -> Result<Option<FoundCredential>, AuthenticatorError> {
+        for descriptor in list {
+            if let Some(credential) = self.find_credential(&descriptor.id, rp_id_hash).await? {
+                return Ok(Some(credential));
+            }
+        }
+        Ok(None)
+    }
+
+    /// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#sctn-getAssert-authnr-alg
+    pub async fn handle_get_assertion(
+        &mut self,
+        params: AuthenticatorGetAssertionParams,
+    ) -> Result<CTAP2ResponseData, AuthenticatorError> {
+        if params.pin_uv_auth_param.is_some() {
+            error!("PIN/UV auth protocols aren't supported");
+            return Err(StatusCode::Ctap1ErrInvalidParameter.into());
+        }
+        let options = params.options.unwrap_or_default();
+        if options.rk.is_some() {
+            return Err(StatusCode::Ctap2ErrUnsupportedOption.into());
+        }
+        // TODO: user verification isn't implemented, thus assertions never have the UV flag.
+        let user_present = options.up != Some(false);
+
+        let rp_id_hash = RpIdHash::from(&params.rp_id);
+        let allow_list = params.allow_list.unwrap_or_default();
+        let (credential, others) = if allow_list.is_empty() {
+            let mut credentials: Vec<_> = self
+                .storage
+                .get_credentials_for_rp(&rp_id_hash)
+                .await?
+                .into_iter()
+                .filter(|cred| cred.discoverable)
+                .collect();
+            // the most recent credential first, the others by authenticatorGetNextAssertion
+            let credential = credentials.pop().map(|source| FoundCredential {
+                source,
+                stateless: false,
+            });
+            (credential, credentials)
+        } else {
+            let credential = self
+                .find_allowed_credential(&allow_list, &rp_id_hash)
+                .await?;
+            (credential, Vec::new())
+        };
+        let credential = credential.ok_or(StatusCode::Ctap2ErrNoCredentials)?;
+        if user_present {
+            self.wait_for_presence(&format!("an authentication at {}", params.rp_id.0))
+                .await?;
+        }
+        let mut response = self
+            .assert_credential(
+                rp_id_hash,
+                &params.client_data_hash,
+                user_present,
+                credential,
+            )
+            .await?;
+        if !others.is_empty() {
+            response.number_of_credentials = Some(others.len() as u32 + 1);
+            self.next_assertions = Some(NextAssertions {
+                rp_id_hash,
+                client_data_hash: params.client_data_hash,
+                user_present,
+                credentials: others,
+                deadline: Instant::now() + NEXT_ASSERTION_TIMEOUT,
+            });
+        }
+        Ok(CTAP2ResponseData::GetAssertion(response))
+    }
+
+    /// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#authenticatorGetNextAssertion
+    pub(super) async fn handle_get_next_assertion(
+        &mut self,
+        next: Option<NextAssertions>,
+    ) -> Result<CTAP2ResponseData, AuthenticatorError> {
+        let mut next = next.ok_or(StatusCode::Ctap2ErrNotAllowed)?;
+        if Instant::now() >= next.deadline {
+            debug!("The remaining credentials weren't asserted in time");
+            return Err(StatusCode::Ctap2ErrNotAllowed.into());
+        }
+        let source = next
+            .credentials
+            .pop()
+            .ok_or(StatusCode::Ctap2ErrNotAllowed)?;
+        let credential = FoundCredential {
+            source,
+            stateless: false,
+        };
+        let response = self
+            .assert_credential(
+                next.rp_id_hash,
+                &next.client_data_hash,
+                next.user_present,
+                credential,
+            )
+            .await?;
+        if !next.credentials.is_empty() {
+            next.deadline = Instant::now() + NEXT_ASSERTION_TIMEOUT;
+            self.next_assertions = Some(next);
+        }
+        Ok(CTAP2ResponseData::GetAssertion(response))
+    }
+
+    /// Asserts a credential, signing the authenticator data along with the client data hash,
+    /// whose flags tell whether the user confirmed their presence
+    async fn assert_credential(
+        &mut self,
+        rp_id_hash: RpIdHash,
+        client_data_hash: &ClientDataHash,
+        user_present: bool,
+        credential: FoundCredential,
+    ) -> Result<AuthenticatorGetAssertionResponse, AuthenticatorError> {
+        let credential = self.increment_sign_count(credential).await?;
+        debug!(id = ?credential.id, counter = credential.sign_count, "Asserting credential");
+
+        let auth_data = AuthenticatorData {
+            rp_id_hash,
+            flags: AuthenticatorDataFlags::new().with_user_present(user_present),
+            counter: credential.sign_count,
+            attested_cred_data: None,
+            extensions: None,
+        };
+        let mut signed_data = auth_data.to_bytes();
+        signed_data.extend_from_slice(&client_data_hash.0);
+        let signature = self.sign_with_credential(&credential, &signed_data)?;
+
+        Ok(AuthenticatorGetAssertionResponse {
+            credential: PublicKeyCredentialDescriptor::from(credential.id),
+            auth_data,
+            signature,
+            user: credential.user_handle.map(Into::into),
+            number_of_credentials: None,
+        })
+    }
+}
@@ -1,15 +1,445 @@
+use coset::CborSerializable;
+use tracing::{debug, error};
+
 use crate::authenticator::{
     api::{AuthenticatorError, CTAP2ResponseData},
-    types::AuthenticatorMakeCredentialParams,
+    command::StatusCode,
+    crypto::{CryptoKeyPair, CryptoSystem},
+    types::{
+        AttestedCredData, AuthenticatorData, AuthenticatorDataFlags,
+        AuthenticatorMakeCredentialParams, AuthenticatorMakeCredentialResponse,
+        CredentialPublicKey, RpIdHash,
+    },
 };
 
 use super::CTAP2ServiceImpl;
 
 impl CTAP2ServiceImpl {
+    /// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#sctn-makeCred-authnr-alg
     pub async fn handle_make_credential(
         &mut self,
-        _params: AuthenticatorMakeCredentialParams,
+        params: AuthenticatorMakeCredentialParams,
     ) -> Result<CTAP2ResponseData, AuthenticatorError> {
-        todo!()
+        if params.pin_uv_auth_param.is_some() {
+            error!("PIN/UV auth protocols aren't supported");
+            return Err(StatusCode::Ctap1ErrInvalidParameter.into());
+        }
+        let alg = params
+            .pub_key_cred_params
+            .iter()
+            .map(|param| param.alg)
+            .find(|alg| self.crypto.is_supported_alg(*alg).unwrap_or(false))
+            .ok_or(StatusCode::Ctap2ErrUnsupportedAlgorithm)?;
+        let options = params.options.unwrap_or_default();
+        if options.up == Some(false) {
+            return Err(StatusCode::Ctap2ErrInvalidOption.into());
+        }
+        if options.rk == Some(true) && !self.info.options().rk {
+            debug!("Discoverable credentials are disabled by the profile");
+            return Err(StatusCode::Ctap2ErrUnsupportedOption.into());
+        }
+        // TODO: user verification isn't implemented, thus attestations never have the UV flag.
+
+        let rp_id_hash = RpIdHash::from(&params.rp.id);
+        let purpose = format!("a registration at {}", params.rp.id.0);
+        let exclude_list = params.exclude_list.unwrap_or_default();
+        if let Some(excluded) = self
+            .find_allowed_credential(&exclude_list, &rp_id_hash)
+            .await?
+        {
+            debug!(id = ?excluded.source.id, "The authenticator already has an excluded credential");
+            // the user confirms their presence before the RP learns of the credential
+            self.wait_for_presence(&purpose).await?;
+            return Err(StatusCode::Ctap2ErrCredentialExcluded.into());
+        }
+        self.wait_for_presence(&purpose).await?;
+
+        let (credential, key_pair) = if options.rk == Some(true) {
+            self.new_discoverable_credential(&params.rp.id, &params.user.id, alg)
+                .await?
+        } else {
+            self.new_stateless_credential(&rp_id_hash, alg).await?
+        };
+        let credential_public_key = key_pair.to_public_cose_key().to_vec().map_err(|e| {
+            error!(?e, "Couldn't encode the public key of a credential");
+            StatusCode::Ctap1ErrOther
+        })?;
+        debug!(id = ?credential.id, discoverable = credential.discoverable, "Created credential");
+
+        let auth_data = AuthenticatorData {
+            rp_id_hash,
+            flags: AuthenticatorDataFlags::new()
+                .with_user_present(true)
+                .with_attested_data_included(true),
+            counter: credential.sign_count,
+            attested_cred_data: Some(AttestedCredData {
+                aaguid: self.info.aaguid().clone(),
+                credential_id_length: credential.id.0.len() as u16,
+                credential_id: credential.id,
+                credential_public_key: CredentialPublicKey(credential_public_key),
+            }),
+            extensions: None,
+        };
+        let att_stmt = self
+            .attestation_key
+            .packed_statement(&auth_data.to_bytes(), &params.client_data_hash.0)
+            .map_err(|e| {
+                error!(?e, "Couldn't attest a credential");
+                StatusCode::Ctap1ErrOther
+            })?;
+
+        Ok(CTAP2ResponseData::MakeCredential(
+            AuthenticatorMakeCredentialResponse::new(auth_data, att_stmt),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ciborium::value::Value;
+    use coset::CoseKey;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+    use super::*;
+    use crate::{
+        authenticator::{
+            api::CTAP2Command,
+            auth_impl::{
+                get_assertion_impl::NEXT_ASSERTION_TIMEOUT, u2f_impl::uncompressed_p256_point,
+            },
+            crypto::{AttestationKey, COSEAlgorithmIdentifier, ES256},
+            presence::TestFrontend,
+            storage::{memory::InMemoryStorage, state::PersistentState},
+            types::{
+                AuthenticatorGetAssertionParams, AuthenticatorGetInfoResponse,
+                AuthenticatorOptions, ClientDataHash, CredentialId, PublicKeyCredentialDescriptor,
+                PublicKeyCredentialParameters, PublicKeyCredentialRpEntity, PublicKeyType, RpId,
+                UserHandle,
+            },
+        },
+        profile::Profile,
+    };
+
+    fn new_service(frontend: Arc<TestFrontend>) -> CTAP2ServiceImpl {
+        CTAP2ServiceImpl::new(
+            false,
+            AuthenticatorGetInfoResponse::default(),
+            AttestationKey::builtin(),
+            Box::new(InMemoryStorage::new()),
+            PersistentState::in_memory(),
+            frontend,
+        )
+    }
+
+    fn params(
+        user: &[u8],
+        rk: bool,
+        exclude_list: Vec<PublicKeyCredentialDescriptor>,
+    ) -> AuthenticatorMakeCredentialParams {
+        AuthenticatorMakeCredentialParams {
+            client_data_hash: ClientDataHash(vec![0xcc; 32]),
+            rp: PublicKeyCredentialRpEntity {
+                id: RpId("example.com".into()),
+                name: None,
+            },
+            user: UserHandle(user.to_vec()).into(),
+            pub_key_cred_params: vec![PublicKeyCredentialParameters {
+                _type: PublicKeyType::PublicKey,
+                alg: ES256,
+            }],
+            exclude_list: Some(exclude_list),
+            extensions: None,
+            options: Some(AuthenticatorOptions {
+                rk: Some(rk),
+                up: None,
+                uv: None,
+            }),
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+            enterprise_attestation: None,
+        }
+    }
+
+    async fn make_credential(
+        service: &mut CTAP2ServiceImpl,
+        params: AuthenticatorMakeCredentialParams,
+    ) -> Result<Vec<u8>, AuthenticatorError> {
+        service
+            .handle_command(CTAP2Command::MakeCredential(Box::new(params)))
+            .await
+            .map(Vec::from)
+    }
+
+    fn map_value(map: &Value, key: Value) -> &Value {
+        map.as_map()
+            .unwrap()
+            .iter()
+            .find(|(k, _)| k == &key)
+            .map(|(_, v)| v)
+            .unwrap()
+    }
+
+    /// Parses a successful response, returning the authenticator data, the ID of the
+    /// credential and its public key
+    fn parse_response(response: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        assert_eq!(response[0], StatusCode::Ctap1ErrSuccess as u8);
+        let value: Value = ciborium::de::from_reader(&response[1..]).unwrap();
+        assert_eq!(map_value(&value, 1.into()), &Value::from("packed"));
+        let auth_data = map_value(&value, 2.into()).as_bytes().unwrap().clone();
+        let att_stmt = map_value(&value, 3.into());
+        assert_eq!(map_value(att_stmt, "alg".into()), &Value::from(ES256.0));
+        assert!(map_value(att_stmt, "sig".into()).is_bytes());
+        assert!(map_value(att_stmt, "x5c".into()).is_array());
+
+        let flags = auth_data[32];
+        assert_eq!(flags, 0x41, "user present and attested data included");
+        let (aaguid, rest) = auth_data[37..].split_at(16);
+        assert_eq!(aaguid, AuthenticatorGetInfoResponse::default().aaguid().0);
+        let (length, rest) = rest.split_at(2);
+        let (id, public_key) = rest.split_at(u16::from_be_bytes([length[0], length[1]]) as usize);
+        let public_key = CoseKey::from_slice(public_key).unwrap();
+        let public_key = uncompressed_p256_point(&public_key).unwrap();
+        (auth_data.clone(), id.to_vec(), public_key)
+    }
+
+    async fn get_assertion(
+        service: &mut CTAP2ServiceImpl,
+        allow_list: Option<Vec<PublicKeyCredentialDescriptor>>,
+    ) -> Result<CTAP2ResponseData, AuthenticatorError> {
+        get_assertion_with(service, allow_list, None).await
+    }
+
+    async fn get_assertion_with(
+        service: &mut CTAP2ServiceImpl,
+        allow_list: Option<Vec<PublicKeyCredentialDescriptor>>,
+        up: Option<bool>,
+    ) -> Result<CTAP2ResponseData, AuthenticatorError> {
+        let params = AuthenticatorGetAssertionParams {
+            rp_id: RpId("example.com".into()),
+            client_data_hash: ClientDataHash(vec![0xdd; 32]),
+            allow_list,
+            extensions: None,
+            options: Some(AuthenticatorOptions {
+                rk: None,
+                up,
+                uv: None,
+            }),
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        service
+            .handle_command(CTAP2Command::GetAssertion(Box::new(params)))
+            .await
+    }
+
+    async fn get_next_assertion(
+        service: &mut CTAP2ServiceImpl,
+    ) -> Result<CTAP2ResponseData, AuthenticatorError> {
+        service.handle_command(CTAP2Command::GetNextAssertion).await
+    }
+
+    #[tokio::test]
+    async fn test_make_credential() {
+        let frontend = Arc::new(TestFrontend::default());
+        let mut service = new_service(frontend.clone());
+        let response = make_credential(&mut service, params(b"user", false, vec![]))
+            .await
+            .unwrap();
+        let (auth_data, id, public_key) = parse_response(&response);
+        assert_eq!(frontend.presence_checks(), 1);
+        assert_eq!(service.stats().await.unwrap().credentials, 0);
+        assert_eq!(
+            &auth_data[..32],
+            RpIdHash::from(&RpId("example.com".into())).0
+        );
+
+        let allow_list = vec![CredentialId(id.clone()).into()];
+        let res = match get_assertion(&mut service, Some(allow_list)).await {
+            Ok(CTAP2ResponseData::GetAssertion(res)) => res,
+            other => panic!("Unexpected response {:?}", other),
+        };
+        assert_eq!(res.credential.id.0, id);
+        assert_eq!(frontend.presence_checks(), 2);
+        let mut signed_data = res.auth_data.to_bytes();
+        assert_eq!(signed_data[32], 0x01, "user present");
+        signed_data.extend_from_slice(&[0xdd; 32]);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &public_key)
+            .verify(&signed_data, &res.signature)
+            .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_assertion_presence() {
+        let frontend = Arc::new(TestFrontend::default());
+        let mut service = new_service(frontend.clone());
+        let response = make_credential(&mut service, params(b"user", false, vec![]))
+            .await
+            .unwrap();
+        let (_, id, _) = parse_response(&response);
+        let allow_list = || Some(vec![CredentialId(id.clone()).into()]);
+
+        // no signature without the user's presence
+        frontend.set_present(false);
+        let res = get_assertion_with(&mut service, allow_list(), None).await;
+        assert!(matches!(
+            res,
+            Err(AuthenticatorError::CTAPErrorStatus(
+                StatusCode::Ctap2ErrUserActionTimeout
+            ))
+        ));
+        // every wait ends its request, whether the user confirmed it or not
+        assert_eq!(frontend.ended_presence_requests(), 2);
+
+        // unless the RP doesn't ask for it
+        let checks = frontend.presence_checks();
+        let res = match get_assertion_with(&mut service, allow_list(), Some(false)).await {
+            Ok(CTAP2ResponseData::GetAssertion(res)) => res,
+            other => panic!("Unexpected response {:?}", other),
+        };
+        assert_eq!(frontend.presence_checks(), checks);
+        assert!(!res.auth_data.flags.user_present());
+    }
+
+    #[tokio::test]
+    async fn test_exclude_list() {
+        let frontend = Arc::new(TestFrontend::default());
+        let mut service = new_service(frontend.clone());
+        let response = make_credential(&mut service, params(b"user", false, vec![]))
+            .await
+            .unwrap();
+        let (_, id, _) = parse_response(&response);
+
+        let unknown = CredentialId(vec![1, 2, 3, 4]).into();
+        let excluded = vec![unknown, CredentialId(id).into()];
+        let res = make_credential(&mut service, params(b"user", false, excluded)).await;
+        assert!(matches!(
+            res,
+            Err(AuthenticatorError::CTAPErrorStatus(
+                StatusCode::Ctap2ErrCredentialExcluded
+            ))
+        ));
+        assert_eq!(frontend.presence_checks(), 2);
+
+        let unknown = vec![CredentialId(vec![1, 2, 3, 4]).into()];
+        make_credential(&mut service, params(b"user", false, unknown))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_discoverable_credential() {
+        let mut service = new_service(Arc::new(TestFrontend::default()));
+        make_credential(&mut service, params(b"first", true, vec![]))
+            .await
+            .unwrap();
+        make_credential(&mut service, params(b"second", true, vec![]))
+            .await
+            .unwrap();
+        // replaces the first credential of the user
+        let response = make_credential(&mut service, params(b"first", true, vec![]))
+            .await
+            .unwrap();
+        let (_, id, _) = parse_response(&response);
+        assert_eq!(service.stats().await.unwrap().credentials, 2);
+
+        let res = match get_assertion(&mut service, None).await {
+            Ok(CTAP2ResponseData::GetAssertion(res)) => res,
+            other => panic!("Unexpected response {:?}", other),
+        };
+        assert_eq!(res.credential.id.0, id);
+        assert_eq!(
+            res.user.map(|user| user.id),
+            Some(UserHandle(b"first".to_vec()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_next_assertion() {
+        let frontend = Arc::new(TestFrontend::default());
+        let mut service = new_service(frontend.clone());
+        for user in [b"first", b"other", b"third"] {
+            make_credential(&mut service, params(user, true, vec![]))
+                .await
+                .unwrap();
+        }
+        let user = |res: CTAP2ResponseData| match res {
+            CTAP2ResponseData::GetAssertion(res) => {
+                assert!(res.auth_data.flags.user_present());
+                res.user.unwrap().id
+            }
+            other => panic!("Unexpected response {:?}", other),
+        };
+
+        // the most recent credential first, along with the number of credentials
+        let res = get_assertion(&mut service, None).await.unwrap();
+        let response = Vec::from(res);
+        let value: Value = ciborium::de::from_reader(&response[1..]).unwrap();
+        assert_eq!(map_value(&value, 5.into()), &Value::from(3));
+        let checks = frontend.presence_checks();
+        // the others without asking the user again
+        let res = get_next_assertion(&mut service).await.unwrap();
+        assert_eq!(user(res), UserHandle(b"other".to_vec()));
+        let res = get_next_assertion(&mut service).await.unwrap();
+        assert_eq!(user(res), UserHandle(b"first".to_vec()));
+        assert_eq!(frontend.presence_checks(), checks);
+        assert!(matches!(
+            get_next_assertion(&mut service).await,
+            Err(AuthenticatorError::CTAPErrorStatus(
+                StatusCode::Ctap2ErrNotAllowed
+            ))
+        ));
+
+        // any other command discards them
+        let res = get_assertion(&mut service, None).await.unwrap();
+        assert_eq!(user(res), UserHandle(b"third".to_vec()));
+        service.handle_command(CTAP2Command::GetInfo).await.unwrap();
+        assert!(get_next_assertion(&mut service).await.is_err());
+
+        // as does waiting too long
+        get_assertion(&mut service, None).await.unwrap();
+        tokio::time::advance(NEXT_ASSERTION_TIMEOUT).await;
+        assert!(get_next_assertion(&mut service).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_algorithm() {
+        let mut service = new_service(Arc::new(TestFrontend::default()));
+        let mut params = params(b"user", false, vec![]);
+        params.pub_key_cred_params[0].alg = COSEAlgorithmIdentifier(-257);
+        let res = make_credential(&mut service, params).await;
+        assert!(matches!(
+            res,
+            Err(AuthenticatorError::CTAPErrorStatus(
+                StatusCode::Ctap2ErrUnsupportedAlgorithm
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_discoverable_credentials_disabled() {
+        let frontend = Arc::new(TestFrontend::default());
+        let mut service = CTAP2ServiceImpl::new(
+            false,
+            Profile::builtin("ctap2.0-minimal").unwrap().get_info(false),
+            AttestationKey::builtin(),
+            Box::new(InMemoryStorage::new()),
+            PersistentState::in_memory(),
+            frontend.clone(),
+        );
+        let res = make_credential(&mut service, params(b"user", true, vec![])).await;
+        assert!(matches!(
+            res,
+            Err(AuthenticatorError::CTAPErrorStatus(
+                StatusCode::Ctap2ErrUnsupportedOption
+            ))
+        ));
+        assert_eq!(frontend.presence_checks(), 0);
+        assert_eq!(service.stats().await.unwrap().credentials, 0);
+
+        make_credential(&mut service, params(b"user", false, vec![]))
+            .await
+            .unwrap();
     }
 }
@@ -1,3 +1,5 @@
 mod ctap2_impl;
+mod get_assertion_impl;
 mod make_credential_impl;
-pub use ctap2_impl::CTAP2ServiceImpl;
+mod u2f_impl;
+pub use ctap2_impl::{AuthenticatorStats, CTAP2ServiceImpl};
@@ -0,0 +1,444 @@
+use ciborium::value::Value;
+use coset::{iana, CoseKey, Label};
+use tracing::{debug, error, trace};
+
+use crate::authenticator::{
+    apdu::{CommandApdu, ResponseApdu, StatusWord},
+    api::CTAP2ResponseData,
+    crypto::{CryptoKeyPair, ES256},
+    presence::PresenceToken,
+    types::{
+        U2FAuthenticateControl, U2FAuthenticateRequest, U2FAuthenticateResponse, U2FInstruction,
+        U2FRegisterRequest, U2FRegisterResponse, U2F_VERSION,
+    },
+};
+
+use super::CTAP2ServiceImpl;
+
+/// The user presence byte of an authentication response, when the user was present
+const U2F_USER_PRESENT: u8 = 0x01;
+
+/// The byte preceding the data signed by the attestation key upon registration
+const U2F_REGISTER_RESERVED: u8 = 0x00;
+
+impl CTAP2ServiceImpl {
+    /// Handles a U2F request APDU, returning the response APDU
+    pub async fn handle_u2f(&mut self, apdu: &[u8]) -> CTAP2ResponseData {
+        let response = match CommandApdu::parse(apdu) {
+            Ok(request) => self.handle_u2f_request(request).await,
+            Err(e) => {
+                error!(?e, "Received a malformed U2F APDU");
+                StatusWord::from(e).into()
+            }
+        };
+        trace!(?response, "U2F response");
+        CTAP2ResponseData::U2F(response.into())
+    }
+
+    async fn handle_u2f_request(&mut self, request: CommandApdu) -> ResponseApdu {
+        if !self.u2f_enabled {
+            return StatusWord::InsNotSupported.into();
+        }
+        if request.cla != 0 {
+            return StatusWord::ClaNotSupported.into();
+        }
+        let result = match U2FInstruction::try_from(request.ins) {
+            Ok(U2FInstruction::Register) => self.u2f_register(&request.data).await,
+            Ok(U2FInstruction::Authenticate) => {
+                self.u2f_authenticate(request.p1, &request.data).await
+            }
+            Ok(U2FInstruction::Version) if request.data.is_empty() => {
+                Ok(U2F_VERSION.as_bytes().to_owned())
+            }
+            Ok(U2FInstruction::Version) => Err(StatusWord::WrongLength),
+            Err(_) => Err(StatusWord::InsNotSupported),
+        };
+        match result {
+            Ok(data) => ResponseApdu::success(data),
+            Err(status) => status.into(),
+        }
+    }
+
+    /// [See more](https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#registration-messages)
+    async fn u2f_register(&mut self, data: &[u8]) -> Result<Vec<u8>, StatusWord> {
+        let request = U2FRegisterRequest::try_from(data)?;
+        let token = PresenceToken::of_request(data);
+        if !self.frontend.check_presence(token, "a U2F registration") {
+            return Err(StatusWord::ConditionsNotSatisfied);
+        }
+        // U2F credentials are never discoverable, thus they're not stored.
+        let (credential, key_pair) = self
+            .new_stateless_credential(&request.application, ES256)
+            .await
+            .map_err(internal_error)?;
+        let public_key = uncompressed_p256_point(&key_pair.to_public_cose_key())
+            .ok_or(StatusWord::NoPreciseDiagnosis)?;
+        let key_handle = credential.id;
+
+        let mut signed_data = vec![U2F_REGISTER_RESERVED];
+        signed_data.extend_from_slice(&request.application.0);
+        signed_data.extend_from_slice(&request.challenge);
+        signed_data.extend_from_slice(&key_handle.0);
+        signed_data.extend_from_slice(&public_key);
+        let signature = self.attestation_key.sign(&signed_data).map_err(|e| {
+            error!(?e, "Couldn't sign a U2F registration");
+            StatusWord::NoPreciseDiagnosis
+        })?;
+
+        debug!(id = ?key_handle, "Registered U2F credential");
+        // a U2F registration only carries the attestation certificate, without its issuers
+        Ok(U2FRegisterResponse {
+            public_key,
+            key_handle,
+            attestation_cert: self.attestation_key.certificate().to_owned(),
+            signature,
+        }
+        .into())
+    }
+
+    /// [See more](https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#authentication-messages)
+    async fn u2f_authenticate(&mut self, control: u8, data: &[u8]) -> Result<Vec<u8>, StatusWord> {
+        let control =
+            U2FAuthenticateControl::try_from(control).map_err(|_| StatusWord::WrongData)?;
+        let request = U2FAuthenticateRequest::try_from(data)?;
+        let credential = self
+            .find_credential(&request.key_handle, &request.application)
+            .await
+            .map_err(internal_error)?
+            .ok_or(StatusWord::WrongData)?;
+        let user_presence = match control {
+            // a key handle of ours requires user presence to be signed
+            U2FAuthenticateControl::CheckOnly => return Err(StatusWord::ConditionsNotSatisfied),
+            // the client polls until the user confirms their presence
+            U2FAuthenticateControl::EnforceUserPresenceAndSign => {
+                let token = PresenceToken::of_request(data);
+                if !self.frontend.check_presence(token, "a U2F authentication") {
+                    return Err(StatusWord::ConditionsNotSatisfied);
+                }
+                U2F_USER_PRESENT
+            }
+            U2FAuthenticateControl::DontEnforceUserPresenceAndSign => 0,
+        };
+        let credential = self
+            .increment_sign_count(credential)
+            .await
+            .map_err(internal_error)?;
+
+        let mut signed_data = request.application.0.to_vec();
+        signed_data.push(user_presence);
+        signed_data.extend_from_slice(&credential.sign_count.to_be_bytes());
+        signed_data.extend_from_slice(&request.challenge);
+        let signature = self
+            .sign_with_credential(&credential, &signed_data)
+            .map_err(|_| StatusWord::NoPreciseDiagnosis)?;
+
+        debug!(id = ?credential.id, counter = credential.sign_count, "Authenticated via U2F");
+        Ok(U2FAuthenticateResponse {
+            user_presence,
+            counter: credential.sign_count,
+            signature,
+        }
+        .into())
+    }
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> StatusWord {
+    error!(?e, "Couldn't access the U2F credentials");
+    StatusWord::NoPreciseDiagnosis
+}
+
+/// Encodes the public key of a P-256 COSE key as an uncompressed curve point, which is
+/// how U2F represents public keys.
+pub(super) fn uncompressed_p256_point(key: &CoseKey) -> Option<Vec<u8>> {
+    let coordinate = |param: iana::Ec2KeyParameter| {
+        key.params
+            .iter()
+            .find_map(|(label, value)| match (label, value) {
+                (Label::Int(label), Value::Bytes(bytes)) if *label == param as i64 => Some(bytes),
+                _ => None,
+            })
+    };
+    let mut point = vec![0x04];
+    point.extend_from_slice(coordinate(iana::Ec2KeyParameter::X)?);
+    point.extend_from_slice(coordinate(iana::Ec2KeyParameter::Y)?);
+    Some(point)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ring::{
+        digest::{digest, SHA256},
+        signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1},
+    };
+
+    use super::*;
+    use crate::authenticator::{
+        api::{AuthenticatorError, CTAP2Command},
+        command::StatusCode,
+        crypto::{AttestationKey, DERIVED_CREDENTIAL_V1, SEED_LENGTH},
+        presence::TestFrontend,
+        storage::{
+            memory::InMemoryStorage,
+            sqlite::SqliteStorage,
+            state::{PersistentState, SecretBytes},
+            store::Storage,
+        },
+        types::{
+            AuthenticatorGetAssertionParams, AuthenticatorGetInfoResponse, ClientDataHash,
+            CredentialId, RpId,
+        },
+    };
+    use crate::test_util::temp_dir;
+
+    const CHALLENGE: [u8; 32] = [0xcc; 32];
+
+    fn u2f(service: &mut CTAP2ServiceImpl, ins: u8, p1: u8, data: &[u8]) -> (Vec<u8>, u16) {
+        let mut apdu = vec![0, ins, p1, 0, 0];
+        apdu.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        apdu.extend_from_slice(data);
+        apdu.extend_from_slice(&[0, 0]);
+        match futures::executor::block_on(service.handle_u2f(&apdu)) {
+            CTAP2ResponseData::U2F(mut res) => {
+                let sw = res.split_off(res.len() - 2);
+                (res, u16::from_be_bytes([sw[0], sw[1]]))
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    fn new_service(u2f_enabled: bool) -> CTAP2ServiceImpl {
+        service_with_frontend(u2f_enabled, Arc::new(TestFrontend::default()))
+    }
+
+    fn service_with_frontend(u2f_enabled: bool, frontend: Arc<TestFrontend>) -> CTAP2ServiceImpl {
+        let info = AuthenticatorGetInfoResponse::default().with_u2f();
+        CTAP2ServiceImpl::new(
+            u2f_enabled,
+            info,
+            AttestationKey::builtin(),
+            Box::new(InMemoryStorage::new()),
+            PersistentState::in_memory(),
+            frontend,
+        )
+    }
+
+    fn service_with_storage(storage: Box<dyn Storage>, state: PersistentState) -> CTAP2ServiceImpl {
+        let info = AuthenticatorGetInfoResponse::default().with_u2f();
+        CTAP2ServiceImpl::new(
+            true,
+            info,
+            AttestationKey::builtin(),
+            storage,
+            state,
+            Arc::new(TestFrontend::default()),
+        )
+    }
+
+    /// A service deriving the keys of its credentials from the given seed
+    fn seeded_service(seed: [u8; SEED_LENGTH]) -> CTAP2ServiceImpl {
+        let mut state = PersistentState::in_memory();
+        futures::executor::block_on(
+            state.update(|state| state.seed = Some(SecretBytes::from(&seed[..]))),
+        )
+        .unwrap();
+        service_with_storage(Box::new(InMemoryStorage::new()), state)
+    }
+
+    fn application(rp_id: &str) -> Vec<u8> {
+        digest(&SHA256, rp_id.as_bytes()).as_ref().to_owned()
+    }
+
+    /// Registers a U2F credential, returning its public key and key handle
+    fn register(service: &mut CTAP2ServiceImpl, rp_id: &str) -> (Vec<u8>, Vec<u8>) {
+        let mut data = CHALLENGE.to_vec();
+        data.extend_from_slice(&application(rp_id));
+        let (res, sw) = u2f(service, 0x01, 0, &data);
+        assert_eq!(sw, 0x9000);
+        assert_eq!(res[0], 0x05);
+        let public_key = res[1..66].to_vec();
+        let key_handle_length = res[66] as usize;
+        let key_handle = res[67..67 + key_handle_length].to_vec();
+        let cert_and_signature = &res[67 + key_handle_length..];
+        let cert = service.attestation_key.certificate();
+        assert_eq!(&cert_and_signature[..cert.len()], cert);
+        (public_key, key_handle)
+    }
+
+    fn authenticate_data(rp_id: &str, key_handle: &[u8]) -> Vec<u8> {
+        let mut data = CHALLENGE.to_vec();
+        data.extend_from_slice(&application(rp_id));
+        data.push(key_handle.len() as u8);
+        data.extend_from_slice(key_handle);
+        data
+    }
+
+    #[test]
+    fn test_version() {
+        let mut service = new_service(true);
+        assert_eq!(
+            u2f(&mut service, 0x03, 0, &[]),
+            (b"U2F_V2".to_vec(), 0x9000)
+        );
+        assert_eq!(u2f(&mut service, 0x42, 0, &[]), (vec![], 0x6D00));
+
+        let mut service = new_service(false);
+        assert_eq!(u2f(&mut service, 0x03, 0, &[]), (vec![], 0x6D00));
+    }
+
+    #[test]
+    fn test_register_and_authenticate() {
+        let mut service = new_service(true);
+        let (public_key, key_handle) = register(&mut service, "example.com");
+        let data = authenticate_data("example.com", &key_handle);
+
+        assert_eq!(u2f(&mut service, 0x02, 0x07, &data), (vec![], 0x6985));
+        let other_rp_data = authenticate_data("example.org", &key_handle);
+        assert_eq!(
+            u2f(&mut service, 0x02, 0x07, &other_rp_data),
+            (vec![], 0x6A80)
+        );
+        assert_eq!(u2f(&mut service, 0x02, 0x03, &data[1..]), (vec![], 0x6700));
+
+        for (control, user_presence, counter) in [(0x03, 0x01, 1u32), (0x08, 0x00, 2u32)] {
+            let (res, sw) = u2f(&mut service, 0x02, control, &data);
+            assert_eq!(sw, 0x9000);
+            assert_eq!(res[0], user_presence);
+            assert_eq!(&res[1..5], &counter.to_be_bytes());
+
+            let mut signed_data = application("example.com");
+            signed_data.extend_from_slice(&res[..5]);
+            signed_data.extend_from_slice(&CHALLENGE);
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &public_key)
+                .verify(&signed_data, &res[5..])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_credentials_survive_restart() {
+        // the SQLite storage writes on the blocking threads of a runtime
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let _runtime = runtime.enter();
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.db");
+        let storage = Box::new(SqliteStorage::open(&path).unwrap());
+        let mut service = service_with_storage(storage, PersistentState::in_memory());
+        let (public_key, key_handle) = register(&mut service, "example.com");
+        drop(service);
+
+        // the credential key is kept along with the credentials, even without a state file
+        let storage = Box::new(SqliteStorage::open(&path).unwrap());
+        let mut restarted = service_with_storage(storage, PersistentState::in_memory());
+        let data = authenticate_data("example.com", &key_handle);
+        let (res, sw) = u2f(&mut restarted, 0x02, 0x03, &data);
+        assert_eq!(sw, 0x9000);
+        let mut signed_data = application("example.com");
+        signed_data.extend_from_slice(&res[..5]);
+        signed_data.extend_from_slice(&CHALLENGE);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &public_key)
+            .verify(&signed_data, &res[5..])
+            .unwrap();
+
+        // a reset invalidates them
+        futures::executor::block_on(restarted.reset_device()).unwrap();
+        assert_eq!(u2f(&mut restarted, 0x02, 0x07, &data), (vec![], 0x6A80));
+    }
+
+    #[test]
+    fn test_user_presence_is_required() {
+        let frontend = Arc::new(TestFrontend::default());
+        let mut service = service_with_frontend(true, frontend.clone());
+        let mut data = CHALLENGE.to_vec();
+        data.extend_from_slice(&application("example.com"));
+
+        frontend.set_present(false);
+        assert_eq!(u2f(&mut service, 0x01, 0, &data), (vec![], 0x6985));
+        assert_eq!(frontend.presence_checks(), 1);
+        frontend.set_present(true);
+        let (_, key_handle) = register(&mut service, "example.com");
+        let data = authenticate_data("example.com", &key_handle);
+
+        frontend.set_present(false);
+        assert_eq!(u2f(&mut service, 0x02, 0x03, &data), (vec![], 0x6985));
+        // not enforcing presence doesn't ask the user
+        let checks = frontend.presence_checks();
+        let (res, sw) = u2f(&mut service, 0x02, 0x08, &data);
+        assert_eq!((res[0], sw), (0x00, 0x9000));
+        assert_eq!(frontend.presence_checks(), checks);
+
+        frontend.set_present(true);
+        let (res, sw) = u2f(&mut service, 0x02, 0x03, &data);
+        assert_eq!((res[0], sw), (0x01, 0x9000));
+        // the signature counter only counts signatures
+        assert_eq!(&res[1..5], &2u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_u2f_credential_works_with_get_assertion() {
+        let mut service = new_service(true);
+        let (public_key, key_handle) = register(&mut service, "example.com");
+        let params = |rp_id: &str| AuthenticatorGetAssertionParams {
+            rp_id: RpId(rp_id.into()),
+            client_data_hash: ClientDataHash(vec![0xdd; 32]),
+            allow_list: Some(vec![CredentialId(key_handle.clone()).into()]),
+            extensions: None,
+            options: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+
+        let res = futures::executor::block_on(
+            service.handle_command(CTAP2Command::GetAssertion(Box::new(params("example.org")))),
+        );
+        assert!(matches!(
+            res,
+            Err(AuthenticatorError::CTAPErrorStatus(
+                StatusCode::Ctap2ErrNoCredentials
+            ))
+        ));
+
+        let res = futures::executor::block_on(
+            service.handle_command(CTAP2Command::GetAssertion(Box::new(params("example.com")))),
+        );
+        let res = match res {
+            Ok(CTAP2ResponseData::GetAssertion(res)) => res,
+            other => panic!("Unexpected response {:?}", other),
+        };
+        assert_eq!(res.credential.id.0, key_handle);
+        let mut signed_data = res.auth_data.to_bytes();
+        assert_eq!(&signed_data[..32], application("example.com").as_slice());
+        assert_eq!(&signed_data[33..37], &1u32.to_be_bytes());
+        signed_data.extend_from_slice(&[0xdd; 32]);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &public_key)
+            .verify(&signed_data, &res.signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_seed_restores_credentials() {
+        let mut service = seeded_service([7; SEED_LENGTH]);
+        let (public_key, key_handle) = register(&mut service, "example.com");
+        assert_eq!(key_handle[0], DERIVED_CREDENTIAL_V1);
+        let data = authenticate_data("example.com", &key_handle);
+
+        // e.g, on a rebuilt machine, whose state was restored from the recovery phrase
+        let mut restored = seeded_service([7; SEED_LENGTH]);
+        let (res, sw) = u2f(&mut restored, 0x02, 0x03, &data);
+        assert_eq!(sw, 0x9000);
+        let mut signed_data = application("example.com");
+        signed_data.extend_from_slice(&res[..5]);
+        signed_data.extend_from_slice(&CHALLENGE);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &public_key)
+            .verify(&signed_data, &res[5..])
+            .unwrap();
+
+        let mut other_seed = seeded_service([8; SEED_LENGTH]);
+        assert_eq!(u2f(&mut other_seed, 0x02, 0x07, &data), (vec![], 0x6A80));
+        let mut unseeded = new_service(true);
+        assert_eq!(u2f(&mut unseeded, 0x02, 0x07, &data), (vec![], 0x6A80));
+    }
+}
@@ -0,0 +1,154 @@
+use ring::{
+    error::KeyRejected,
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+
+use crate::authenticator::types::{
+    AttestationCert, AttestationStatement, CaCert, PackedAttestationStatement, X5cElement,
+};
+
+use super::{RingError, ES256};
+
+/// PKCS#8 encoded P-256 private key of the built-in attestation certificate
+const BUILTIN_ATTESTATION_KEY: &[u8] = include_bytes!("attestation/key.der");
+
+/// DER encoded, self-signed attestation certificate, generated via
+/// `openssl req -new -x509 -key key.pem -days 7300 -config cert.cnf -sha256 -outform DER`
+const BUILTIN_ATTESTATION_CERT: &[u8] = include_bytes!("attestation/cert.der");
+
+/// A key used for attesting newly created credentials, along with its certificate.
+///
+/// Note that the built-in key is embedded in the binary and thus public, so an attestation
+/// it makes only identifies the authenticator model, not the authenticator itself.
+pub struct AttestationKey {
+    key_pair: EcdsaKeyPair,
+    /// The certificate of the key, followed by the certificates of its issuers
+    chain: Vec<Vec<u8>>,
+}
+
+impl AttestationKey {
+    pub fn builtin() -> Self {
+        Self::from_pkcs8(
+            BUILTIN_ATTESTATION_KEY,
+            BUILTIN_ATTESTATION_CERT.to_owned(),
+            Vec::new(),
+        )
+        .expect("Built-in attestation key must be valid")
+    }
+
+    /// Loads a PKCS#8 encoded P-256 private key along with its DER encoded certificate
+    /// (whose public key isn't verified) and the certificates of its issuers, if any.
+    pub fn from_pkcs8(
+        key: &[u8],
+        certificate: Vec<u8>,
+        issuers: Vec<Vec<u8>>,
+    ) -> Result<Self, KeyRejected> {
+        let mut chain = vec![certificate];
+        chain.extend(issuers);
+        Ok(AttestationKey {
+            key_pair: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, key)?,
+            chain,
+        })
+    }
+
+    /// The DER encoded X.509 certificate of the attestation key
+    pub fn certificate(&self) -> &[u8] {
+        &self.chain[0]
+    }
+
+    /// The DER encoded certificate of the attestation key, followed by those of its issuers
+    pub fn chain(&self) -> &[Vec<u8>] {
+        &self.chain
+    }
+
+    /// Attests a new credential in the packed format, signing its authenticator data and
+    /// the client data hash, with the whole certificate chain as x5c.
+    /// [See more](https://www.w3.org/TR/webauthn-2/#sctn-packed-attestation)
+    pub fn packed_statement(
+        &self,
+        auth_data: &[u8],
+        client_data_hash: &[u8],
+    ) -> Result<AttestationStatement, RingError> {
+        let mut signed_data = auth_data.to_vec();
+        signed_data.extend_from_slice(client_data_hash);
+        let x5c = self
+            .chain()
+            .iter()
+            .enumerate()
+            .map(|(i, cert)| match i {
+                0 => X5cElement::AttestationCert(AttestationCert(cert.clone())),
+                _ => X5cElement::CaCert(CaCert(cert.clone())),
+            })
+            .collect();
+        Ok(AttestationStatement::Packed {
+            att_stmt: PackedAttestationStatement {
+                alg: ES256,
+                sig: self.sign(&signed_data)?,
+                x5c,
+            },
+        })
+    }
+
+    /// Signs the given data via ECDSA over P-256 with SHA-256, returning an ASN.1 DER signature
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, RingError> {
+        let rng = SystemRandom::new();
+        let signature = self
+            .key_pair
+            .sign(&rng, data)
+            .map_err(RingError::RingUnspecified)?;
+        Ok(signature.as_ref().to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+    use super::*;
+
+    #[test]
+    fn test_builtin_key_matches_certificate() {
+        let key = AttestationKey::builtin();
+        let public_key = key.key_pair.public_key().as_ref();
+        assert!(key
+            .certificate()
+            .windows(public_key.len())
+            .any(|window| window == public_key));
+
+        let signature = key.sign(b"softauth").unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key)
+            .verify(b"softauth", &signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_packed_statement_includes_chain() {
+        let ca = vec![0xca; 16];
+        let key = AttestationKey::from_pkcs8(
+            BUILTIN_ATTESTATION_KEY,
+            BUILTIN_ATTESTATION_CERT.to_owned(),
+            vec![ca.clone()],
+        )
+        .unwrap();
+        assert_eq!(
+            key.chain(),
+            [BUILTIN_ATTESTATION_CERT.to_owned(), ca.clone()]
+        );
+
+        let AttestationStatement::Packed { att_stmt } =
+            key.packed_statement(b"auth data", &[0xdd; 32]).unwrap();
+        assert_eq!(att_stmt.alg, ES256);
+        assert!(matches!(
+            &att_stmt.x5c[..],
+            [X5cElement::AttestationCert(AttestationCert(cert)), X5cElement::CaCert(CaCert(issuer))]
+                if cert == BUILTIN_ATTESTATION_CERT && issuer == &ca
+        ));
+
+        let mut signed_data = b"auth data".to_vec();
+        signed_data.extend_from_slice(&[0xdd; 32]);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key.key_pair.public_key().as_ref())
+            .verify(&signed_data, &att_stmt.sig)
+            .unwrap();
+    }
+}
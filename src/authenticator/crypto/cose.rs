@@ -14,3 +14,6 @@ pub struct CoseKey {
     //  The COSE_Key-encoded credential public key MUST contain the "alg" parameter and MUST NOT contain any other OPTIONAL parameters
     alg: COSEAlgorithmIdentifier,
 }
+
+/// ECDSA over P-256 with SHA-256, the only algorithm usable by U2F credentials
+pub const ES256: COSEAlgorithmIdentifier = COSEAlgorithmIdentifier(-7);
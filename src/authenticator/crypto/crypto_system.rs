@@ -1,11 +1,26 @@
 use std::collections::HashSet;
 
 use super::COSEAlgorithmIdentifier;
+use crate::authenticator::types::CredentialPrivateKey;
 use coset::CoseKey;
 use serde::{de::DeserializeOwned, Serialize};
 
 pub trait CryptoKeyPair: Sized + Serialize + DeserializeOwned {
     fn to_public_cose_key(&self) -> CoseKey;
+
+    /// Encodes the key pair as the private key of a credential source
+    fn to_private_key(&self) -> CredentialPrivateKey {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(self, &mut bytes)
+            .expect("Serializing a key pair into a vector cannot fail");
+        CredentialPrivateKey(bytes)
+    }
+
+    /// Decodes a key pair from the private key of a credential source, returning `None` if
+    /// the private key wasn't created by this kind of key pair.
+    fn from_private_key(private_key: &CredentialPrivateKey) -> Option<Self> {
+        ciborium::de::from_reader(private_key.0.as_slice()).ok()
+    }
 }
 
 /// This trait encompasses the asymetric cryptographic operations required for the authenticator - creating key pairs and signing data with them,
@@ -25,5 +40,13 @@ pub trait CryptoSystem {
         alg: COSEAlgorithmIdentifier,
     ) -> Result<Self::KeyPair, Self::Error>;
 
+    /// Creates a key pair deterministically from 32 bytes of secret key material, e.g, derived
+    /// from a seed, such that the same material always yields the same key pair
+    fn derive_credential_keypair(
+        &self,
+        alg: COSEAlgorithmIdentifier,
+        material: &[u8; 32],
+    ) -> Result<Self::KeyPair, Self::Error>;
+
     fn sign_data(&self, keypair: &Self::KeyPair, data: &[u8]) -> Result<Vec<u8>, Self::Error>;
 }
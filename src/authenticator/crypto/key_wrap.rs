@@ -0,0 +1,129 @@
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    error::Unspecified,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::authenticator::types::{CredentialId, CredentialPrivateKey, RpIdHash};
+
+/// Length (bytes) of the device key wrapping stateless credentials
+pub const CREDENTIAL_KEY_LENGTH: usize = 32;
+
+/// The first byte of credential IDs wrapping a private key, followed by the nonce and the
+/// AES-256-GCM encrypted private key along with its tag
+pub const WRAPPED_CREDENTIAL_V1: u8 = 1;
+
+/// Length (bytes) of the AES-GCM tag
+const TAG_LENGTH: usize = 16;
+
+/// Wraps the private key of a non-discoverable credential into its credential ID, so that
+/// the authenticator doesn't need to store it, which is what hardware authenticators do.
+///
+/// The private key is encrypted with a device key, and bound to the RP ID hash, thus the
+/// credential ID only unwraps for the RP it was created for.
+pub struct CredentialWrapper {
+    key: LessSafeKey,
+}
+
+impl CredentialWrapper {
+    pub fn new(key: &[u8; CREDENTIAL_KEY_LENGTH]) -> Self {
+        CredentialWrapper {
+            key: LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap()),
+        }
+    }
+
+    /// The associated data authenticated along with the private key
+    fn aad(version: u8, rp_id_hash: &RpIdHash) -> Aad<[u8; 33]> {
+        let mut aad = [version; 33];
+        aad[1..].copy_from_slice(&rp_id_hash.0);
+        Aad::from(aad)
+    }
+
+    pub fn wrap(
+        &self,
+        rp_id_hash: &RpIdHash,
+        private_key: &CredentialPrivateKey,
+        rng: &SystemRandom,
+    ) -> Result<CredentialId, Unspecified> {
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill(&mut nonce)?;
+        let mut sealed = private_key.0.clone();
+        self.key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Self::aad(WRAPPED_CREDENTIAL_V1, rp_id_hash),
+            &mut sealed,
+        )?;
+        let mut id = Vec::with_capacity(1 + NONCE_LEN + sealed.len());
+        id.push(WRAPPED_CREDENTIAL_V1);
+        id.extend_from_slice(&nonce);
+        id.extend_from_slice(&sealed);
+        Ok(CredentialId(id))
+    }
+
+    /// Returns the private key wrapped into `id`, or `None` if `id` wasn't created by this
+    /// authenticator for the given RP, e.g, as it's the ID of a stored credential
+    pub fn unwrap(&self, id: &CredentialId, rp_id_hash: &RpIdHash) -> Option<CredentialPrivateKey> {
+        let (&version, rest) = id.0.split_first()?;
+        if version != WRAPPED_CREDENTIAL_V1 || rest.len() < NONCE_LEN + TAG_LENGTH {
+            return None;
+        }
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut private_key = sealed.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, Self::aad(version, rp_id_hash), &mut private_key)
+            .ok()?
+            .len();
+        private_key.truncate(len);
+        Some(CredentialPrivateKey(private_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::authenticator::types::RpId;
+
+    use super::*;
+
+    #[test]
+    fn test_wrap_and_unwrap() {
+        let rng = SystemRandom::new();
+        let wrapper = CredentialWrapper::new(&[7; CREDENTIAL_KEY_LENGTH]);
+        let rp_id_hash = RpIdHash::from(&RpId("example.com".into()));
+        let private_key = CredentialPrivateKey(vec![0xab; 100]);
+        let id = wrapper.wrap(&rp_id_hash, &private_key, &rng).unwrap();
+        assert_eq!(id.0[0], WRAPPED_CREDENTIAL_V1);
+        assert_eq!(id.0.len(), 1 + NONCE_LEN + 100 + TAG_LENGTH);
+        assert_eq!(wrapper.unwrap(&id, &rp_id_hash), Some(private_key.clone()));
+        // every wrapping uses its own nonce
+        assert_ne!(wrapper.wrap(&rp_id_hash, &private_key, &rng).unwrap(), id);
+    }
+
+    #[test]
+    fn test_unwrap_rejects_foreign_ids() {
+        let rng = SystemRandom::new();
+        let wrapper = CredentialWrapper::new(&[7; CREDENTIAL_KEY_LENGTH]);
+        let rp_id_hash = RpIdHash::from(&RpId("example.com".into()));
+        let id = wrapper
+            .wrap(&rp_id_hash, &CredentialPrivateKey(vec![1; 32]), &rng)
+            .unwrap();
+
+        let other_rp = RpIdHash::from(&RpId("example.org".into()));
+        assert_eq!(wrapper.unwrap(&id, &other_rp), None);
+        let other_device = CredentialWrapper::new(&[8; CREDENTIAL_KEY_LENGTH]);
+        assert_eq!(other_device.unwrap(&id, &rp_id_hash), None);
+
+        let mut tampered = id.clone();
+        tampered.0[20] ^= 1;
+        assert_eq!(wrapper.unwrap(&tampered, &rp_id_hash), None);
+        let mut other_version = id;
+        other_version.0[0] = 2;
+        assert_eq!(wrapper.unwrap(&other_version, &rp_id_hash), None);
+        assert_eq!(
+            wrapper.unwrap(&CredentialId(vec![1; 16]), &rp_id_hash),
+            None
+        );
+        assert_eq!(wrapper.unwrap(&CredentialId(vec![]), &rp_id_hash), None);
+    }
+}
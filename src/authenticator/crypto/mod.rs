@@ -1,6 +1,13 @@
+mod attestation;
 mod cose;
 mod crypto_system;
+mod key_wrap;
 mod ring;
+mod seed;
+pub use self::ring::{RingCryptoSystem, RingError, RingKeyPair};
 pub use ::ring::*;
+pub use attestation::*;
 pub use cose::*;
 pub use crypto_system::*;
+pub use key_wrap::*;
+pub use seed::*;
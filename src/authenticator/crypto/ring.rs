@@ -6,6 +6,11 @@ use coset::{
     Algorithm, CoseKey, CoseKeyBuilder, KeyType, Label,
 };
 use once_cell::sync::Lazy;
+use p256::{
+    elliptic_curve::{bigint::U256, ops::Reduce},
+    pkcs8::EncodePrivateKey,
+    NonZeroScalar, Scalar, SecretKey,
+};
 use ring::{
     rand::SystemRandom,
     signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
@@ -13,7 +18,7 @@ use ring::{
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use super::{COSEAlgorithmIdentifier, CryptoKeyPair, CryptoSystem};
+use super::{COSEAlgorithmIdentifier, CryptoKeyPair, CryptoSystem, ES256};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RingKeyPair {
@@ -32,6 +37,7 @@ impl CryptoKeyPair for RingKeyPair {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RingP256KeyPair {
+    #[serde(with = "serde_bytes")]
     private: Vec<u8>,
 }
 
@@ -58,6 +64,7 @@ impl CryptoKeyPair for RingP256KeyPair {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RingEd25519KeyPair {
+    #[serde(with = "serde_bytes")]
     private: Vec<u8>,
 }
 
@@ -84,7 +91,8 @@ impl CryptoKeyPair for RingEd25519KeyPair {
     }
 }
 
-struct RingCryptoSystem;
+#[derive(Debug, Default)]
+pub struct RingCryptoSystem;
 
 #[derive(Debug, Error)]
 pub enum RingError {
@@ -145,6 +153,27 @@ impl CryptoSystem for RingCryptoSystem {
         }
     }
 
+    fn derive_credential_keypair(
+        &self,
+        alg: COSEAlgorithmIdentifier,
+        material: &[u8; 32],
+    ) -> Result<Self::KeyPair, Self::Error> {
+        // ring can't import raw private keys, thus the key pair is encoded as PKCS#8 via p256
+        if alg != ES256 {
+            return Err(RingError::UnsupportedAlgorithm(alg.0));
+        }
+        let scalar = <Scalar as Reduce<U256>>::from_be_bytes_reduced((*material).into());
+        let secret = Option::<NonZeroScalar>::from(NonZeroScalar::new(scalar))
+            .map(SecretKey::from)
+            .ok_or(RingError::RingUnspecified(ring::error::Unspecified))?;
+        let doc = secret
+            .to_pkcs8_der()
+            .map_err(|_| RingError::RingUnspecified(ring::error::Unspecified))?;
+        Ok(RingKeyPair::P256(RingP256KeyPair {
+            private: doc.as_bytes().to_owned(),
+        }))
+    }
+
     fn sign_data(&self, keypair: &Self::KeyPair, data: &[u8]) -> Result<Vec<u8>, Self::Error> {
         match keypair {
             RingKeyPair::P256(p256) => {
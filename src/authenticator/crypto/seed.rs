@@ -0,0 +1,180 @@
+use bip39::Mnemonic;
+use ring::{
+    constant_time::verify_slices_are_equal,
+    error::Unspecified,
+    hkdf::{Prk, Salt, HKDF_SHA256},
+    hmac::{self, HMAC_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use zeroize::Zeroizing;
+
+use crate::authenticator::types::{CredentialId, RpIdHash};
+
+/// Length (bytes) of the master seed, which is the entropy of a 24 word recovery phrase
+pub const SEED_LENGTH: usize = 32;
+
+/// The first byte of credential IDs whose key is derived from the master seed, followed by
+/// the nonce and a MAC
+pub const DERIVED_CREDENTIAL_V1: u8 = 2;
+
+/// Length (bytes) of the per-credential nonce
+const NONCE_LENGTH: usize = 16;
+
+/// Length (bytes) of the truncated HMAC-SHA256 binding a credential ID to its RP
+const MAC_LENGTH: usize = 16;
+
+/// Distinguishes the keys derived from the seed from the ones of other protocols
+const SEED_SALT: &[u8] = b"softauth seed v1";
+const ID_KEY_INFO: &[u8] = b"credential id";
+const CREDENTIAL_KEY_INFO: &[u8] = b"credential key";
+
+/// Derives the keys of non-discoverable credentials from a master seed, the RP ID hash and a
+/// nonce embedded in the credential ID. Restoring the seed, e.g, from its recovery phrase,
+/// thus restores every credential created from it.
+pub struct CredentialSeed {
+    prk: Prk,
+    id_key: hmac::Key,
+}
+
+impl CredentialSeed {
+    pub fn new(seed: &[u8; SEED_LENGTH]) -> Self {
+        let prk = Salt::new(HKDF_SHA256, SEED_SALT).extract(seed);
+        let id_key = prk
+            .expand(&[ID_KEY_INFO], HMAC_SHA256)
+            .expect("HMAC-SHA256 keys are shorter than the HKDF-SHA256 limit")
+            .into();
+        CredentialSeed { prk, id_key }
+    }
+
+    fn mac(&self, version: u8, rp_id_hash: &RpIdHash, nonce: &[u8]) -> hmac::Tag {
+        let mut context = hmac::Context::with_key(&self.id_key);
+        context.update(&[version]);
+        context.update(&rp_id_hash.0);
+        context.update(nonce);
+        context.sign()
+    }
+
+    /// Creates the ID of a new credential for the RP, whose key is given by [Self::key_material]
+    pub fn new_credential_id(
+        &self,
+        rp_id_hash: &RpIdHash,
+        rng: &SystemRandom,
+    ) -> Result<CredentialId, Unspecified> {
+        let mut nonce = [0; NONCE_LENGTH];
+        rng.fill(&mut nonce)?;
+        let mac = self.mac(DERIVED_CREDENTIAL_V1, rp_id_hash, &nonce);
+        let mut id = Vec::with_capacity(1 + NONCE_LENGTH + MAC_LENGTH);
+        id.push(DERIVED_CREDENTIAL_V1);
+        id.extend_from_slice(&nonce);
+        id.extend_from_slice(&mac.as_ref()[..MAC_LENGTH]);
+        Ok(CredentialId(id))
+    }
+
+    /// Returns the secret key material of the credential, or `None` if `id` wasn't created
+    /// from this seed for the given RP
+    pub fn key_material(
+        &self,
+        id: &CredentialId,
+        rp_id_hash: &RpIdHash,
+    ) -> Option<Zeroizing<[u8; 32]>> {
+        let (&version, rest) = id.0.split_first()?;
+        if version != DERIVED_CREDENTIAL_V1 || rest.len() != NONCE_LENGTH + MAC_LENGTH {
+            return None;
+        }
+        let (nonce, mac) = rest.split_at(NONCE_LENGTH);
+        let expected = self.mac(version, rp_id_hash, nonce);
+        verify_slices_are_equal(&expected.as_ref()[..MAC_LENGTH], mac).ok()?;
+
+        let mut material = Zeroizing::new([0; 32]);
+        self.prk
+            .expand(&[CREDENTIAL_KEY_INFO, &rp_id_hash.0, nonce], HKDF_SHA256)
+            .and_then(|okm| okm.fill(material.as_mut()))
+            .ok()?;
+        Some(material)
+    }
+}
+
+/// Encodes the seed as a BIP39 mnemonic, to be written down as a backup
+pub fn recovery_phrase(seed: &[u8; SEED_LENGTH]) -> Zeroizing<String> {
+    let mnemonic = Mnemonic::from_entropy(seed).expect("The seed has a valid BIP39 length");
+    Zeroizing::new(mnemonic.to_string())
+}
+
+/// Decodes the seed from its BIP39 recovery phrase
+pub fn seed_from_recovery_phrase(
+    phrase: &str,
+) -> Result<Zeroizing<[u8; SEED_LENGTH]>, bip39::Error> {
+    let mnemonic = Mnemonic::parse(phrase.to_lowercase())?;
+    let entropy = Zeroizing::new(mnemonic.to_entropy());
+    if entropy.len() != SEED_LENGTH {
+        return Err(bip39::Error::BadWordCount(mnemonic.word_count()));
+    }
+    let mut seed = Zeroizing::new([0; SEED_LENGTH]);
+    seed.copy_from_slice(&entropy);
+    Ok(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::authenticator::types::RpId;
+
+    use super::*;
+
+    #[test]
+    fn test_derivation_is_deterministic() {
+        let rng = SystemRandom::new();
+        let rp_id_hash = RpIdHash::from(&RpId("example.com".into()));
+        let seed = CredentialSeed::new(&[7; SEED_LENGTH]);
+        let id = seed.new_credential_id(&rp_id_hash, &rng).unwrap();
+        assert_eq!(id.0[0], DERIVED_CREDENTIAL_V1);
+        assert_eq!(id.0.len(), 1 + NONCE_LENGTH + MAC_LENGTH);
+
+        let material = seed.key_material(&id, &rp_id_hash).unwrap();
+        let restored = CredentialSeed::new(&[7; SEED_LENGTH]);
+        assert_eq!(
+            restored.key_material(&id, &rp_id_hash),
+            Some(material.clone())
+        );
+        // every credential has its own key
+        let other_id = seed.new_credential_id(&rp_id_hash, &rng).unwrap();
+        assert_ne!(seed.key_material(&other_id, &rp_id_hash), Some(material));
+    }
+
+    #[test]
+    fn test_foreign_ids_are_rejected() {
+        let rng = SystemRandom::new();
+        let rp_id_hash = RpIdHash::from(&RpId("example.com".into()));
+        let seed = CredentialSeed::new(&[7; SEED_LENGTH]);
+        let id = seed.new_credential_id(&rp_id_hash, &rng).unwrap();
+
+        let other_rp = RpIdHash::from(&RpId("example.org".into()));
+        assert_eq!(seed.key_material(&id, &other_rp), None);
+        let other_seed = CredentialSeed::new(&[8; SEED_LENGTH]);
+        assert_eq!(other_seed.key_material(&id, &rp_id_hash), None);
+
+        let mut tampered = id.clone();
+        tampered.0[5] ^= 1;
+        assert_eq!(seed.key_material(&tampered, &rp_id_hash), None);
+        let mut truncated = id;
+        truncated.0.pop();
+        assert_eq!(seed.key_material(&truncated, &rp_id_hash), None);
+    }
+
+    #[test]
+    fn test_recovery_phrase_roundtrip() {
+        let seed = [0x5a; SEED_LENGTH];
+        let phrase = recovery_phrase(&seed);
+        assert_eq!(phrase.split(' ').count(), 24);
+        assert_eq!(*seed_from_recovery_phrase(&phrase).unwrap(), seed);
+        // case and spacing don't matter when typing the phrase back in
+        let typed = format!(" {}\n", phrase.to_uppercase().replace(' ', "  "));
+        assert_eq!(*seed_from_recovery_phrase(&typed).unwrap(), seed);
+
+        let mut words: Vec<_> = phrase.split(' ').collect();
+        words.swap(0, 1);
+        assert!(seed_from_recovery_phrase(&words.join(" ")).is_err());
+        // 12 words encode too little entropy for a seed
+        let short = Mnemonic::from_entropy(&[0x5a; 16]).unwrap().to_string();
+        assert!(seed_from_recovery_phrase(&short).is_err());
+    }
+}
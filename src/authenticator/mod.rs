@@ -1,6 +1,9 @@
+pub(crate) mod apdu;
 pub(crate) mod api;
+pub(crate) mod arbiter;
 pub(crate) mod auth_impl;
 pub(crate) mod command;
 pub(crate) mod crypto;
-pub(crate) mod transport;
+pub(crate) mod presence;
+pub(crate) mod storage;
 pub(crate) mod types;
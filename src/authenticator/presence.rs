@@ -0,0 +1,285 @@
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Once,
+    },
+    time::{Duration, Instant},
+};
+
+use ring::digest::{digest, SHA256};
+use tracing::{debug, error, info, warn};
+
+/// How long a confirmation of the user's presence may be used for, like the touch of a
+/// hardware authenticator
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before reading the terminal again, once reading it failed
+const READ_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Identifies a request for the user's presence, which their confirmation is tied to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresenceToken(u64);
+
+impl PresenceToken {
+    /// A token of its own, e.g, for a CTAP2 request waiting for the user's presence
+    pub fn unique() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        PresenceToken(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// A token identifying a request by its contents, as U2F clients repeat the same
+    /// request until the user is present
+    pub fn of_request(data: &[u8]) -> Self {
+        let hash = digest(&SHA256, data);
+        let (token, _) = hash.as_ref().split_at(8);
+        PresenceToken(u64::from_be_bytes(token.try_into().unwrap()))
+    }
+}
+
+/// The part of the authenticator that interacts with its user, e.g, for showing
+/// which authenticator is being used.
+pub trait PresenceFrontend: Send + Sync {
+    /// Shows a visual cue identifying the authenticator, in response to CTAPHID_WINK.
+    fn wink(&self);
+
+    /// Returns whether the user confirmed their presence for the request identified by
+    /// `token`, consuming the confirmation. Otherwise asks the user to confirm it for
+    /// `purpose` without waiting for them, as U2F clients poll the authenticator until the
+    /// user is present.
+    fn check_presence(&self, token: PresenceToken, purpose: &str) -> bool;
+
+    /// Forgets the request identified by `token` once the authenticator no longer waits for
+    /// it, e.g, as the wait timed out or was cancelled, along with an unused confirmation.
+    fn end_presence_request(&self, _token: PresenceToken) {}
+}
+
+/// The request the user is asked to confirm on the terminal
+#[derive(Debug, Default)]
+struct Prompt {
+    token: Option<PresenceToken>,
+    confirmed_at: Option<Instant>,
+}
+
+impl Prompt {
+    /// Confirms the pending request, if any, as the user pressed Enter
+    fn confirm(&mut self) {
+        if self.token.is_some() {
+            self.confirmed_at = Some(Instant::now());
+        }
+    }
+}
+
+/// A frontend which interacts with the user via the terminal the daemon was ran from.
+/// The user confirms their presence by pressing Enter.
+pub struct TerminalFrontend {
+    prompt: Arc<Mutex<Prompt>>,
+    reader: Once,
+}
+
+impl Default for TerminalFrontend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TerminalFrontend {
+    pub fn new() -> Self {
+        TerminalFrontend {
+            prompt: Arc::new(Mutex::new(Prompt::default())),
+            reader: Once::new(),
+        }
+    }
+
+    /// Reads the terminal on a thread of its own, once the user is first asked for their
+    /// presence and for as long as the daemon runs. Every line confirms the pending request.
+    fn spawn_reader(&self) {
+        let prompt = self.prompt.clone();
+        let spawned = std::thread::Builder::new()
+            .name("presence".into())
+            .spawn(move || {
+                let mut failing = false;
+                loop {
+                    let mut line = String::new();
+                    match std::io::stdin().read_line(&mut line) {
+                        Ok(read) if read > 0 => {
+                            failing = false;
+                            prompt.lock().unwrap().confirm();
+                        }
+                        // e.g, the end of input, which a terminal may still be read after
+                        res => {
+                            if !failing {
+                                warn!(
+                                    ?res,
+                                    "Couldn't read the confirmation of presence from the terminal"
+                                );
+                            }
+                            failing = true;
+                            std::thread::sleep(READ_RETRY_INTERVAL);
+                        }
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            error!(?e, "Couldn't spawn the thread reading the terminal");
+        }
+    }
+
+    fn check(&self, token: PresenceToken, purpose: &str) -> bool {
+        let mut prompt = self.prompt.lock().unwrap();
+        if prompt.token == Some(token) {
+            match prompt.confirmed_at {
+                Some(at) if at.elapsed() < CONFIRMATION_TIMEOUT => {
+                    *prompt = Prompt::default();
+                    return true;
+                }
+                // the confirmation expired, thus the user is asked again
+                Some(_) => {}
+                None => return false,
+            }
+        }
+        *prompt = Prompt {
+            token: Some(token),
+            confirmed_at: None,
+        };
+        eprintln!("\x07Press Enter to confirm your presence for {}", purpose);
+        false
+    }
+}
+
+impl PresenceFrontend for TerminalFrontend {
+    fn wink(&self) {
+        info!("Wink! The authenticator is requesting your attention");
+        // The BEL character causes most terminals to flash or beep
+        let mut stderr = std::io::stderr();
+        if let Err(e) = stderr.write_all(b"\x07").and_then(|_| stderr.flush()) {
+            warn!(?e, "Couldn't flash the terminal");
+        }
+    }
+
+    fn check_presence(&self, token: PresenceToken, purpose: &str) -> bool {
+        self.reader.call_once(|| self.spawn_reader());
+        self.check(token, purpose)
+    }
+
+    fn end_presence_request(&self, token: PresenceToken) {
+        let mut prompt = self.prompt.lock().unwrap();
+        if prompt.token == Some(token) {
+            debug!("The user's presence is no longer needed");
+            *prompt = Prompt::default();
+        }
+    }
+}
+
+/// A frontend for daemons without a terminal, e.g, when testing RPs automatically, which
+/// considers the user to always be present.
+pub struct AssumedPresenceFrontend;
+
+impl PresenceFrontend for AssumedPresenceFrontend {
+    fn wink(&self) {
+        info!("Wink! The authenticator is requesting your attention");
+    }
+
+    fn check_presence(&self, _token: PresenceToken, purpose: &str) -> bool {
+        info!(purpose, "Assuming the user is present");
+        true
+    }
+}
+
+/// A frontend counting the cues it was asked to show, for tests. The user is present
+/// unless set otherwise.
+#[cfg(test)]
+#[derive(Default)]
+pub struct TestFrontend {
+    winks: AtomicUsize,
+    absent: AtomicBool,
+    presence_checks: AtomicUsize,
+    ended_presence_requests: AtomicUsize,
+}
+
+#[cfg(test)]
+impl TestFrontend {
+    pub fn winks(&self) -> usize {
+        self.winks.load(Ordering::SeqCst)
+    }
+
+    pub fn set_present(&self, present: bool) {
+        self.absent.store(!present, Ordering::SeqCst);
+    }
+
+    pub fn presence_checks(&self) -> usize {
+        self.presence_checks.load(Ordering::SeqCst)
+    }
+
+    pub fn ended_presence_requests(&self) -> usize {
+        self.ended_presence_requests.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+impl PresenceFrontend for TestFrontend {
+    fn wink(&self) {
+        self.winks.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn check_presence(&self, _token: PresenceToken, _purpose: &str) -> bool {
+        self.presence_checks.fetch_add(1, Ordering::SeqCst);
+        !self.absent.load(Ordering::SeqCst)
+    }
+
+    fn end_presence_request(&self, _token: PresenceToken) {
+        self.ended_presence_requests.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_confirmation_is_tied_to_its_request() {
+        let frontend = TerminalFrontend::new();
+        let first = PresenceToken::unique();
+        let second = PresenceToken::unique();
+        assert!(!frontend.check(first, "a registration at example.com"));
+        frontend.prompt.lock().unwrap().confirm();
+
+        // another request asks the user again, rather than using the confirmation
+        assert!(!frontend.check(second, "an authentication at example.com"));
+        assert!(!frontend.check(first, "a registration at example.com"));
+        frontend.prompt.lock().unwrap().confirm();
+        assert!(frontend.check(first, "a registration at example.com"));
+        // the confirmation was consumed
+        assert!(!frontend.check(first, "a registration at example.com"));
+    }
+
+    #[test]
+    fn test_ended_request_drops_its_confirmation() {
+        let frontend = TerminalFrontend::new();
+        let token = PresenceToken::unique();
+        assert!(!frontend.check(token, "a registration at example.com"));
+        frontend.prompt.lock().unwrap().confirm();
+        frontend.end_presence_request(token);
+        assert!(!frontend.check(token, "a registration at example.com"));
+
+        // nor is the user confirming while no request is pending used later
+        frontend.end_presence_request(token);
+        frontend.prompt.lock().unwrap().confirm();
+        assert!(!frontend.check(token, "a registration at example.com"));
+    }
+
+    #[test]
+    fn test_u2f_requests_keep_their_token() {
+        let request = [0xcc; 64];
+        assert_eq!(
+            PresenceToken::of_request(&request),
+            PresenceToken::of_request(&request)
+        );
+        assert_ne!(
+            PresenceToken::of_request(&request),
+            PresenceToken::of_request(&[0xdd; 64])
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! The conformance suite every [Storage] backend must pass, run against fresh, empty
+//! storages created by the backend's test, e.g:
+//!
+//! ```ignore
+//! #[tokio::test]
+//! async fn test_conformance() {
+//!     conformance::check(|| async { InMemoryStorage::new() }).await;
+//! }
+//! ```
+
+use std::future::Future;
+
+use zeroize::Zeroizing;
+
+use crate::authenticator::types::{
+    CredentialId, CredentialPrivateKey, PublicKeyCredentialSource, PublicKeyType, RpId, RpIdHash,
+    UserHandle,
+};
+
+use super::store::{Storage, StorageError, StorageTransaction};
+
+/// Runs every check against its own storage, panicking upon the first failure
+pub async fn check<S, F, Fut>(mut new_storage: F)
+where
+    S: Storage,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = S>,
+{
+    create_and_get(new_storage().await).await;
+    create_duplicate(new_storage().await).await;
+    update(new_storage().await).await;
+    delete(new_storage().await).await;
+    lookup_by_rp(new_storage().await).await;
+    enumerate(new_storage().await).await;
+    sign_count(new_storage().await).await;
+    atomic_transaction(new_storage().await).await;
+    delete_all(new_storage().await).await;
+    credential_key(new_storage().await).await;
+}
+
+/// A credential whose fields are all derived from `n`
+pub fn credential(n: u8, rp_id: &str) -> PublicKeyCredentialSource {
+    let rp_id = RpId(rp_id.into());
+    PublicKeyCredentialSource {
+        _type: PublicKeyType::PublicKey,
+        id: CredentialId(vec![n; 16]),
+        rp_id_hash: RpIdHash::from(&rp_id),
+        rp_id: Some(rp_id),
+        private_key: CredentialPrivateKey(vec![n; 32]),
+        user_handle: Some(UserHandle(vec![n; 8])),
+        discoverable: n.is_multiple_of(2),
+        sign_count: 0,
+    }
+}
+
+/// Creates a single credential, which the authenticator only does within a transaction
+pub async fn create_credential(
+    storage: &mut impl Storage,
+    credential: PublicKeyCredentialSource,
+) -> Result<(), StorageError> {
+    storage
+        .apply(StorageTransaction::new().create(credential))
+        .await
+}
+
+fn ids(credentials: &[PublicKeyCredentialSource]) -> Vec<CredentialId> {
+    credentials.iter().map(|cred| cred.id.clone()).collect()
+}
+
+async fn create_and_get(mut storage: impl Storage) {
+    let cred = credential(1, "example.com");
+    create_credential(&mut storage, cred.clone()).await.unwrap();
+    assert_eq!(storage.get_credential(&cred.id).await.unwrap(), Some(cred));
+    assert_eq!(
+        storage
+            .get_credential(&CredentialId(vec![9; 16]))
+            .await
+            .unwrap(),
+        None
+    );
+
+    // U2F credentials don't know their RP ID
+    let mut u2f = credential(2, "example.org");
+    u2f.rp_id = None;
+    u2f.user_handle = None;
+    create_credential(&mut storage, u2f.clone()).await.unwrap();
+    assert_eq!(storage.get_credential(&u2f.id).await.unwrap(), Some(u2f));
+}
+
+async fn create_duplicate(mut storage: impl Storage) {
+    let cred = credential(1, "example.com");
+    create_credential(&mut storage, cred.clone()).await.unwrap();
+    let duplicate = credential(1, "example.org");
+    let res = create_credential(&mut storage, duplicate).await;
+    assert!(matches!(res, Err(StorageError::AlreadyExists(id)) if id == cred.id));
+    assert_eq!(storage.get_credential(&cred.id).await.unwrap(), Some(cred));
+}
+
+async fn update(mut storage: impl Storage) {
+    let mut cred = credential(1, "example.com");
+    create_credential(&mut storage, cred.clone()).await.unwrap();
+    cred.user_handle = Some(UserHandle(vec![0xff]));
+    cred.sign_count = 42;
+    storage.update_credential(cred.clone()).await.unwrap();
+    assert_eq!(storage.get_credential(&cred.id).await.unwrap(), Some(cred));
+
+    let missing = credential(2, "example.com");
+    let res = storage.update_credential(missing.clone()).await;
+    assert!(matches!(res, Err(StorageError::NotFound(id)) if id == missing.id));
+    assert_eq!(storage.get_credential(&missing.id).await.unwrap(), None);
+}
+
+async fn delete(mut storage: impl Storage) {
+    let first = credential(1, "example.com");
+    let second = credential(2, "example.com");
+    create_credential(&mut storage, first.clone())
+        .await
+        .unwrap();
+    create_credential(&mut storage, second.clone())
+        .await
+        .unwrap();
+    storage
+        .apply(StorageTransaction::new().delete(first.id.clone()))
+        .await
+        .unwrap();
+    assert_eq!(storage.get_credential(&first.id).await.unwrap(), None);
+    assert_eq!(storage.list_credentials().await.unwrap(), vec![second]);
+
+    let res = storage
+        .apply(StorageTransaction::new().delete(first.id.clone()))
+        .await;
+    assert!(matches!(res, Err(StorageError::NotFound(id)) if id == first.id));
+}
+
+async fn lookup_by_rp(mut storage: impl Storage) {
+    let creds = [
+        credential(1, "example.com"),
+        credential(2, "example.org"),
+        credential(3, "example.com"),
+    ];
+    for cred in &creds {
+        create_credential(&mut storage, cred.clone()).await.unwrap();
+    }
+    let found = storage
+        .get_credentials_for_rp(&creds[0].rp_id_hash)
+        .await
+        .unwrap();
+    assert_eq!(found, vec![creds[0].clone(), creds[2].clone()]);
+    let unknown = RpIdHash::from(&RpId("example.net".into()));
+    assert!(storage
+        .get_credentials_for_rp(&unknown)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+async fn enumerate(mut storage: impl Storage) {
+    assert!(storage.list_credentials().await.unwrap().is_empty());
+    assert_eq!(storage.count_credentials().await.unwrap(), 0);
+    // creation order is kept, regardless of the IDs
+    let creds = [
+        credential(3, "example.com"),
+        credential(1, "example.org"),
+        credential(2, "example.com"),
+    ];
+    for cred in &creds {
+        create_credential(&mut storage, cred.clone()).await.unwrap();
+    }
+    let listed = storage.list_credentials().await.unwrap();
+    assert_eq!(ids(&listed), ids(&creds));
+    assert_eq!(listed, creds);
+    assert_eq!(storage.count_credentials().await.unwrap(), 3);
+}
+
+async fn sign_count(mut storage: impl Storage) {
+    let mut cred = credential(1, "example.com");
+    cred.sign_count = u32::MAX - 1;
+    create_credential(&mut storage, cred.clone()).await.unwrap();
+    assert_eq!(
+        storage.increment_sign_count(&cred.id).await.unwrap(),
+        u32::MAX
+    );
+    assert_eq!(
+        storage.increment_sign_count(&cred.id).await.unwrap(),
+        u32::MAX
+    );
+    let stored = storage.get_credential(&cred.id).await.unwrap().unwrap();
+    assert_eq!(stored.sign_count, u32::MAX);
+
+    let missing = CredentialId(vec![9; 16]);
+    let res = storage.increment_sign_count(&missing).await;
+    assert!(matches!(res, Err(StorageError::NotFound(id)) if id == missing));
+}
+
+async fn atomic_transaction(mut storage: impl Storage) {
+    let existing = credential(1, "example.com");
+    create_credential(&mut storage, existing.clone())
+        .await
+        .unwrap();
+
+    // the last operation fails, thus none takes effect
+    let new = credential(2, "example.com");
+    let mut updated = existing.clone();
+    updated.sign_count = 7;
+    let failing = StorageTransaction::new()
+        .create(new.clone())
+        .update(updated.clone())
+        .delete(CredentialId(vec![9; 16]));
+    assert!(matches!(
+        storage.apply(failing).await,
+        Err(StorageError::NotFound(_))
+    ));
+    assert_eq!(
+        storage.list_credentials().await.unwrap(),
+        vec![existing.clone()]
+    );
+
+    // operations observe the effect of the previous ones
+    let mut new_updated = new.clone();
+    new_updated.sign_count = 3;
+    let succeeding = StorageTransaction::new()
+        .create(new)
+        .update(new_updated.clone())
+        .delete(existing.id.clone())
+        .create(existing.clone());
+    storage.apply(succeeding).await.unwrap();
+    assert_eq!(
+        storage.list_credentials().await.unwrap(),
+        vec![new_updated, existing]
+    );
+}
+
+async fn delete_all(mut storage: impl Storage) {
+    for n in 0..4 {
+        create_credential(&mut storage, credential(n, "example.com"))
+            .await
+            .unwrap();
+    }
+    storage.delete_all_credentials().await.unwrap();
+    assert_eq!(storage.count_credentials().await.unwrap(), 0);
+
+    // a reset may be followed by new credentials within the same transaction
+    let cred = credential(5, "example.com");
+    let transaction = StorageTransaction::new().delete_all().create(cred.clone());
+    storage.apply(transaction).await.unwrap();
+    assert_eq!(storage.list_credentials().await.unwrap(), vec![cred]);
+}
+
+async fn credential_key(mut storage: impl Storage) {
+    assert_eq!(storage.credential_key().await.unwrap(), None);
+    storage
+        .set_credential_key(&Zeroizing::new([7; 32]))
+        .await
+        .unwrap();
+    create_credential(&mut storage, credential(1, "example.com"))
+        .await
+        .unwrap();
+    assert_eq!(
+        storage.credential_key().await.unwrap(),
+        Some(Zeroizing::new([7; 32]))
+    );
+    // the key doesn't count as a credential
+    assert_eq!(storage.count_credentials().await.unwrap(), 1);
+    assert_eq!(storage.list_credentials().await.unwrap().len(), 1);
+    storage
+        .set_credential_key(&Zeroizing::new([8; 32]))
+        .await
+        .unwrap();
+    assert_eq!(
+        storage.credential_key().await.unwrap(),
+        Some(Zeroizing::new([8; 32]))
+    );
+
+    // a failing transaction keeps the key
+    let failing = StorageTransaction::new()
+        .delete_all()
+        .delete(CredentialId(vec![9; 16]));
+    assert!(storage.apply(failing).await.is_err());
+    assert!(storage.credential_key().await.unwrap().is_some());
+
+    storage.delete_all_credentials().await.unwrap();
+    assert_eq!(storage.credential_key().await.unwrap(), None);
+}
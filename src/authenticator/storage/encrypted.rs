@@ -0,0 +1,830 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use async_trait::async_trait;
+use linux_keyutils::KeyError;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+use zeroize::Zeroizing;
+
+use crate::authenticator::types::{CredentialId, PublicKeyCredentialSource, RpIdHash};
+
+use super::{
+    file::{write_atomically, FileLock},
+    keyring::KeyringCache,
+    passphrase::{PassphraseError, PassphraseSource},
+    store::{credential_key_from_bytes, CredentialKey, Storage, StorageError, StorageTransaction},
+};
+
+/// Version of the file format, to be increased upon incompatible changes
+const FORMAT_VERSION: u32 = 1;
+
+/// Length (bytes) of the Argon2 salt
+const SALT_LENGTH: usize = 16;
+
+/// Length (bytes) of the key encrypting the credentials, and of the one derived from the
+/// passphrase
+pub const KEY_LENGTH: usize = 32;
+
+/// Bound to the data key sealed with the passphrase, see [EncryptedFile]
+const WRAPPED_KEY_AAD: &[u8] = b"softauth encrypted store v1: data key";
+
+/// Bound to the credentials sealed with the data key, see [EncryptedFile]
+const CREDENTIALS_AAD: &[u8] = b"softauth encrypted store v1: credentials";
+
+/// Bound to the credential key sealed with the data key, see [EncryptedFile]
+const CREDENTIAL_KEY_AAD: &[u8] = b"softauth encrypted store v1: credential key";
+
+/// The key encrypting the credentials, which is only ever written to disk sealed with the
+/// passphrase
+pub type DataKey = Zeroizing<[u8; KEY_LENGTH]>;
+
+#[derive(Debug, Error)]
+pub enum EncryptedStoreError {
+    #[error("Couldn't access the encrypted store: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0:?} already exists")]
+    AlreadyExists(PathBuf),
+
+    #[error("{0:?} is in use by another process, e.g, a running daemon")]
+    InUse(PathBuf),
+
+    #[error("Malformed encrypted store: {0}")]
+    Malformed(String),
+
+    #[error("The encrypted store has version {0}, only version {FORMAT_VERSION} is supported")]
+    UnsupportedVersion(u32),
+
+    #[error("Wrong passphrase")]
+    WrongPassphrase,
+
+    #[error(transparent)]
+    Passphrase(#[from] PassphraseError),
+
+    #[error("Couldn't derive a key from the passphrase: {0}")]
+    Kdf(argon2::Error),
+
+    #[error("Couldn't generate random data")]
+    Random,
+
+    #[error("The encrypted store is locked, its key is no longer in the kernel keyring")]
+    Locked,
+
+    #[error("Couldn't access the kernel keyring: {0}")]
+    Keyring(#[from] KeyError),
+}
+
+impl From<EncryptedStoreError> for StorageError {
+    fn from(e: EncryptedStoreError) -> Self {
+        StorageError::Backend(Box::new(e))
+    }
+}
+
+/// Argon2id parameters deriving the key that seals the data key from the passphrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The second recommended option of [RFC 9106](https://www.rfc-editor.org/rfc/rfc9106#section-4)
+    fn default() -> Self {
+        KdfParams {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 4,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Derives a key from `passphrase` on a blocking thread, as it takes a while on purpose
+    async fn derive_key(
+        self,
+        passphrase: &str,
+        salt: &[u8],
+    ) -> Result<Zeroizing<[u8; KEY_LENGTH]>, EncryptedStoreError> {
+        let passphrase = Zeroizing::new(passphrase.to_owned());
+        let salt = salt.to_vec();
+        tokio::task::spawn_blocking(move || self.derive_key_blocking(&passphrase, &salt))
+            .await
+            .map_err(io::Error::from)?
+    }
+
+    fn derive_key_blocking(
+        &self,
+        passphrase: &str,
+        salt: &[u8],
+    ) -> Result<Zeroizing<[u8; KEY_LENGTH]>, EncryptedStoreError> {
+        let params = Params::new(
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+            Some(KEY_LENGTH),
+        )
+        .map_err(EncryptedStoreError::Kdf)?;
+        let mut key = Zeroizing::new([0; KEY_LENGTH]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+            .map_err(EncryptedStoreError::Kdf)?;
+        Ok(key)
+    }
+}
+
+/// The contents of the store file, encoded as CBOR. The credentials are sealed with a random
+/// data key, itself sealed with a key derived from the passphrase, so that changing the
+/// passphrase only seals the data key anew.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncryptedFile {
+    version: u32,
+    kdf: KdfParams,
+    salt: ByteBuf,
+    /// The data key, sealed with the key derived from the passphrase
+    wrapped_key: ByteBuf,
+    /// The CBOR encoded credentials, sealed with the data key
+    credentials: ByteBuf,
+    /// The credential key, sealed with the data key, once created
+    #[serde(default)]
+    credential_key: Option<ByteBuf>,
+}
+
+/// Encrypts `plaintext` with AES-256-GCM under a random nonce, which is prepended to the
+/// ciphertext
+fn seal(
+    key: &[u8; KEY_LENGTH],
+    aad: &[u8],
+    plaintext: &[u8],
+    rng: &SystemRandom,
+) -> Result<Vec<u8>, EncryptedStoreError> {
+    let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap());
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill(&mut nonce)
+        .map_err(|_| EncryptedStoreError::Random)?;
+    let mut sealed = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(aad),
+        &mut sealed,
+    )
+    .map_err(|_| EncryptedStoreError::Random)?;
+    sealed.splice(0..0, nonce);
+    Ok(sealed)
+}
+
+/// Reverses [seal], returning `None` if the data wasn't sealed with `key` and `aad`
+fn open(key: &[u8; KEY_LENGTH], aad: &[u8], sealed: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap());
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut plaintext = Zeroizing::new(ciphertext.to_vec());
+    let len = key
+        .open_in_place(nonce, Aad::from(aad), &mut plaintext)
+        .ok()?
+        .len();
+    plaintext.truncate(len);
+    Some(plaintext)
+}
+
+/// Where the data key of an unlocked store is kept
+enum KeyHolder {
+    Memory(DataKey),
+    /// The kernel keyring, which locks the store again once the key expires
+    Keyring(KeyringCache),
+}
+
+/// Keeps credentials in a file encrypted at rest with a key protected by a passphrase.
+///
+/// The credentials are decrypted upon every operation, and the whole file is replaced
+/// atomically upon every change, which suits up to a few hundred credentials.
+///
+/// A store opened via [EncryptedFileStorage::open] locks the file for as long as it exists,
+/// and any other instance locks it before its first change, so that the file isn't changed
+/// behind the back of the daemon.
+pub struct EncryptedFileStorage {
+    path: PathBuf,
+    file: EncryptedFile,
+    key: KeyHolder,
+    rng: SystemRandom,
+    lock: Option<FileLock>,
+}
+
+impl EncryptedFileStorage {
+    /// Creates an empty store protected by `passphrase`, failing if `path` already exists.
+    /// The daemon creates its store via [EncryptedFileStorage::open] instead.
+    #[cfg(test)]
+    pub async fn create(
+        path: &Path,
+        passphrase: &str,
+        kdf: KdfParams,
+    ) -> Result<Self, EncryptedStoreError> {
+        if tokio::fs::metadata(path).await.is_ok() {
+            return Err(EncryptedStoreError::AlreadyExists(path.to_owned()));
+        }
+        let lock = Self::lock(path)?;
+        Self::create_locked(path, passphrase, kdf, lock).await
+    }
+
+    async fn create_locked(
+        path: &Path,
+        passphrase: &str,
+        kdf: KdfParams,
+        lock: FileLock,
+    ) -> Result<Self, EncryptedStoreError> {
+        if tokio::fs::metadata(path).await.is_ok() {
+            return Err(EncryptedStoreError::AlreadyExists(path.to_owned()));
+        }
+        let rng = SystemRandom::new();
+        let mut data_key = Zeroizing::new([0; KEY_LENGTH]);
+        rng.fill(data_key.as_mut())
+            .map_err(|_| EncryptedStoreError::Random)?;
+        let mut storage = EncryptedFileStorage {
+            path: path.to_owned(),
+            file: EncryptedFile {
+                version: FORMAT_VERSION,
+                kdf,
+                salt: ByteBuf::new(),
+                wrapped_key: ByteBuf::new(),
+                credentials: ByteBuf::new(),
+                credential_key: None,
+            },
+            key: KeyHolder::Memory(data_key),
+            rng,
+            lock: Some(lock),
+        };
+        storage.wrap_data_key(passphrase).await?;
+        storage.save(&[]).await?;
+        Ok(storage)
+    }
+
+    /// Unlocks the store at `path` with a passphrase read from `source`, or creates it,
+    /// asking for a new passphrase, if it doesn't exist yet.
+    ///
+    /// With a `keyring`, the key cached there unlocks the store without a passphrase, and
+    /// the key of a store unlocked with a passphrase is cached there.
+    ///
+    /// Fails if another process has the store locked.
+    pub async fn open(
+        path: &Path,
+        source: &PassphraseSource,
+        kdf: KdfParams,
+        keyring: Option<KeyringCache>,
+    ) -> Result<Self, EncryptedStoreError> {
+        let lock = Self::lock(path)?;
+        if let Some(cache) = &keyring {
+            if let Some(mut storage) = Self::unlock_from_keyring(path, cache.clone()).await? {
+                info!(?path, "Unlocked with the key cached in the kernel keyring");
+                storage.lock = Some(lock);
+                return Ok(storage);
+            }
+        }
+        let mut storage = match tokio::fs::metadata(path).await {
+            Ok(_) => {
+                let passphrase = source.read("Passphrase of the credential store").await?;
+                let mut storage = Self::unlock(path, &passphrase).await?;
+                storage.lock = Some(lock);
+                storage
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!(?path, "Creating an encrypted credential store");
+                let passphrase = source
+                    .read_new("New passphrase of the credential store")
+                    .await?;
+                Self::create_locked(path, &passphrase, kdf, lock).await?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(cache) = keyring {
+            storage.keep_key_in(cache)?;
+        }
+        Ok(storage)
+    }
+
+    /// Decrypts the store at `path` with the key cached in the kernel keyring, returning
+    /// `None` if none is cached. A key cached for a store which was removed or recreated
+    /// since is cleared, returning `None` as well.
+    async fn unlock_from_keyring(
+        path: &Path,
+        cache: KeyringCache,
+    ) -> Result<Option<Self>, EncryptedStoreError> {
+        let Some(data_key) = cache.load()? else {
+            return Ok(None);
+        };
+        let file = match Self::read_file(path).await {
+            Ok(file) => file,
+            Err(EncryptedStoreError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                warn!(
+                    ?path,
+                    "Clearing the cached key of a store which no longer exists"
+                );
+                cache.clear()?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        if open(&data_key, CREDENTIALS_AAD, &file.credentials).is_none() {
+            warn!(
+                ?path,
+                "Clearing the cached key, which doesn't match the recreated store"
+            );
+            cache.clear()?;
+            return Ok(None);
+        }
+        let mut storage = Self::unlock_with_key(path, file, data_key)?;
+        storage.key = KeyHolder::Keyring(cache);
+        Ok(Some(storage))
+    }
+
+    /// Moves the data key into the kernel keyring, from which it expires after the timeout of
+    /// `cache`, locking the store
+    pub fn keep_key_in(&mut self, cache: KeyringCache) -> Result<(), EncryptedStoreError> {
+        cache.store(self.data_key()?.as_ref())?;
+        debug!(path = ?self.path, "Cached the key in the kernel keyring");
+        self.key = KeyHolder::Keyring(cache);
+        Ok(())
+    }
+
+    /// Locks the store at `path`, failing if another process has it locked
+    fn lock(path: &Path) -> Result<FileLock, EncryptedStoreError> {
+        FileLock::try_lock(path)?.ok_or_else(|| EncryptedStoreError::InUse(path.to_owned()))
+    }
+
+    /// Locks the file of this store unless it's locked already, e.g, before changing it, so
+    /// that this fails early while another process uses the store. The file is read again,
+    /// as another process may have changed it before.
+    pub async fn lock_file(&mut self) -> Result<(), EncryptedStoreError> {
+        if self.lock.is_none() {
+            let lock = Self::lock(&self.path)?;
+            self.file = Self::read_file(&self.path).await?;
+            self.lock = Some(lock);
+        }
+        Ok(())
+    }
+
+    /// Decrypts the store at `path` with `passphrase`, without locking it until it's changed
+    pub async fn unlock(path: &Path, passphrase: &str) -> Result<Self, EncryptedStoreError> {
+        let file = Self::read_file(path).await?;
+        let passphrase_key = file.kdf.derive_key(passphrase, &file.salt).await?;
+        let data_key = open(&passphrase_key, WRAPPED_KEY_AAD, &file.wrapped_key)
+            .ok_or(EncryptedStoreError::WrongPassphrase)?;
+        let data_key = Zeroizing::new(
+            data_key[..]
+                .try_into()
+                .map_err(|_| EncryptedStoreError::Malformed("Invalid data key".into()))?,
+        );
+        Self::unlock_with_key(path, file, data_key)
+    }
+
+    fn unlock_with_key(
+        path: &Path,
+        file: EncryptedFile,
+        data_key: DataKey,
+    ) -> Result<Self, EncryptedStoreError> {
+        let storage = EncryptedFileStorage {
+            path: path.to_owned(),
+            file,
+            key: KeyHolder::Memory(data_key),
+            rng: SystemRandom::new(),
+            lock: None,
+        };
+        // fail right away rather than upon the first operation
+        storage.credentials()?;
+        Ok(storage)
+    }
+
+    fn data_key(&self) -> Result<DataKey, EncryptedStoreError> {
+        match &self.key {
+            KeyHolder::Memory(data_key) => Ok(data_key.clone()),
+            KeyHolder::Keyring(cache) => cache.load()?.ok_or(EncryptedStoreError::Locked),
+        }
+    }
+
+    /// Decrypts the credentials
+    fn credentials(&self) -> Result<Vec<PublicKeyCredentialSource>, EncryptedStoreError> {
+        let data_key = self.data_key()?;
+        let plaintext =
+            open(&data_key, CREDENTIALS_AAD, &self.file.credentials).ok_or_else(|| {
+                EncryptedStoreError::Malformed("The credentials don't match their key".into())
+            })?;
+        ciborium::de::from_reader(plaintext.as_slice())
+            .map_err(|e| EncryptedStoreError::Malformed(e.to_string()))
+    }
+
+    async fn read_file(path: &Path) -> Result<EncryptedFile, EncryptedStoreError> {
+        let bytes = tokio::fs::read(path).await?;
+        let file: EncryptedFile = ciborium::de::from_reader(bytes.as_slice())
+            .map_err(|e| EncryptedStoreError::Malformed(e.to_string()))?;
+        if file.version != FORMAT_VERSION {
+            return Err(EncryptedStoreError::UnsupportedVersion(file.version));
+        }
+        Ok(file)
+    }
+
+    /// Protects the store with another passphrase, which takes effect atomically
+    pub async fn change_passphrase(&mut self, passphrase: &str) -> Result<(), EncryptedStoreError> {
+        self.lock_file().await?;
+        let previous = self.file.clone();
+        self.wrap_data_key(passphrase).await?;
+        let res = match self.credentials() {
+            Ok(credentials) => self.save(&credentials).await,
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            self.file = previous;
+        }
+        res
+    }
+
+    /// Seals the data key with a key derived from `passphrase` and a new salt
+    async fn wrap_data_key(&mut self, passphrase: &str) -> Result<(), EncryptedStoreError> {
+        let mut salt = vec![0; SALT_LENGTH];
+        self.rng
+            .fill(&mut salt)
+            .map_err(|_| EncryptedStoreError::Random)?;
+        let passphrase_key = self.file.kdf.derive_key(passphrase, &salt).await?;
+        let wrapped_key = seal(
+            &passphrase_key,
+            WRAPPED_KEY_AAD,
+            self.data_key()?.as_ref(),
+            &self.rng,
+        )?;
+        self.file.salt = ByteBuf::from(salt);
+        self.file.wrapped_key = ByteBuf::from(wrapped_key);
+        Ok(())
+    }
+
+    /// Decrypts the credential key
+    fn read_credential_key(&self) -> Result<Option<CredentialKey>, StorageError> {
+        let Some(sealed) = &self.file.credential_key else {
+            return Ok(None);
+        };
+        let data_key = self.data_key()?;
+        let key = open(&data_key, CREDENTIAL_KEY_AAD, sealed).ok_or_else(|| {
+            EncryptedStoreError::Malformed("The credential key doesn't match its key".into())
+        })?;
+        credential_key_from_bytes(&key).map(Some)
+    }
+
+    /// Seals `credentials` and writes them to the file
+    async fn save(
+        &mut self,
+        credentials: &[PublicKeyCredentialSource],
+    ) -> Result<(), EncryptedStoreError> {
+        self.lock_file().await?;
+        self.write(self.file.clone(), credentials).await
+    }
+
+    /// Seals `credentials` into `file`, which then replaces the file of the store
+    async fn write(
+        &mut self,
+        mut file: EncryptedFile,
+        credentials: &[PublicKeyCredentialSource],
+    ) -> Result<(), EncryptedStoreError> {
+        let mut plaintext = Zeroizing::new(Vec::new());
+        ciborium::ser::into_writer(credentials, &mut *plaintext)
+            .expect("Serializing credentials into a vector cannot fail");
+        let data_key = self.data_key()?;
+        let sealed = seal(&data_key, CREDENTIALS_AAD, &plaintext, &self.rng)?;
+        file.credentials = ByteBuf::from(sealed);
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&file, &mut bytes)
+            .expect("Serializing the store into a vector cannot fail");
+        write_atomically(&self.path, &bytes).await?;
+        self.file = file;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for EncryptedFileStorage {
+    async fn get_credential(
+        &self,
+        id: &CredentialId,
+    ) -> Result<Option<PublicKeyCredentialSource>, StorageError> {
+        Ok(self.credentials()?.into_iter().find(|cred| &cred.id == id))
+    }
+
+    async fn get_credentials_for_rp(
+        &self,
+        rp_id_hash: &RpIdHash,
+    ) -> Result<Vec<PublicKeyCredentialSource>, StorageError> {
+        Ok(self
+            .credentials()?
+            .into_iter()
+            .filter(|cred| &cred.rp_id_hash == rp_id_hash)
+            .collect())
+    }
+
+    async fn list_credentials(&self) -> Result<Vec<PublicKeyCredentialSource>, StorageError> {
+        Ok(self.credentials()?)
+    }
+
+    async fn apply(&mut self, transaction: StorageTransaction) -> Result<(), StorageError> {
+        self.lock_file().await?;
+        let mut credentials = self.credentials()?;
+        transaction.apply_to(&mut credentials)?;
+        let mut file = self.file.clone();
+        if transaction.deletes_all() {
+            file.credential_key = None;
+        }
+        self.write(file, &credentials).await?;
+        Ok(())
+    }
+
+    async fn credential_key(&self) -> Result<Option<CredentialKey>, StorageError> {
+        self.read_credential_key()
+    }
+
+    async fn set_credential_key(&mut self, key: &CredentialKey) -> Result<(), StorageError> {
+        self.lock_file().await?;
+        let credentials = self.credentials()?;
+        let data_key = self.data_key()?;
+        let sealed = seal(&data_key, CREDENTIAL_KEY_AAD, &key[..], &self.rng)?;
+        let mut file = self.file.clone();
+        file.credential_key = Some(ByteBuf::from(sealed));
+        self.write(file, &credentials).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        authenticator::storage::{
+            conformance::{self, create_credential, credential},
+            keyring::KeyringKind,
+        },
+        test_util::temp_dir,
+    };
+
+    use super::*;
+
+    /// Cheap parameters, as the defaults take a while in debug builds
+    const TEST_KDF: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let dir = temp_dir();
+        let mut n = 0;
+        conformance::check(|| {
+            n += 1;
+            let path = dir.path().join(format!("{}.store", n));
+            async move {
+                EncryptedFileStorage::create(&path, "secret", TEST_KDF)
+                    .await
+                    .unwrap()
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_unlock() {
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.store");
+        let cred = credential(1, "example.com");
+        let mut storage = EncryptedFileStorage::create(&path, "secret", TEST_KDF)
+            .await
+            .unwrap();
+        create_credential(&mut storage, cred.clone()).await.unwrap();
+        let credential_key = Zeroizing::new([0x5a; 32]);
+        storage.set_credential_key(&credential_key).await.unwrap();
+        assert!(matches!(
+            EncryptedFileStorage::create(&path, "secret", TEST_KDF).await,
+            Err(EncryptedStoreError::AlreadyExists(_))
+        ));
+
+        let storage = EncryptedFileStorage::unlock(&path, "secret").await.unwrap();
+        assert_eq!(
+            storage.list_credentials().await.unwrap(),
+            vec![cred.clone()]
+        );
+        assert_eq!(
+            storage.credential_key().await.unwrap(),
+            Some(credential_key.clone())
+        );
+        assert!(matches!(
+            EncryptedFileStorage::unlock(&path, "wrong").await,
+            Err(EncryptedStoreError::WrongPassphrase)
+        ));
+
+        // neither the private key, the user handle nor the credential key are written in
+        // the clear
+        let contents = std::fs::read(&path).unwrap();
+        for secret in [
+            &cred.private_key.0,
+            &cred.user_handle.unwrap().0,
+            &credential_key.to_vec(),
+        ] {
+            assert!(!contents
+                .windows(secret.len())
+                .any(|window| window == &secret[..]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_creates_store() {
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.store");
+        let env_file = path.with_extension("env");
+        std::fs::write(&env_file, "SOFTAUTH_PASSPHRASE=secret\n").unwrap();
+        let source = PassphraseSource::EnvFile(env_file);
+        let mut storage = EncryptedFileStorage::open(&path, &source, TEST_KDF, None)
+            .await
+            .unwrap();
+        let cred = credential(1, "example.com");
+        create_credential(&mut storage, cred.clone()).await.unwrap();
+        drop(storage);
+
+        let storage = EncryptedFileStorage::open(&path, &source, TEST_KDF, None)
+            .await
+            .unwrap();
+        assert_eq!(storage.list_credentials().await.unwrap(), vec![cred]);
+    }
+
+    #[tokio::test]
+    async fn test_open_store_is_locked() {
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.store");
+        let env_file = path.with_extension("env");
+        std::fs::write(&env_file, "SOFTAUTH_PASSPHRASE=secret\n").unwrap();
+        let source = PassphraseSource::EnvFile(env_file);
+        let mut daemon = EncryptedFileStorage::open(&path, &source, TEST_KDF, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            EncryptedFileStorage::open(&path, &source, TEST_KDF, None).await,
+            Err(EncryptedStoreError::InUse(_))
+        ));
+
+        // e.g, `change-passphrase` while the daemon runs, which would revert the passphrase
+        let mut other = EncryptedFileStorage::unlock(&path, "secret").await.unwrap();
+        assert!(matches!(
+            other.change_passphrase("new").await,
+            Err(EncryptedStoreError::InUse(_))
+        ));
+        assert!(matches!(
+            create_credential(&mut other, credential(2, "example.com")).await,
+            Err(StorageError::Backend(_))
+        ));
+        let cred = credential(1, "example.com");
+        create_credential(&mut daemon, cred.clone()).await.unwrap();
+        EncryptedFileStorage::unlock(&path, "secret").await.unwrap();
+
+        drop(daemon);
+        other.change_passphrase("new").await.unwrap();
+        drop(other);
+        let storage = EncryptedFileStorage::unlock(&path, "new").await.unwrap();
+        assert_eq!(storage.list_credentials().await.unwrap(), vec![cred]);
+    }
+
+    /// Uses the kernel keyring, run it via `cargo test -- --ignored keyring`
+    #[tokio::test]
+    #[ignore]
+    async fn test_keyring() {
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.store");
+        let env_file = path.with_extension("env");
+        std::fs::write(&env_file, "SOFTAUTH_PASSPHRASE=secret\n").unwrap();
+        let cache = KeyringCache::new(KeyringKind::Session, &path).unwrap();
+        let mut storage = EncryptedFileStorage::open(
+            &path,
+            &PassphraseSource::EnvFile(env_file.clone()),
+            TEST_KDF,
+            Some(cache.clone()),
+        )
+        .await
+        .unwrap();
+        let cred = credential(1, "example.com");
+        create_credential(&mut storage, cred.clone()).await.unwrap();
+        drop(storage);
+
+        // restarting doesn't need the passphrase
+        std::fs::remove_file(&env_file).unwrap();
+        let no_passphrase = PassphraseSource::EnvFile(env_file);
+        let mut restarted =
+            EncryptedFileStorage::open(&path, &no_passphrase, TEST_KDF, Some(cache.clone()))
+                .await
+                .unwrap();
+        assert_eq!(
+            restarted.list_credentials().await.unwrap(),
+            vec![cred.clone()]
+        );
+
+        // locking affects running instances, until unlocked again
+        assert!(cache.clear().unwrap());
+        assert!(restarted.list_credentials().await.is_err());
+        assert!(
+            create_credential(&mut restarted, credential(2, "example.com"))
+                .await
+                .is_err()
+        );
+        assert!(
+            EncryptedFileStorage::unlock_from_keyring(&path, cache.clone())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        EncryptedFileStorage::unlock(&path, "secret")
+            .await
+            .unwrap()
+            .keep_key_in(cache.clone())
+            .unwrap();
+        assert_eq!(restarted.list_credentials().await.unwrap(), vec![cred]);
+        cache.clear().unwrap();
+    }
+
+    /// Uses the kernel keyring, run it via `cargo test -- --ignored keyring`
+    #[tokio::test]
+    #[ignore]
+    async fn test_stale_keyring_key() {
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.store");
+        let env_file = path.with_extension("env");
+        std::fs::write(&env_file, "SOFTAUTH_PASSPHRASE=secret\n").unwrap();
+        let source = PassphraseSource::EnvFile(env_file.clone());
+        let cache = KeyringCache::new(KeyringKind::Session, &path).unwrap();
+        let open =
+            |source| EncryptedFileStorage::open(&path, source, TEST_KDF, Some(cache.clone()));
+        drop(open(&source).await.unwrap());
+
+        // the key of a removed store is cleared, and the store created again
+        std::fs::remove_file(&path).unwrap();
+        let mut storage = open(&source).await.unwrap();
+        let cred = credential(1, "example.com");
+        create_credential(&mut storage, cred.clone()).await.unwrap();
+        drop(storage);
+
+        // the key of a recreated store is cleared, and the passphrase asked for
+        std::fs::remove_file(&path).unwrap();
+        drop(
+            EncryptedFileStorage::create(&path, "secret", TEST_KDF)
+                .await
+                .unwrap(),
+        );
+        let storage = open(&source).await.unwrap();
+        assert_eq!(storage.list_credentials().await.unwrap(), vec![]);
+        drop(storage);
+
+        // whose key is cached in its place
+        std::fs::remove_file(&env_file).unwrap();
+        open(&PassphraseSource::EnvFile(env_file)).await.unwrap();
+        cache.clear().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_change_passphrase() {
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.store");
+        let cred = credential(1, "example.com");
+        let mut storage = EncryptedFileStorage::create(&path, "old", TEST_KDF)
+            .await
+            .unwrap();
+        create_credential(&mut storage, cred.clone()).await.unwrap();
+        storage.change_passphrase("new").await.unwrap();
+
+        assert!(matches!(
+            EncryptedFileStorage::unlock(&path, "old").await,
+            Err(EncryptedStoreError::WrongPassphrase)
+        ));
+        let storage = EncryptedFileStorage::unlock(&path, "new").await.unwrap();
+        assert_eq!(storage.list_credentials().await.unwrap(), vec![cred]);
+    }
+
+    #[tokio::test]
+    async fn test_tampering_is_detected() {
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.store");
+        EncryptedFileStorage::create(&path, "secret", TEST_KDF)
+            .await
+            .unwrap();
+        let mut file = EncryptedFileStorage::read_file(&path).await.unwrap();
+        let last = file.credentials.len() - 1;
+        file.credentials[last] ^= 1;
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&file, &mut bytes).unwrap();
+        std::fs::write(&path, bytes).unwrap();
+        assert!(matches!(
+            EncryptedFileStorage::unlock(&path, "secret").await,
+            Err(EncryptedStoreError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    ffi::OsString,
+    fs::TryLockError,
+    io,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tracing::debug;
+
+use super::state::{AuthenticatorState, StateError, StateStore};
+
+/// The path of a sibling of the file at `path`, named after it
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Replaces the file at `path` with `data`, such that a crash leaves either the previous or
+/// the new contents behind. The data is written to a sibling file readable by the owner
+/// only, which is then renamed over `path`.
+pub async fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp_path = sibling(path, ".tmp");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    fs::rename(&temp_path, path).await?;
+
+    // the rename itself is only durable once the directory was synced
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir).await?.sync_all().await
+}
+
+/// An exclusive lock on a file replaced via [write_atomically], e.g, held by the daemon for
+/// as long as it uses the file, so that other processes don't change it behind its back.
+///
+/// The lock is taken on a sibling `.lock` file, as the file itself is replaced upon every
+/// change, and is released once dropped, or once the process exits.
+#[derive(Debug)]
+pub struct FileLock {
+    _file: std::fs::File,
+}
+
+impl FileLock {
+    /// Locks the file at `path`, returning `None` if it's already locked, even by this process
+    pub fn try_lock(path: &Path) -> io::Result<Option<Self>> {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(sibling(path, ".lock"))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(FileLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+/// Keeps the authenticator state in a file, replaced atomically upon every change. The file
+/// is locked for as long as the store is open, such that e.g. the seed can't be changed while
+/// a daemon uses it.
+#[derive(Debug)]
+pub struct FileStateStore {
+    path: PathBuf,
+    _lock: FileLock,
+}
+
+impl FileStateStore {
+    /// Opens the state file at `path`, failing if another process has it open
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, StateError> {
+        let path = path.into();
+        let lock = FileLock::try_lock(&path)?.ok_or_else(|| StateError::InUse(path.clone()))?;
+        Ok(FileStateStore { path, _lock: lock })
+    }
+}
+
+#[async_trait]
+impl StateStore for FileStateStore {
+    async fn load(&self) -> Result<AuthenticatorState, StateError> {
+        match fs::read(&self.path).await {
+            Ok(bytes) => AuthenticatorState::from_bytes(&bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!(path = ?self.path, "No saved state, starting from the factory state");
+                Ok(AuthenticatorState::default())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save(&mut self, state: &AuthenticatorState) -> Result<(), StateError> {
+        Ok(write_atomically(&self.path, &state.to_bytes()).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::test_util::temp_dir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_state_survives_restart() {
+        let dir = temp_dir();
+        let path = dir.path().join("state.cbor");
+        let mut store = FileStateStore::open(&path).unwrap();
+        assert_eq!(store.load().await.unwrap(), AuthenticatorState::default());
+
+        let mut state = AuthenticatorState::default();
+        state.pin.retries = 0;
+        state.global_sign_count = 7;
+        store.save(&state).await.unwrap();
+        drop(store);
+        assert_eq!(
+            FileStateStore::open(&path).unwrap().load().await.unwrap(),
+            state
+        );
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!path.with_file_name("state.cbor.tmp").exists());
+    }
+
+    #[test]
+    fn test_file_lock() {
+        let dir = temp_dir();
+        let path = dir.path().join("state.cbor");
+        let lock = FileLock::try_lock(&path).unwrap();
+        assert!(lock.is_some());
+        assert!(FileLock::try_lock(&path).unwrap().is_none());
+        drop(lock);
+        assert!(FileLock::try_lock(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_state_file_is_locked() {
+        let dir = temp_dir();
+        let path = dir.path().join("state.cbor");
+        let store = FileStateStore::open(&path).unwrap();
+        assert!(matches!(
+            FileStateStore::open(&path),
+            Err(StateError::InUse(_))
+        ));
+        drop(store);
+        FileStateStore::open(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_malformed_state_is_rejected() {
+        let dir = temp_dir();
+        let path = dir.path().join("state.cbor");
+        std::fs::write(&path, b"not cbor").unwrap();
+        assert!(matches!(
+            FileStateStore::open(&path).unwrap().load().await,
+            Err(StateError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use linux_keyutils::{KeyError, KeyPermissionsBuilder, KeyRing, KeyRingIdentifier, Permission};
+use zeroize::Zeroizing;
+
+/// How long an unlocked store stays unlocked unless configured otherwise
+pub const DEFAULT_KEY_TIMEOUT: Duration = Duration::from_secs(8 * 60 * 60);
+
+/// The kernel keyring caching a key, see
+/// [keyrings(7)](https://man7.org/linux/man-pages/man7/keyrings.7.html)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyringKind {
+    /// Shared by all processes of the user, e.g, a daemon run by the service manager
+    User,
+    /// Shared by the processes of the login session only
+    Session,
+}
+
+impl KeyringKind {
+    fn keyring(self) -> Result<KeyRing, KeyError> {
+        let id = match self {
+            KeyringKind::User => KeyRingIdentifier::User,
+            KeyringKind::Session => KeyRingIdentifier::Session,
+        };
+        KeyRing::from_special_id(id, true)
+    }
+}
+
+/// Caches a secret key of fixed length in a kernel keyring rather than in process memory,
+/// so that it outlives restarts of the process, until it expires after a timeout.
+///
+/// Only processes possessing the key, i.e. subscribed to the keyring keeping it, may access
+/// it, while other processes of the user can't read it by its serial number, or even see it
+/// in `/proc/keys`. Note every process of the user possesses the user keyring though, which
+/// could read the memory of the process keeping the key anyway.
+#[derive(Debug, Clone)]
+pub struct KeyringCache {
+    kind: KeyringKind,
+    description: String,
+    timeout: Duration,
+}
+
+impl KeyringCache {
+    /// The cache of the key of the store at `path`
+    pub fn new(kind: KeyringKind, path: &Path) -> io::Result<Self> {
+        let path: PathBuf = std::path::absolute(path)?;
+        Ok(KeyringCache {
+            kind,
+            description: format!("softauth:{}", path.display()),
+            timeout: DEFAULT_KEY_TIMEOUT,
+        })
+    }
+
+    /// Expires the key this long after it was stored
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Caches `key`, replacing the one cached before
+    pub fn store(&self, key: &[u8]) -> Result<(), KeyError> {
+        let cached = self.kind.keyring()?.add_key(&self.description, key)?;
+        let restricted = cached
+            .set_perms(
+                KeyPermissionsBuilder::builder()
+                    .posessor(Permission::ALL)
+                    .build(),
+            )
+            // a timeout of 0 would keep the key forever
+            .and_then(|_| cached.set_timeout((self.timeout.as_secs() as usize).max(1)));
+        if let Err(e) = restricted {
+            // don't leave the key readable by others, or cached forever
+            let _ = cached.invalidate();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Returns the cached key, or `None` if none was cached, or it expired or was cleared
+    pub fn load<const N: usize>(&self) -> Result<Option<Zeroizing<[u8; N]>>, KeyError> {
+        let cached = match self.kind.keyring()?.search(&self.description) {
+            Ok(cached) => cached,
+            Err(KeyError::KeyDoesNotExist | KeyError::KeyExpired | KeyError::KeyRevoked) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        // one more byte tells a longer key apart, as does the returned length, which is the
+        // length of the whole key even if it doesn't fit
+        let mut buf = Zeroizing::new(vec![0; N + 1]);
+        match cached.read(&mut *buf) {
+            Ok(len) if len == N => {
+                let mut key = Zeroizing::new([0; N]);
+                key.copy_from_slice(&buf[..N]);
+                Ok(Some(key))
+            }
+            Ok(_) => Ok(None),
+            Err(KeyError::KeyDoesNotExist | KeyError::KeyExpired | KeyError::KeyRevoked) => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the cached key, returning whether one was cached
+    pub fn clear(&self) -> Result<bool, KeyError> {
+        match self.kind.keyring()?.search(&self.description) {
+            Ok(cached) => cached.invalidate().map(|_| true),
+            Err(KeyError::KeyDoesNotExist | KeyError::KeyExpired | KeyError::KeyRevoked) => {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::test_util::temp_dir;
+
+    use super::*;
+
+    /// A cache for a store within `dir`, whose path sets the description of the key
+    fn cache_in(kind: KeyringKind, dir: &TempDir) -> KeyringCache {
+        KeyringCache::new(kind, &dir.path().join("credentials.store")).unwrap()
+    }
+
+    /// Uses the kernel keyring, run it via `cargo test -- --ignored keyring`
+    #[test]
+    #[ignore]
+    fn test_store_load_clear() {
+        let dir = temp_dir();
+        let cache = cache_in(KeyringKind::Session, &dir);
+        assert_eq!(cache.load::<32>().unwrap(), None);
+        cache.store(&[7; 32]).unwrap();
+        assert_eq!(cache.load::<32>().unwrap(), Some(Zeroizing::new([7; 32])));
+        // a key of another length isn't returned
+        assert_eq!(cache.load::<16>().unwrap(), None);
+        assert_eq!(cache.load::<31>().unwrap(), None);
+        assert_eq!(cache.load::<33>().unwrap(), None);
+        assert_eq!(cache.load::<128>().unwrap(), None);
+        cache.store(&[8; 32]).unwrap();
+        assert_eq!(cache.load::<32>().unwrap(), Some(Zeroizing::new([8; 32])));
+
+        assert!(cache.clear().unwrap());
+        assert_eq!(cache.load::<32>().unwrap(), None);
+        assert!(!cache.clear().unwrap());
+    }
+
+    /// Uses the kernel keyring, run it via `cargo test -- --ignored keyring`
+    #[test]
+    #[ignore]
+    fn test_user_keyring() {
+        let dir = temp_dir();
+        let cache = cache_in(KeyringKind::User, &dir);
+        cache.store(&[7; 32]).unwrap();
+        assert_eq!(cache.load::<32>().unwrap(), Some(Zeroizing::new([7; 32])));
+        // the permissions of the possessor only, see keyrings(7)
+        let keys = std::fs::read_to_string("/proc/keys").unwrap();
+        let key = keys
+            .lines()
+            .find(|line| line.ends_with(&format!("{}: 32", cache.description)))
+            .unwrap();
+        assert_eq!(key.split_whitespace().nth(4), Some("3f000000"));
+        assert!(cache.clear().unwrap());
+    }
+
+    /// Uses the kernel keyring, run it via `cargo test -- --ignored keyring`
+    #[test]
+    #[ignore]
+    fn test_key_expires() {
+        let dir = temp_dir();
+        let cache = cache_in(KeyringKind::Session, &dir).with_timeout(Duration::from_secs(1));
+        cache.store(&[7; 32]).unwrap();
+        assert!(cache.load::<32>().unwrap().is_some());
+        std::thread::sleep(Duration::from_millis(1500));
+        assert_eq!(cache.load::<32>().unwrap(), None);
+        cache.clear().unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::authenticator::types::{CredentialId, PublicKeyCredentialSource, RpIdHash};
+
+use super::{
+    state::{AuthenticatorState, StateError, StateStore},
+    store::{CredentialKey, Storage, StorageError, StorageTransaction},
+};
+
+/// Keeps credentials in memory only, thus they're lost once the authenticator stops.
+/// Serves as the reference implementation of [Storage].
+#[derive(Debug, Default)]
+pub struct InMemoryStorage {
+    credentials: Vec<PublicKeyCredentialSource>,
+    credential_key: Option<CredentialKey>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Storage for InMemoryStorage {
+    async fn get_credential(
+        &self,
+        id: &CredentialId,
+    ) -> Result<Option<PublicKeyCredentialSource>, StorageError> {
+        Ok(self.credentials.iter().find(|cred| &cred.id == id).cloned())
+    }
+
+    async fn get_credentials_for_rp(
+        &self,
+        rp_id_hash: &RpIdHash,
+    ) -> Result<Vec<PublicKeyCredentialSource>, StorageError> {
+        Ok(self
+            .credentials
+            .iter()
+            .filter(|cred| &cred.rp_id_hash == rp_id_hash)
+            .cloned()
+            .collect())
+    }
+
+    async fn list_credentials(&self) -> Result<Vec<PublicKeyCredentialSource>, StorageError> {
+        Ok(self.credentials.clone())
+    }
+
+    async fn count_credentials(&self) -> Result<usize, StorageError> {
+        Ok(self.credentials.len())
+    }
+
+    async fn apply(&mut self, transaction: StorageTransaction) -> Result<(), StorageError> {
+        transaction.apply_to(&mut self.credentials)?;
+        if transaction.deletes_all() {
+            self.credential_key = None;
+        }
+        Ok(())
+    }
+
+    async fn credential_key(&self) -> Result<Option<CredentialKey>, StorageError> {
+        Ok(self.credential_key.clone())
+    }
+
+    async fn set_credential_key(&mut self, key: &CredentialKey) -> Result<(), StorageError> {
+        self.credential_key = Some(key.clone());
+        Ok(())
+    }
+}
+
+/// Keeps the authenticator state in memory only, shared by its clones
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryStateStore {
+    state: Arc<Mutex<AuthenticatorState>>,
+}
+
+impl InMemoryStateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl StateStore for InMemoryStateStore {
+    async fn load(&self) -> Result<AuthenticatorState, StateError> {
+        Ok(self.state.lock().unwrap().clone())
+    }
+
+    async fn save(&mut self, state: &AuthenticatorState) -> Result<(), StateError> {
+        *self.state.lock().unwrap() = state.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::authenticator::storage::conformance;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_conformance() {
+        conformance::check(|| async { InMemoryStorage::new() }).await;
+    }
+}
@@ -0,0 +1,11 @@
+#[cfg(test)]
+pub(crate) mod conformance;
+pub(crate) mod encrypted;
+pub(crate) mod file;
+pub(crate) mod keyring;
+pub(crate) mod memory;
+pub(crate) mod passphrase;
+pub(crate) mod secret_service;
+pub(crate) mod sqlite;
+pub(crate) mod state;
+pub(crate) mod store;
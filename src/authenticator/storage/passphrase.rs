@@ -0,0 +1,152 @@
+use std::{io, path::PathBuf};
+
+use thiserror::Error;
+use tokio::process::Command;
+use zeroize::Zeroizing;
+
+/// The variable holding the passphrase within an environment file
+pub const PASSPHRASE_VARIABLE: &str = "SOFTAUTH_PASSPHRASE";
+
+#[derive(Debug, Error)]
+pub enum PassphraseError {
+    #[error("Couldn't read the passphrase: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0:?} doesn't set {PASSPHRASE_VARIABLE}")]
+    MissingVariable(PathBuf),
+
+    #[error("systemd-ask-password failed: {0}")]
+    AskPassword(String),
+
+    #[error("The passphrases don't match")]
+    Mismatch,
+
+    #[error("The passphrase is empty")]
+    Empty,
+}
+
+/// Where the passphrase of an encrypted store comes from
+#[derive(Debug, Clone)]
+pub enum PassphraseSource {
+    /// Prompt on the controlling terminal
+    Terminal,
+
+    /// Read [PASSPHRASE_VARIABLE] from an environment file, e.g, the `EnvironmentFile=` of
+    /// a systemd unit
+    EnvFile(PathBuf),
+
+    /// Prompt via systemd-ask-password, e.g, on the console or a graphical agent when
+    /// started at boot
+    SystemdAskPassword,
+}
+
+impl PassphraseSource {
+    pub async fn read(&self, prompt: &str) -> Result<Zeroizing<String>, PassphraseError> {
+        let passphrase = match self {
+            PassphraseSource::Terminal => {
+                let prompt = format!("{}: ", prompt);
+                let passphrase =
+                    tokio::task::spawn_blocking(move || rpassword::prompt_password(prompt))
+                        .await
+                        .map_err(io::Error::from)??;
+                Zeroizing::new(passphrase)
+            }
+            PassphraseSource::EnvFile(path) => {
+                let contents = Zeroizing::new(tokio::fs::read_to_string(path).await?);
+                parse_env_file(&contents)
+                    .ok_or_else(|| PassphraseError::MissingVariable(path.clone()))?
+            }
+            PassphraseSource::SystemdAskPassword => {
+                let output = Command::new("systemd-ask-password")
+                    .arg("--id=softauth")
+                    .arg(format!("{}:", prompt))
+                    .output()
+                    .await?;
+                let stdout = Zeroizing::new(output.stdout);
+                if !output.status.success() {
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    return Err(PassphraseError::AskPassword(stderr.trim().to_owned()));
+                }
+                let stdout = std::str::from_utf8(&stdout)
+                    .map_err(|e| PassphraseError::AskPassword(e.to_string()))?;
+                Zeroizing::new(stdout.trim_end_matches('\n').to_owned())
+            }
+        };
+        if passphrase.is_empty() {
+            return Err(PassphraseError::Empty);
+        }
+        Ok(passphrase)
+    }
+
+    /// Reads a passphrase about to be set, asking for it twice unless it comes from a file
+    pub async fn read_new(&self, prompt: &str) -> Result<Zeroizing<String>, PassphraseError> {
+        let passphrase = self.read(prompt).await?;
+        if let PassphraseSource::EnvFile(_) = self {
+            return Ok(passphrase);
+        }
+        let confirmation = self.read("Repeat the passphrase").await?;
+        if passphrase != confirmation {
+            return Err(PassphraseError::Mismatch);
+        }
+        Ok(passphrase)
+    }
+}
+
+/// Finds the value of [PASSPHRASE_VARIABLE] among the `KEY=VALUE` lines of an environment
+/// file, which may be quoted
+fn parse_env_file(contents: &str) -> Option<Zeroizing<String>> {
+    contents.lines().rev().find_map(|line| {
+        let (key, value) = line.trim_start().split_once('=')?;
+        if key.trim_end() != PASSPHRASE_VARIABLE {
+            return None;
+        }
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(value);
+        Some(Zeroizing::new(value.to_owned()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_env_file() {
+        let parse = |contents| parse_env_file(contents).map(|value| value.to_string());
+        let contents = "# softauth\nRUST_LOG=debug\nSOFTAUTH_PASSPHRASE=correct horse\n";
+        assert_eq!(parse(contents).as_deref(), Some("correct horse"));
+        assert_eq!(
+            parse("SOFTAUTH_PASSPHRASE = \"battery staple\"").as_deref(),
+            Some("battery staple")
+        );
+        // the last assignment wins, as with systemd
+        assert_eq!(
+            parse("SOFTAUTH_PASSPHRASE='a'\nSOFTAUTH_PASSPHRASE=b").as_deref(),
+            Some("b")
+        );
+        assert_eq!(parse("RUST_LOG=debug\n#SOFTAUTH_PASSPHRASE=a"), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_env_file() {
+        let dir = temp_dir();
+        let path = dir.path().join("passphrase.env");
+        std::fs::write(&path, "SOFTAUTH_PASSPHRASE=secret\n").unwrap();
+        let source = PassphraseSource::EnvFile(path.clone());
+        assert_eq!(
+            source.read_new("Passphrase").await.unwrap().as_str(),
+            "secret"
+        );
+
+        std::fs::write(&path, "SOFTAUTH_PASSPHRASE=\n").unwrap();
+        assert!(matches!(
+            source.read("Passphrase").await,
+            Err(PassphraseError::Empty)
+        ));
+    }
+}
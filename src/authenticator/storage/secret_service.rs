@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use secret_service::{Collection, EncryptionType, Item, SecretService};
+use thiserror::Error;
+use tracing::{debug, info};
+use zeroize::Zeroizing;
+
+use crate::authenticator::types::{CredentialId, PublicKeyCredentialSource, RpIdHash};
+
+use super::store::{
+    credential_key_from_bytes, CredentialKey, Storage, StorageError, StorageOperation,
+    StorageTransaction,
+};
+
+/// Marks the items of this authenticator among the others of the collection
+const APPLICATION: (&str, &str) = ("application", "softauth");
+
+/// The content type of the secret of an item, which is the whole credential
+const CONTENT_TYPE: &str = "application/cbor";
+
+/// Marks the item keeping the credential key, which isn't among the credential items
+const CREDENTIAL_KEY_APPLICATION: (&str, &str) = ("application", "softauth-credential-key");
+
+#[derive(Debug, Error)]
+pub enum SecretServiceError {
+    #[error("Secret Service failed: {0}")]
+    SecretService(#[from] secret_service::Error),
+
+    #[error("The keyring item {0} is malformed: {1}")]
+    Malformed(String, String),
+}
+
+impl From<secret_service::Error> for StorageError {
+    fn from(e: secret_service::Error) -> Self {
+        StorageError::Backend(Box::new(e))
+    }
+}
+
+impl From<SecretServiceError> for StorageError {
+    fn from(e: SecretServiceError) -> Self {
+        StorageError::Backend(Box::new(e))
+    }
+}
+
+/// A credential along with the item keeping it
+struct StoredItem<'a> {
+    item: Item<'a>,
+    seq: u64,
+    credential: PublicKeyCredentialSource,
+}
+
+/// The attributes the items are searched by, besides their creation sequence number
+fn attributes(credential: &PublicKeyCredentialSource, seq: u64) -> HashMap<String, String> {
+    let mut attributes = HashMap::from([
+        (APPLICATION.0.to_owned(), APPLICATION.1.to_owned()),
+        ("credential_id".to_owned(), hex::encode(&credential.id.0)),
+        (
+            "rp_id_hash".to_owned(),
+            hex::encode(credential.rp_id_hash.0),
+        ),
+        ("seq".to_owned(), seq.to_string()),
+    ]);
+    if let Some(rp_id) = &credential.rp_id {
+        attributes.insert("rp_id".to_owned(), rp_id.0.clone());
+    }
+    if let Some(user_handle) = &credential.user_handle {
+        attributes.insert("user_handle".to_owned(), hex::encode(&user_handle.0));
+    }
+    attributes
+}
+
+fn as_str(attributes: &HashMap<String, String>) -> HashMap<&str, &str> {
+    attributes
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect()
+}
+
+/// The secret of an item
+fn encode(credential: &PublicKeyCredentialSource) -> Zeroizing<Vec<u8>> {
+    let mut secret = Zeroizing::new(Vec::new());
+    ciborium::ser::into_writer(credential, &mut *secret)
+        .expect("Serializing a credential into a vector cannot fail");
+    secret
+}
+
+fn label(credential: &PublicKeyCredentialSource) -> String {
+    match &credential.rp_id {
+        Some(rp_id) => format!("softauth credential for {}", rp_id.0),
+        None => format!(
+            "softauth credential for {}",
+            hex::encode(credential.rp_id_hash.0)
+        ),
+    }
+}
+
+/// Keeps credentials as items of the default collection of the freedesktop
+/// [Secret Service](https://specifications.freedesktop.org/secret-service-spec/latest/), e.g,
+/// gnome-keyring or KWallet. The secret of an item is the whole credential, while its
+/// attributes name the RP, user handle and credential ID. The credential key is another item.
+///
+/// The collection is locked and unlocked along with the desktop session. If it's locked when
+/// accessed, the Secret Service prompts the user to unlock it.
+///
+/// The Secret Service has no transactions, thus a transaction is validated before any
+/// operation is applied, but the Secret Service failing midway leaves it partially applied.
+pub struct SecretServiceStorage {
+    service: SecretService<'static>,
+}
+
+impl SecretServiceStorage {
+    /// Connects to the Secret Service of the D-Bus session, which must have a default
+    /// collection
+    pub async fn connect() -> Result<Self, SecretServiceError> {
+        let service = SecretService::connect(EncryptionType::Dh).await?;
+        let storage = SecretServiceStorage { service };
+        storage.collection().await?;
+        info!("Connected to the Secret Service");
+        Ok(storage)
+    }
+
+    /// The default collection, unlocked
+    async fn collection(&self) -> Result<Collection<'_>, SecretServiceError> {
+        let collection = self.service.get_default_collection().await?;
+        if collection.is_locked().await? {
+            debug!("Unlocking the default collection");
+            collection.unlock().await?;
+        }
+        Ok(collection)
+    }
+
+    /// Finds the items having the given attributes besides [APPLICATION], in creation order
+    async fn search<'a>(
+        collection: &'a Collection<'a>,
+        attribute: Option<(&str, &str)>,
+    ) -> Result<Vec<StoredItem<'a>>, SecretServiceError> {
+        let query = HashMap::from_iter([Some(APPLICATION), attribute].into_iter().flatten());
+        let mut items = Vec::new();
+        for item in collection.search_items(query).await? {
+            items.push(Self::read(item).await?);
+        }
+        items.sort_by_key(|item| item.seq);
+        Ok(items)
+    }
+
+    /// The creation sequence number of an item, read from its attributes
+    async fn seq(item: &Item<'_>) -> Result<u64, SecretServiceError> {
+        item.get_attributes()
+            .await?
+            .get("seq")
+            .and_then(|seq| seq.parse().ok())
+            .ok_or_else(|| {
+                SecretServiceError::Malformed(
+                    item.item_path.as_str().to_owned(),
+                    "missing sequence number".to_owned(),
+                )
+            })
+    }
+
+    /// The sequence number of the next item created, which only reads the attributes of
+    /// the items rather than their secrets
+    async fn next_seq(collection: &Collection<'_>) -> Result<u64, SecretServiceError> {
+        let mut next_seq = 0;
+        for item in collection
+            .search_items(HashMap::from([APPLICATION]))
+            .await?
+        {
+            next_seq = next_seq.max(Self::seq(&item).await? + 1);
+        }
+        Ok(next_seq)
+    }
+
+    async fn read(item: Item<'_>) -> Result<StoredItem<'_>, SecretServiceError> {
+        let malformed = |reason: String| {
+            SecretServiceError::Malformed(item.item_path.as_str().to_owned(), reason)
+        };
+        let seq = Self::seq(&item).await?;
+        let secret = Zeroizing::new(item.get_secret().await?);
+        let credential =
+            ciborium::de::from_reader(secret.as_slice()).map_err(|e| malformed(e.to_string()))?;
+        Ok(StoredItem {
+            item,
+            seq,
+            credential,
+        })
+    }
+
+    async fn find<'a>(
+        collection: &'a Collection<'a>,
+        id: &CredentialId,
+    ) -> Result<Option<StoredItem<'a>>, SecretServiceError> {
+        let id = hex::encode(&id.0);
+        let mut items = Self::search(collection, Some(("credential_id", &id))).await?;
+        Ok(items.pop())
+    }
+
+    /// The item keeping the credential key, of which there's at most one
+    async fn credential_key_items<'a>(
+        collection: &'a Collection<'a>,
+    ) -> Result<Vec<Item<'a>>, SecretServiceError> {
+        let query = HashMap::from([CREDENTIAL_KEY_APPLICATION]);
+        Ok(collection.search_items(query).await?)
+    }
+
+    async fn create(
+        collection: &Collection<'_>,
+        credential: &PublicKeyCredentialSource,
+        seq: u64,
+    ) -> Result<(), SecretServiceError> {
+        let attributes = attributes(credential, seq);
+        collection
+            .create_item(
+                &label(credential),
+                as_str(&attributes),
+                &encode(credential),
+                false,
+                CONTENT_TYPE,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for SecretServiceStorage {
+    async fn get_credential(
+        &self,
+        id: &CredentialId,
+    ) -> Result<Option<PublicKeyCredentialSource>, StorageError> {
+        let collection = self.collection().await?;
+        let item = Self::find(&collection, id).await?;
+        Ok(item.map(|item| item.credential))
+    }
+
+    async fn get_credentials_for_rp(
+        &self,
+        rp_id_hash: &RpIdHash,
+    ) -> Result<Vec<PublicKeyCredentialSource>, StorageError> {
+        let collection = self.collection().await?;
+        let rp_id_hash = hex::encode(rp_id_hash.0);
+        let items = Self::search(&collection, Some(("rp_id_hash", &rp_id_hash))).await?;
+        Ok(items.into_iter().map(|item| item.credential).collect())
+    }
+
+    async fn list_credentials(&self) -> Result<Vec<PublicKeyCredentialSource>, StorageError> {
+        let collection = self.collection().await?;
+        let items = Self::search(&collection, None).await?;
+        Ok(items.into_iter().map(|item| item.credential).collect())
+    }
+
+    async fn apply(&mut self, transaction: StorageTransaction) -> Result<(), StorageError> {
+        let collection = self.collection().await?;
+        // fails without side effects if an operation can't be applied, which only depends
+        // on the credentials the transaction touches
+        let mut ids = Vec::new();
+        for operation in transaction.operations() {
+            let id = match operation {
+                StorageOperation::Create(credential) | StorageOperation::Update(credential) => {
+                    &credential.id
+                }
+                StorageOperation::Delete(id) => id,
+                StorageOperation::DeleteAll => continue,
+            };
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        let mut credentials = Vec::new();
+        for id in ids {
+            if let Some(stored) = Self::find(&collection, id).await? {
+                credentials.push(stored.credential);
+            }
+        }
+        transaction.apply_to(&mut credentials)?;
+
+        let creates = transaction
+            .operations()
+            .iter()
+            .any(|operation| matches!(operation, StorageOperation::Create(_)));
+        let mut next_seq = if creates {
+            Self::next_seq(&collection).await?
+        } else {
+            0
+        };
+
+        for operation in transaction.operations() {
+            match operation {
+                StorageOperation::Create(credential) => {
+                    Self::create(&collection, credential, next_seq).await?;
+                    next_seq += 1;
+                }
+                StorageOperation::Update(credential) => {
+                    let stored = Self::find(&collection, &credential.id)
+                        .await?
+                        .ok_or_else(|| StorageError::NotFound(credential.id.clone()))?;
+                    // the secret comes first, as it's what credentials are read from, while
+                    // the attributes searched by, i.e. the IDs, never change
+                    stored
+                        .item
+                        .set_secret(&encode(credential), CONTENT_TYPE)
+                        .await?;
+                    let attributes = attributes(credential, stored.seq);
+                    stored.item.set_attributes(as_str(&attributes)).await?;
+                    stored.item.set_label(&label(credential)).await?;
+                }
+                StorageOperation::Delete(id) => {
+                    let stored = Self::find(&collection, id)
+                        .await?
+                        .ok_or_else(|| StorageError::NotFound(id.clone()))?;
+                    stored.item.delete().await?;
+                }
+                StorageOperation::DeleteAll => {
+                    for item in collection
+                        .search_items(HashMap::from([APPLICATION]))
+                        .await?
+                    {
+                        item.delete().await?;
+                    }
+                    for item in Self::credential_key_items(&collection).await? {
+                        item.delete().await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Only rewrites the secret of the credential's item, as its attributes and label don't
+    /// include the counter
+    async fn increment_sign_count(&mut self, id: &CredentialId) -> Result<u32, StorageError> {
+        let collection = self.collection().await?;
+        let mut stored = Self::find(&collection, id)
+            .await?
+            .ok_or_else(|| StorageError::NotFound(id.clone()))?;
+        stored.credential.sign_count = stored.credential.sign_count.saturating_add(1);
+        stored
+            .item
+            .set_secret(&encode(&stored.credential), CONTENT_TYPE)
+            .await?;
+        Ok(stored.credential.sign_count)
+    }
+
+    async fn credential_key(&self) -> Result<Option<CredentialKey>, StorageError> {
+        let collection = self.collection().await?;
+        let items = Self::credential_key_items(&collection).await?;
+        match items.first() {
+            Some(item) => {
+                let secret = Zeroizing::new(item.get_secret().await?);
+                credential_key_from_bytes(&secret).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn set_credential_key(&mut self, key: &CredentialKey) -> Result<(), StorageError> {
+        let collection = self.collection().await?;
+        collection
+            .create_item(
+                "softauth credential key",
+                HashMap::from([CREDENTIAL_KEY_APPLICATION]),
+                &key[..],
+                true,
+                "application/octet-stream",
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, BufRead, BufReader, Write},
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use tempfile::TempDir;
+
+    use crate::{
+        authenticator::storage::conformance::{self, create_credential, credential},
+        test_util::temp_dir,
+    };
+
+    use super::*;
+
+    /// Set in the environment of the test process which uses the private session bus
+    const KEYRING_SESSION_VAR: &str = "SOFTAUTH_TEST_KEYRING_SESSION";
+
+    /// A private D-Bus session bus along with gnome-keyring, which are killed when dropped
+    struct KeyringSession {
+        dbus: Child,
+        keyring: Child,
+        /// Keeps the keyrings, removed once dropped
+        _home: TempDir,
+        address: String,
+    }
+
+    impl Drop for KeyringSession {
+        fn drop(&mut self) {
+            let _ = self.keyring.kill();
+            let _ = self.dbus.kill();
+            let _ = self.keyring.wait();
+            let _ = self.dbus.wait();
+        }
+    }
+
+    /// Spawns the session. Keyrings are kept in a temporary home, rather than the one of the
+    /// user.
+    fn spawn_session() -> io::Result<KeyringSession> {
+        let mut dbus = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut address = String::new();
+        BufReader::new(dbus.stdout.take().unwrap()).read_line(&mut address)?;
+        let address = address.trim_end().to_owned();
+
+        let home = temp_dir();
+        let keyring = Command::new("gnome-keyring-daemon")
+            .args(["--foreground", "--components=secrets", "--unlock"])
+            .env("DBUS_SESSION_BUS_ADDRESS", &address)
+            .env("HOME", home.path())
+            .env("XDG_DATA_HOME", home.path())
+            .env("XDG_RUNTIME_DIR", home.path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn();
+        let keyring = match keyring {
+            Ok(keyring) => keyring,
+            Err(e) => {
+                let _ = dbus.kill();
+                let _ = dbus.wait();
+                return Err(e);
+            }
+        };
+        let mut session = KeyringSession {
+            dbus,
+            keyring,
+            _home: home,
+            address,
+        };
+        // the password of the login keyring, which becomes the default collection
+        let mut stdin = session.keyring.stdin.take().unwrap();
+        stdin.write_all(b"password")?;
+        drop(stdin);
+        Ok(session)
+    }
+
+    /// Connects once gnome-keyring has set up its default collection
+    async fn connect() -> SecretServiceStorage {
+        for _ in 0..50 {
+            if let Ok(storage) = SecretServiceStorage::connect().await {
+                return storage;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("The Secret Service didn't come up");
+    }
+
+    /// Requires dbus-daemon and gnome-keyring-daemon, run it via
+    /// `cargo test -- --ignored test_secret_service`.
+    ///
+    /// The Secret Service client only connects to the session bus named by the environment,
+    /// so the test runs itself again in a child process given the address of a private bus,
+    /// rather than touching the user's session.
+    #[tokio::test]
+    #[ignore]
+    async fn test_secret_service() {
+        if std::env::var_os(KEYRING_SESSION_VAR).is_none() {
+            let session = spawn_session().expect("Couldn't spawn dbus-daemon and gnome-keyring");
+            let (_, module) = module_path!().split_once("::").unwrap();
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args([&format!("{}::test_secret_service", module), "--exact"])
+                .args(["--ignored", "--nocapture"])
+                .env("DBUS_SESSION_BUS_ADDRESS", &session.address)
+                .env(KEYRING_SESSION_VAR, "1")
+                .status()
+                .unwrap();
+            assert!(
+                status.success(),
+                "The test failed on the private session bus"
+            );
+            return;
+        }
+        let mut storage = connect().await;
+
+        conformance::check(|| async {
+            let mut storage = connect().await;
+            storage.delete_all_credentials().await.unwrap();
+            storage
+        })
+        .await;
+
+        // items can be found by other applications, e.g, seahorse
+        storage.delete_all_credentials().await.unwrap();
+        let cred = credential(1, "example.com");
+        create_credential(&mut storage, cred.clone()).await.unwrap();
+        let collection = storage.collection().await.unwrap();
+        let user_handle = hex::encode(&cred.user_handle.as_ref().unwrap().0);
+        let items = collection
+            .search_items(HashMap::from([
+                ("rp_id", "example.com"),
+                ("user_handle", user_handle.as_str()),
+                ("credential_id", &hex::encode(&cred.id.0)),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            items[0].get_label().await.unwrap(),
+            "softauth credential for example.com"
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    fs::OpenOptions,
+    io,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, Row};
+use thiserror::Error;
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+use crate::authenticator::types::{
+    CredentialId, CredentialPrivateKey, PublicKeyCredentialSource, PublicKeyType, RpId, RpIdHash,
+    UserHandle,
+};
+
+use super::store::{
+    credential_key_from_bytes, CredentialKey, Storage, StorageError, StorageOperation,
+    StorageTransaction,
+};
+
+/// Forward migrations of the schema, a database having version `n` once the first `n`
+/// migrations were applied. Released migrations mustn't be changed, only new ones appended.
+const MIGRATIONS: &[&str] = &[
+    // 1: credentials, kept in creation order by their rowid
+    "CREATE TABLE credentials (
+        seq INTEGER PRIMARY KEY,
+        id BLOB NOT NULL,
+        rp_id TEXT,
+        rp_id_hash BLOB NOT NULL,
+        private_key BLOB NOT NULL,
+        user_handle BLOB,
+        discoverable INTEGER NOT NULL,
+        sign_count INTEGER NOT NULL
+    );
+    CREATE UNIQUE INDEX credentials_by_id ON credentials (id);
+    CREATE INDEX credentials_by_rp ON credentials (rp_id_hash);",
+    // 2: the credential key, in a single row
+    "CREATE TABLE credential_key (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        key BLOB NOT NULL
+    );",
+];
+
+const COLUMNS: &str = "id, rp_id, rp_id_hash, private_key, user_handle, discoverable, sign_count";
+
+#[derive(Debug, Error)]
+pub enum SqliteError {
+    #[error("Couldn't create the database: {0}")]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("The database has schema version {0}, only versions up to {} are supported", MIGRATIONS.len())]
+    UnsupportedSchema(u32),
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Backend(Box::new(e))
+    }
+}
+
+impl From<SqliteError> for StorageError {
+    fn from(e: SqliteError) -> Self {
+        StorageError::Backend(Box::new(e))
+    }
+}
+
+/// Keeps credentials in an SQLite database, indexed by credential ID and RP ID hash, which
+/// scales to many thousands of credentials.
+///
+/// Reads are run on the calling task, as they only take a few microseconds. Writes wait for
+/// the database to be synced to disk, thus run on a blocking thread.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if needed, and migrates it to the latest
+    /// schema
+    pub fn open(path: &Path) -> Result<Self, SqliteError> {
+        // the database holds private keys, thus mustn't be readable by others, which also
+        // applies to the journal files SQLite creates alongside
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)?;
+        let mut connection = Connection::open(path)?;
+        let journal_mode: String =
+            connection.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            warn!(?path, journal_mode, "Couldn't enable write-ahead logging");
+        }
+        // a transaction is only reported as committed once it's durable
+        connection.pragma_update(None, "synchronous", "FULL")?;
+        migrate(&mut connection)?;
+        Ok(SqliteStorage {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap()
+    }
+
+    /// Runs a write on a blocking thread, as committing it syncs the database to disk
+    async fn write<R, F>(&self, write: F) -> Result<R, StorageError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<R, StorageError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || write(&mut connection.lock().unwrap()))
+            .await
+            .map_err(|e| StorageError::Backend(Box::new(e)))?
+    }
+}
+
+/// Applies the migrations the database is missing, all at once
+fn migrate(connection: &mut Connection) -> Result<(), SqliteError> {
+    let transaction = connection.transaction()?;
+    transaction.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        [],
+    )?;
+    let version: Option<u32> = transaction
+        .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+        .optional()?;
+    if version.is_none() {
+        transaction.execute("INSERT INTO schema_version (version) VALUES (0)", [])?;
+    }
+    let version = version.unwrap_or(0);
+    let latest = MIGRATIONS.len() as u32;
+    if version > latest {
+        return Err(SqliteError::UnsupportedSchema(version));
+    }
+    for (migration, to) in MIGRATIONS[version as usize..].iter().zip(version + 1..) {
+        info!(from = to - 1, to, "Migrating the credential database");
+        transaction.execute_batch(migration)?;
+    }
+    transaction.execute("UPDATE schema_version SET version = ?1", [latest])?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn credential_from_row(row: &Row) -> rusqlite::Result<PublicKeyCredentialSource> {
+    Ok(PublicKeyCredentialSource {
+        _type: PublicKeyType::PublicKey,
+        id: CredentialId(row.get(0)?),
+        rp_id: row.get::<_, Option<String>>(1)?.map(RpId),
+        rp_id_hash: RpIdHash(row.get(2)?),
+        private_key: CredentialPrivateKey(row.get(3)?),
+        user_handle: row.get::<_, Option<Vec<u8>>>(4)?.map(UserHandle),
+        discoverable: row.get(5)?,
+        sign_count: row.get(6)?,
+    })
+}
+
+/// The parameters ?1 to ?7 of statements writing a credential, in the order of [COLUMNS]
+macro_rules! credential_params {
+    ($cred:expr) => {
+        params![
+            $cred.id.0,
+            $cred.rp_id.as_ref().map(|rp_id| &rp_id.0),
+            &$cred.rp_id_hash.0[..],
+            $cred.private_key.0,
+            $cred.user_handle.as_ref().map(|user| &user.0),
+            $cred.discoverable,
+            $cred.sign_count,
+        ]
+    };
+}
+
+fn is_constraint_violation(e: &rusqlite::Error) -> bool {
+    matches!(e.sqlite_error_code(), Some(ErrorCode::ConstraintViolation))
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_credential(
+        &self,
+        id: &CredentialId,
+    ) -> Result<Option<PublicKeyCredentialSource>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare_cached(&format!("SELECT {COLUMNS} FROM credentials WHERE id = ?1"))?;
+        Ok(statement
+            .query_row([&id.0], credential_from_row)
+            .optional()?)
+    }
+
+    async fn get_credentials_for_rp(
+        &self,
+        rp_id_hash: &RpIdHash,
+    ) -> Result<Vec<PublicKeyCredentialSource>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare_cached(&format!(
+            "SELECT {COLUMNS} FROM credentials WHERE rp_id_hash = ?1 ORDER BY seq"
+        ))?;
+        let credentials = statement
+            .query_map([&rp_id_hash.0[..]], credential_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(credentials)
+    }
+
+    async fn list_credentials(&self) -> Result<Vec<PublicKeyCredentialSource>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare_cached(&format!("SELECT {COLUMNS} FROM credentials ORDER BY seq"))?;
+        let credentials = statement
+            .query_map([], credential_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(credentials)
+    }
+
+    async fn count_credentials(&self) -> Result<usize, StorageError> {
+        let count: u32 =
+            self.connection()
+                .query_row("SELECT COUNT(*) FROM credentials", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    async fn apply(&mut self, transaction: StorageTransaction) -> Result<(), StorageError> {
+        self.write(move |connection| {
+            // rolled back when dropped, i.e, unless every operation succeeded
+            let tx = connection.transaction()?;
+            for operation in transaction.operations() {
+                match operation {
+                    StorageOperation::Create(cred) => {
+                        let res = tx
+                            .prepare_cached(
+                                "INSERT INTO credentials (id, rp_id, rp_id_hash, private_key,
+                                user_handle, discoverable, sign_count)
+                            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                            )?
+                            .execute(credential_params!(cred));
+                        match res {
+                            Err(e) if is_constraint_violation(&e) => {
+                                return Err(StorageError::AlreadyExists(cred.id.clone()))
+                            }
+                            res => res?,
+                        };
+                    }
+                    StorageOperation::Update(cred) => {
+                        let updated = tx
+                        .prepare_cached(
+                            "UPDATE credentials SET rp_id = ?2, rp_id_hash = ?3, private_key = ?4,
+                                user_handle = ?5, discoverable = ?6, sign_count = ?7
+                            WHERE id = ?1",
+                        )?
+                        .execute(credential_params!(cred))?;
+                        if updated == 0 {
+                            return Err(StorageError::NotFound(cred.id.clone()));
+                        }
+                    }
+                    StorageOperation::Delete(id) => {
+                        let deleted = tx
+                            .prepare_cached("DELETE FROM credentials WHERE id = ?1")?
+                            .execute([&id.0])?;
+                        if deleted == 0 {
+                            return Err(StorageError::NotFound(id.clone()));
+                        }
+                    }
+                    StorageOperation::DeleteAll => {
+                        tx.execute("DELETE FROM credentials", [])?;
+                        tx.execute("DELETE FROM credential_key", [])?;
+                    }
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn credential_key(&self) -> Result<Option<CredentialKey>, StorageError> {
+        let key: Option<Vec<u8>> = self
+            .connection()
+            .query_row("SELECT key FROM credential_key", [], |row| row.get(0))
+            .optional()?;
+        key.map(|key| credential_key_from_bytes(&Zeroizing::new(key)))
+            .transpose()
+    }
+
+    async fn set_credential_key(&mut self, key: &CredentialKey) -> Result<(), StorageError> {
+        let key = key.clone();
+        self.write(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO credential_key (id, key) VALUES (0, ?1)",
+                [&key[..]],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn increment_sign_count(&mut self, id: &CredentialId) -> Result<u32, StorageError> {
+        let id = id.clone();
+        self.write(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "UPDATE credentials SET sign_count = MIN(sign_count + 1, 4294967295) WHERE id = ?1
+                RETURNING sign_count",
+            )?;
+            statement
+                .query_row([&id.0], |row| row.get(0))
+                .optional()?
+                .ok_or(StorageError::NotFound(id))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        authenticator::storage::conformance::{self, create_credential, credential},
+        test_util::temp_dir,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let dir = temp_dir();
+        let mut n = 0;
+        conformance::check(|| {
+            n += 1;
+            let path = dir.path().join(format!("{}.db", n));
+            async move { SqliteStorage::open(&path).unwrap() }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_credentials_survive_reopening() {
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.db");
+        let cred = credential(1, "example.com");
+        let mut storage = SqliteStorage::open(&path).unwrap();
+        create_credential(&mut storage, cred.clone()).await.unwrap();
+        drop(storage);
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.list_credentials().await.unwrap(), vec![cred]);
+        let connection = storage.connection();
+        let version: u32 = connection
+            .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+        let journal_mode: String = connection
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+    }
+
+    #[test]
+    fn test_newer_schema_is_rejected() {
+        let dir = temp_dir();
+        let path = dir.path().join("credentials.db");
+        drop(SqliteStorage::open(&path).unwrap());
+        Connection::open(&path)
+            .unwrap()
+            .execute("UPDATE schema_version SET version = version + 1", [])
+            .unwrap();
+        assert!(matches!(
+            SqliteStorage::open(&path),
+            Err(SqliteError::UnsupportedSchema(v)) if v as usize == MIGRATIONS.len() + 1
+        ));
+    }
+
+    #[test]
+    fn test_lookups_use_indexes() {
+        let dir = temp_dir();
+        let storage = SqliteStorage::open(&dir.path().join("credentials.db")).unwrap();
+        let plan = |query: &str| -> String {
+            let connection = storage.connection();
+            let mut statement = connection
+                .prepare(&format!("EXPLAIN QUERY PLAN {}", query))
+                .unwrap();
+            let rows = statement
+                .query_map([], |row| row.get::<_, String>(3))
+                .unwrap();
+            rows.collect::<Result<Vec<_>, _>>().unwrap().join("\n")
+        };
+        assert!(plan("SELECT * FROM credentials WHERE id = x'00'").contains("credentials_by_id"));
+        assert!(
+            plan("SELECT * FROM credentials WHERE rp_id_hash = x'00' ORDER BY seq")
+                .contains("credentials_by_rp")
+        );
+    }
+}
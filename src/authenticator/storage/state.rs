@@ -0,0 +1,344 @@
+use std::{fmt, ops::Deref, path::PathBuf};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::ByteBuf;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// Version of the encoding produced by [AuthenticatorState::to_bytes], to be increased
+/// whenever a change to the state can't be read by older releases
+pub const STATE_VERSION: u32 = 1;
+
+/// The number of wrong PINs after which the authenticator is blocked until reset
+pub const MAX_PIN_RETRIES: u8 = 8;
+
+/// The minimum PIN length (code points) unless raised by authenticatorConfig
+pub const DEFAULT_MIN_PIN_LENGTH: u8 = 4;
+
+/// The serialized large-blob array of an authenticator without large blobs: an empty CBOR
+/// array followed by the first 16 bytes of its SHA-256 hash.
+/// [See more](https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#authenticatorLargeBlobs)
+pub const EMPTY_LARGE_BLOB_ARRAY: [u8; 17] = [
+    0x80, 0x76, 0xbe, 0x8b, 0x52, 0x8d, 0x00, 0x75, 0xf7, 0xaa, 0xe9, 0x8d, 0x6f, 0xa5, 0x7a, 0x6d,
+    0x3c,
+];
+
+#[derive(Debug, Error)]
+pub enum StateError {
+    #[error("Couldn't access the authenticator state: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("{0:?} is in use by another process, e.g, a running daemon")]
+    InUse(PathBuf),
+
+    #[error("Malformed authenticator state: {0}")]
+    Malformed(String),
+
+    #[error("The authenticator state has version {0}, only version {STATE_VERSION} is supported")]
+    UnsupportedVersion(u32),
+}
+
+/// State of the PIN/UV auth protocol, see
+/// [authenticatorClientPIN](https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#authenticatorClientPIN)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PinState {
+    /// The first 16 bytes of the SHA-256 hash of the PIN, if one is set
+    pub hash: Option<ByteBuf>,
+
+    /// Attempts left before the PIN is blocked
+    pub retries: u8,
+
+    /// Whether the PIN must be changed before being used, e.g, after raising minPinLength
+    pub force_change: bool,
+}
+
+impl Default for PinState {
+    fn default() -> Self {
+        PinState {
+            hash: None,
+            retries: MAX_PIN_RETRIES,
+            force_change: false,
+        }
+    }
+}
+
+/// Settings changed via
+/// [authenticatorConfig](https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#authenticatorConfig)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthenticatorConfig {
+    pub always_uv: bool,
+    pub enterprise_attestation: bool,
+    pub min_pin_length: u8,
+}
+
+impl Default for AuthenticatorConfig {
+    fn default() -> Self {
+        AuthenticatorConfig {
+            always_uv: false,
+            enterprise_attestation: false,
+            min_pin_length: DEFAULT_MIN_PIN_LENGTH,
+        }
+    }
+}
+
+/// Secret bytes of the state, wiped from memory once dropped and never logged
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretBytes(Zeroizing<Vec<u8>>);
+
+impl From<&[u8]> for SecretBytes {
+    fn from(bytes: &[u8]) -> Self {
+        SecretBytes(Zeroizing::new(bytes.to_vec()))
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes({} bytes)", self.0.len())
+    }
+}
+
+impl Serialize for SecretBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = ByteBuf::deserialize(deserializer)?;
+        Ok(SecretBytes(Zeroizing::new(bytes.into_vec())))
+    }
+}
+
+/// Everything the authenticator must remember across restarts, besides its credentials
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthenticatorState {
+    pub pin: PinState,
+
+    /// Signature counter of credentials not keeping their own, which mustn't go backwards
+    pub global_sign_count: u32,
+
+    pub config: AuthenticatorConfig,
+
+    /// The serialized large-blob array
+    pub large_blob_array: ByteBuf,
+
+    /// The master seed from which the keys of new non-discoverable credentials are derived
+    /// instead, if set up. It's backed up as a BIP39 recovery phrase, and discarded upon reset.
+    pub seed: Option<SecretBytes>,
+}
+
+impl Default for AuthenticatorState {
+    fn default() -> Self {
+        AuthenticatorState {
+            pin: PinState::default(),
+            global_sign_count: 0,
+            config: AuthenticatorConfig::default(),
+            large_blob_array: ByteBuf::from(EMPTY_LARGE_BLOB_ARRAY),
+            seed: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VersionedState<'a> {
+    version: u32,
+    state: &'a AuthenticatorState,
+}
+
+#[derive(Deserialize)]
+struct RawVersionedState {
+    version: u32,
+    state: ciborium::value::Value,
+}
+
+impl AuthenticatorState {
+    /// Restores the factory state upon authenticatorReset. The signature counter belongs
+    /// to the device rather than its user, thus is kept.
+    pub fn reset(&mut self) {
+        *self = AuthenticatorState {
+            global_sign_count: self.global_sign_count,
+            ..Default::default()
+        };
+    }
+
+    /// Encodes the state as CBOR, along with [STATE_VERSION]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let versioned = VersionedState {
+            version: STATE_VERSION,
+            state: self,
+        };
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&versioned, &mut bytes)
+            .expect("Serializing the state into a vector cannot fail");
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        let raw: RawVersionedState =
+            ciborium::de::from_reader(bytes).map_err(|e| StateError::Malformed(e.to_string()))?;
+        // older versions would be migrated here
+        if raw.version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(raw.version));
+        }
+        raw.state
+            .deserialized()
+            .map_err(|e| StateError::Malformed(e.to_string()))
+    }
+}
+
+/// Keeps the [AuthenticatorState] across restarts
+#[async_trait]
+pub trait StateStore: Send + Sync {
+    /// Loads the saved state, or the factory state if none was saved yet
+    async fn load(&self) -> Result<AuthenticatorState, StateError>;
+
+    /// Replaces the saved state atomically, such that a crash leaves either the previous or
+    /// the new state behind
+    async fn save(&mut self, state: &AuthenticatorState) -> Result<(), StateError>;
+}
+
+/// The state of the authenticator, kept in sync with its [StateStore]
+pub struct PersistentState {
+    state: AuthenticatorState,
+    store: Box<dyn StateStore>,
+}
+
+impl PersistentState {
+    pub async fn load(store: Box<dyn StateStore>) -> Result<Self, StateError> {
+        let state = store.load().await?;
+        Ok(PersistentState { state, store })
+    }
+
+    /// The factory state, kept in memory only
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        PersistentState {
+            state: AuthenticatorState::default(),
+            store: Box::new(super::memory::InMemoryStateStore::new()),
+        }
+    }
+
+    pub fn get(&self) -> &AuthenticatorState {
+        &self.state
+    }
+
+    /// Changes the state, which only takes effect once it was saved
+    pub async fn update<R>(
+        &mut self,
+        change: impl FnOnce(&mut AuthenticatorState) -> R,
+    ) -> Result<R, StateError> {
+        let mut state = self.state.clone();
+        let res = change(&mut state);
+        self.store.save(&state).await?;
+        self.state = state;
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::authenticator::storage::memory::InMemoryStateStore;
+
+    use super::*;
+
+    fn used_state() -> AuthenticatorState {
+        AuthenticatorState {
+            pin: PinState {
+                hash: Some(ByteBuf::from([0xab; 16])),
+                retries: 2,
+                force_change: false,
+            },
+            global_sign_count: 42,
+            config: AuthenticatorConfig {
+                always_uv: true,
+                enterprise_attestation: true,
+                min_pin_length: 6,
+            },
+            large_blob_array: ByteBuf::from(vec![0x81, 0x00]),
+            seed: Some(SecretBytes::from(&[8; 32][..])),
+        }
+    }
+
+    #[test]
+    fn test_encoding_roundtrip() {
+        let state = used_state();
+        assert_eq!(
+            AuthenticatorState::from_bytes(&state.to_bytes()).unwrap(),
+            state
+        );
+    }
+
+    #[test]
+    fn test_seed_is_not_logged() {
+        let state = used_state();
+        assert!(format!("{:?}", state).contains("SecretBytes(32 bytes)"));
+        // still encoded as a byte string, thus existing state files remain readable
+        let seed = ByteBuf::from([8; 32]);
+        let mut expected = Vec::new();
+        ciborium::ser::into_writer(&seed, &mut expected).unwrap();
+        let mut encoded = Vec::new();
+        ciborium::ser::into_writer(&state.seed, &mut encoded).unwrap();
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut bytes = Vec::new();
+        let versioned = VersionedState {
+            version: STATE_VERSION + 1,
+            state: &AuthenticatorState::default(),
+        };
+        ciborium::ser::into_writer(&versioned, &mut bytes).unwrap();
+        assert!(matches!(
+            AuthenticatorState::from_bytes(&bytes),
+            Err(StateError::UnsupportedVersion(v)) if v == STATE_VERSION + 1
+        ));
+        assert!(matches!(
+            AuthenticatorState::from_bytes(&[0xff, 0x00]),
+            Err(StateError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_reset_keeps_device_state() {
+        let mut state = used_state();
+        state.reset();
+        assert_eq!(
+            state,
+            AuthenticatorState {
+                global_sign_count: 42,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_is_saved() {
+        let store = InMemoryStateStore::new();
+        let mut state = PersistentState::load(Box::new(store.clone()))
+            .await
+            .unwrap();
+        let retries = state
+            .update(|state| {
+                state.pin.retries -= 1;
+                state.pin.retries
+            })
+            .await
+            .unwrap();
+        assert_eq!(retries, MAX_PIN_RETRIES - 1);
+        assert_eq!(store.load().await.unwrap().pin.retries, retries);
+    }
+}
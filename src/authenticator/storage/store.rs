@@ -0,0 +1,187 @@
+use async_trait::async_trait;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::authenticator::{
+    crypto::CREDENTIAL_KEY_LENGTH,
+    types::{CredentialId, PublicKeyCredentialSource, RpIdHash},
+};
+
+/// The key wrapping non-discoverable credentials into their IDs, see [Storage::credential_key]
+pub type CredentialKey = Zeroizing<[u8; CREDENTIAL_KEY_LENGTH]>;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("Credential {0:?} already exists")]
+    AlreadyExists(CredentialId),
+
+    #[error("Credential {0:?} doesn't exist")]
+    NotFound(CredentialId),
+
+    #[error("The stored credential key has an invalid length")]
+    MalformedCredentialKey,
+
+    #[error("Storage backend failed: {0}")]
+    Backend(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Decodes a [CredentialKey] read by a backend
+pub fn credential_key_from_bytes(bytes: &[u8]) -> Result<CredentialKey, StorageError> {
+    let key = bytes
+        .try_into()
+        .map_err(|_| StorageError::MalformedCredentialKey)?;
+    Ok(Zeroizing::new(key))
+}
+
+/// A single change to the stored credentials, see [StorageTransaction]
+#[derive(Debug, Clone)]
+pub enum StorageOperation {
+    /// Adds a credential, whose ID mustn't be taken
+    Create(PublicKeyCredentialSource),
+
+    /// Replaces the credential with the same ID, which must exist
+    Update(PublicKeyCredentialSource),
+
+    /// Removes the credential with the given ID, which must exist
+    Delete(CredentialId),
+
+    /// Removes all credentials, including the credential key, e.g, upon authenticatorReset
+    DeleteAll,
+}
+
+/// Operations applied atomically and in order: either all of them take effect, or none
+/// does (in case one fails, or the backend fails to commit them).
+#[derive(Debug, Clone, Default)]
+pub struct StorageTransaction {
+    operations: Vec<StorageOperation>,
+}
+
+impl StorageTransaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn create(mut self, credential: PublicKeyCredentialSource) -> Self {
+        self.operations.push(StorageOperation::Create(credential));
+        self
+    }
+
+    pub fn update(mut self, credential: PublicKeyCredentialSource) -> Self {
+        self.operations.push(StorageOperation::Update(credential));
+        self
+    }
+
+    pub fn delete(mut self, id: CredentialId) -> Self {
+        self.operations.push(StorageOperation::Delete(id));
+        self
+    }
+
+    pub fn delete_all(mut self) -> Self {
+        self.operations.push(StorageOperation::DeleteAll);
+        self
+    }
+
+    pub fn operations(&self) -> &[StorageOperation] {
+        &self.operations
+    }
+
+    /// Whether the transaction removes all credentials, and thus the credential key
+    pub fn deletes_all(&self) -> bool {
+        self.operations
+            .iter()
+            .any(|operation| matches!(operation, StorageOperation::DeleteAll))
+    }
+
+    /// Applies the operations to credentials kept in creation order, leaving them untouched
+    /// if an operation fails. Meant for backends which load all credentials at once.
+    pub fn apply_to(
+        &self,
+        credentials: &mut Vec<PublicKeyCredentialSource>,
+    ) -> Result<(), StorageError> {
+        let mut updated = credentials.clone();
+        for operation in &self.operations {
+            let position = |id: &CredentialId| updated.iter().position(|cred| &cred.id == id);
+            match operation {
+                StorageOperation::Create(credential) => match position(&credential.id) {
+                    Some(_) => return Err(StorageError::AlreadyExists(credential.id.clone())),
+                    None => updated.push(credential.clone()),
+                },
+                StorageOperation::Update(credential) => match position(&credential.id) {
+                    Some(index) => updated[index] = credential.clone(),
+                    None => return Err(StorageError::NotFound(credential.id.clone())),
+                },
+                StorageOperation::Delete(id) => match position(id) {
+                    Some(index) => {
+                        updated.remove(index);
+                    }
+                    None => return Err(StorageError::NotFound(id.clone())),
+                },
+                StorageOperation::DeleteAll => updated.clear(),
+            }
+        }
+        *credentials = updated;
+        Ok(())
+    }
+}
+
+/// Keeps the credentials of the authenticator, including their private keys, along with
+/// the key wrapping the private keys of non-discoverable credentials into their IDs.
+///
+/// Credentials are listed in the order they were created. Every backend must pass the
+/// [conformance suite](super::conformance).
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_credential(
+        &self,
+        id: &CredentialId,
+    ) -> Result<Option<PublicKeyCredentialSource>, StorageError>;
+
+    async fn get_credentials_for_rp(
+        &self,
+        rp_id_hash: &RpIdHash,
+    ) -> Result<Vec<PublicKeyCredentialSource>, StorageError>;
+
+    /// Lists all credentials, e.g, for credential management
+    async fn list_credentials(&self) -> Result<Vec<PublicKeyCredentialSource>, StorageError>;
+
+    async fn count_credentials(&self) -> Result<usize, StorageError> {
+        Ok(self.list_credentials().await?.len())
+    }
+
+    /// Applies all operations of the transaction atomically
+    async fn apply(&mut self, transaction: StorageTransaction) -> Result<(), StorageError>;
+
+    /// Returns the key wrapping non-discoverable credentials, if it was created yet. It's
+    /// kept alongside the stored credentials, thus protected like them, and invalidates the
+    /// non-discoverable ones once removed along with them.
+    async fn credential_key(&self) -> Result<Option<CredentialKey>, StorageError>;
+
+    /// Sets the key wrapping non-discoverable credentials, replacing the previous one
+    async fn set_credential_key(&mut self, key: &CredentialKey) -> Result<(), StorageError>;
+
+    async fn update_credential(
+        &mut self,
+        credential: PublicKeyCredentialSource,
+    ) -> Result<(), StorageError> {
+        self.apply(StorageTransaction::new().update(credential))
+            .await
+    }
+
+    async fn delete_all_credentials(&mut self) -> Result<(), StorageError> {
+        self.apply(StorageTransaction::new().delete_all()).await
+    }
+
+    /// Increments the signature counter of a credential, returning the new value. The counter
+    /// stays at `u32::MAX` once reached, as relying parties would take a counter going back
+    /// to 0 for a cloned authenticator.
+    async fn increment_sign_count(&mut self, id: &CredentialId) -> Result<u32, StorageError> {
+        let mut credential = self
+            .get_credential(id)
+            .await?
+            .ok_or_else(|| StorageError::NotFound(id.clone()))?;
+        credential.sign_count = credential.sign_count.saturating_add(1);
+        let sign_count = credential.sign_count;
+        self.update_credential(credential).await?;
+        Ok(sign_count)
+    }
+}
@@ -1,12 +1,13 @@
 use modular_bitfield::{bitfield, prelude::B3};
-use serde::{ser::SerializeTuple, Deserialize, Serialize};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
 
-use crate::authenticator::crypto::COSEAlgorithmIdentifier;
+use crate::{authenticator::crypto::COSEAlgorithmIdentifier, cbor::serde_bytes_array};
 
 use super::{Aaguid, Extension};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CredentialPrivateKey(pub Vec<u8>);
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CredentialPrivateKey(#[serde(with = "serde_bytes")] pub Vec<u8>);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CredentialPublicKey(pub Vec<u8>);
@@ -16,10 +17,22 @@ pub struct CredentialPublicKey(pub Vec<u8>);
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RpId(pub String);
 
+/// The SHA-256 hash of an [RpId], which is also the application parameter of U2F requests.
+/// [See more](https://w3c.github.io/webauthn/#rpidhash)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct RpIdHash(#[serde(with = "serde_bytes_array")] pub [u8; 32]);
+
+impl From<&RpId> for RpIdHash {
+    fn from(rp_id: &RpId) -> Self {
+        let hash = digest(&SHA256, rp_id.0.as_bytes());
+        RpIdHash(hash.as_ref().try_into().unwrap())
+    }
+}
+
 /// Identifies a credential.
 /// [See more](https://w3c.github.io/webauthn/#credential-id)
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct CredentialId(pub Vec<u8>);
+pub struct CredentialId(#[serde(with = "serde_bytes")] pub Vec<u8>);
 
 /// Identifies a user's account within a particular RP.
 /// [See more](https://w3c.github.io/webauthn/#dom-publickeycredentialuserentity-id)
@@ -29,14 +42,19 @@ pub struct UserHandle(#[serde(with = "serde_bytes")] pub Vec<u8>);
 /// Used by the authenticator to create assertions. This is essentially
 /// the entire data
 /// [See more](https://www.w3.org/TR/webauthn/#public-key-credential-source)
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PublicKeyCredentialSource {
     #[serde(rename = "type")]
     pub _type: PublicKeyType,
     pub id: CredentialId,
-    pub rp_id: RpId,
+    /// Unknown for credentials registered via U2F, which are only given the hash of the RP ID
+    pub rp_id: Option<RpId>,
+    pub rp_id_hash: RpIdHash,
     pub private_key: CredentialPrivateKey,
     pub user_handle: Option<UserHandle>,
+    /// Whether the credential may be used without being listed in an allowList
+    pub discoverable: bool,
+    pub sign_count: u32,
 }
 
 /// Currently there's only 1 source type (public key)
@@ -52,36 +70,41 @@ pub enum PublicKeyType {
 /// [See more](https://www.w3.org/TR/webauthn/#authenticator-data)
 #[derive(Debug)]
 pub struct AuthenticatorData {
-    pub rp_id_hash: u32,
+    pub rp_id_hash: RpIdHash,
     pub flags: AuthenticatorDataFlags,
     pub counter: u32,
     pub attested_cred_data: Option<AttestedCredData>,
     pub extensions: Option<Vec<Extension>>,
 }
 
-impl Serialize for AuthenticatorData {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let n_fields =
-            3 + (self.attested_cred_data.is_some() as u8) + (self.extensions.is_some() as u8);
-        let mut tup = serializer.serialize_tuple(n_fields as usize)?;
-        tup.serialize_element(&self.rp_id_hash)?;
+impl AuthenticatorData {
+    /// Encodes the authenticator data into the byte array that is signed by attestations and assertions
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.rp_id_hash.0.to_vec();
         assert_eq!(
             self.flags.bytes.len(),
             1,
             "AuthenticatorDataFlags must be 1 byte"
         );
-        tup.serialize_element(&self.flags.bytes[0])?;
-        tup.serialize_element(&self.counter)?;
+        bytes.push(self.flags.bytes[0]);
+        bytes.extend_from_slice(&self.counter.to_be_bytes());
         if let Some(attested_cred_data) = &self.attested_cred_data {
-            tup.serialize_element(attested_cred_data)?;
+            attested_cred_data.write_bytes(&mut bytes);
         }
         if let Some(extensions) = &self.extensions {
-            tup.serialize_element(extensions)?;
+            ciborium::ser::into_writer(extensions, &mut bytes)
+                .expect("Serializing extensions into a vector cannot fail");
         }
-        tup.end()
+        bytes
+    }
+}
+
+impl Serialize for AuthenticatorData {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&self.to_bytes())
     }
 }
 
@@ -108,17 +131,12 @@ pub struct AttestedCredData {
     pub credential_public_key: CredentialPublicKey,
 }
 
-impl Serialize for AttestedCredData {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let mut tup = serializer.serialize_tuple(4)?;
-        tup.serialize_element(&self.aaguid)?;
-        tup.serialize_element(&self.credential_id_length)?;
-        tup.serialize_element(&self.credential_id)?;
-        tup.serialize_element(&self.credential_public_key)?;
-        tup.end()
+impl AttestedCredData {
+    fn write_bytes(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.aaguid.0);
+        bytes.extend_from_slice(&self.credential_id_length.to_be_bytes());
+        bytes.extend_from_slice(&self.credential_id.0);
+        bytes.extend_from_slice(&self.credential_public_key.0);
     }
 }
 
@@ -133,6 +151,15 @@ pub enum AttestationStatement {
     },
 }
 
+impl AttestationStatement {
+    /// The attestation statement format identifier
+    pub fn fmt(&self) -> &'static str {
+        match self {
+            AttestationStatement::Packed { .. } => "packed",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PackedAttestationStatement {
     pub alg: COSEAlgorithmIdentifier,
@@ -162,10 +189,12 @@ mod tests {
     #[test]
     fn test_auth_data() {
         let auth_data = AuthenticatorData {
-            counter: 0,
+            counter: 0x1337,
             extensions: None,
-            flags: AuthenticatorDataFlags::new(),
-            rp_id_hash: 0x1337,
+            flags: AuthenticatorDataFlags::new()
+                .with_user_present(true)
+                .with_attested_data_included(true),
+            rp_id_hash: RpIdHash([0xaa; 32]),
             attested_cred_data: Some(AttestedCredData {
                 aaguid: APP_AAGUID,
                 credential_id: CredentialId(vec![1, 3, 3, 7]),
@@ -175,6 +204,24 @@ mod tests {
         };
         let mut vec = vec![];
         ciborium::ser::into_writer(&auth_data, &mut vec).unwrap();
-        // TODO: this test doesn't check the proper structure yet
+        let bytes: serde_bytes::ByteBuf = ciborium::de::from_reader(vec.as_slice()).unwrap();
+        assert_eq!(bytes.as_slice(), auth_data.to_bytes().as_slice());
+
+        let (rp_id_hash, rest) = bytes.split_at(32);
+        assert_eq!(rp_id_hash, &[0xaa; 32]);
+        let (flags_and_counter, rest) = rest.split_at(5);
+        assert_eq!(flags_and_counter, &[0x41, 0, 0, 0x13, 0x37]);
+        let (aaguid, rest) = rest.split_at(16);
+        assert_eq!(aaguid, &APP_AAGUID.0);
+        assert_eq!(rest, &[0, 4, 1, 3, 3, 7, 5, 5, 5, 5]);
+    }
+
+    #[test]
+    fn test_rp_id_hash() {
+        let hash = RpIdHash::from(&RpId("example.com".into()));
+        assert_eq!(
+            hex::encode(hash.0),
+            "a379a6f6eeafb9a55e378c118034e2751e682fab9f2d30ab13d2125586ce1947"
+        );
     }
 }
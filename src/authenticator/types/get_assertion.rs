@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cbor::key_mapped::VecKeymappable;
+
+use super::{
+    AuthenticatorData, AuthenticatorOptions, ClientDataHash, Extension,
+    PublicKeyCredentialDescriptor, PublicKeyCredentialUserEntity, RpId,
+};
+
+/// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#authenticatorGetAssertion
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticatorGetAssertionParams {
+    pub rp_id: RpId,
+    pub client_data_hash: ClientDataHash,
+    pub allow_list: Option<Vec<PublicKeyCredentialDescriptor>>,
+    pub extensions: Option<BTreeMap<String, Extension>>,
+    pub options: Option<AuthenticatorOptions>,
+    #[serde(default, with = "serde_bytes")]
+    pub pin_uv_auth_param: Option<Vec<u8>>,
+    pub pin_uv_auth_protocol: Option<u64>,
+}
+
+impl VecKeymappable<u8> for AuthenticatorGetAssertionParams {
+    fn field_mappings() -> Vec<(&'static str, u8)> {
+        vec![
+            ("rp_id", 0x01),
+            ("client_data_hash", 0x02),
+            ("allow_list", 0x03),
+            ("extensions", 0x04),
+            ("options", 0x05),
+            ("pin_uv_auth_param", 0x06),
+            ("pin_uv_auth_protocol", 0x07),
+        ]
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthenticatorGetAssertionResponse {
+    pub credential: PublicKeyCredentialDescriptor,
+    pub auth_data: AuthenticatorData,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<PublicKeyCredentialUserEntity>,
+    /// The number of discoverable credentials of the RP, when more than one, the others being
+    /// returned by authenticatorGetNextAssertion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number_of_credentials: Option<u32>,
+}
+
+impl VecKeymappable<u8> for AuthenticatorGetAssertionResponse {
+    fn field_mappings() -> Vec<(&'static str, u8)> {
+        vec![
+            ("credential", 0x01),
+            ("auth_data", 0x02),
+            ("signature", 0x03),
+            ("user", 0x04),
+            ("number_of_credentials", 0x05),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{authenticator::types::CredentialId, cbor::key_mapped::KeymappedStruct};
+
+    use super::AuthenticatorGetAssertionParams;
+
+    #[test]
+    fn can_parse_get_assertion() {
+        // {1: "example.com", 2: h'0101..01', 3: [{"id": h'01020304', "type": "public-key"}], 5: {"up": false}}
+        let cbor = hex::decode("a4016b6578616d706c652e636f6d02582001010101010101010101010101010101010101010101010101010101010101010381a2626964440102030464747970656a7075626c69632d6b657905a1627570f4").unwrap();
+        let val: KeymappedStruct<AuthenticatorGetAssertionParams, u8> =
+            ciborium::de::from_reader(&*cbor).unwrap();
+        let val = val.into_inner();
+        assert_eq!(val.rp_id.0, "example.com");
+        assert_eq!(val.client_data_hash.0, vec![1; 32]);
+        let allow_list = val.allow_list.unwrap();
+        assert_eq!(allow_list.len(), 1);
+        assert_eq!(allow_list[0].id, CredentialId(vec![1, 2, 3, 4]));
+        assert_eq!(val.options.unwrap().up, Some(false));
+        assert_eq!(val.pin_uv_auth_param, None);
+    }
+}
@@ -1,22 +1,27 @@
-use crate::cbor::{key_mapped::VecKeymappable, serde_bytes_array};
+use crate::{
+    cbor::{key_mapped::VecKeymappable, serde_bytes_array},
+    hid::reassembly::DEFAULT_MAX_MESSAGE_SIZE,
+};
+
+use super::U2F_VERSION;
 /// This module defines the various features and options supported by the authenticator
 use serde::{Deserialize, Serialize};
 
 /// https://www.w3.org/TR/webauthn-2/#aaguid
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct Aaguid(#[serde(with = "serde_bytes_array")] [u8; 16]);
+pub struct Aaguid(#[serde(with = "serde_bytes_array")] pub [u8; 16]);
 
 pub const APP_AAGUID: Aaguid = Aaguid([1, 3, 3, 7, 1, 1, 2, 3, 5, 8, 13, 21, 1, 3, 3, 7]);
 
 /// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#option-id
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct AuthenticatorGetInfoOptions {
-    plat: bool,
-    rk: bool,
+    pub plat: bool,
+    pub rk: bool,
     // client_pin: bool,
-    up: bool,
-    uv: bool,
+    pub up: bool,
+    pub uv: bool,
     // pin_uv_auth_token: bool,
 }
 
@@ -40,17 +45,60 @@ pub struct AuthenticatorGetInfoResponse {
     extensions: Vec<String>,
     aaguid: Aaguid,
     options: AuthenticatorGetInfoOptions,
+    max_msg_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    firmware_version: Option<u64>,
 }
 
 impl Default for AuthenticatorGetInfoResponse {
     fn default() -> Self {
+        Self::new(
+            vec!["FIDO_2_0".into()],
+            Default::default(),
+            APP_AAGUID,
+            Default::default(),
+        )
+    }
+}
+
+impl AuthenticatorGetInfoResponse {
+    pub fn new(
+        versions: Vec<String>,
+        extensions: Vec<String>,
+        aaguid: Aaguid,
+        options: AuthenticatorGetInfoOptions,
+    ) -> Self {
         Self {
-            versions: vec!["FIDO_2_0".into()],
-            extensions: Default::default(),
-            aaguid: APP_AAGUID,
-            options: Default::default(),
+            versions,
+            extensions,
+            aaguid,
+            options,
+            max_msg_size: DEFAULT_MAX_MESSAGE_SIZE as u64,
+            firmware_version: None,
         }
     }
+
+    /// Advertises the firmware version, as introduced by CTAP 2.1
+    pub fn with_firmware_version(mut self, firmware_version: u64) -> Self {
+        self.firmware_version = Some(firmware_version);
+        self
+    }
+
+    /// Advertises support of U2F (CTAP1) alongside CTAP2
+    pub fn with_u2f(mut self) -> Self {
+        self.versions.insert(0, U2F_VERSION.into());
+        self
+    }
+
+    /// The AAGUID of the authenticator model, included in the attested credential data
+    pub fn aaguid(&self) -> &Aaguid {
+        &self.aaguid
+    }
+
+    /// The options the authenticator advertises, and thus supports
+    pub fn options(&self) -> &AuthenticatorGetInfoOptions {
+        &self.options
+    }
 }
 
 impl VecKeymappable<u8> for AuthenticatorGetInfoResponse {
@@ -60,6 +108,8 @@ impl VecKeymappable<u8> for AuthenticatorGetInfoResponse {
             ("extensions", 0x02),
             ("aaguid", 0x03),
             ("options", 0x04),
+            ("max_msg_size", 0x05),
+            ("firmware_version", 0x0E),
         ]
     }
 }
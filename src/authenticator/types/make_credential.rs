@@ -1,6 +1,6 @@
 use std::collections::BTreeMap;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 
 use crate::{authenticator::crypto::COSEAlgorithmIdentifier, cbor::key_mapped::VecKeymappable};
 
@@ -12,19 +12,30 @@ use super::{
 /// [See more](https://w3c.github.io/webauthn/#dictdef-publickeycredentialrpentity)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PublicKeyCredentialRpEntity {
-    id: RpId,
-    name: Option<String>,
+    pub id: RpId,
+    pub name: Option<String>,
 }
 
 /// Used when creating a credential, contains attributes related to the user account.
 /// [See more](https://w3c.github.io/webauthn/#dictdef-publickeycredentialuserentity)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PublicKeyCredentialUserEntity {
-    id: UserHandle,
-    name: Option<String>,
+    pub id: UserHandle,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 
-    #[serde(rename = "displayName")]
-    display_name: Option<String>,
+    #[serde(rename = "displayName", skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+impl From<UserHandle> for PublicKeyCredentialUserEntity {
+    fn from(id: UserHandle) -> Self {
+        PublicKeyCredentialUserEntity {
+            id,
+            name: None,
+            display_name: None,
+        }
+    }
 }
 
 /// Identifies a crypto algorithm supported by the RP.
@@ -32,8 +43,8 @@ pub struct PublicKeyCredentialUserEntity {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PublicKeyCredentialParameters {
     #[serde(rename = "type")]
-    _type: PublicKeyType,
-    alg: COSEAlgorithmIdentifier,
+    pub _type: PublicKeyType,
+    pub alg: COSEAlgorithmIdentifier,
 }
 
 /// Identifies a credential (similar to [CredentialId]) along with the transports it can be used on.
@@ -41,9 +52,20 @@ pub struct PublicKeyCredentialParameters {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PublicKeyCredentialDescriptor {
     #[serde(rename = "type")]
-    _type: PublicKeyType,
-    id: CredentialId,
-    transports: Option<Vec<String>>,
+    pub _type: PublicKeyType,
+    pub id: CredentialId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transports: Option<Vec<String>>,
+}
+
+impl From<CredentialId> for PublicKeyCredentialDescriptor {
+    fn from(id: CredentialId) -> Self {
+        PublicKeyCredentialDescriptor {
+            _type: PublicKeyType::PublicKey,
+            id,
+            transports: None,
+        }
+    }
 }
 
 /// https://www.w3.org/TR/webauthn-2#sctn-extension-id
@@ -55,30 +77,30 @@ pub struct ExtensionIdentifier(String);
 pub struct Extension {}
 
 /// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#makecred-option-key
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AuthenticatorOptions {
-    rk: Option<bool>,
-    up: Option<bool>,
+    pub rk: Option<bool>,
+    pub up: Option<bool>,
     // Depracated in CTAP2.1
-    uv: Option<bool>,
+    pub uv: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct ClientDataHash(#[serde(with = "serde_bytes")] Vec<u8>);
+pub struct ClientDataHash(#[serde(with = "serde_bytes")] pub Vec<u8>);
 
 /// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#authenticatorMakeCredential
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthenticatorMakeCredentialParams {
-    client_data_hash: ClientDataHash,
-    rp: PublicKeyCredentialRpEntity,
-    user: PublicKeyCredentialUserEntity,
-    pub_key_cred_params: Vec<PublicKeyCredentialParameters>,
-    exclude_list: Option<Vec<PublicKeyCredentialDescriptor>>,
-    extensions: Option<BTreeMap<String, Extension>>,
-    options: Option<AuthenticatorOptions>,
-    pin_uv_auth_param: Option<Vec<u8>>,
-    pin_uv_auth_protocol: Option<u64>,
-    enterprise_attestation: Option<u64>,
+    pub client_data_hash: ClientDataHash,
+    pub rp: PublicKeyCredentialRpEntity,
+    pub user: PublicKeyCredentialUserEntity,
+    pub pub_key_cred_params: Vec<PublicKeyCredentialParameters>,
+    pub exclude_list: Option<Vec<PublicKeyCredentialDescriptor>>,
+    pub extensions: Option<BTreeMap<String, Extension>>,
+    pub options: Option<AuthenticatorOptions>,
+    pub pin_uv_auth_param: Option<Vec<u8>>,
+    pub pin_uv_auth_protocol: Option<u64>,
+    pub enterprise_attestation: Option<u64>,
 }
 
 impl VecKeymappable<u8> for AuthenticatorMakeCredentialParams {
@@ -102,9 +124,30 @@ impl VecKeymappable<u8> for AuthenticatorMakeCredentialParams {
 pub struct AuthenticatorMakeCredentialResponse {
     fmt: String,
     auth_data: AuthenticatorData,
+    #[serde(serialize_with = "serialize_att_stmt")]
     att_stmt: AttestationStatement,
 }
 
+impl AuthenticatorMakeCredentialResponse {
+    pub fn new(auth_data: AuthenticatorData, att_stmt: AttestationStatement) -> Self {
+        Self {
+            fmt: att_stmt.fmt().to_owned(),
+            auth_data,
+            att_stmt,
+        }
+    }
+}
+
+/// The statement is serialized without its format, which is a separate member of the response
+fn serialize_att_stmt<S: Serializer>(
+    att_stmt: &AttestationStatement,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match att_stmt {
+        AttestationStatement::Packed { att_stmt } => att_stmt.serialize(serializer),
+    }
+}
+
 impl VecKeymappable<u8> for AuthenticatorMakeCredentialResponse {
     fn field_mappings() -> Vec<(&'static str, u8)> {
         vec![("fmt", 0x01), ("auth_data", 0x02), ("att_stmt", 0x03)]
@@ -1,7 +1,11 @@
 mod common;
+mod get_assertion;
 mod get_info;
 mod make_credential;
+mod u2f;
 
 pub use common::*;
+pub use get_assertion::*;
 pub use get_info::*;
 pub use make_credential::*;
+pub use u2f::*;
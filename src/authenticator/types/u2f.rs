@@ -0,0 +1,152 @@
+//! U2F (CTAP1) raw messages
+//! [See more](https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html)
+
+use num_enum::TryFromPrimitive;
+
+use crate::authenticator::apdu::StatusWord;
+
+use super::{CredentialId, RpIdHash};
+
+/// The version string returned by U2F_VERSION, also advertised by authenticatorGetInfo
+pub const U2F_VERSION: &str = "U2F_V2";
+
+/// Length of the challenge and application parameters
+const PARAMETER_LENGTH: usize = 32;
+
+/// The reserved byte preceding a registration response
+const REGISTER_RESPONSE_RESERVED: u8 = 0x05;
+
+/// The instruction byte of a U2F request APDU
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+pub enum U2FInstruction {
+    Register = 0x01,
+    Authenticate = 0x02,
+    Version = 0x03,
+}
+
+/// The control byte (P1) of an authentication request
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+pub enum U2FAuthenticateControl {
+    /// Only check whether the key handle was created by this authenticator for the application
+    CheckOnly = 0x07,
+    EnforceUserPresenceAndSign = 0x03,
+    DontEnforceUserPresenceAndSign = 0x08,
+}
+
+/// [See more](https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#registration-request-message---u2f_register)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct U2FRegisterRequest {
+    pub challenge: [u8; PARAMETER_LENGTH],
+    pub application: RpIdHash,
+}
+
+impl TryFrom<&[u8]> for U2FRegisterRequest {
+    type Error = StatusWord;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if data.len() != 2 * PARAMETER_LENGTH {
+            return Err(StatusWord::WrongLength);
+        }
+        let (challenge, application) = data.split_at(PARAMETER_LENGTH);
+        Ok(U2FRegisterRequest {
+            challenge: challenge.try_into().unwrap(),
+            application: RpIdHash(application.try_into().unwrap()),
+        })
+    }
+}
+
+/// [See more](https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#registration-response-message-success)
+#[derive(Debug, Clone)]
+pub struct U2FRegisterResponse {
+    /// An uncompressed P-256 point
+    pub public_key: Vec<u8>,
+    pub key_handle: CredentialId,
+    pub attestation_cert: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl From<U2FRegisterResponse> for Vec<u8> {
+    fn from(res: U2FRegisterResponse) -> Self {
+        let mut bytes = vec![REGISTER_RESPONSE_RESERVED];
+        bytes.extend_from_slice(&res.public_key);
+        bytes.push(res.key_handle.0.len() as u8);
+        bytes.extend_from_slice(&res.key_handle.0);
+        bytes.extend_from_slice(&res.attestation_cert);
+        bytes.extend_from_slice(&res.signature);
+        bytes
+    }
+}
+
+/// [See more](https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#authentication-request-message---u2f_authenticate)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct U2FAuthenticateRequest {
+    pub challenge: [u8; PARAMETER_LENGTH],
+    pub application: RpIdHash,
+    pub key_handle: CredentialId,
+}
+
+impl TryFrom<&[u8]> for U2FAuthenticateRequest {
+    type Error = StatusWord;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let (parameters, key_handle) = match data.get(2 * PARAMETER_LENGTH..) {
+            Some([key_handle_length, key_handle @ ..])
+                if key_handle.len() == *key_handle_length as usize =>
+            {
+                (&data[..2 * PARAMETER_LENGTH], key_handle)
+            }
+            _ => return Err(StatusWord::WrongLength),
+        };
+        let (challenge, application) = parameters.split_at(PARAMETER_LENGTH);
+        Ok(U2FAuthenticateRequest {
+            challenge: challenge.try_into().unwrap(),
+            application: RpIdHash(application.try_into().unwrap()),
+            key_handle: CredentialId(key_handle.to_owned()),
+        })
+    }
+}
+
+/// [See more](https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#authentication-response-message-success)
+#[derive(Debug, Clone)]
+pub struct U2FAuthenticateResponse {
+    pub user_presence: u8,
+    pub counter: u32,
+    pub signature: Vec<u8>,
+}
+
+impl From<U2FAuthenticateResponse> for Vec<u8> {
+    fn from(res: U2FAuthenticateResponse) -> Self {
+        let mut bytes = vec![res.user_presence];
+        bytes.extend_from_slice(&res.counter.to_be_bytes());
+        bytes.extend_from_slice(&res.signature);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_authenticate_request() {
+        let mut data = vec![1; PARAMETER_LENGTH];
+        data.extend_from_slice(&[2; PARAMETER_LENGTH]);
+        data.extend_from_slice(&[3, 7, 7, 7]);
+        let req = U2FAuthenticateRequest::try_from(data.as_slice()).unwrap();
+        assert_eq!(req.challenge, [1; PARAMETER_LENGTH]);
+        assert_eq!(req.application, RpIdHash([2; PARAMETER_LENGTH]));
+        assert_eq!(req.key_handle, CredentialId(vec![7, 7, 7]));
+
+        data.pop();
+        assert_eq!(
+            U2FAuthenticateRequest::try_from(data.as_slice()),
+            Err(StatusWord::WrongLength)
+        );
+        assert_eq!(
+            U2FAuthenticateRequest::try_from(&data[..2 * PARAMETER_LENGTH]),
+            Err(StatusWord::WrongLength)
+        );
+    }
+}
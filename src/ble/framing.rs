@@ -0,0 +1,440 @@
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use thiserror::Error;
+use tracing::{debug, trace};
+
+use crate::hid::{
+    command::{CommandType, InvalidCommandType},
+    packet::{Message, MessageDecodeError},
+    reassembly::{Fragment, MessageReassembler, ReassemblyConfig, DEFAULT_MAX_MESSAGE_SIZE},
+};
+
+/// Minimal and maximal values of `fidoControlPointLength`, see
+/// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#ble-fido-service
+pub const MIN_CONTROL_POINT_LENGTH: usize = 20;
+pub const MAX_CONTROL_POINT_LENGTH: usize = 512;
+
+/// Bits of `fidoServiceRevisionBitfield`
+pub const SERVICE_REVISION_U2F_1_1: u8 = 0x80;
+pub const SERVICE_REVISION_U2F_1_2: u8 = 0x40;
+pub const SERVICE_REVISION_FIDO2: u8 = 0x20;
+
+/// The channel identifier of reassembled messages, as BLE has no channels
+pub const BLE_CHANNEL: u32 = 0;
+
+/// Length of the CMD, HLEN and LLEN header of an initialization fragment
+const INIT_HEADER_LENGTH: usize = 3;
+
+/// Length of the SEQ header of a continuation fragment
+const CONT_HEADER_LENGTH: usize = 1;
+
+/// The bit set in the first byte of an initialization fragment, but not in a continuation one
+const INIT_FRAGMENT_BIT: u8 = 0x80;
+
+/// The first byte of a U2F APDU (CLA), which isn't a valid CTAP2 command byte
+const U2F_CLA: u8 = 0x00;
+
+/// A command of a BLE frame
+/// [See more](https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#ble-framing)
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+pub enum BleCommand {
+    Ping = 0x81,
+    Keepalive = 0x82,
+    Msg = 0x83,
+    Cancel = 0xBE,
+    Error = 0xBF,
+}
+
+impl From<BleCommand> for CommandType {
+    fn from(command: BleCommand) -> Self {
+        match command {
+            BleCommand::Ping => CommandType::Ping,
+            BleCommand::Keepalive => CommandType::Keepalive,
+            BleCommand::Msg => CommandType::Msg,
+            BleCommand::Cancel => CommandType::Cancel,
+            BleCommand::Error => CommandType::Error,
+        }
+    }
+}
+
+impl TryFrom<CommandType> for BleCommand {
+    type Error = CommandType;
+
+    fn try_from(command: CommandType) -> Result<Self, CommandType> {
+        Ok(match command {
+            CommandType::Ping => BleCommand::Ping,
+            CommandType::Keepalive => BleCommand::Keepalive,
+            // both CTAP2 and U2F messages are carried by MSG
+            CommandType::Msg | CommandType::Cbor => BleCommand::Msg,
+            CommandType::Cancel => BleCommand::Cancel,
+            CommandType::Error => BleCommand::Error,
+            other => return Err(other),
+        })
+    }
+}
+
+/// The error codes of an ERROR frame
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive)]
+pub enum BleErrorCode {
+    InvalidCmd = 0x01,
+    InvalidPar = 0x02,
+    InvalidLen = 0x03,
+    InvalidSeq = 0x04,
+    ReqTimeout = 0x05,
+    Busy = 0x06,
+    Other = 0x7F,
+}
+
+impl BleErrorCode {
+    pub fn to_message(self) -> Message {
+        Message {
+            channel_identifier: BLE_CHANNEL,
+            command: Ok(CommandType::Error),
+            payload: vec![self.into()],
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BleFramingError {
+    #[error(transparent)]
+    MessageDecodeError(#[from] MessageDecodeError),
+
+    #[error("Got a fragment of {len} bytes, exceeding the control point length")]
+    FragmentTooLong { len: usize },
+
+    #[error("Got a fragment of {len} bytes, shorter than its header")]
+    FragmentTooShort { len: usize },
+
+    #[error("Got an invalid command: {0}")]
+    InvalidCommand(#[from] InvalidCommandType),
+
+    #[error("Got an empty MSG")]
+    EmptyMessage,
+
+    #[error("Service revision bits {0:#04x} aren't a single supported revision")]
+    UnsupportedRevision(u8),
+}
+
+impl From<&BleFramingError> for BleErrorCode {
+    fn from(err: &BleFramingError) -> Self {
+        match err {
+            BleFramingError::MessageDecodeError(err) => match err {
+                MessageDecodeError::UnexpectedSeq { .. }
+                | MessageDecodeError::UnexpectedInit { .. }
+                | MessageDecodeError::UnexpectedCont { .. } => BleErrorCode::InvalidSeq,
+                MessageDecodeError::InvalidPayloadLength { .. } => BleErrorCode::InvalidLen,
+                MessageDecodeError::TooManyChannels { .. } => BleErrorCode::Busy,
+                MessageDecodeError::InvalidCommand { .. } => BleErrorCode::InvalidCmd,
+                MessageDecodeError::InvalidParameter { .. } => BleErrorCode::InvalidPar,
+                MessageDecodeError::IoError(_) => BleErrorCode::Other,
+            },
+            BleFramingError::FragmentTooLong { .. }
+            | BleFramingError::FragmentTooShort { .. }
+            | BleFramingError::EmptyMessage => BleErrorCode::InvalidLen,
+            BleFramingError::InvalidCommand(_) | BleFramingError::UnsupportedRevision(_) => {
+                BleErrorCode::InvalidCmd
+            }
+        }
+    }
+}
+
+/// Parses a fidoControlPoint write or a fidoStatus notification into a fragment
+pub fn decode_fragment(fragment: &[u8]) -> Result<Fragment<'_>, BleFramingError> {
+    let len = fragment.len();
+    match fragment {
+        [cmd, hlen, llen, data @ ..] if cmd & INIT_FRAGMENT_BIT != 0 => {
+            Ok(Fragment::Initialization {
+                channel: BLE_CHANNEL,
+                command: BleCommand::try_from(*cmd)
+                    .map(CommandType::from)
+                    .map_err(|_| InvalidCommandType::InvalidCommand(*cmd)),
+                payload_length: u16::from_be_bytes([*hlen, *llen]) as usize,
+                data,
+            })
+        }
+        [seq, data @ ..] if seq & INIT_FRAGMENT_BIT == 0 => Ok(Fragment::Continuation {
+            channel: BLE_CHANNEL,
+            seq: *seq,
+            data,
+        }),
+        _ => Err(BleFramingError::FragmentTooShort { len }),
+    }
+}
+
+/// Splits a message into fragments of at most `max_fragment_length` bytes
+pub fn encode_fragments(message: &Message, max_fragment_length: usize) -> Vec<Vec<u8>> {
+    let command = message
+        .command
+        .ok()
+        .and_then(|command| BleCommand::try_from(command).ok())
+        .expect("Cannot encode a message whose command isn't supported over BLE");
+    let payload = &message.payload;
+    assert!(
+        payload.len() <= u16::MAX as usize,
+        "Message payload is too long"
+    );
+
+    let init_data_length = payload.len().min(max_fragment_length - INIT_HEADER_LENGTH);
+    let mut init = vec![command.into()];
+    init.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    init.extend_from_slice(&payload[..init_data_length]);
+
+    let mut fragments = vec![init];
+    let conts = payload[init_data_length..].chunks(max_fragment_length - CONT_HEADER_LENGTH);
+    for (i, data) in conts.enumerate() {
+        let mut cont = vec![(i % (INIT_FRAGMENT_BIT as usize)) as u8];
+        cont.extend_from_slice(data);
+        fragments.push(cont);
+    }
+    fragments
+}
+
+/// The result of processing a valid fragment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BleProcessingResult {
+    /// Waiting for continuation fragments
+    WaitingForMoreFragments,
+
+    /// An immediate response message is available (i.e, for PING)
+    ResponseReady(Message),
+
+    /// A CBOR or U2F request has been received, its handling should be delegated
+    /// to the authenticator.
+    CTAP2Request(Message),
+
+    /// The client cancelled the pending request
+    Cancel,
+}
+
+/// Handles the CTAP BLE framing in a synchronous manner, i.e, reassembly of fidoControlPoint
+/// writes into messages and the semantics of fidoServiceRevisionBitfield and
+/// fidoControlPointLength. Independent of the Bluetooth stack, and doesn't handle IO or
+/// keepalives.
+/// [See more](https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#ble)
+pub struct BleFraming {
+    control_point_length: usize,
+    u2f_enabled: bool,
+    /// The service revision selected by the client, if any
+    revision: Option<u8>,
+    reassembler: MessageReassembler,
+}
+
+impl BleFraming {
+    /// Creates the framing given the maximal length of a fragment (usually the ATT MTU - 3)
+    pub fn new(control_point_length: usize, u2f_enabled: bool) -> Self {
+        assert!(
+            (MIN_CONTROL_POINT_LENGTH..=MAX_CONTROL_POINT_LENGTH).contains(&control_point_length),
+            "Control point length must lie within [{}, {}]",
+            MIN_CONTROL_POINT_LENGTH,
+            MAX_CONTROL_POINT_LENGTH
+        );
+        BleFraming {
+            control_point_length,
+            u2f_enabled,
+            revision: None,
+            reassembler: MessageReassembler::new(ReassemblyConfig::ble(DEFAULT_MAX_MESSAGE_SIZE)),
+        }
+    }
+
+    /// The value of fidoControlPointLength
+    // served by the GATT server, which isn't bound to a Bluetooth stack yet
+    #[allow(dead_code)]
+    pub fn control_point_length(&self) -> [u8; 2] {
+        (self.control_point_length as u16).to_be_bytes()
+    }
+
+    /// The value of fidoServiceRevisionBitfield when read, i.e, the supported revisions
+    pub fn supported_revisions(&self) -> u8 {
+        if self.u2f_enabled {
+            SERVICE_REVISION_FIDO2 | SERVICE_REVISION_U2F_1_2 | SERVICE_REVISION_U2F_1_1
+        } else {
+            SERVICE_REVISION_FIDO2
+        }
+    }
+
+    /// Selects a revision upon a write of fidoServiceRevisionBitfield, which must have
+    /// exactly one supported bit set.
+    pub fn select_revision(&mut self, revision: u8) -> Result<(), BleFramingError> {
+        if revision.count_ones() != 1 || revision & self.supported_revisions() == 0 {
+            return Err(BleFramingError::UnsupportedRevision(revision));
+        }
+        debug!(revision, "Client selected a service revision");
+        self.revision = Some(revision);
+        Ok(())
+    }
+
+    /// Resets all state, e.g, when a client connects or disconnects
+    pub fn reset(&mut self) {
+        self.revision = None;
+        self.reassembler.reset();
+    }
+
+    /// Handles a fidoControlPoint write. Upon an error, the message being reassembled
+    /// is discarded.
+    pub fn handle_fragment(
+        &mut self,
+        fragment: &[u8],
+    ) -> Result<BleProcessingResult, BleFramingError> {
+        let res = self.add_fragment(fragment);
+        if res.is_err() {
+            self.reassembler.reset();
+        }
+        res
+    }
+
+    fn add_fragment(&mut self, fragment: &[u8]) -> Result<BleProcessingResult, BleFramingError> {
+        if fragment.len() > self.control_point_length {
+            return Err(BleFramingError::FragmentTooLong {
+                len: fragment.len(),
+            });
+        }
+        let message = match self.reassembler.add_fragment(decode_fragment(fragment)?)? {
+            Some(message) => message,
+            None => return Ok(BleProcessingResult::WaitingForMoreFragments),
+        };
+        trace!(?message, "Reassembled a BLE message");
+
+        match message.command? {
+            CommandType::Ping => Ok(BleProcessingResult::ResponseReady(message)),
+            CommandType::Cancel => Ok(BleProcessingResult::Cancel),
+            CommandType::Msg => self.handle_msg(message),
+            other => Err(InvalidCommandType::InvalidCommand(
+                BleCommand::try_from(other).map_or(0, u8::from),
+            )
+            .into()),
+        }
+    }
+
+    /// Distinguishes CTAP2 requests from U2F ones by their first byte
+    fn handle_msg(&self, mut message: Message) -> Result<BleProcessingResult, BleFramingError> {
+        let first = *message
+            .payload
+            .first()
+            .ok_or(BleFramingError::EmptyMessage)?;
+        let u2f_only = self
+            .revision
+            .is_some_and(|revision| revision != SERVICE_REVISION_FIDO2);
+        message.command = if first == U2F_CLA {
+            if !self.u2f_enabled {
+                return Err(InvalidCommandType::InvalidCommand(BleCommand::Msg.into()).into());
+            }
+            Ok(CommandType::Msg)
+        } else {
+            if u2f_only {
+                return Err(InvalidCommandType::InvalidCommand(BleCommand::Msg.into()).into());
+            }
+            Ok(CommandType::Cbor)
+        };
+        Ok(BleProcessingResult::CTAP2Request(message))
+    }
+
+    /// Splits a response message into fidoStatus notifications
+    pub fn encode_message(&self, message: &Message) -> Vec<Vec<u8>> {
+        encode_fragments(message, self.control_point_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(command: CommandType, payload: Vec<u8>) -> Message {
+        Message {
+            channel_identifier: BLE_CHANNEL,
+            command: Ok(command),
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let mut framing = BleFraming::new(MIN_CONTROL_POINT_LENGTH, true);
+        let ping = message(CommandType::Ping, (0..100).collect());
+        let fragments = framing.encode_message(&ping);
+        assert_eq!(fragments.len(), 6);
+        assert_eq!(&fragments[0][..3], &[0x81, 0, 100]);
+        assert_eq!(fragments[1][0], 0);
+        assert!(fragments
+            .iter()
+            .all(|f| f.len() <= MIN_CONTROL_POINT_LENGTH));
+
+        for fragment in &fragments[..5] {
+            assert_eq!(
+                framing.handle_fragment(fragment).unwrap(),
+                BleProcessingResult::WaitingForMoreFragments
+            );
+        }
+        assert_eq!(
+            framing.handle_fragment(&fragments[5]).unwrap(),
+            BleProcessingResult::ResponseReady(ping)
+        );
+    }
+
+    #[test]
+    fn test_msg() {
+        let mut framing = BleFraming::new(MIN_CONTROL_POINT_LENGTH, true);
+        assert_eq!(
+            framing.handle_fragment(&[0x83, 0, 1, 0x04]).unwrap(),
+            BleProcessingResult::CTAP2Request(message(CommandType::Cbor, vec![0x04]))
+        );
+        let u2f_version = [0x83, 0, 4, 0x00, 0x03, 0x00, 0x00];
+        assert_eq!(
+            framing.handle_fragment(&u2f_version).unwrap(),
+            BleProcessingResult::CTAP2Request(message(CommandType::Msg, vec![0, 3, 0, 0]))
+        );
+
+        framing.select_revision(SERVICE_REVISION_U2F_1_2).unwrap();
+        assert!(matches!(
+            framing.handle_fragment(&[0x83, 0, 1, 0x04]),
+            Err(BleFramingError::InvalidCommand(_))
+        ));
+
+        let mut framing = BleFraming::new(MIN_CONTROL_POINT_LENGTH, false);
+        assert!(matches!(
+            framing.handle_fragment(&u2f_version),
+            Err(BleFramingError::InvalidCommand(_))
+        ));
+        assert_eq!(
+            framing.handle_fragment(&[0xBE, 0, 0]).unwrap(),
+            BleProcessingResult::Cancel
+        );
+    }
+
+    #[test]
+    fn test_invalid_fragments() {
+        let mut framing = BleFraming::new(MIN_CONTROL_POINT_LENGTH, true);
+        let err = framing
+            .handle_fragment(&[0; MIN_CONTROL_POINT_LENGTH + 1])
+            .unwrap_err();
+        assert_eq!(BleErrorCode::from(&err), BleErrorCode::InvalidLen);
+
+        let err = framing.handle_fragment(&[0, 1]).unwrap_err();
+        assert_eq!(BleErrorCode::from(&err), BleErrorCode::InvalidSeq);
+
+        framing.handle_fragment(&[0x81, 0, 40, 1]).unwrap();
+        let err = framing.handle_fragment(&[1, 1]).unwrap_err();
+        assert_eq!(BleErrorCode::from(&err), BleErrorCode::InvalidSeq);
+        // the message was discarded
+        let err = framing.handle_fragment(&[0, 1]).unwrap_err();
+        assert_eq!(BleErrorCode::from(&err), BleErrorCode::InvalidSeq);
+
+        let err = framing.handle_fragment(&[0x84, 0, 0]).unwrap_err();
+        assert_eq!(BleErrorCode::from(&err), BleErrorCode::InvalidCmd);
+        let err = framing.handle_fragment(&[0x83, 0, 0]).unwrap_err();
+        assert_eq!(BleErrorCode::from(&err), BleErrorCode::InvalidLen);
+    }
+
+    #[test]
+    fn test_service_revision() {
+        let mut framing = BleFraming::new(MAX_CONTROL_POINT_LENGTH, false);
+        assert_eq!(framing.control_point_length(), [0x02, 0x00]);
+        assert_eq!(framing.supported_revisions(), SERVICE_REVISION_FIDO2);
+        assert!(framing.select_revision(SERVICE_REVISION_U2F_1_2).is_err());
+        assert!(framing
+            .select_revision(SERVICE_REVISION_FIDO2 | SERVICE_REVISION_U2F_1_2)
+            .is_err());
+        framing.select_revision(SERVICE_REVISION_FIDO2).unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+use futures::{Sink, Stream};
+
+use crate::hid::transport::TransportError;
+
+/// An event of a BLE link, as delivered by a GATT server hosting the FIDO service
+// only delivered by loopback links in tests, as no GATT server is bound to a Bluetooth stack yet
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BleLinkEvent {
+    /// A client connected
+    Connected,
+
+    /// The client wrote a fragment to fidoControlPoint
+    ControlPointWrite(Vec<u8>),
+
+    /// The client wrote fidoServiceRevisionBitfield, selecting a service revision
+    ServiceRevisionWrite(u8),
+
+    /// The client disconnected
+    Disconnected,
+}
+
+/// A byte link to a BLE client, independent of the Bluetooth stack: a sink of fidoStatus
+/// notifications (each a single fragment), and a stream of [BleLinkEvent]s.
+///
+/// Reads of fidoControlPointLength and fidoServiceRevisionBitfield are answered by the
+/// GATT server itself, see [BleServer](super::server::BleServer).
+pub trait BleLink:
+    Sink<Vec<u8>, Error = TransportError> + Stream<Item = Result<BleLinkEvent, TransportError>>
+{
+}
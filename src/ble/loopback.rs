@@ -0,0 +1,120 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::hid::{
+    packet::Message,
+    reassembly::{MessageReassembler, ReassemblyConfig, DEFAULT_MAX_MESSAGE_SIZE},
+    transport::TransportError,
+};
+
+use super::{
+    framing::{decode_fragment, encode_fragments},
+    link::{BleLink, BleLinkEvent},
+};
+
+/// How long the client waits for a notification before failing the test
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Creates an in-memory link connected to a simulated BLE client, which writes fragments of
+/// at most `fragment_length` bytes.
+pub fn loopback(fragment_length: usize) -> (LoopbackLink, LoopbackClient) {
+    let (event_send, event_recv) = mpsc::unbounded_channel();
+    let (notification_send, notification_recv) = mpsc::unbounded_channel();
+    let link = LoopbackLink {
+        events: event_recv,
+        notifications: notification_send,
+    };
+    let client = LoopbackClient {
+        events: event_send,
+        notifications: notification_recv,
+        fragment_length,
+        reassembler: MessageReassembler::new(ReassemblyConfig::ble(DEFAULT_MAX_MESSAGE_SIZE)),
+    };
+    (link, client)
+}
+
+/// The authenticator's end of a [loopback], to be driven by a
+/// [BleServer](super::server::BleServer)
+pub struct LoopbackLink {
+    events: UnboundedReceiver<BleLinkEvent>,
+    notifications: UnboundedSender<Vec<u8>>,
+}
+
+impl futures::Stream for LoopbackLink {
+    type Item = Result<BleLinkEvent, TransportError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx).map(|event| event.map(Ok))
+    }
+}
+
+impl futures::Sink<Vec<u8>> for LoopbackLink {
+    type Error = TransportError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.notifications
+            .send(item)
+            .map_err(|_| anyhow!("Loopback client was dropped").into())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl BleLink for LoopbackLink {}
+
+/// The client's end of a [loopback], writing fidoControlPoint and receiving fidoStatus
+/// notifications
+pub struct LoopbackClient {
+    events: UnboundedSender<BleLinkEvent>,
+    notifications: UnboundedReceiver<Vec<u8>>,
+    fragment_length: usize,
+    reassembler: MessageReassembler,
+}
+
+impl LoopbackClient {
+    pub fn send_event(&self, event: BleLinkEvent) {
+        self.events.send(event).expect("Loopback link was dropped");
+    }
+
+    /// Fragments a message into fidoControlPoint writes and sends them
+    pub fn send_message(&self, message: &Message) {
+        for fragment in encode_fragments(message, self.fragment_length) {
+            self.send_event(BleLinkEvent::ControlPointWrite(fragment));
+        }
+    }
+
+    /// Receives the next notification, panicking if none arrives in time
+    pub async fn recv_notification(&mut self) -> Vec<u8> {
+        tokio::time::timeout(RECV_TIMEOUT, self.notifications.recv())
+            .await
+            .expect("Timed out waiting for a notification")
+            .expect("Loopback link was dropped")
+    }
+
+    /// Receives notifications until a complete message is reassembled
+    pub async fn recv_message(&mut self) -> Message {
+        loop {
+            let notification = self.recv_notification().await;
+            let fragment = decode_fragment(&notification).unwrap();
+            if let Some(message) = self.reassembler.add_fragment(fragment).unwrap() {
+                return message;
+            }
+        }
+    }
+}
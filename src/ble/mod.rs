@@ -0,0 +1,5 @@
+pub(crate) mod framing;
+pub(crate) mod link;
+#[cfg(test)]
+pub(crate) mod loopback;
+pub(crate) mod server;
@@ -0,0 +1,288 @@
+use std::{future::poll_fn, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use tokio::time::Instant;
+use tower::Service;
+use tracing::{debug, debug_span, error, trace, warn};
+
+use crate::{
+    authenticator::{
+        api::{AuthServiceError, CTAP2Request, CTAP2Service},
+        command::StatusCode,
+    },
+    hid::{
+        command::{CommandType, KeepaliveStatus},
+        packet::Message,
+    },
+};
+
+use super::{
+    framing::{BleErrorCode, BleFraming, BleProcessingResult, BLE_CHANNEL},
+    link::{BleLink, BleLinkEvent},
+};
+
+/// Interval between keepalives sent while a request is processed (`kKeepAliveMillis`)
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Serves the authenticator over a BLE link
+// not bound to a Bluetooth stack yet, thus only served over loopback links in tests
+#[allow(dead_code)]
+pub struct BleServer<L> {
+    link: L,
+    framing: BleFraming,
+}
+
+#[allow(dead_code)]
+impl<L> BleServer<L>
+where
+    L: BleLink + Unpin,
+{
+    /// Creates a server given a link, and the length of fidoControlPoint (see [BleFraming::new])
+    pub fn new(link: L, control_point_length: usize, u2f_enabled: bool) -> Self {
+        BleServer {
+            link,
+            framing: BleFraming::new(control_point_length, u2f_enabled),
+        }
+    }
+
+    /// The framing, whose fidoControlPointLength and fidoServiceRevisionBitfield values
+    /// are to be served by the GATT server
+    pub fn framing(&self) -> &BleFraming {
+        &self.framing
+    }
+
+    /// Runs until the link is closed, processing requests of the authenticator one at a time.
+    /// May return early in case of link errors.
+    pub async fn run(&mut self, mut service: CTAP2Service) -> anyhow::Result<()> {
+        // the pending request, and whether it's a CTAP2 (rather than U2F) request
+        let mut pending: Option<(<CTAP2Service as Service<CTAP2Request>>::Future, bool)> = None;
+        let keepalive = tokio::time::sleep(KEEPALIVE_INTERVAL);
+        tokio::pin!(keepalive);
+
+        loop {
+            tokio::select! {
+                event = self.link.next() => {
+                    let event = match event {
+                        Some(event) => event?,
+                        None => return Ok(()),
+                    };
+                    match event {
+                        BleLinkEvent::Connected | BleLinkEvent::Disconnected => {
+                            debug!(?event, "Resetting BLE state");
+                            self.framing.reset();
+                            pending = None;
+                        }
+                        BleLinkEvent::ServiceRevisionWrite(revision) => {
+                            if let Err(e) = self.framing.select_revision(revision) {
+                                warn!("Rejecting service revision: {}", e);
+                            }
+                        }
+                        BleLinkEvent::ControlPointWrite(fragment) => {
+                            let span = debug_span!("BLE fragment");
+                            let _enter = span.enter();
+                            match self.framing.handle_fragment(&fragment) {
+                                Ok(BleProcessingResult::WaitingForMoreFragments) => {}
+                                Ok(BleProcessingResult::ResponseReady(message)) => {
+                                    self.write_message(&message).await?;
+                                }
+                                Ok(BleProcessingResult::CTAP2Request(_)) if pending.is_some() => {
+                                    warn!("Received a request while another is processed");
+                                    self.write_message(&BleErrorCode::Busy.to_message()).await?;
+                                }
+                                Ok(BleProcessingResult::CTAP2Request(message)) => {
+                                    let is_ctap2 = message.command == Ok(CommandType::Cbor);
+                                    match CTAP2Request::try_from(&message) {
+                                        Ok(request) => {
+                                            poll_fn(|cx| service.poll_ready(cx)).await?;
+                                            pending = Some((service.call(request), is_ctap2));
+                                            keepalive.as_mut().reset(Instant::now() + KEEPALIVE_INTERVAL);
+                                        }
+                                        Err(e) => {
+                                            error!(?e, "Couldn't parse a request");
+                                            let error = AuthServiceError::new(e, BLE_CHANNEL);
+                                            self.write_message(&Message::from(&error)).await?;
+                                        }
+                                    }
+                                }
+                                Ok(BleProcessingResult::Cancel) => {
+                                    if let Some((_, is_ctap2)) = pending.take() {
+                                        debug!("Cancelled the pending request");
+                                        if is_ctap2 {
+                                            let cancelled = Message {
+                                                channel_identifier: BLE_CHANNEL,
+                                                command: Ok(CommandType::Cbor),
+                                                payload: vec![StatusCode::Ctap2ErrKeepaliveCancel as u8],
+                                            };
+                                            self.write_message(&cancelled).await?;
+                                        }
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("Error while processing a BLE fragment: {}", e);
+                                    let code = BleErrorCode::from(&e);
+                                    self.write_message(&code.to_message()).await?;
+                                }
+                            }
+                        }
+                    }
+                }
+                result = async { (&mut pending.as_mut().unwrap().0).await }, if pending.is_some() => {
+                    pending = None;
+                    let message = match result {
+                        Ok(response) => Message::from(response),
+                        Err(error) => Message::from(&error),
+                    };
+                    trace!(?message, "Writing a response message");
+                    self.write_message(&message).await?;
+                }
+                _ = &mut keepalive, if pending.is_some() => {
+                    keepalive.as_mut().reset(Instant::now() + KEEPALIVE_INTERVAL);
+                    let status = if service.awaiting_presence() {
+                        KeepaliveStatus::Upneeded
+                    } else {
+                        KeepaliveStatus::Processing
+                    };
+                    trace!(?status, "Sending a keepalive");
+                    let message = Message {
+                        channel_identifier: BLE_CHANNEL,
+                        command: Ok(CommandType::Keepalive),
+                        payload: vec![status.into()],
+                    };
+                    self.write_message(&message).await?;
+                }
+            }
+        }
+    }
+
+    async fn write_message(&mut self, message: &Message) -> anyhow::Result<()> {
+        for fragment in self.framing.encode_message(message) {
+            self.link.send(fragment).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::Future;
+
+    use crate::{
+        authenticator::presence::TestFrontend,
+        ble::{
+            framing::{BleCommand, MIN_CONTROL_POINT_LENGTH},
+            loopback::{loopback, LoopbackClient},
+        },
+    };
+
+    use super::*;
+
+    /// Runs a test against a server driven by a loopback link
+    async fn with_server<F, Fut>(test: F)
+    where
+        F: FnOnce(LoopbackClient) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        with_service(CTAP2Service::new(true), test).await
+    }
+
+    /// Runs a test against a server of the given authenticator, driven by a loopback link
+    async fn with_service<F, Fut>(service: CTAP2Service, test: F)
+    where
+        F: FnOnce(LoopbackClient) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let (link, client) = loopback(MIN_CONTROL_POINT_LENGTH);
+        let mut server = BleServer::new(link, MIN_CONTROL_POINT_LENGTH, true);
+        tokio::select! {
+            res = server.run(service) => panic!("Server stopped early: {:?}", res),
+            _ = test(client) => {}
+        }
+    }
+
+    fn message(command: CommandType, payload: Vec<u8>) -> Message {
+        Message {
+            channel_identifier: BLE_CHANNEL,
+            command: Ok(command),
+            payload,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        with_server(|mut client| async move {
+            client.send_event(BleLinkEvent::Connected);
+            let ping = message(CommandType::Ping, (0..100).collect());
+            client.send_message(&ping);
+            assert_eq!(client.recv_message().await, ping);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_get_info() {
+        with_server(|mut client| async move {
+            client.send_message(&message(CommandType::Msg, vec![0x04]));
+            let res = client.recv_message().await;
+            assert_eq!(res.command, Ok(CommandType::Msg));
+            assert_eq!(res.payload[0], StatusCode::Ctap1ErrSuccess as u8);
+            assert!(res.payload.len() > MIN_CONTROL_POINT_LENGTH);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_u2f_version() {
+        with_server(|mut client| async move {
+            client.send_message(&message(CommandType::Msg, vec![0, 3, 0, 0, 0]));
+            let res = client.recv_message().await;
+            assert_eq!(res.payload, b"U2F_V2\x90\x00".to_vec());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_invalid_seq() {
+        with_server(|mut client| async move {
+            client.send_event(BleLinkEvent::ControlPointWrite(vec![0x05, 0x01]));
+            let res = client.recv_message().await;
+            assert_eq!(res.command, Ok(CommandType::Error));
+            assert_eq!(res.payload, vec![u8::from(BleErrorCode::InvalidSeq)]);
+
+            let keepalive = vec![u8::from(BleCommand::Keepalive), 0, 1, 1];
+            client.send_event(BleLinkEvent::ControlPointWrite(keepalive));
+            let res = client.recv_message().await;
+            assert_eq!(res.payload, vec![u8::from(BleErrorCode::InvalidCmd)]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_and_cancel() {
+        // a makeCredential request of Chromium
+        let make_credential = hex::decode("01a5015820a830e6419cd1e40a074b78365370c64a8796c2fe8cbb70903c8cf60dd534045b02a26269646b776562617574686e2e696f646e616d656b776562617574686e2e696f03a36269644a8a893e00000000000000646e616d656273666b646973706c61794e616d65627366048aa263616c672664747970656a7075626c69632d6b6579a263616c67382264747970656a7075626c69632d6b6579a263616c67382364747970656a7075626c69632d6b6579a263616c6739010064747970656a7075626c69632d6b6579a263616c6739010164747970656a7075626c69632d6b6579a263616c6739010264747970656a7075626c69632d6b6579a263616c67382464747970656a7075626c69632d6b6579a263616c67382564747970656a7075626c69632d6b6579a263616c67382664747970656a7075626c69632d6b6579a263616c672764747970656a7075626c69632d6b657907a1627576f5").unwrap();
+        let frontend = Arc::new(TestFrontend::default());
+        frontend.set_present(false);
+        let service = CTAP2Service::with_frontend(true, frontend.clone());
+        with_service(service, |mut client| async move {
+            client.send_message(&message(CommandType::Msg, make_credential));
+            let upneeded = vec![KeepaliveStatus::Upneeded.into()];
+            assert_eq!(
+                client.recv_message().await,
+                message(CommandType::Keepalive, upneeded)
+            );
+
+            client.send_message(&message(CommandType::Cancel, vec![]));
+            let cancelled = vec![StatusCode::Ctap2ErrKeepaliveCancel as u8];
+            loop {
+                let res = client.recv_message().await;
+                if res.command != Ok(CommandType::Keepalive) {
+                    assert_eq!(res, message(CommandType::Msg, cancelled));
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+}
@@ -0,0 +1,19 @@
+use async_trait::async_trait;
+
+use super::keys::ADVERT_SIZE;
+
+/// The 16 bit UUID under which the advertisement is broadcast as service data
+// for advertisers bound to a Bluetooth stack, none of which exists yet
+#[allow(dead_code)]
+pub const CABLE_SERVICE_UUID: u16 = 0xFFF9;
+
+/// Broadcasts the encrypted advertisement over BLE, which proves to the platform that the
+/// authenticator is nearby and tells it how to reach the tunnel. Independent of the Bluetooth
+/// stack, such that tests can hand the advertisement to the platform directly.
+#[async_trait]
+pub trait Advertiser: Send {
+    /// Starts advertising the given service data, until [Advertiser::stop] is called
+    async fn start(&mut self, service_data: [u8; ADVERT_SIZE]) -> anyhow::Result<()>;
+
+    async fn stop(&mut self) -> anyhow::Result<()>;
+}
@@ -0,0 +1,214 @@
+//! The handshake establishing an encrypted channel through the tunnel, and the
+//! encryption of messages exchanged over it
+
+use p256::{ecdh, PublicKey, SecretKey};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    rand::SecureRandom,
+};
+use thiserror::Error;
+
+use super::noise::{encode_point, HandshakeType, Noise};
+
+/// Size of an uncompressed X9.62 P-256 point, which starts each handshake message
+pub const P256_X962_LENGTH: usize = 65;
+
+/// Messages are padded to a multiple of this size, to hide their exact length
+const PADDING_GRANULARITY: usize = 32;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    #[error("The handshake message is malformed")]
+    MalformedMessage,
+
+    #[error("The handshake message contains an invalid point")]
+    InvalidPoint,
+
+    #[error("Couldn't authenticate a message")]
+    Authentication,
+
+    #[error("Too many messages were exchanged over the channel")]
+    SequenceExhausted,
+}
+
+/// Generates a P-256 key, using the given source of randomness
+pub fn generate_key(rng: &dyn SecureRandom) -> SecretKey {
+    loop {
+        let mut bytes = [0u8; 32];
+        rng.fill(&mut bytes)
+            .expect("Couldn't generate random bytes");
+        // fails with negligible probability, for values beyond the curve order
+        if let Ok(key) = SecretKey::from_be_bytes(&bytes) {
+            return key;
+        }
+    }
+}
+
+pub fn ecdh(secret: &SecretKey, public: &PublicKey) -> Vec<u8> {
+    let shared = ecdh::diffie_hellman(secret.to_nonzero_scalar(), public.as_affine());
+    shared.raw_secret_bytes().to_vec()
+}
+
+/// Starts a handshake of the given type, mixing in the known static key as the prologue
+pub fn start_handshake(handshake_type: HandshakeType, static_key: &PublicKey, psk: &[u8]) -> Noise {
+    let mut noise = Noise::new(handshake_type);
+    let prologue = match handshake_type {
+        HandshakeType::KNpsk0 => 1,
+        HandshakeType::NKpsk0 => 0,
+    };
+    noise.mix_hash(&[prologue]);
+    noise.mix_hash_point(static_key);
+    noise.mix_key_and_hash(psk);
+    noise
+}
+
+/// Splits a handshake message into the sender's ephemeral key and the ciphertext
+pub fn parse_handshake_message(message: &[u8]) -> Result<(PublicKey, &[u8]), HandshakeError> {
+    if message.len() < P256_X962_LENGTH {
+        return Err(HandshakeError::MalformedMessage);
+    }
+    let (point, ciphertext) = message.split_at(P256_X962_LENGTH);
+    let point = PublicKey::from_sec1_bytes(point).map_err(|_| HandshakeError::InvalidPoint)?;
+    Ok((point, ciphertext))
+}
+
+/// Answers the platform's handshake message of a QR-initiated transaction, as the responder of
+/// a KNpsk0 handshake. Returns the response and the channel's crypter.
+pub fn respond(
+    psk: &[u8; 32],
+    peer_identity: &PublicKey,
+    message: &[u8],
+    rng: &dyn SecureRandom,
+) -> Result<(Vec<u8>, Crypter), HandshakeError> {
+    let mut noise = start_handshake(HandshakeType::KNpsk0, peer_identity, psk);
+    let (peer_point, ciphertext) = parse_handshake_message(message)?;
+    noise.mix_hash_point(&peer_point);
+    noise.mix_key(&encode_point(&peer_point));
+    let payload = noise
+        .decrypt_and_hash(ciphertext)
+        .ok_or(HandshakeError::Authentication)?;
+    if !payload.is_empty() {
+        return Err(HandshakeError::MalformedMessage);
+    }
+
+    let ephemeral = generate_key(rng);
+    let ephemeral_public = ephemeral.public_key();
+    noise.mix_hash_point(&ephemeral_public);
+    noise.mix_key(&encode_point(&ephemeral_public));
+    noise.mix_key(&ecdh(&ephemeral, &peer_point));
+    noise.mix_key(&ecdh(&ephemeral, peer_identity));
+
+    let mut response = encode_point(&ephemeral_public);
+    response.extend(noise.encrypt_and_hash(&[]));
+    let (read_key, write_key) = noise.traffic_keys();
+    Ok((response, Crypter::new(read_key, write_key)))
+}
+
+/// Encrypts and decrypts the messages of an established channel. Each direction has its own
+/// key and sequence number.
+pub struct Crypter {
+    read_key: LessSafeKey,
+    write_key: LessSafeKey,
+    read_sequence: u32,
+    write_sequence: u32,
+}
+
+impl Crypter {
+    pub fn new(read_key: [u8; 32], write_key: [u8; 32]) -> Self {
+        let key =
+            |key: [u8; 32]| LessSafeKey::new(UnboundKey::new(&aead::AES_256_GCM, &key).unwrap());
+        Crypter {
+            read_key: key(read_key),
+            write_key: key(write_key),
+            read_sequence: 0,
+            write_sequence: 0,
+        }
+    }
+
+    /// Pads and encrypts a message. The last byte of the padding is its number of zeros.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let padded_len = (plaintext.len() + 1).div_ceil(PADDING_GRANULARITY) * PADDING_GRANULARITY;
+        let zeros = padded_len - plaintext.len() - 1;
+        let mut message = plaintext.to_vec();
+        message.resize(padded_len - 1, 0);
+        message.push(zeros as u8);
+
+        let nonce = next_nonce(&mut self.write_sequence)?;
+        self.write_key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut message)
+            .map_err(|_| HandshakeError::MalformedMessage)?;
+        Ok(message)
+    }
+
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let nonce = next_nonce(&mut self.read_sequence)?;
+        let mut message = ciphertext.to_vec();
+        let len = self
+            .read_key
+            .open_in_place(nonce, Aad::empty(), &mut message)
+            .map_err(|_| HandshakeError::Authentication)?
+            .len();
+        message.truncate(len);
+
+        let zeros = *message.last().ok_or(HandshakeError::MalformedMessage)? as usize;
+        if zeros + 1 > message.len() {
+            return Err(HandshakeError::MalformedMessage);
+        }
+        message.truncate(message.len() - zeros - 1);
+        Ok(message)
+    }
+}
+
+/// The nonce of a message is its 32 bit big endian sequence number, preceded by zeros
+fn next_nonce(sequence: &mut u32) -> Result<Nonce, HandshakeError> {
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    nonce[aead::NONCE_LEN - 4..].copy_from_slice(&sequence.to_be_bytes());
+    *sequence = sequence
+        .checked_add(1)
+        .ok_or(HandshakeError::SequenceExhausted)?;
+    Ok(Nonce::assume_unique_for_key(nonce))
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+
+    use super::*;
+
+    #[test]
+    fn test_crypter() {
+        let mut platform = Crypter::new([1; 32], [2; 32]);
+        let mut authenticator = Crypter::new([2; 32], [1; 32]);
+        for len in [0, 31, 32, 100] {
+            let message = vec![0x17; len];
+            let ciphertext = authenticator.encrypt(&message).unwrap();
+            assert_eq!((ciphertext.len() - 16) % PADDING_GRANULARITY, 0);
+            assert_eq!(platform.decrypt(&ciphertext).unwrap(), message);
+        }
+        // messages can't be replayed
+        let ciphertext = platform.encrypt(b"hello").unwrap();
+        assert_eq!(authenticator.decrypt(&ciphertext).unwrap(), b"hello");
+        assert_eq!(
+            authenticator.decrypt(&ciphertext),
+            Err(HandshakeError::Authentication)
+        );
+    }
+
+    #[test]
+    fn test_respond_rejects_wrong_psk() {
+        let rng = SystemRandom::new();
+        let identity = generate_key(&rng);
+        let ephemeral = generate_key(&rng);
+        let mut noise = start_handshake(HandshakeType::KNpsk0, &identity.public_key(), &[1; 32]);
+        let ephemeral_public = encode_point(&ephemeral.public_key());
+        noise.mix_hash(&ephemeral_public);
+        noise.mix_key(&ephemeral_public);
+        let mut message = ephemeral_public;
+        message.extend(noise.encrypt_and_hash(&[]));
+
+        let res = respond(&[2; 32], &identity.public_key(), &message, &rng);
+        assert_eq!(res.err(), Some(HandshakeError::Authentication));
+        let res = respond(&[1; 32], &identity.public_key(), &message[..10], &rng);
+        assert_eq!(res.err(), Some(HandshakeError::MalformedMessage));
+    }
+}
@@ -0,0 +1,181 @@
+//! Key derivation and the encrypted BLE advertisement of the hybrid transport, see
+//! https://fidoalliance.org/specs/fido-v2.2-rd-20230321/fido-client-to-authenticator-protocol-v2.2-rd-20230321.html#sctn-hybrid
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes256,
+};
+use ring::{constant_time, digest, hkdf, hmac};
+
+/// Size of the plaintext of an advertisement, a single AES block
+pub const ADVERT_PLAINTEXT_SIZE: usize = 16;
+
+/// Size of an advertisement: the encrypted plaintext, followed by a truncated HMAC
+pub const ADVERT_SIZE: usize = ADVERT_PLAINTEXT_SIZE + 4;
+
+/// Tunnel server domains which are assigned a 16 bit ID below 256
+const ASSIGNED_DOMAINS: [&str; 2] = ["cable.ua5v.com", "cable.auth.com"];
+
+/// What a key derived from a QR secret is used for, which is the HKDF info
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPurpose {
+    /// Encrypts and authenticates the advertisement, 64 bytes
+    EidKey = 1,
+    /// Identifies the tunnel, 16 bytes
+    TunnelId = 2,
+    /// The pre-shared key of the handshake, 32 bytes
+    Psk = 3,
+}
+
+/// An output length usable with [ring::hkdf]
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Fills `out` with HKDF-SHA256 output
+pub fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], out: &mut [u8]) {
+    hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+        .extract(ikm)
+        .expand(&[info], Len(out.len()))
+        .and_then(|okm| okm.fill(out))
+        .expect("HKDF output is too long");
+}
+
+/// Derives a key of the given purpose from the QR secret
+pub fn derive<const N: usize>(secret: &[u8], salt: &[u8], purpose: KeyPurpose) -> [u8; N] {
+    let mut key = [0u8; N];
+    hkdf_sha256(salt, secret, &(purpose as u32).to_le_bytes(), &mut key);
+    key
+}
+
+/// The plaintext of an advertisement, telling the platform how to reach the tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdvertPlaintext {
+    pub nonce: [u8; 10],
+    /// Assigned by the tunnel server, identifies the server instance hosting the tunnel
+    pub routing_id: [u8; 3],
+    /// The encoded domain of the tunnel server, see [decode_tunnel_domain]
+    pub tunnel_domain: u16,
+}
+
+impl AdvertPlaintext {
+    pub fn to_bytes(self) -> [u8; ADVERT_PLAINTEXT_SIZE] {
+        let mut bytes = [0u8; ADVERT_PLAINTEXT_SIZE];
+        // the first byte is reserved
+        bytes[1..11].copy_from_slice(&self.nonce);
+        bytes[11..14].copy_from_slice(&self.routing_id);
+        bytes[14..].copy_from_slice(&self.tunnel_domain.to_le_bytes());
+        bytes
+    }
+
+    // the platform's side, only used by loopback tests
+    #[allow(dead_code)]
+    pub fn from_bytes(bytes: &[u8; ADVERT_PLAINTEXT_SIZE]) -> Option<Self> {
+        if bytes[0] != 0 {
+            return None;
+        }
+        Some(AdvertPlaintext {
+            nonce: bytes[1..11].try_into().unwrap(),
+            routing_id: bytes[11..14].try_into().unwrap(),
+            tunnel_domain: u16::from_le_bytes([bytes[14], bytes[15]]),
+        })
+    }
+}
+
+/// Encrypts an advertisement with the first half of the EID key, and authenticates it with
+/// the second half
+pub fn encrypt_advert(
+    eid_key: &[u8; 64],
+    plaintext: &[u8; ADVERT_PLAINTEXT_SIZE],
+) -> [u8; ADVERT_SIZE] {
+    let mut block = GenericArray::clone_from_slice(plaintext);
+    Aes256::new(GenericArray::from_slice(&eid_key[..32])).encrypt_block(&mut block);
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &eid_key[32..]), &block);
+
+    let mut advert = [0u8; ADVERT_SIZE];
+    advert[..ADVERT_PLAINTEXT_SIZE].copy_from_slice(&block);
+    advert[ADVERT_PLAINTEXT_SIZE..].copy_from_slice(&tag.as_ref()[..4]);
+    advert
+}
+
+/// Authenticates and decrypts an advertisement, as done by the platform while scanning
+// the platform's side, only used by loopback tests
+#[allow(dead_code)]
+pub fn decrypt_advert(
+    eid_key: &[u8; 64],
+    advert: &[u8; ADVERT_SIZE],
+) -> Option<[u8; ADVERT_PLAINTEXT_SIZE]> {
+    let (ciphertext, tag) = advert.split_at(ADVERT_PLAINTEXT_SIZE);
+    let expected = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, &eid_key[32..]),
+        ciphertext,
+    );
+    constant_time::verify_slices_are_equal(&expected.as_ref()[..4], tag).ok()?;
+
+    let mut block = GenericArray::clone_from_slice(ciphertext);
+    Aes256::new(GenericArray::from_slice(&eid_key[..32])).decrypt_block(&mut block);
+    Some(block.into())
+}
+
+/// Decodes the 16 bit domain of a tunnel server. IDs below 256 are assigned to well known
+/// domains, others are hashed into a domain name.
+pub fn decode_tunnel_domain(id: u16) -> Option<String> {
+    if id < 256 {
+        return ASSIGNED_DOMAINS
+            .get(id as usize)
+            .map(|&domain| domain.to_owned());
+    }
+    const BASE32_CHARS: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    const TLDS: [&str; 4] = [".com", ".org", ".net", ".info"];
+
+    let mut input = b"caBLEv2 tunnel server domain".to_vec();
+    input.extend_from_slice(&id.to_le_bytes());
+    input.push(0);
+    let digest = digest::digest(&digest::SHA256, &input);
+    let mut v = u64::from_le_bytes(digest.as_ref()[..8].try_into().unwrap());
+    let tld = TLDS[(v & 3) as usize];
+    v >>= 2;
+
+    let mut domain = "cable.".to_owned();
+    while v != 0 {
+        domain.push(BASE32_CHARS[(v & 31) as usize] as char);
+        v >>= 5;
+    }
+    domain.push_str(tld);
+    Some(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advert_roundtrip() {
+        let eid_key = derive::<64>(&[0x42; 16], &[], KeyPurpose::EidKey);
+        let plaintext = AdvertPlaintext {
+            nonce: [7; 10],
+            routing_id: [1, 2, 3],
+            tunnel_domain: 1,
+        };
+        let mut advert = encrypt_advert(&eid_key, &plaintext.to_bytes());
+        let decrypted = decrypt_advert(&eid_key, &advert).unwrap();
+        assert_eq!(AdvertPlaintext::from_bytes(&decrypted), Some(plaintext));
+
+        advert[0] ^= 1;
+        assert_eq!(decrypt_advert(&eid_key, &advert), None);
+    }
+
+    #[test]
+    fn test_tunnel_domains() {
+        assert_eq!(decode_tunnel_domain(0).as_deref(), Some("cable.ua5v.com"));
+        assert_eq!(decode_tunnel_domain(2), None);
+        let hashed = decode_tunnel_domain(266).unwrap();
+        assert!(hashed.starts_with("cable."));
+        assert_eq!(decode_tunnel_domain(266), Some(hashed));
+    }
+}
@@ -0,0 +1,310 @@
+//! Local stand-ins for the tunnel service, the BLE advertisement and the platform, so that
+//! hybrid transactions can be tested without a phone or a desktop browser
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use ciborium::value::{Integer, Value};
+use futures::{SinkExt, StreamExt};
+use p256::{elliptic_curve::sec1::ToEncodedPoint, SecretKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        http::HeaderValue,
+        Message as WsMessage,
+    },
+    WebSocketStream,
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use super::{
+    advert::Advertiser,
+    handshake::{ecdh, generate_key, parse_handshake_message, start_handshake, Crypter},
+    keys::{decrypt_advert, derive, AdvertPlaintext, KeyPurpose, ADVERT_SIZE},
+    noise::{encode_point, HandshakeType},
+    qr::{QrCode, QR_SECRET_SIZE},
+    session::MessageType,
+    tunnel::{connect, TunnelServer, TunnelStream, CABLE_PROTOCOL, ROUTING_ID_HEADER},
+};
+
+/// The routing ID assigned to every tunnel by the local tunnel server
+const ROUTING_ID: [u8; 3] = [0xAB, 0xCD, 0xEF];
+
+type Tunnels = Arc<Mutex<HashMap<String, oneshot::Receiver<WebSocketStream<TcpStream>>>>>;
+
+/// A tunnel server on localhost, relaying messages once the platform joins a tunnel
+pub struct LocalTunnelServer {
+    address: SocketAddr,
+    _cancel: DropGuard,
+}
+
+impl LocalTunnelServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let tunnels = Tunnels::default();
+        let accept = {
+            let cancel = cancel.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let serve = serve_connection(stream, tunnels.clone());
+                    let cancel = cancel.clone();
+                    tokio::spawn(async move {
+                        tokio::select! {
+                            _ = cancel.cancelled() => {},
+                            _ = serve => {},
+                        }
+                    });
+                }
+            }
+        };
+        let stop = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = stop.cancelled() => {},
+                _ = accept => {},
+            }
+        });
+        LocalTunnelServer {
+            address,
+            _cancel: cancel.drop_guard(),
+        }
+    }
+
+    pub fn tunnel_server(&self) -> TunnelServer {
+        TunnelServer::new(format!("ws://{}", self.address), 0)
+    }
+}
+
+async fn serve_connection(stream: TcpStream, tunnels: Tunnels) {
+    let mut path = String::new();
+    let mut new_tunnel = None;
+    // the error type is imposed by tungstenite
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| {
+        path = request.uri().path().to_owned();
+        let headers = response.headers_mut();
+        headers.insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(CABLE_PROTOCOL),
+        );
+        if let Some(tunnel_id) = path.strip_prefix("/cable/new/") {
+            // registered before the response is sent, the platform can't join too early
+            let (send, recv) = oneshot::channel();
+            tunnels.lock().unwrap().insert(tunnel_id.to_owned(), recv);
+            new_tunnel = Some(send);
+            let routing_id = HeaderValue::from_str(&hex::encode_upper(ROUTING_ID)).unwrap();
+            headers.insert(ROUTING_ID_HEADER, routing_id);
+        }
+        Ok(response)
+    };
+    let websocket = match accept_hdr_async(stream, callback).await {
+        Ok(websocket) => websocket,
+        Err(_) => return,
+    };
+
+    if let Some(new_tunnel) = new_tunnel {
+        let _ = new_tunnel.send(websocket);
+    } else if let Some(rest) = path.strip_prefix("/cable/connect/") {
+        let (routing_id, tunnel_id) = rest.split_once('/').unwrap();
+        assert_eq!(routing_id, hex::encode_upper(ROUTING_ID));
+        let authenticator = tunnels.lock().unwrap().remove(tunnel_id);
+        if let Some(authenticator) = authenticator {
+            if let Ok(authenticator) = authenticator.await {
+                relay(authenticator, websocket).await;
+            }
+        }
+    }
+}
+
+async fn relay(mut first: WebSocketStream<TcpStream>, mut second: WebSocketStream<TcpStream>) {
+    loop {
+        let (message, to) = tokio::select! {
+            message = first.next() => (message, &mut second),
+            message = second.next() => (message, &mut first),
+        };
+        match message {
+            Some(Ok(message)) if !message.is_close() => {
+                if to.send(message).await.is_err() {
+                    break;
+                }
+            }
+            _ => break,
+        }
+    }
+    let _ = first.close(None).await;
+    let _ = second.close(None).await;
+}
+
+/// Hands the advertisement to the platform, rather than broadcasting it
+pub struct SimulatedAdvertiser {
+    adverts: mpsc::UnboundedSender<[u8; ADVERT_SIZE]>,
+}
+
+pub fn simulated_advertiser() -> (
+    SimulatedAdvertiser,
+    mpsc::UnboundedReceiver<[u8; ADVERT_SIZE]>,
+) {
+    let (send, recv) = mpsc::unbounded_channel();
+    (SimulatedAdvertiser { adverts: send }, recv)
+}
+
+#[async_trait]
+impl Advertiser for SimulatedAdvertiser {
+    async fn start(&mut self, service_data: [u8; ADVERT_SIZE]) -> anyhow::Result<()> {
+        self.adverts.send(service_data)?;
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// The platform side of a hybrid transaction, e.g, a desktop browser
+pub struct Platform {
+    identity: SecretKey,
+    secret: [u8; QR_SECRET_SIZE],
+    rng: SystemRandom,
+}
+
+impl Platform {
+    pub fn new() -> Self {
+        let rng = SystemRandom::new();
+        let mut secret = [0u8; QR_SECRET_SIZE];
+        rng.fill(&mut secret).unwrap();
+        Platform {
+            identity: generate_key(&rng),
+            secret,
+            rng,
+        }
+    }
+
+    /// A platform sharing the secret of this one, but with another identity key
+    pub fn impostor(&self) -> Self {
+        Platform {
+            secret: self.secret,
+            ..Platform::new()
+        }
+    }
+
+    pub fn qr_code(&self) -> QrCode {
+        let identity = self.identity.public_key().to_encoded_point(true);
+        QrCode {
+            peer_identity: identity.as_bytes().try_into().unwrap(),
+            secret: self.secret,
+            known_domains: 2,
+            timestamp: None,
+            supports_linking: Some(false),
+            request_type: Some("ga".to_owned()),
+        }
+    }
+
+    /// Joins the tunnel announced by an advertisement, as the initiator of the handshake
+    pub async fn connect(
+        &self,
+        tunnel_server: &TunnelServer,
+        advert: &[u8; ADVERT_SIZE],
+    ) -> anyhow::Result<PlatformChannel> {
+        let eid_key = derive::<64>(&self.secret, &[], KeyPurpose::EidKey);
+        let plaintext = decrypt_advert(&eid_key, advert)
+            .ok_or_else(|| anyhow!("Couldn't decrypt the advertisement"))?;
+        let advert = AdvertPlaintext::from_bytes(&plaintext).unwrap();
+        assert_eq!(advert.tunnel_domain, tunnel_server.domain_id());
+        let tunnel_id = derive::<16>(&self.secret, &[], KeyPurpose::TunnelId);
+        let url = tunnel_server.connect_tunnel_url(&advert.routing_id, &tunnel_id);
+        let (mut tunnel, _) = connect(&url).await?;
+
+        let psk = derive::<32>(&self.secret, &plaintext, KeyPurpose::Psk);
+        let identity = self.identity.public_key();
+        let mut noise = start_handshake(HandshakeType::KNpsk0, &identity, &psk);
+        let ephemeral = generate_key(&self.rng);
+        let ephemeral_public = encode_point(&ephemeral.public_key());
+        noise.mix_hash(&ephemeral_public);
+        noise.mix_key(&ephemeral_public);
+        let mut message = ephemeral_public;
+        message.extend(noise.encrypt_and_hash(&[]));
+        tunnel.send(WsMessage::Binary(message)).await?;
+
+        let response = next_message(&mut tunnel).await?;
+        let (peer_point, ciphertext) = parse_handshake_message(&response)?;
+        noise.mix_hash_point(&peer_point);
+        noise.mix_key(&encode_point(&peer_point));
+        noise.mix_key(&ecdh(&ephemeral, &peer_point));
+        noise.mix_key(&ecdh(&self.identity, &peer_point));
+        let payload = noise
+            .decrypt_and_hash(ciphertext)
+            .ok_or_else(|| anyhow!("Couldn't authenticate the handshake response"))?;
+        assert!(payload.is_empty());
+        let (write_key, read_key) = noise.traffic_keys();
+        let mut crypter = Crypter::new(read_key, write_key);
+
+        let post_handshake = crypter.decrypt(&next_message(&mut tunnel).await?)?;
+        let get_info = match ciborium::de::from_reader(post_handshake.as_slice())? {
+            Value::Map(map) => map
+                .into_iter()
+                .find(|(k, _)| k.as_integer() == Some(Integer::from(1)))
+                .and_then(|(_, v)| v.into_bytes().ok()),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow!("The post-handshake message has no getInfo response"))?;
+        Ok(PlatformChannel {
+            tunnel,
+            crypter,
+            get_info,
+        })
+    }
+}
+
+async fn next_message(tunnel: &mut TunnelStream) -> anyhow::Result<Vec<u8>> {
+    match tunnel.next().await {
+        Some(Ok(WsMessage::Binary(data))) => Ok(data),
+        other => Err(anyhow!("Expected a binary message, got {:?}", other)),
+    }
+}
+
+/// The platform's end of an established channel
+pub struct PlatformChannel {
+    tunnel: TunnelStream,
+    crypter: Crypter,
+    /// The getInfo response sent right after the handshake, without its status byte
+    pub get_info: Vec<u8>,
+}
+
+impl PlatformChannel {
+    /// Sends a CTAP2 request, returning the response including its status byte
+    pub async fn request(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut message = vec![MessageType::Ctap.into()];
+        message.extend_from_slice(request);
+        let ciphertext = self.crypter.encrypt(&message).unwrap();
+        self.tunnel
+            .send(WsMessage::Binary(ciphertext))
+            .await
+            .unwrap();
+
+        let response = next_message(&mut self.tunnel).await.unwrap();
+        let response = self.crypter.decrypt(&response).unwrap();
+        assert_eq!(response[0], u8::from(MessageType::Ctap));
+        response[1..].to_vec()
+    }
+
+    pub async fn shutdown(mut self) {
+        let message = self
+            .crypter
+            .encrypt(&[MessageType::Shutdown.into()])
+            .unwrap();
+        self.tunnel.send(WsMessage::Binary(message)).await.unwrap();
+    }
+}
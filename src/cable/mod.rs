@@ -0,0 +1,9 @@
+pub(crate) mod advert;
+pub(crate) mod handshake;
+pub(crate) mod keys;
+#[cfg(test)]
+pub(crate) mod loopback;
+pub(crate) mod noise;
+pub(crate) mod qr;
+pub(crate) mod session;
+pub(crate) mod tunnel;
@@ -0,0 +1,131 @@
+//! The symmetric state of a [Noise](https://noiseprotocol.org/noise.html) handshake, with
+//! P-256, AES-256-GCM and SHA-256
+
+use p256::{elliptic_curve::sec1::ToEncodedPoint, PublicKey};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    digest,
+};
+
+use super::keys::hkdf_sha256;
+
+/// The handshake patterns used by the hybrid transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeType {
+    /// The initiator's static key is known to the responder, through a QR code
+    KNpsk0,
+    /// The responder's static key is known to the initiator, through linking
+    // linking isn't implemented yet
+    #[allow(dead_code)]
+    NKpsk0,
+}
+
+impl HandshakeType {
+    fn protocol_name(self) -> &'static [u8] {
+        match self {
+            HandshakeType::KNpsk0 => b"Noise_KNpsk0_P256_AESGCM_SHA256",
+            HandshakeType::NKpsk0 => b"Noise_NKpsk0_P256_AESGCM_SHA256",
+        }
+    }
+}
+
+/// Encodes a point in the uncompressed X9.62 form
+pub fn encode_point(point: &PublicKey) -> Vec<u8> {
+    point.to_encoded_point(false).as_bytes().to_vec()
+}
+
+pub struct Noise {
+    chaining_key: [u8; 32],
+    h: [u8; 32],
+    symmetric_key: [u8; 32],
+    symmetric_nonce: u32,
+}
+
+impl Noise {
+    pub fn new(handshake_type: HandshakeType) -> Self {
+        // protocol names shorter than the hash are zero padded rather than hashed
+        let mut h = [0u8; 32];
+        let name = handshake_type.protocol_name();
+        h[..name.len()].copy_from_slice(name);
+        Noise {
+            chaining_key: h,
+            h,
+            symmetric_key: [0; 32],
+            symmetric_nonce: 0,
+        }
+    }
+
+    pub fn mix_hash(&mut self, data: &[u8]) {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&self.h);
+        ctx.update(data);
+        self.h.copy_from_slice(ctx.finish().as_ref());
+    }
+
+    pub fn mix_hash_point(&mut self, point: &PublicKey) {
+        self.mix_hash(&encode_point(point));
+    }
+
+    pub fn mix_key(&mut self, ikm: &[u8]) {
+        let mut output = [0u8; 64];
+        hkdf_sha256(&self.chaining_key, ikm, &[], &mut output);
+        self.chaining_key.copy_from_slice(&output[..32]);
+        self.init_key(&output[32..]);
+    }
+
+    pub fn mix_key_and_hash(&mut self, ikm: &[u8]) {
+        let mut output = [0u8; 96];
+        hkdf_sha256(&self.chaining_key, ikm, &[], &mut output);
+        self.chaining_key.copy_from_slice(&output[..32]);
+        self.mix_hash(&output[32..64]);
+        self.init_key(&output[64..]);
+    }
+
+    pub fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut ciphertext = plaintext.to_vec();
+        self.key()
+            .seal_in_place_append_tag(self.next_nonce(), Aad::from(self.h), &mut ciphertext)
+            .expect("Plaintext is too long");
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    pub fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let mut plaintext = ciphertext.to_vec();
+        let len = self
+            .key()
+            .open_in_place(self.next_nonce(), Aad::from(self.h), &mut plaintext)
+            .ok()?
+            .len();
+        plaintext.truncate(len);
+        self.mix_hash(ciphertext);
+        Some(plaintext)
+    }
+
+    /// Splits the final chaining key into the keys of both directions, the first of which is
+    /// used by the initiator to write
+    pub fn traffic_keys(&self) -> ([u8; 32], [u8; 32]) {
+        let mut output = [0u8; 64];
+        hkdf_sha256(&self.chaining_key, &[], &[], &mut output);
+        (
+            output[..32].try_into().unwrap(),
+            output[32..].try_into().unwrap(),
+        )
+    }
+
+    fn init_key(&mut self, key: &[u8]) {
+        self.symmetric_key.copy_from_slice(key);
+        self.symmetric_nonce = 0;
+    }
+
+    fn key(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&aead::AES_256_GCM, &self.symmetric_key).unwrap())
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[..4].copy_from_slice(&self.symmetric_nonce.to_be_bytes());
+        self.symmetric_nonce += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+}
@@ -0,0 +1,217 @@
+//! The `FIDO:/` URI displayed by the platform as a QR code, which starts a hybrid transaction.
+//! Its payload is a CBOR map, encoded as decimal digits so that the QR code can use the
+//! compact numeric mode.
+
+use ciborium::value::{Integer, Value};
+use thiserror::Error;
+
+const URI_PREFIX: &str = "FIDO:/";
+
+/// A chunk of 7 bytes is encoded as 17 digits
+const CHUNK_SIZE: usize = 7;
+const CHUNK_DIGITS: usize = 17;
+
+/// The number of digits encoding a partial chunk, by its size
+const PARTIAL_CHUNK_DIGITS: [usize; CHUNK_SIZE] = [0, 3, 5, 8, 10, 13, 15];
+
+/// Size of a compressed P-256 public key
+pub const COMPRESSED_PUBLIC_KEY_SIZE: usize = 33;
+
+/// Size of the secret shared through the QR code
+pub const QR_SECRET_SIZE: usize = 16;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum QrCodeError {
+    #[error("The URI doesn't start with {URI_PREFIX}")]
+    NotFidoUri,
+
+    #[error("The URI payload isn't a valid digit encoding")]
+    InvalidDigits,
+
+    #[error("The URI payload isn't a valid CBOR map")]
+    MalformedCbor,
+
+    #[error("Missing or invalid field {0} in the URI payload")]
+    InvalidField(u8),
+}
+
+/// The contents of a hybrid QR code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrCode {
+    /// The platform's compressed P-256 identity key, authenticated by the handshake
+    pub peer_identity: [u8; COMPRESSED_PUBLIC_KEY_SIZE],
+    /// The secret all keys of the transaction are derived from
+    pub secret: [u8; QR_SECRET_SIZE],
+    /// The number of tunnel server domains known to the platform
+    pub known_domains: u64,
+    /// When the QR code was generated, in seconds since the epoch
+    pub timestamp: Option<u64>,
+    /// Whether the platform supports being linked, which isn't supported here
+    pub supports_linking: Option<bool>,
+    /// The kind of request the platform is about to make, e.g, "mc" or "ga"
+    pub request_type: Option<String>,
+}
+
+// the QR code is scanned by a hybrid session, which needs an advertiser bound to a Bluetooth
+// stack, none of which exists yet
+#[allow(dead_code)]
+impl QrCode {
+    pub fn parse(uri: &str) -> Result<Self, QrCodeError> {
+        let digits = match uri.get(..URI_PREFIX.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(URI_PREFIX) => &uri[URI_PREFIX.len()..],
+            _ => return Err(QrCodeError::NotFidoUri),
+        };
+        let payload = decode_digits(digits).ok_or(QrCodeError::InvalidDigits)?;
+        let map = match ciborium::de::from_reader(payload.as_slice()) {
+            Ok(Value::Map(map)) => map,
+            _ => return Err(QrCodeError::MalformedCbor),
+        };
+        let field = |key: u8| {
+            map.iter()
+                .find(|(k, _)| k.as_integer() == Some(Integer::from(key)))
+                .map(|(_, v)| v)
+        };
+        let bytes = |key: u8| {
+            field(key)
+                .and_then(Value::as_bytes)
+                .ok_or(QrCodeError::InvalidField(key))
+        };
+        let uint = |key: u8| {
+            field(key)
+                .map(|v| {
+                    v.as_integer()
+                        .and_then(|i| u64::try_from(i).ok())
+                        .ok_or(QrCodeError::InvalidField(key))
+                })
+                .transpose()
+        };
+
+        Ok(QrCode {
+            peer_identity: bytes(0)?
+                .as_slice()
+                .try_into()
+                .map_err(|_| QrCodeError::InvalidField(0))?,
+            secret: bytes(1)?
+                .as_slice()
+                .try_into()
+                .map_err(|_| QrCodeError::InvalidField(1))?,
+            known_domains: uint(2)?.unwrap_or(0),
+            timestamp: uint(3)?,
+            supports_linking: field(4)
+                .map(|v| v.as_bool().ok_or(QrCodeError::InvalidField(4)))
+                .transpose()?,
+            request_type: field(5)
+                .map(|v| {
+                    v.as_text()
+                        .map(str::to_owned)
+                        .ok_or(QrCodeError::InvalidField(5))
+                })
+                .transpose()?,
+        })
+    }
+
+    /// Encodes the QR code as a `FIDO:/` URI, as done by the platform
+    pub fn to_uri(&self) -> String {
+        let mut map = vec![
+            (Value::from(0), Value::Bytes(self.peer_identity.to_vec())),
+            (Value::from(1), Value::Bytes(self.secret.to_vec())),
+            (Value::from(2), Value::from(self.known_domains)),
+        ];
+        if let Some(timestamp) = self.timestamp {
+            map.push((Value::from(3), Value::from(timestamp)));
+        }
+        if let Some(supports_linking) = self.supports_linking {
+            map.push((Value::from(4), Value::Bool(supports_linking)));
+        }
+        if let Some(request_type) = &self.request_type {
+            map.push((Value::from(5), Value::Text(request_type.clone())));
+        }
+        let mut payload = Vec::new();
+        ciborium::ser::into_writer(&Value::Map(map), &mut payload).unwrap();
+        format!("{}{}", URI_PREFIX, encode_digits(&payload))
+    }
+}
+
+/// Encodes bytes as digits, each chunk of up to 7 bytes being a little endian integer
+fn encode_digits(bytes: &[u8]) -> String {
+    bytes
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| {
+            let mut value = [0u8; 8];
+            value[..chunk.len()].copy_from_slice(chunk);
+            let digits = if chunk.len() == CHUNK_SIZE {
+                CHUNK_DIGITS
+            } else {
+                PARTIAL_CHUNK_DIGITS[chunk.len()]
+            };
+            format!("{:0width$}", u64::from_le_bytes(value), width = digits)
+        })
+        .collect()
+}
+
+fn decode_digits(digits: &str) -> Option<Vec<u8>> {
+    if !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let partial_digits = digits.len() % CHUNK_DIGITS;
+    let partial_size = PARTIAL_CHUNK_DIGITS
+        .iter()
+        .position(|&n| n == partial_digits)?;
+
+    let mut bytes = Vec::new();
+    let (full, partial) = digits.split_at(digits.len() - partial_digits);
+    let chunks = full
+        .as_bytes()
+        .chunks(CHUNK_DIGITS)
+        .map(|chunk| (chunk, CHUNK_SIZE))
+        .chain((partial_size > 0).then_some((partial.as_bytes(), partial_size)));
+    for (chunk, size) in chunks {
+        // the digits were checked to be ASCII
+        let value: u64 = std::str::from_utf8(chunk).ok()?.parse().ok()?;
+        let value = value.to_le_bytes();
+        if value[size..].iter().any(|&b| b != 0) {
+            return None;
+        }
+        bytes.extend_from_slice(&value[..size]);
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digits() {
+        for len in 0..30 {
+            let bytes: Vec<u8> = (0..len).map(|i| 0xff - i).collect();
+            let digits = encode_digits(&bytes);
+            assert_eq!(decode_digits(&digits), Some(bytes));
+        }
+        assert_eq!(encode_digits(&[1, 2]), "00513");
+        // 2^16 doesn't fit in 2 bytes
+        assert_eq!(decode_digits("65536"), None);
+        assert_eq!(decode_digits("1234"), None);
+    }
+
+    #[test]
+    fn test_uri_roundtrip() {
+        let qr = QrCode {
+            peer_identity: [2; COMPRESSED_PUBLIC_KEY_SIZE],
+            secret: [0x5a; QR_SECRET_SIZE],
+            known_domains: 2,
+            timestamp: Some(1_700_000_000),
+            supports_linking: Some(false),
+            request_type: Some("mc".to_owned()),
+        };
+        let uri = qr.to_uri();
+        assert!(uri.starts_with("FIDO:/"));
+        assert_eq!(QrCode::parse(&uri), Ok(qr));
+
+        assert_eq!(QrCode::parse("https://"), Err(QrCodeError::NotFidoUri));
+        assert_eq!(
+            QrCode::parse("FIDO:/00513"),
+            Err(QrCodeError::MalformedCbor)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::future::poll_fn;
+
+use anyhow::anyhow;
+use ciborium::value::Value;
+use futures::{SinkExt, StreamExt};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use p256::PublicKey;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tower::Service;
+use tracing::{debug, error, trace, warn};
+
+use crate::{
+    authenticator::{
+        api::{AuthServiceError, CTAP2Request, CTAP2Response},
+        command::CTAPCommand,
+    },
+    hid::{command::CommandType, packet::Message},
+};
+
+use super::{
+    advert::Advertiser,
+    handshake::respond,
+    keys::{derive, encrypt_advert, AdvertPlaintext, KeyPurpose},
+    qr::QrCode,
+    tunnel::{TunnelServer, TunnelStream},
+};
+
+/// Channel identifier of requests received through the tunnel, which has no channels
+pub const HYBRID_CHANNEL: u32 = 0;
+
+/// The type of a message exchanged over the encrypted channel, its first byte
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+pub enum MessageType {
+    Shutdown = 0,
+    Ctap = 1,
+    Update = 2,
+}
+
+/// The authenticator side of a hybrid transaction started by scanning a QR code: creates a
+/// tunnel, advertises it over BLE, and once the platform joins the tunnel, answers its
+/// CTAP2 requests over the encrypted channel.
+// needs an advertiser, none of which is bound to a Bluetooth stack yet
+#[allow(dead_code)]
+pub struct HybridAuthenticator<A> {
+    qr_code: QrCode,
+    tunnel_server: TunnelServer,
+    advertiser: A,
+    rng: SystemRandom,
+}
+
+#[allow(dead_code)]
+impl<A: Advertiser> HybridAuthenticator<A> {
+    pub fn new(qr_code: QrCode, tunnel_server: TunnelServer, advertiser: A) -> Self {
+        HybridAuthenticator {
+            qr_code,
+            tunnel_server,
+            advertiser,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Runs the transaction until the platform shuts it down or closes the tunnel
+    pub async fn run<S>(&mut self, mut service: S) -> anyhow::Result<()>
+    where
+        S: Service<CTAP2Request, Response = CTAP2Response, Error = AuthServiceError>,
+    {
+        let secret = self.qr_code.secret;
+        let peer_identity = PublicKey::from_sec1_bytes(&self.qr_code.peer_identity)
+            .map_err(|_| anyhow!("The QR code contains an invalid identity key"))?;
+
+        let tunnel_id = derive::<16>(&secret, &[], KeyPurpose::TunnelId);
+        let (mut tunnel, routing_id) = self.tunnel_server.new_tunnel(&tunnel_id).await?;
+        debug!(routing_id = %hex::encode(routing_id), "Created a tunnel");
+
+        let mut nonce = [0u8; 10];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("Couldn't generate a nonce"))?;
+        let plaintext = AdvertPlaintext {
+            nonce,
+            routing_id,
+            tunnel_domain: self.tunnel_server.domain_id(),
+        }
+        .to_bytes();
+        let eid_key = derive::<64>(&secret, &[], KeyPurpose::EidKey);
+        self.advertiser
+            .start(encrypt_advert(&eid_key, &plaintext))
+            .await?;
+        let handshake = next_binary_message(&mut tunnel).await;
+        self.advertiser.stop().await?;
+        let handshake =
+            handshake?.ok_or_else(|| anyhow!("The tunnel was closed before the handshake"))?;
+
+        let psk = derive::<32>(&secret, &plaintext, KeyPurpose::Psk);
+        let (response, mut crypter) = respond(&psk, &peer_identity, &handshake, &self.rng)?;
+        tunnel.send(WsMessage::Binary(response)).await?;
+        debug!("Completed the handshake");
+
+        let get_info = call(&mut service, vec![CTAPCommand::GetInfo.into()]).await?;
+        if get_info.first() != Some(&0) {
+            return Err(anyhow!("Couldn't get the authenticator info"));
+        }
+        let post_handshake = Value::Map(vec![
+            (Value::from(1), Value::Bytes(get_info[1..].to_vec())),
+            (
+                Value::from(3),
+                Value::Array(vec![Value::Text("ctap".to_owned())]),
+            ),
+        ]);
+        let mut post_handshake_bytes = Vec::new();
+        ciborium::ser::into_writer(&post_handshake, &mut post_handshake_bytes)?;
+        tunnel
+            .send(WsMessage::Binary(crypter.encrypt(&post_handshake_bytes)?))
+            .await?;
+
+        while let Some(message) = next_binary_message(&mut tunnel).await? {
+            let message = crypter.decrypt(&message)?;
+            let (&message_type, payload) = message
+                .split_first()
+                .ok_or_else(|| anyhow!("Received an empty message"))?;
+            match MessageType::try_from(message_type) {
+                Ok(MessageType::Ctap) => {
+                    trace!(payload = %hex::encode(payload), "Received a CTAP request");
+                    let mut response = vec![MessageType::Ctap.into()];
+                    response.extend(call(&mut service, payload.to_vec()).await?);
+                    tunnel
+                        .send(WsMessage::Binary(crypter.encrypt(&response)?))
+                        .await?;
+                }
+                Ok(MessageType::Shutdown) => {
+                    debug!("The platform has shut down the transaction");
+                    break;
+                }
+                Ok(MessageType::Update) => debug!("Ignoring an update message"),
+                Err(_) => warn!(message_type, "Ignoring a message of unknown type"),
+            }
+        }
+        let _ = tunnel.close(None).await;
+        Ok(())
+    }
+}
+
+/// Reads the next binary websocket message, or `None` once the tunnel is closed
+async fn next_binary_message(tunnel: &mut TunnelStream) -> anyhow::Result<Option<Vec<u8>>> {
+    while let Some(message) = tunnel.next().await {
+        match message? {
+            WsMessage::Binary(data) => return Ok(Some(data)),
+            WsMessage::Close(_) => break,
+            // pings are answered by the websocket itself
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Processes a CTAP2 request (a command byte followed by CBOR), returning the response
+/// (a status byte followed by CBOR)
+async fn call<S>(service: &mut S, payload: Vec<u8>) -> anyhow::Result<Vec<u8>>
+where
+    S: Service<CTAP2Request, Response = CTAP2Response, Error = AuthServiceError>,
+{
+    let message = Message {
+        channel_identifier: HYBRID_CHANNEL,
+        command: Ok(CommandType::Cbor),
+        payload,
+    };
+    let response = match CTAP2Request::try_from(&message) {
+        Ok(request) => {
+            poll_fn(|cx| service.poll_ready(cx)).await?;
+            match service.call(request).await {
+                Ok(response) => Message::from(response),
+                Err(error) => Message::from(&error),
+            }
+        }
+        Err(e) => {
+            error!(?e, "Couldn't parse a request");
+            Message::from(&AuthServiceError::new(e, HYBRID_CHANNEL))
+        }
+    };
+    Ok(response.payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        authenticator::{api::CTAP2Service, command::StatusCode},
+        cable::loopback::{simulated_advertiser, LocalTunnelServer, Platform},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hybrid_get_info() {
+        let tunnel_server = LocalTunnelServer::start().await;
+        let platform = Platform::new();
+        let (advertiser, mut adverts) = simulated_advertiser();
+        let mut authenticator = HybridAuthenticator::new(
+            platform.qr_code(),
+            tunnel_server.tunnel_server(),
+            advertiser,
+        );
+
+        let client = async {
+            let advert = adverts.recv().await.unwrap();
+            let mut channel = platform
+                .connect(&tunnel_server.tunnel_server(), &advert)
+                .await
+                .unwrap();
+            let response = channel.request(&[CTAPCommand::GetInfo.into()]).await;
+            assert_eq!(response[0], StatusCode::Ctap1ErrSuccess as u8);
+            assert_eq!(&response[1..], &channel.get_info[..]);
+
+            let response = channel.request(&[0x42]).await;
+            assert_eq!(response, vec![StatusCode::Ctap1ErrInvalidCommand as u8]);
+            channel.shutdown().await;
+        };
+        let (res, ()) = tokio::join!(authenticator.run(CTAP2Service::new(false)), client);
+        res.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_rejects_unknown_platform() {
+        let tunnel_server = LocalTunnelServer::start().await;
+        let platform = Platform::new();
+        let (advertiser, mut adverts) = simulated_advertiser();
+        let mut authenticator = HybridAuthenticator::new(
+            platform.qr_code(),
+            tunnel_server.tunnel_server(),
+            advertiser,
+        );
+
+        // a platform which knows the QR secret, but not the identity key, can't complete
+        // the handshake
+        let impostor = async {
+            let advert = adverts.recv().await.unwrap();
+            let res = platform
+                .impostor()
+                .connect(&tunnel_server.tunnel_server(), &advert)
+                .await;
+            assert!(res.is_err());
+        };
+        let (res, ()) = tokio::join!(authenticator.run(CTAP2Service::new(false)), impostor);
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Client of the tunnel service relaying messages between the platform and the authenticator
+
+use anyhow::anyhow;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, handshake::client::Response, http::HeaderValue},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use super::keys::decode_tunnel_domain;
+
+/// The websocket subprotocol spoken over tunnels
+pub const CABLE_PROTOCOL: &str = "fido.cable";
+
+/// Response header carrying the routing ID of a new tunnel, as hex
+pub const ROUTING_ID_HEADER: &str = "X-caBLE-Routing-ID";
+
+pub type TunnelStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A tunnel server, and the domain ID under which the authenticator advertises it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelServer {
+    base_url: String,
+    domain_id: u16,
+}
+
+// chosen by a hybrid session, which needs an advertiser bound to a Bluetooth stack, none of
+// which exists yet
+#[allow(dead_code)]
+impl TunnelServer {
+    /// A tunnel server of a well known or hashed domain, reached over TLS
+    pub fn from_domain_id(domain_id: u16) -> Option<Self> {
+        let domain = decode_tunnel_domain(domain_id)?;
+        Some(TunnelServer::new(format!("wss://{}", domain), domain_id))
+    }
+
+    /// A tunnel server at the given base URL, e.g, a local stand-in at `ws://localhost:8080`,
+    /// which the platform is expected to find under the given domain ID
+    pub fn new(base_url: impl Into<String>, domain_id: u16) -> Self {
+        TunnelServer {
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+            domain_id,
+        }
+    }
+
+    pub fn domain_id(&self) -> u16 {
+        self.domain_id
+    }
+
+    /// The URL the authenticator connects to, to create a tunnel
+    pub fn new_tunnel_url(&self, tunnel_id: &[u8; 16]) -> String {
+        format!(
+            "{}/cable/new/{}",
+            self.base_url,
+            hex::encode_upper(tunnel_id)
+        )
+    }
+
+    /// The URL the platform connects to, to join a tunnel
+    pub fn connect_tunnel_url(&self, routing_id: &[u8; 3], tunnel_id: &[u8; 16]) -> String {
+        format!(
+            "{}/cable/connect/{}/{}",
+            self.base_url,
+            hex::encode_upper(routing_id),
+            hex::encode_upper(tunnel_id)
+        )
+    }
+
+    /// Creates a tunnel, returning it along with its routing ID
+    pub async fn new_tunnel(
+        &self,
+        tunnel_id: &[u8; 16],
+    ) -> anyhow::Result<(TunnelStream, [u8; 3])> {
+        let (tunnel, response) = connect(&self.new_tunnel_url(tunnel_id)).await?;
+        let routing_id = response
+            .headers()
+            .get(ROUTING_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| hex::decode(value).ok())
+            .and_then(|value| <[u8; 3]>::try_from(value).ok())
+            .ok_or_else(|| anyhow!("The tunnel server didn't assign a valid routing ID"))?;
+        Ok((tunnel, routing_id))
+    }
+}
+
+/// Opens a websocket speaking [CABLE_PROTOCOL]
+pub async fn connect(url: &str) -> anyhow::Result<(TunnelStream, Response)> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(CABLE_PROTOCOL),
+    );
+    Ok(connect_async(request).await?)
+}
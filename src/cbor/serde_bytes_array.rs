@@ -11,12 +11,14 @@ where
     serde_bytes::serialize(bytes, serializer)
 }
 
-/// This takes the result of [`serde_bytes::deserialize`] from `[u8]` to `[u8; N]`.
+/// This takes the result of [`serde_bytes::deserialize`] from `ByteBuf` to `[u8; N]`, which
+/// unlike `&[u8]` can also be deserialized from readers that can't lend their input.
 pub fn deserialize<'de, D, const N: usize>(deserializer: D) -> Result<[u8; N], D::Error>
 where
     D: Deserializer<'de>,
 {
-    let slice: &[u8] = serde_bytes::deserialize(deserializer)?;
+    let bytes: serde_bytes::ByteBuf = serde_bytes::deserialize(deserializer)?;
+    let slice: &[u8] = &bytes;
     let array: [u8; N] = slice.try_into().map_err(|_| {
         let expected = format!("[u8; {}]", N);
         D::Error::invalid_length(slice.len(), &expected.as_str())
@@ -1,29 +1,171 @@
-use std::collections::BTreeSet;
+use std::{collections::HashMap, time::Instant};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{debug, error};
 
 pub const BROADCAST_CHANNEL: u32 = 0xffffffff;
 pub const RESERVED_CHANNEL: u32 = 0;
 
+/// Default maximal number of channels that may be allocated at once, before
+/// the least recently used ones are evicted.
+pub const DEFAULT_MAX_CHANNELS: usize = 128;
+
+/// How many random channel identifiers to try before giving up, in the
+/// (practically impossible) case where every one of them is taken.
+const MAX_ALLOCATION_ATTEMPTS: usize = 32;
+
+/// Allocates CTAP-HID channel identifiers.
+///
+/// Channels are allocated from random non-reserved values, so that clients can't guess
+/// each other's channels. Every allocated channel carries the time it was last used, and
+/// once `max_channels` are allocated, allocating another one evicts the least recently
+/// used channel that may be evicted, i.e, one that neither holds the lock nor is in the
+/// middle of a transaction.
 pub struct ChannelAllocator {
-    used: BTreeSet<u32>,
+    // maps each allocated channel to the last time it was used
+    last_used: HashMap<u32, Instant>,
+    max_channels: usize,
+    rng: SystemRandom,
 }
 
 impl ChannelAllocator {
     pub fn new() -> Self {
+        Self::with_max_channels(DEFAULT_MAX_CHANNELS)
+    }
+
+    pub fn with_max_channels(max_channels: usize) -> Self {
+        assert!(max_channels > 0, "Must allow at least one channel");
         ChannelAllocator {
-            used: BTreeSet::new(),
+            last_used: HashMap::new(),
+            max_channels,
+            rng: SystemRandom::new(),
         }
     }
 
     pub fn is_allocated(&self, chan: u32) -> bool {
-        self.used.contains(&chan)
+        self.last_used.contains_key(&chan)
     }
 
-    pub fn allocate(&mut self) -> Option<u32> {
-        for i in 1..BROADCAST_CHANNEL {
-            if self.used.insert(i) {
-                return Some(i);
+    /// Marks an allocated channel as used right now, returning false if the channel
+    /// isn't allocated.
+    pub fn touch(&mut self, chan: u32) -> bool {
+        match self.last_used.get_mut(&chan) {
+            Some(last_used) => {
+                *last_used = Instant::now();
+                true
             }
+            None => false,
+        }
+    }
+
+    /// Allocates a new random channel. If the allocator is full, the least recently used
+    /// channel for which `can_evict` holds is evicted, and if there's none, no channel is
+    /// allocated.
+    pub fn allocate(&mut self, can_evict: impl Fn(u32) -> bool) -> Option<u32> {
+        if self.last_used.len() >= self.max_channels && !self.evict_least_recently_used(can_evict) {
+            return None;
+        }
+        for _ in 0..MAX_ALLOCATION_ATTEMPTS {
+            let chan = self.random_channel()?;
+            if chan == RESERVED_CHANNEL || chan == BROADCAST_CHANNEL || self.is_allocated(chan) {
+                continue;
+            }
+            self.last_used.insert(chan, Instant::now());
+            return Some(chan);
         }
         None
     }
+
+    /// Releases all allocated channels, e.g, once the HID device is closed by the host.
+    pub fn release_all(&mut self) {
+        debug!(count = self.last_used.len(), "Releasing all channels");
+        self.last_used.clear();
+    }
+
+    /// Returns whether a channel was evicted
+    fn evict_least_recently_used(&mut self, can_evict: impl Fn(u32) -> bool) -> bool {
+        let lru = self
+            .last_used
+            .iter()
+            .filter(|(chan, _)| can_evict(**chan))
+            .min_by_key(|(_, last_used)| **last_used)
+            .map(|(chan, _)| *chan);
+        match lru {
+            Some(chan) => {
+                debug!(?chan, "Evicting least recently used channel");
+                self.last_used.remove(&chan);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn random_channel(&self) -> Option<u32> {
+        let mut bytes = [0u8; 4];
+        if let Err(e) = self.rng.fill(&mut bytes) {
+            error!(?e, "Couldn't generate a random channel identifier");
+            return None;
+        }
+        Some(u32::from_be_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocates_distinct_non_reserved_channels() {
+        let mut alloc = ChannelAllocator::new();
+        let mut chans = Vec::new();
+        for _ in 0..DEFAULT_MAX_CHANNELS {
+            let chan = alloc.allocate(|_| true).unwrap();
+            assert_ne!(chan, RESERVED_CHANNEL);
+            assert_ne!(chan, BROADCAST_CHANNEL);
+            assert!(!chans.contains(&chan));
+            chans.push(chan);
+        }
+        assert!(chans.iter().all(|chan| alloc.is_allocated(*chan)));
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut alloc = ChannelAllocator::with_max_channels(3);
+        let first = alloc.allocate(|_| true).unwrap();
+        let second = alloc.allocate(|_| true).unwrap();
+        let third = alloc.allocate(|_| true).unwrap();
+        assert!(alloc.touch(first));
+
+        let fourth = alloc.allocate(|_| true).unwrap();
+        assert!(alloc.is_allocated(first));
+        assert!(!alloc.is_allocated(second));
+        assert!(alloc.is_allocated(third));
+        assert!(alloc.is_allocated(fourth));
+    }
+
+    #[test]
+    fn test_skips_channels_that_cant_be_evicted() {
+        let mut alloc = ChannelAllocator::with_max_channels(2);
+        let first = alloc.allocate(|_| true).unwrap();
+        let second = alloc.allocate(|_| true).unwrap();
+
+        let third = alloc.allocate(|chan| chan != first).unwrap();
+        assert!(alloc.is_allocated(first));
+        assert!(!alloc.is_allocated(second));
+        assert!(alloc.is_allocated(third));
+
+        assert_eq!(alloc.allocate(|_| false), None);
+        assert!(alloc.is_allocated(first) && alloc.is_allocated(third));
+    }
+
+    #[test]
+    fn test_release_all() {
+        let mut alloc = ChannelAllocator::new();
+        let first = alloc.allocate(|_| true).unwrap();
+        let second = alloc.allocate(|_| true).unwrap();
+        alloc.release_all();
+        assert!(!alloc.is_allocated(first));
+        assert!(!alloc.is_allocated(second));
+        assert!(!alloc.touch(first));
+    }
 }
@@ -1,4 +1,5 @@
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::Deserialize;
 use thiserror::Error;
 use zerocopy::{AsBytes, BigEndian, FromBytes, Unaligned, U32};
 
@@ -6,48 +7,80 @@ use super::packet::Message;
 
 /// A CTAP-HID command (note that the MSB isn't set, unlike in the wire protocol)
 /// See https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#usb-commands
-#[repr(u8)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CommandType {
-    Msg = 0x03,
-    Cbor = 0x10,
-    Init = 0x06,
-    Ping = 0x01,
-    Cancel = 0x11,
-    Error = 0x3F,
-    Keepalive = 0x3B,
+    Msg,
+    Cbor,
+    Init,
+    Ping,
+    Cancel,
+    Error,
+    Keepalive,
     // optional:
-    Wink = 0x08,
-    Lock = 0x04,
+    Wink,
+    Lock,
+    /// A vendor specific command, whose identifier lies within
+    /// [CTAPHID_VENDOR_FIRST]..=[CTAPHID_VENDOR_LAST]
+    Vendor(u8),
 }
 
-const CTAPHID_VENDOR_FIRST: u8 = 0x40;
-const CTAPHID_VENDOR_LAST: u8 = 0x7F;
+pub const CTAPHID_VENDOR_FIRST: u8 = 0x40;
+pub const CTAPHID_VENDOR_LAST: u8 = 0x7F;
 
 #[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum InvalidCommandType {
-    #[error("'{0}' is a vendor command identifier, and thus unsupported")]
-    UnsupportedVendor(u8),
-
     #[error("'{0}' is not a valid CTAP-HID command identifier")]
     InvalidCommand(u8),
 }
 
+impl From<CommandType> for u8 {
+    fn from(command: CommandType) -> Self {
+        match command {
+            CommandType::Msg => 0x03,
+            CommandType::Cbor => 0x10,
+            CommandType::Init => 0x06,
+            CommandType::Ping => 0x01,
+            CommandType::Cancel => 0x11,
+            CommandType::Error => 0x3F,
+            CommandType::Keepalive => 0x3B,
+            CommandType::Wink => 0x08,
+            CommandType::Lock => 0x04,
+            CommandType::Vendor(command_identifier) => command_identifier,
+        }
+    }
+}
+
+impl TryFrom<u8> for CommandType {
+    type Error = InvalidCommandType;
+
+    /// Parses a command identifier whose MSB isn't set
+    fn try_from(command_identifier: u8) -> Result<Self, InvalidCommandType> {
+        Ok(match command_identifier {
+            0x03 => CommandType::Msg,
+            0x10 => CommandType::Cbor,
+            0x06 => CommandType::Init,
+            0x01 => CommandType::Ping,
+            0x11 => CommandType::Cancel,
+            0x3F => CommandType::Error,
+            0x3B => CommandType::Keepalive,
+            0x08 => CommandType::Wink,
+            0x04 => CommandType::Lock,
+            CTAPHID_VENDOR_FIRST..=CTAPHID_VENDOR_LAST => CommandType::Vendor(command_identifier),
+            _ => return Err(InvalidCommandType::InvalidCommand(command_identifier)),
+        })
+    }
+}
+
 impl CommandType {
     /// Parses a command identifier from CTAP-HID packet
     pub fn from_packet_command_identifier(
-        mut command_identifier: u8,
+        command_identifier: u8,
     ) -> Result<CommandType, InvalidCommandType> {
         assert!(
             command_identifier & 0x80 != 0,
             "Command identifier MSB must be set"
         );
-        command_identifier &= 0x7F;
-        if (CTAPHID_VENDOR_FIRST..=CTAPHID_VENDOR_LAST).contains(&command_identifier) {
-            return Err(InvalidCommandType::UnsupportedVendor(command_identifier));
-        }
-        CommandType::try_from(command_identifier)
-            .map_err(|_| InvalidCommandType::InvalidCommand(command_identifier))
+        CommandType::try_from(command_identifier & 0x7F)
     }
 }
 
@@ -95,19 +128,31 @@ pub struct InitCommandResponse {
     pub capabilities_flag: u8,
 }
 
-const CAPABILITY_CBOR: u8 = 0x04;
-const CAPABILITY_NMSG: u8 = 0x08;
+pub const CAPABILITY_WINK: u8 = 0x01;
+pub const CAPABILITY_CBOR: u8 = 0x04;
+pub const CAPABILITY_NMSG: u8 = 0x08;
+
+/// The version of the device, as reported in response to CTAPHID_INIT
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct DeviceVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub build: u8,
+}
 
 impl InitCommandResponse {
-    pub fn new(nonce: [u8; 8], channel_id: u32) -> Self {
+    /// Creates a response to CTAPHID_INIT with the given capability flags. `CBOR` is always
+    /// advertised.
+    pub fn new(nonce: [u8; 8], channel_id: u32, version: DeviceVersion, capabilities: u8) -> Self {
         InitCommandResponse {
             nonce,
             channel_id: channel_id.into(),
             ctaphid_version: 2,
-            major_device_version: 0,
-            minor_device_version: 0,
-            build_device_version: 0,
-            capabilities_flag: CAPABILITY_CBOR | CAPABILITY_NMSG,
+            major_device_version: version.major,
+            minor_device_version: version.minor,
+            build_device_version: version.build,
+            capabilities_flag: capabilities | CAPABILITY_CBOR,
         }
     }
 }
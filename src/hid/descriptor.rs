@@ -0,0 +1,62 @@
+use serde::Deserialize;
+
+use super::packet::HID_REPORT_SIZE;
+
+/// The name of the HID device, as seen by the host
+pub const DEVICE_NAME: &str = "Software CTAP2";
+
+pub const CTAPHID_VENDOR_ID: u16 = 1337;
+pub const CTAPHID_PRODUCT_ID: u16 = 1337;
+
+/// The bus a UHID device claims to be attached to. USB/IP devices are always on USB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HidBus {
+    Usb,
+    Bluetooth,
+}
+
+/// How the HID device identifies itself to the host
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct HidIdentity {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus: HidBus,
+}
+
+impl Default for HidIdentity {
+    fn default() -> Self {
+        HidIdentity {
+            name: DEVICE_NAME.to_owned(),
+            vendor_id: CTAPHID_VENDOR_ID,
+            product_id: CTAPHID_PRODUCT_ID,
+            // TODO: somehow Bus::USB is ignored by hidapi 'hid_enumerate' (used by google ctap2 test tool),
+            // but Bluetooth works
+            bus: HidBus::Bluetooth,
+        }
+    }
+}
+
+/// The HID report descriptor of a CTAP-HID device, see
+/// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#usb-discovery
+#[rustfmt::skip]
+pub static CTAP_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0xD0, 0xF1, // HID_UsagePage ( FIDO_USAGE_PAGE ),
+    0x09, 0x01, // HID_Usage ( FIDO_USAGE_CTAPHID ),
+    0xA1, 0x01, // HID_Collection ( HID_Application ),
+    0x09, 0x20, // HID_Usage ( FIDO_USAGE_DATA_IN ),
+    0x15, 0x00, // HID_LogicalMin ( 0 ),
+    0x26, 0xFF, 0x00, // HID_LogicalMaxS ( 0xff ),
+    0x75, 0x08, // HID_ReportSize ( 8 ),
+    0x95, HID_REPORT_SIZE, // HID_ReportCount ( HID_INPUT_REPORT_BYTES ),
+    0x81, 0x02, // HID_Input ( HID_Data | HID_Absolute | HID_Variable ),
+    0x09, 0x21, // HID_Usage ( FIDO_USAGE_DATA_OUT ),
+    0x15, 0x00, // HID_LogicalMin ( 0 ),
+    0x26, 0xFF, 0x00, // HID_LogicalMaxS ( 0xff ),
+    0x75, 0x08, // HID_ReportSize ( 8 ),
+    0x95, HID_REPORT_SIZE, // HID_ReportCount ( HID_OUTPUT_REPORT_BYTES ),
+    0x91, 0x02, // HID_Output ( HID_Data | HID_Absolute | HID_Variable ),
+    0xC0, // HID_EndCollection
+];
@@ -1,2 +1,3 @@
 pub(crate) mod device;
+pub(crate) mod privsep;
 pub(crate) mod uhid_transport;
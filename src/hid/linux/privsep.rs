@@ -0,0 +1,276 @@
+//! Privilege separation for UHID: opening `/dev/uhid` requires root, thus a minimal helper
+//! running as root creates the CTAP-HID device and passes the file descriptor over a Unix
+//! socket (SCM_RIGHTS) to the daemon. The daemon then parses CTAP messages and performs all
+//! cryptography as an unprivileged user, who also owns the credential store.
+
+use std::{
+    fs::File,
+    io, mem,
+    os::unix::{
+        fs::FileTypeExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use anyhow::anyhow;
+use tracing::{debug, error, info, warn};
+use uhid_virt::{create_uhid_device_file, CreateParams};
+
+use crate::hid::{descriptor::HidIdentity, socket_transport::bind_private};
+
+use super::device::ctaphid_create_params;
+
+/// The socket the helper listens on by default
+pub const DEFAULT_HELPER_SOCKET: &str = "/run/softauth-uhid.sock";
+
+/// Runs the helper, creating a UHID device for every connection of the allowed user (or
+/// root) to the socket, until the process is interrupted.
+///
+/// The device is created with the given identity on the UHID character device at
+/// `device_path`, or `/dev/uhid`.
+/// The socket is only accessible to the allowed user, whose credentials are checked again
+/// once connected.
+pub async fn run_helper(
+    socket_path: &Path,
+    allowed_uid: u32,
+    identity: &HidIdentity,
+    device_path: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    match std::fs::symlink_metadata(socket_path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(socket_path)?,
+        Ok(_) => {
+            return Err(anyhow!(
+                "{:?} already exists and isn't a socket",
+                socket_path
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = bind_private(socket_path)?;
+    std::os::unix::fs::chown(socket_path, Some(allowed_uid), None)?;
+    info!(?socket_path, allowed_uid, "UHID helper is listening");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let stream = stream.into_std()?;
+        let params = ctaphid_create_params(identity);
+        let device_path = device_path.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            serve_request(&stream, allowed_uid, params, device_path.as_deref())
+        })
+        .await?;
+        if let Err(e) = res {
+            error!("Couldn't pass a UHID device: {:?}", e);
+        }
+    }
+}
+
+fn serve_request(
+    stream: &UnixStream,
+    allowed_uid: u32,
+    params: CreateParams,
+    device_path: Option<&Path>,
+) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    let uid = peer_uid(stream)?;
+    if uid != allowed_uid && uid != 0 {
+        return Err(anyhow!("Refusing to pass a UHID device to user {}", uid));
+    }
+    let device = create_uhid_device_file(params, device_path)?;
+    send_fd(stream, device.as_raw_fd())?;
+    // the daemon's descriptor keeps the device alive once ours is closed
+    debug!(uid, "Passed a UHID device");
+    Ok(())
+}
+
+/// Obtains a UHID character device, on which the CTAP-HID device was created, from the
+/// helper listening at the given socket
+pub async fn receive_uhid_device(socket_path: &Path) -> anyhow::Result<File> {
+    let socket_path = socket_path.to_owned();
+    let fd = tokio::task::spawn_blocking(move || {
+        let stream = UnixStream::connect(&socket_path)?;
+        recv_fd(&stream)
+    })
+    .await?;
+    match fd {
+        Ok(fd) => Ok(File::from(fd)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(anyhow!(
+            "The UHID helper couldn't create the device, see its log"
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The uid of the process connected to the other end of the socket
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    // SAFETY: ucred is plain data, and getsockopt writes at most `len` bytes to it
+    unsafe {
+        let mut cred: libc::ucred = mem::zeroed();
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let res = libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        );
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(cred.uid)
+    }
+}
+
+/// A control message buffer holding a single file descriptor, suitably aligned for cmsghdr
+fn fd_control_buffer() -> Vec<u64> {
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+    vec![0u64; space.div_ceil(mem::size_of::<u64>())]
+}
+
+/// Sends a file descriptor along with a single byte, as SCM_RIGHTS can't be sent alone
+fn send_fd(stream: &UnixStream, fd: RawFd) -> io::Result<()> {
+    let payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut control = fd_control_buffer();
+    // SAFETY: the message points to live buffers for the duration of sendmsg, and the single
+    // control message fits the buffer, which is sized with CMSG_SPACE
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), fd);
+
+        if libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receives a file descriptor sent by [send_fd], which is closed on exec
+fn recv_fd(stream: &UnixStream) -> io::Result<OwnedFd> {
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+    let mut control = fd_control_buffer();
+    // SAFETY: the message points to live buffers for the duration of recvmsg, and the control
+    // message is only read once the kernel reported it as a complete SCM_RIGHTS message
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = (control.len() * mem::size_of::<u64>()) as _;
+
+        let received = libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if received == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if msg.msg_flags & libc::MSG_CTRUNC != 0
+            || cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            warn!("Received a message without a file descriptor");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected a file descriptor",
+            ));
+        }
+        let fd = ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>());
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Seek, Write},
+        os::unix::fs::MetadataExt,
+    };
+
+    use uhid_virt::UHID_EVENT_SIZE;
+
+    use crate::test_util::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn test_fd_passing() {
+        let dir = temp_dir();
+        let path = dir.path().join("fd-passing");
+        let file = File::create(&path).unwrap();
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        send_fd(&sender, file.as_raw_fd()).unwrap();
+        drop(file);
+
+        let mut received = File::from(recv_fd(&receiver).unwrap());
+        received.write_all(b"passed").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"passed");
+
+        drop(sender);
+        let err = recv_fd(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_helper() {
+        // a regular file stands in for the UHID character device
+        let dir = temp_dir();
+        let device_path = dir.path().join("uhid");
+        File::create(&device_path).unwrap();
+        let socket_path = dir.path().join("uhid-helper.sock");
+        // SAFETY: getuid can't fail
+        let uid = unsafe { libc::getuid() };
+        let helper = tokio::spawn({
+            let socket_path = socket_path.clone();
+            let device_path = device_path.clone();
+            async move {
+                run_helper(
+                    &socket_path,
+                    uid,
+                    &HidIdentity::default(),
+                    Some(device_path),
+                )
+                .await
+            }
+        });
+        while !socket_path.exists() {
+            tokio::task::yield_now().await;
+        }
+
+        let mut device = receive_uhid_device(&socket_path).await.unwrap();
+        let metadata = device.metadata().unwrap();
+        assert_eq!(
+            metadata.ino(),
+            std::fs::metadata(&device_path).unwrap().ino()
+        );
+        // the helper created the device
+        let mut event = Vec::new();
+        device.rewind().unwrap();
+        device.read_to_end(&mut event).unwrap();
+        assert_eq!(event.len(), UHID_EVENT_SIZE);
+
+        helper.abort();
+    }
+}
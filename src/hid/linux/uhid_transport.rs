@@ -1,120 +1,396 @@
-use std::{pin::Pin, task::Poll};
-
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tracing::{debug, error};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
 
-use uhid_virt::StreamError;
+use anyhow::anyhow;
+use tracing::{debug, error, warn};
 
-use uhid_virt::{OutputEvent, UHIDRead, UHIDWrite};
+use uhid_virt::{
+    AsyncUHIDDevice, DevFlags, InputEvent, OutputEvent, ReportType, StreamError, UHID_EVENT_SIZE,
+};
 
 use crate::hid::{
+    descriptor::HidIdentity,
     packet::HID_REPORT_SIZE,
-    transport::{HIDTransport, TransportError},
+    transport::{HIDTransport, HIDTransportEvent, TransportError},
 };
 
 use super::device::create_ctaphid_device;
 
+/// Error reported to the kernel for GET_REPORT/SET_REPORT requests we can't satisfy (EIO)
+const UHID_REPORT_ERROR: u16 = 5;
+
+/// The report number prepended to numbered input reports, as the CTAP-HID report descriptor
+/// doesn't declare report IDs
+const CTAPHID_REPORT_NUMBER: u8 = 0;
+
+/// A HID transport over a Linux UHID device, driven by the tokio reactor.
+///
+/// Closing the transport (as a sink) destroys the HID device, as does dropping it.
 pub struct LinuxUHIDTransport {
-    recv_read: UnboundedReceiver<Result<Vec<u8>, TransportError>>,
-    send_write: UnboundedSender<Vec<u8>>,
+    device: AsyncUHIDDevice,
+    /// An encoded input event which hasn't been written yet, at most one report is buffered
+    pending_write: Option<[u8; UHID_EVENT_SIZE]>,
+    events: OutputEventHandler,
+    destroyed: bool,
+}
+
+/// The state of a UHID device as set by the kernel's output events, which is kept apart
+/// from the device itself to be tested without one
+#[derive(Debug, Default)]
+struct OutputEventHandler {
+    /// Encoded replies to GET_REPORT/SET_REPORT requests which haven't been written yet
+    pending_replies: VecDeque<[u8; UHID_EVENT_SIZE]>,
+    /// Whether the kernel started the device (UHID_START) and hasn't stopped it since
+    started: bool,
+    output_reports_numbered: bool,
+    input_reports_numbered: bool,
 }
 
 impl LinuxUHIDTransport {
-    pub async fn new() -> anyhow::Result<Self> {
-        let (send_read, recv_read) = unbounded_channel::<Result<Vec<u8>, TransportError>>();
-        let (send_write, mut recv_write) = unbounded_channel::<Vec<u8>>();
-        let mut file_rh = create_ctaphid_device()?;
-        let mut file_wh = file_rh.try_clone()?;
-        let _read_jh = tokio::task::spawn_blocking(move || {
-            loop {
-                let event = file_rh.read_output_event();
-                match event {
-                    Ok(OutputEvent::Output { mut data }) => {
-                        // TODO: BUG: why do UHID output event come with an extra byte in the front?
-                        data.remove(0);
-                        if let Err(e) = send_read.send(Ok(data)) {
-                            error!("UHID reader is closing due to send error: {:?}", e);
-                            break;
-                        }
-                    }
-                    Ok(event) => {
-                        debug!(?event, "Got an OutputEvent which isn't Output, ignoring.");
-                    }
-                    Err(StreamError::Io(e)) => {
-                        send_read
-                            .send(Err(TransportError::IoError(e)))
-                            .unwrap_or_else(|_| error!("Couldn't send IO error to server"));
-                        break;
-                    }
-                    Err(StreamError::UnknownEventType(e)) => {
-                        error!("Received event of unknown type '{}', ignoring", e);
+    pub async fn new(identity: &HidIdentity) -> anyhow::Result<Self> {
+        Ok(Self::from_device(create_ctaphid_device(identity)?))
+    }
+
+    /// Drives a CTAP-HID device which was already created, see [super::privsep]
+    pub fn from_device(device: AsyncUHIDDevice) -> Self {
+        Self {
+            device,
+            pending_write: None,
+            events: OutputEventHandler::default(),
+            destroyed: false,
+        }
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), TransportError>> {
+        if let Some(event) = &self.pending_write {
+            ready!(self.device.poll_write_event(cx, event))?;
+            self.pending_write = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write_replies(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), TransportError>> {
+        while let Some(reply) = self.events.pending_replies.front() {
+            ready!(self.device.poll_write_event(cx, reply))?;
+            self.events.pending_replies.pop_front();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl OutputEventHandler {
+    /// Handles a single output event, returning the transport event it corresponds to (if any)
+    fn handle_output_event(&mut self, event: OutputEvent) -> Option<HIDTransportEvent> {
+        match event {
+            OutputEvent::Start { dev_flags } => {
+                debug!(?dev_flags, "UHID device was started");
+                self.started = true;
+                self.output_reports_numbered = dev_flags.contains(&DevFlags::OutputReportsNumbered);
+                self.input_reports_numbered = dev_flags.contains(&DevFlags::InputReportsNumbered);
+                None
+            }
+            OutputEvent::Stop => {
+                debug!("UHID device was stopped");
+                self.started = false;
+                None
+            }
+            OutputEvent::Open => {
+                debug!("UHID device was opened by the host");
+                Some(HIDTransportEvent::Opened)
+            }
+            OutputEvent::Close => {
+                debug!("UHID device was closed by the host");
+                Some(HIDTransportEvent::Closed)
+            }
+            OutputEvent::Output { data } => parse_output_report(data, self.output_reports_numbered)
+                .map(HIDTransportEvent::Report),
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type,
+            } => {
+                warn!(?report_number, ?report_type, "Rejecting GET_REPORT request");
+                self.pending_replies.push_back(
+                    InputEvent::GetReportReply {
+                        id,
+                        err: UHID_REPORT_ERROR,
+                        data: Vec::new(),
                     }
-                }
+                    .into(),
+                );
+                None
             }
-        });
-        let _write_jh = tokio::task::spawn_blocking(move || {
-            while let Some(data) = recv_write.blocking_recv() {
-                assert_eq!(
-                    data.len(),
-                    HID_REPORT_SIZE as usize,
-                    "Payload must fit HID Report size"
+            OutputEvent::SetReport {
+                id,
+                report_type: ReportType::Output,
+                data,
+                ..
+            } => {
+                // some hosts send output reports through SET_REPORT rather than the interrupt pipe
+                let report = parse_output_report(data, self.output_reports_numbered);
+                let err = if report.is_some() {
+                    0
+                } else {
+                    UHID_REPORT_ERROR
+                };
+                self.pending_replies
+                    .push_back(InputEvent::SetReportReply { id, err }.into());
+                report.map(HIDTransportEvent::Report)
+            }
+            OutputEvent::SetReport {
+                id,
+                report_number,
+                report_type,
+                ..
+            } => {
+                warn!(?report_number, ?report_type, "Rejecting SET_REPORT request");
+                self.pending_replies.push_back(
+                    InputEvent::SetReportReply {
+                        id,
+                        err: UHID_REPORT_ERROR,
+                    }
+                    .into(),
                 );
-                file_wh
-                    .write_input_event(&data)
-                    .expect("Write task couldn't write input event");
+                None
             }
-            file_wh
-                .write_destroy_event()
-                .expect("Write task couldn't write destroy event");
-        });
-        Ok(Self {
-            recv_read,
-            send_write,
-        })
+        }
+    }
+}
+
+/// Strips the report number from an output report if reports are numbered (per the
+/// UHID_START dev_flags), returning `None` if the report doesn't fit the CTAP-HID report
+/// size.
+fn parse_output_report(mut data: Vec<u8>, numbered: bool) -> Option<Vec<u8>> {
+    if numbered {
+        if data.is_empty() {
+            error!("Received an empty numbered output report, ignoring");
+            return None;
+        }
+        data.remove(0);
     }
+    if data.len() != HID_REPORT_SIZE as usize {
+        error!(
+            len = data.len(),
+            "Received an output report of invalid size, ignoring"
+        );
+        return None;
+    }
+    Some(data)
 }
 
 impl futures::Stream for LinuxUHIDTransport {
-    type Item = Result<Vec<u8>, TransportError>;
+    type Item = Result<HIDTransportEvent, TransportError>;
 
-    fn poll_next(
-        mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Self::Item>> {
-        self.recv_read.poll_recv(cx)
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            // replies are written first, as the kernel blocks the requesting process until then
+            if let Err(e) = ready!(self.poll_write_replies(cx)) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            match ready!(self.device.poll_read_output_event(cx)) {
+                Ok(event) => {
+                    if let Some(event) = self.events.handle_output_event(event) {
+                        return Poll::Ready(Some(Ok(event)));
+                    }
+                }
+                Err(StreamError::Io(e)) => return Poll::Ready(Some(Err(e.into()))),
+                Err(StreamError::UnknownEventType(e)) => {
+                    error!("Received event of unknown type '{}', ignoring", e);
+                }
+            }
+        }
     }
 }
 
 impl futures::Sink<Vec<u8>> for LinuxUHIDTransport {
     type Error = TransportError;
 
-    fn poll_ready(
-        self: Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_write_pending(cx)
     }
 
-    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
-        self.send_write.send(item).map_err(|_| {
-            TransportError::OtherError(anyhow::anyhow!("Couldn't queue message to be sent"))
-        })?;
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        if item.len() != HID_REPORT_SIZE as usize {
+            return Err(anyhow!(
+                "Input report of {} bytes doesn't fit the HID report size",
+                item.len()
+            )
+            .into());
+        }
+        if self.destroyed {
+            return Err(anyhow!("UHID device was already destroyed").into());
+        }
+        if !self.events.started {
+            warn!("Dropping an input report, as the UHID device isn't started");
+            return Ok(());
+        }
+        assert!(
+            self.pending_write.is_none(),
+            "start_send must be preceded by a successful poll_ready"
+        );
+        let mut data = Vec::with_capacity(item.len() + 1);
+        if self.events.input_reports_numbered {
+            data.push(CTAPHID_REPORT_NUMBER);
+        }
+        data.extend_from_slice(&item);
+        self.pending_write = Some(InputEvent::Input { data: &data }.into());
         Ok(())
     }
 
-    fn poll_flush(
-        self: Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_write_pending(cx)
     }
 
-    fn poll_close(
-        self: Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), Self::Error>> {
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_write_pending(cx))?;
+        if !self.destroyed {
+            let destroy: [u8; UHID_EVENT_SIZE] = InputEvent::Destroy.into();
+            ready!(self.device.poll_write_event(cx, &destroy))?;
+            debug!("Destroyed UHID device");
+            self.destroyed = true;
+        }
         Poll::Ready(Ok(()))
     }
 }
 
 impl HIDTransport for LinuxUHIDTransport {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output_report() {
+        let report = vec![7u8; HID_REPORT_SIZE as usize];
+        let mut prefixed = vec![0u8];
+        prefixed.extend_from_slice(&report);
+
+        assert_eq!(
+            parse_output_report(report.clone(), false),
+            Some(report.clone())
+        );
+        // unnumbered reports are never prefixed by the kernel
+        assert_eq!(parse_output_report(prefixed.clone(), false), None);
+        assert_eq!(parse_output_report(prefixed, true), Some(report.clone()));
+        assert_eq!(parse_output_report(report[1..].to_vec(), false), None);
+        assert_eq!(parse_output_report(report, true), None);
+        assert_eq!(parse_output_report(Vec::new(), true), None);
+    }
+
+    fn replies(handler: &mut OutputEventHandler) -> Vec<[u8; UHID_EVENT_SIZE]> {
+        handler.pending_replies.drain(..).collect()
+    }
+
+    #[test]
+    fn test_start_and_stop() {
+        let mut handler = OutputEventHandler::default();
+        let event = handler.handle_output_event(OutputEvent::Start {
+            dev_flags: vec![DevFlags::OutputReportsNumbered],
+        });
+        assert!(event.is_none());
+        assert!(handler.started);
+        assert!(handler.output_reports_numbered);
+        assert!(!handler.input_reports_numbered);
+
+        let report = vec![7u8; HID_REPORT_SIZE as usize];
+        let mut prefixed = vec![CTAPHID_REPORT_NUMBER];
+        prefixed.extend_from_slice(&report);
+        assert!(matches!(
+            handler.handle_output_event(OutputEvent::Output { data: prefixed }),
+            Some(HIDTransportEvent::Report(data)) if data == report
+        ));
+
+        assert!(handler.handle_output_event(OutputEvent::Stop).is_none());
+        assert!(!handler.started);
+
+        // the flags are reset upon restarting
+        handler.handle_output_event(OutputEvent::Start {
+            dev_flags: vec![DevFlags::InputReportsNumbered],
+        });
+        assert!(handler.started);
+        assert!(!handler.output_reports_numbered);
+        assert!(handler.input_reports_numbered);
+        assert!(matches!(
+            handler.handle_output_event(OutputEvent::Output {
+                data: report.clone()
+            }),
+            Some(HIDTransportEvent::Report(data)) if data == report
+        ));
+        assert!(replies(&mut handler).is_empty());
+    }
+
+    #[test]
+    fn test_open_and_close() {
+        let mut handler = OutputEventHandler::default();
+        assert!(matches!(
+            handler.handle_output_event(OutputEvent::Open),
+            Some(HIDTransportEvent::Opened)
+        ));
+        assert!(matches!(
+            handler.handle_output_event(OutputEvent::Close),
+            Some(HIDTransportEvent::Closed)
+        ));
+        assert!(replies(&mut handler).is_empty());
+    }
+
+    #[test]
+    fn test_get_report_is_rejected() {
+        let mut handler = OutputEventHandler::default();
+        let event = handler.handle_output_event(OutputEvent::GetReport {
+            id: 3,
+            report_number: 0,
+            report_type: ReportType::Input,
+        });
+        assert!(event.is_none());
+        let reply: [u8; UHID_EVENT_SIZE] = InputEvent::GetReportReply {
+            id: 3,
+            err: UHID_REPORT_ERROR,
+            data: Vec::new(),
+        }
+        .into();
+        assert_eq!(replies(&mut handler), vec![reply]);
+    }
+
+    #[test]
+    fn test_set_report() {
+        let mut handler = OutputEventHandler::default();
+        let report = vec![7u8; HID_REPORT_SIZE as usize];
+
+        // output reports sent through SET_REPORT are received like interrupt ones
+        let event = handler.handle_output_event(OutputEvent::SetReport {
+            id: 1,
+            report_number: 0,
+            report_type: ReportType::Output,
+            data: report.clone(),
+        });
+        assert!(matches!(event, Some(HIDTransportEvent::Report(data)) if data == report));
+        let event = handler.handle_output_event(OutputEvent::SetReport {
+            id: 2,
+            report_number: 0,
+            report_type: ReportType::Output,
+            data: report[1..].to_vec(),
+        });
+        assert!(event.is_none());
+        let event = handler.handle_output_event(OutputEvent::SetReport {
+            id: 3,
+            report_number: 0,
+            report_type: ReportType::Feature,
+            data: report,
+        });
+        assert!(event.is_none());
+
+        let reply =
+            |id, err| -> [u8; UHID_EVENT_SIZE] { InputEvent::SetReportReply { id, err }.into() };
+        assert_eq!(
+            replies(&mut handler),
+            vec![
+                reply(1, 0),
+                reply(2, UHID_REPORT_ERROR),
+                reply(3, UHID_REPORT_ERROR)
+            ]
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use bytes::BytesMut;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::{
+    packet::{Message, MessageEncoder, Packet, HID_REPORT_SIZE},
+    reassembly::{Fragment, MessageReassembler, ReassemblyConfig},
+    transport::{HIDTransport, HIDTransportEvent, TransportError},
+};
+
+/// How long the host waits for a report before failing the test
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Creates an in-memory transport connected to a simulated host, so that the whole
+/// server can be exercised by tests.
+pub fn loopback() -> (LoopbackTransport, LoopbackHost) {
+    let (event_send, event_recv) = mpsc::unbounded_channel();
+    let (report_send, report_recv) = mpsc::unbounded_channel();
+    let transport = LoopbackTransport {
+        events: event_recv,
+        reports: report_send,
+    };
+    let host = LoopbackHost {
+        events: event_send,
+        reports: report_recv,
+        encoder: MessageEncoder::new(),
+        reassembler: MessageReassembler::new(ReassemblyConfig::default()),
+    };
+    (transport, host)
+}
+
+/// The authenticator's end of a [loopback], to be driven by a
+/// [CTAPServer](super::server::CTAPServer)
+pub struct LoopbackTransport {
+    events: UnboundedReceiver<Result<HIDTransportEvent, TransportError>>,
+    reports: UnboundedSender<Vec<u8>>,
+}
+
+impl futures::Stream for LoopbackTransport {
+    type Item = Result<HIDTransportEvent, TransportError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl futures::Sink<Vec<u8>> for LoopbackTransport {
+    type Error = TransportError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        assert_eq!(item.len(), HID_REPORT_SIZE as usize, "Invalid input report");
+        self.reports
+            .send(item)
+            .map_err(|_| anyhow!("Loopback host was dropped").into())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl HIDTransport for LoopbackTransport {}
+
+/// The host's end of a [loopback], sending output reports and receiving input reports
+pub struct LoopbackHost {
+    events: UnboundedSender<Result<HIDTransportEvent, TransportError>>,
+    reports: UnboundedReceiver<Vec<u8>>,
+    encoder: MessageEncoder,
+    reassembler: MessageReassembler,
+}
+
+impl LoopbackHost {
+    pub fn send_report(&self, report: Vec<u8>) {
+        assert_eq!(
+            report.len(),
+            HID_REPORT_SIZE as usize,
+            "Invalid output report"
+        );
+        self.send_event(HIDTransportEvent::Report(report));
+    }
+
+    /// Simulates the host closing the device
+    pub fn close(&self) {
+        self.send_event(HIDTransportEvent::Closed);
+    }
+
+    /// Simulates the transport failing, e.g, the device disappearing
+    pub fn fail(&self, error: TransportError) {
+        self.events
+            .send(Err(error))
+            .expect("Loopback transport was dropped");
+    }
+
+    fn send_event(&self, event: HIDTransportEvent) {
+        self.events
+            .send(Ok(event))
+            .expect("Loopback transport was dropped");
+    }
+
+    /// Receives the next input report, panicking if none arrives in time
+    pub async fn recv_report(&mut self) -> Vec<u8> {
+        tokio::time::timeout(RECV_TIMEOUT, self.reports.recv())
+            .await
+            .expect("Timed out waiting for an input report")
+            .expect("Loopback transport was dropped")
+    }
+
+    /// Fragments a message into output reports and sends them
+    pub fn send_message(&self, message: &Message) {
+        let mut buf = BytesMut::new();
+        self.encoder.encode_message(message, &mut buf).unwrap();
+        for report in buf.chunks_exact(HID_REPORT_SIZE as usize) {
+            self.send_report(report.to_vec());
+        }
+    }
+
+    /// Receives input reports until a complete message is reassembled
+    pub async fn recv_message(&mut self) -> Message {
+        loop {
+            let report = self.recv_report().await;
+            let packet = Packet::from_report(&report[..]);
+            if let Some(message) = self
+                .reassembler
+                .add_fragment(Fragment::from(&packet))
+                .unwrap()
+            {
+                return message;
+            }
+        }
+    }
+}
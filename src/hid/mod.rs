@@ -1,7 +1,15 @@
 pub(crate) mod channel;
 pub(crate) mod command;
+pub(crate) mod descriptor;
 pub(crate) mod linux;
+#[cfg(test)]
+pub(crate) mod loopback;
 pub(crate) mod packet;
 pub(crate) mod packet_processing;
+pub(crate) mod reassembly;
 pub(crate) mod server;
+pub(crate) mod socket_transport;
+pub(crate) mod supervisor;
 pub(crate) mod transport;
+pub(crate) mod usbip;
+pub(crate) mod vendor;
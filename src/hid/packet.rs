@@ -1,5 +1,3 @@
-use std::collections::{hash_map::Entry, HashMap};
-
 use super::{
     channel::BROADCAST_CHANNEL,
     command::{CommandType, ErrorCode, InvalidCommandType},
@@ -7,7 +5,7 @@ use super::{
 use bytes::BufMut;
 
 use thiserror::Error;
-use tokio_util::codec::{Decoder, Encoder};
+use tokio_util::codec::Encoder;
 
 use zerocopy::{AsBytes, BigEndian, ByteSlice, FromBytes, LayoutVerified, Unaligned, U16, U32};
 
@@ -22,11 +20,11 @@ const INIT_PACKET_PAYLOAD_SIZE: usize = HID_REPORT_SIZE as usize - 7;
 const CONT_PACKET_PAYLOAD_SIZE: usize = HID_REPORT_SIZE as usize - 5;
 
 /// Maximal payload size(bytes) of a CTAP-HID message
-const MAX_MESSAGE_PAYLOAD_SIZE: usize =
+pub const MAX_MESSAGE_PAYLOAD_SIZE: usize =
     INIT_PACKET_PAYLOAD_SIZE + CONT_PACKET_PAYLOAD_SIZE * (MAX_SEQ_NUM as usize + 1);
 
-/// The max amount of packets belonging to a single CTAP-HID message.
-const MAX_SEQ_NUM: u8 = 0x7f;
+/// The maximal sequence number of a CTAP-HID continuation packet
+pub const MAX_SEQ_NUM: u8 = 0x7f;
 
 #[repr(C)]
 #[derive(FromBytes, AsBytes, Unaligned, Debug)]
@@ -90,120 +88,6 @@ pub struct Message {
     pub payload: Vec<u8>,
 }
 
-/// State used for parsing a single CTAP-HID message.
-#[derive(Debug)]
-pub struct ChannelParseState {
-    wip_message: Option<Message>,
-    channel_identifier: u32,
-    remaining_payload_length: usize,
-    next_seq_num: u8,
-}
-
-impl ChannelParseState {
-    /// Initializes the parse state with an initialization packet. Returns an
-    /// error if the packet is malformed.
-    pub fn new(init: &InitializationPacket) -> Result<Self, MessageDecodeError> {
-        let channel_identifier = init.channel_identifier.get();
-        let command = CommandType::from_packet_command_identifier(init.command_identifier);
-        if init.payload_length.get() as usize > MAX_MESSAGE_PAYLOAD_SIZE {
-            return Err(MessageDecodeError::InvalidPayloadLength {
-                chan: channel_identifier,
-                invalid_len: init.payload_length.get(),
-            });
-        }
-
-        let total_payload_length = init.payload_length.get() as usize;
-        let mut payload = Vec::new();
-        payload.reserve_exact(total_payload_length);
-
-        let bytes_to_take = init.data.len().min(total_payload_length);
-        payload.extend_from_slice(&init.data[..bytes_to_take]);
-        let remaining_payload_length = total_payload_length - bytes_to_take;
-
-        let wip_message = Message {
-            channel_identifier,
-            command,
-            payload,
-        };
-        Ok(ChannelParseState {
-            channel_identifier: wip_message.channel_identifier,
-            wip_message: Some(wip_message),
-            remaining_payload_length,
-            next_seq_num: 0,
-        })
-    }
-
-    pub fn is_finished(&self) -> bool {
-        self.remaining_payload_length == 0
-    }
-
-    /// Tries to finish parsing and return the finally assembled message.
-    pub fn try_finish(&mut self) -> Option<Message> {
-        if !self.is_finished() {
-            return None;
-        }
-        self.wip_message.take()
-    }
-
-    /// Advances the parser by adding a continuation packet, assumed to
-    /// belong to the same channel as the initialization packet. An error
-    /// is returned if the continuation packet is malformed or unexpected.
-    pub fn add_continuation_packet(
-        &mut self,
-        cont: &ContinuationPacket,
-    ) -> Result<(), MessageDecodeError> {
-        assert_eq!(
-            cont.channel_identifier.get(),
-            self.channel_identifier,
-            "Got ContinuationPacket with wrong channel ID"
-        );
-        if self.is_finished() || self.wip_message.is_none() {
-            return Err(MessageDecodeError::UnexpectedCont {
-                chan: self.channel_identifier,
-            });
-        }
-        if cont.packet_sequence != self.next_seq_num {
-            return Err(MessageDecodeError::UnexpectedSeq {
-                expected: self.next_seq_num,
-                gotten: cont.packet_sequence,
-                chan: self.channel_identifier,
-            });
-        }
-        if cont.packet_sequence > MAX_SEQ_NUM {
-            // impossible because if seq is at 0x80 or above,
-            // we would've parsed an initialization packet instead.
-            panic!("Impossible state - got too many continuation packets");
-        }
-        let bytes_to_take = self.remaining_payload_length.min(cont.data.len());
-        self.wip_message
-            .as_mut()
-            .unwrap()
-            .payload
-            .extend_from_slice(&cont.data[..bytes_to_take]);
-        self.remaining_payload_length -= bytes_to_take;
-        self.next_seq_num += 1;
-        Ok(())
-    }
-}
-
-#[allow(dead_code)] // TODO: decide whether to use tokio framing or my custom packet_processing
-/// Responsible for decoding HID packets. Packets might arrive from multiple
-/// channels and will be parsed independently of each other(no channel locking is required yet),
-/// but are assumed to arrive in-order within each channel.
-pub struct MessageDecoder {
-    // maps each channel id to state of message being parsed at that channel.
-    chan_packets: HashMap<u32, ChannelParseState>,
-}
-
-impl MessageDecoder {
-    #[allow(dead_code)]
-    pub fn new() -> Self {
-        MessageDecoder {
-            chan_packets: HashMap::new(),
-        }
-    }
-}
-
 #[derive(Debug, Error)]
 pub enum MessageDecodeError {
     #[error("[chan {chan}] Expected a continuation packet with seq {expected}, got {gotten}")]
@@ -218,6 +102,9 @@ pub enum MessageDecodeError {
     #[error("[chan {chan}] Got a packet whose payload length {invalid_len} is invalid.")]
     InvalidPayloadLength { chan: u32, invalid_len: u16 },
 
+    #[error("[chan {chan}] Too many messages are being reassembled in parallel.")]
+    TooManyChannels { chan: u32 },
+
     #[error("[chan {chan}] Got an invalid command: {reason}")]
     InvalidCommand {
         chan: u32,
@@ -238,6 +125,7 @@ impl MessageDecodeError {
             MessageDecodeError::UnexpectedInit { chan, .. } => *chan,
             MessageDecodeError::UnexpectedCont { chan } => *chan,
             MessageDecodeError::InvalidPayloadLength { chan, .. } => *chan,
+            MessageDecodeError::TooManyChannels { chan } => *chan,
             MessageDecodeError::InvalidParameter { chan, .. } => *chan,
             MessageDecodeError::InvalidCommand { chan, .. } => *chan,
             MessageDecodeError::IoError(..) => BROADCAST_CHANNEL,
@@ -252,6 +140,7 @@ impl From<MessageDecodeError> for ErrorCode {
             MessageDecodeError::UnexpectedInit { .. } => ErrorCode::Other,
             MessageDecodeError::UnexpectedCont { .. } => ErrorCode::Other,
             MessageDecodeError::InvalidPayloadLength { .. } => ErrorCode::InvalidLen,
+            MessageDecodeError::TooManyChannels { .. } => ErrorCode::ChannelBusy,
             MessageDecodeError::InvalidCommand { .. } => ErrorCode::InvalidCmd,
             MessageDecodeError::InvalidParameter { .. } => ErrorCode::InvalidPar,
             MessageDecodeError::IoError(..) => ErrorCode::Other,
@@ -267,130 +156,6 @@ impl From<MessageDecodeError> for Message {
     }
 }
 
-impl MessageDecoder {
-    #[allow(dead_code)]
-    /// Resets a channel's parse state, must be invoked after encountering a decode error belonging to a channel.
-    pub fn reset_channel(&mut self, chan: u32) {
-        self.chan_packets.remove(&chan);
-    }
-
-    /// Decodes a new CTAP HID report, updating the state of the decoder, and may also return the final
-    /// decoded message if this was the last packet for said message.
-    pub fn decode_packet<B: AsRef<[u8]>>(
-        &mut self,
-        report: B,
-    ) -> Result<Option<Message>, MessageDecodeError> {
-        let report = report.as_ref();
-        assert_eq!(
-            report.len(),
-            HID_REPORT_SIZE as usize,
-            "Buffer size doesn't match expected HID REPORT SIZE"
-        );
-        if report[4] & 0x80 != 0 {
-            self.decode_initialization_packet(report)
-        } else {
-            self.decode_continuation_packet(report)
-        }
-    }
-
-    /// Tries decoding a new initialization packet. On decode success, might
-    /// return the message if it was entirely contained in the packet's payload.
-    fn decode_initialization_packet<B: AsRef<[u8]>>(
-        &mut self,
-        report: B,
-    ) -> Result<Option<Message>, MessageDecodeError> {
-        let report = report.as_ref();
-        assert_eq!(
-            report.len(),
-            HID_REPORT_SIZE as usize,
-            "Buffer size doesn't match expected HID REPORT SIZE"
-        );
-        assert_ne!(
-            report[4] & 0x80,
-            0,
-            "MSB of 4th byte must be set in an initialization packet"
-        );
-        let packet = LayoutVerified::<&[u8], InitializationPacket>::new_unaligned(report).unwrap();
-        match self.chan_packets.entry(packet.channel_identifier.get()) {
-            Entry::Occupied(ent) => {
-                return Err(MessageDecodeError::UnexpectedInit {
-                    expected_seq: ent.get().next_seq_num,
-                    chan: packet.channel_identifier.get(),
-                })
-            }
-            Entry::Vacant(ent) => {
-                let mut parse_state = ChannelParseState::new(&packet)?;
-                match parse_state.try_finish() {
-                    Some(message) => return Ok(Some(message)),
-                    None => {
-                        ent.insert(parse_state);
-                    }
-                }
-            }
-        }
-        Ok(None)
-    }
-
-    /// Tries decoding a continuation packet. On decode success, if it was
-    /// the final packet of a message - returns that message.
-    fn decode_continuation_packet<B: AsRef<[u8]>>(
-        &mut self,
-        report: B,
-    ) -> Result<Option<Message>, MessageDecodeError> {
-        let report = report.as_ref();
-        assert_eq!(
-            report.len(),
-            HID_REPORT_SIZE as usize,
-            "Buffer size doesn't match expected HID REPORT SIZE"
-        );
-        assert_eq!(
-            report[4] & 0x80,
-            0,
-            "MSB of 4th byte must not be set in a continuation packet"
-        );
-        let packet = LayoutVerified::<&[u8], ContinuationPacket>::new_unaligned(report).unwrap();
-        match self.chan_packets.entry(packet.channel_identifier.get()) {
-            Entry::Occupied(mut ent) => {
-                let parse_state = ent.get_mut();
-                parse_state.add_continuation_packet(&packet)?;
-                if parse_state.is_finished() {
-                    let (_, mut parse_state) = ent.remove_entry();
-                    return Ok(Some(parse_state.try_finish().unwrap()));
-                }
-            }
-            Entry::Vacant(_) => {
-                return Err(MessageDecodeError::UnexpectedCont {
-                    chan: packet.channel_identifier.get(),
-                })
-            }
-        }
-        Ok(None)
-    }
-}
-
-impl Decoder for MessageDecoder {
-    type Item = Message;
-
-    type Error = MessageDecodeError;
-
-    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < HID_REPORT_SIZE as usize {
-            return Ok(None);
-        }
-        // Try to decode as many packets as possible in the buffer,
-        // stopping once we get at least one complete message or
-        // run out of input.
-        while src.len() >= HID_REPORT_SIZE as usize {
-            let report = src.split_to(HID_REPORT_SIZE as usize);
-            let maybe_message = self.decode_packet(report)?;
-            if maybe_message.is_some() {
-                return Ok(maybe_message);
-            }
-        }
-        Ok(None)
-    }
-}
-
 #[derive(Debug, Error)]
 pub enum MessageEncoderError {
     #[error("Got IO error while encoding message: {0}")]
@@ -484,6 +249,19 @@ impl Encoder<Message> for MessageEncoder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hid::reassembly::{Fragment, MessageReassembler, ReassemblyConfig};
+
+    /// Reassembles the messages encoded in `buf`
+    fn reassemble(buf: &[u8]) -> Vec<Message> {
+        let mut reassembler =
+            MessageReassembler::new(ReassemblyConfig::hid(MAX_MESSAGE_PAYLOAD_SIZE));
+        buf.chunks_exact(HID_REPORT_SIZE as usize)
+            .filter_map(|report| {
+                let packet = Packet::from_report(report);
+                reassembler.add_fragment(Fragment::from(&packet)).unwrap()
+            })
+            .collect()
+    }
 
     fn split_to_vecs(d: &[u8]) -> Vec<Vec<u8>> {
         let (first, rest) = split_to_packet_payloads(d);
@@ -554,17 +332,11 @@ mod tests {
 
         let mut buf = bytes::BytesMut::new();
         let mut encoder = MessageEncoder::new();
-        let mut decoder = MessageDecoder::new();
 
         encoder.encode(msg.clone(), &mut buf).unwrap();
         assert_eq!(buf.len(), HID_REPORT_SIZE as usize);
 
-        let res = decoder.decode(&mut buf).unwrap();
-
-        assert_eq!(res, Some(msg));
-
-        let res_2 = decoder.decode(&mut buf).unwrap();
-        assert_eq!(res_2, None);
+        assert_eq!(reassemble(&buf), vec![msg]);
     }
 
     #[test]
@@ -577,16 +349,10 @@ mod tests {
 
         let mut buf = bytes::BytesMut::new();
         let mut encoder = MessageEncoder::new();
-        let mut decoder = MessageDecoder::new();
 
         encoder.encode(msg.clone(), &mut buf).unwrap();
         assert_eq!(buf.len(), HID_REPORT_SIZE as usize * 4);
 
-        let res = decoder.decode(&mut buf).unwrap();
-
-        assert_eq!(res, Some(msg));
-
-        let res_2 = decoder.decode(&mut buf).unwrap();
-        assert_eq!(res_2, None);
+        assert_eq!(reassemble(&buf), vec![msg]);
     }
 }
@@ -1,36 +1,48 @@
-use tracing::{debug_span, error, instrument, trace, warn};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, debug_span, error, instrument, trace, warn};
 use zerocopy::{AsBytes, LayoutVerified};
 
 use crate::hid::{
     channel::{BROADCAST_CHANNEL, RESERVED_CHANNEL},
-    command::{CommandType, InitCommandResponse, InvalidCommandType},
+    command::{
+        CommandType, DeviceVersion, InitCommandResponse, InvalidCommandType, CAPABILITY_NMSG,
+        CAPABILITY_WINK,
+    },
     server::ServerError,
 };
 
 use super::{
     channel::ChannelAllocator,
     command::InitCommand,
-    packet::{ChannelParseState, InitializationPacket, Message, MessageDecodeError, Packet},
+    packet::{Message, MessageDecodeError, Packet},
+    reassembly::{Fragment, MessageReassembler, ReassemblyConfig},
 };
 
+/// The maximal duration of a channel lock, see
+/// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#usb-hid-lock
+const MAX_LOCK_SECONDS: u8 = 10;
+
 /// Handles logic of CTAP-HID packet processing in a synchronous manner:
 /// - Allocating channels upon beginning a new transaction
-/// - Tracking packet parse state
-/// - Returning errors when given the wrong packet (unexpected or busy channel)
+/// - Reassembling messages of multiple channels (via [MessageReassembler])
+/// - Returning errors when given the wrong packet (unexpected, busy or locked channel)
 ///
 /// Does not handle timeouts, IO (includnig writing responses) or the actual logic of CTAP commands.
 pub struct PacketProcessing {
     chan_alloc: ChannelAllocator,
-    state: PacketProcessingState,
+    reassembler: MessageReassembler,
+    lock: Option<ChannelLock>,
+    u2f_enabled: bool,
+    wink_enabled: bool,
+    device_version: DeviceVersion,
 }
 
+/// An exclusive lock of the device by a single channel, acquired via CTAPHID_LOCK
 #[derive(Debug)]
-enum PacketProcessingState {
-    Idle,
-    Busy {
-        chan: u32,
-        decoder: ChannelParseState,
-    },
+struct ChannelLock {
+    chan: u32,
+    expires_at: Instant,
 }
 
 /// The result of processing a valid packet
@@ -43,13 +55,26 @@ pub enum PacketProcessingResult {
     /// for simple commands (like CTAPHID_INIT)
     ResponseReady(Message),
 
-    /// A CBOR request has been received, its handling
+    /// A CBOR or U2F request has been received, its handling
     /// should be delegated to another component.
     CTAP2Request(Message),
 
-    /// The current transaction has been aborted (no response
+    /// The transaction of the packet's channel has been aborted (no response
     /// message is to be sent)
     Aborted,
+
+    /// A CTAPHID_CANCEL was received on the given channel while no message was being
+    /// reassembled. The request the channel is processing, if any, should be cancelled.
+    /// The CANCEL itself isn't responded to.
+    Cancel(u32),
+
+    /// A CTAPHID_WINK was received, the user should be shown a visual cue
+    /// before the given response message is sent.
+    Wink(Message),
+
+    /// A vendor command has been received, its handling should be delegated
+    /// to the vendor command handlers.
+    VendorRequest(Message),
 }
 
 /// The result of a packet handler method in response to receiving a packet.
@@ -57,53 +82,83 @@ pub type HandlerResult = Result<PacketProcessingResult, ServerError>;
 
 impl PacketProcessing {
     pub fn new() -> Self {
+        Self::with_config(ReassemblyConfig::default())
+    }
+
+    pub fn with_config(config: ReassemblyConfig) -> Self {
         PacketProcessing {
             chan_alloc: ChannelAllocator::new(),
-            state: PacketProcessingState::Idle,
+            reassembler: MessageReassembler::new(config),
+            lock: None,
+            u2f_enabled: true,
+            wink_enabled: true,
+            device_version: DeviceVersion::default(),
         }
     }
 
-    pub fn is_busy(&self) -> bool {
-        matches!(self.state, PacketProcessingState::Busy { .. })
+    /// Whether U2F messages (CTAPHID_MSG) are accepted, enabled by default
+    pub fn set_u2f_enabled(&mut self, u2f_enabled: bool) {
+        self.u2f_enabled = u2f_enabled;
     }
 
-    pub fn abort_transaction(&mut self) {
-        if let PacketProcessingState::Busy { chan, .. } = &self.state {
-            warn!(?chan, "Aborted transaction");
-        } else {
-            warn!("Tried to abort a transaction while server is already idle")
+    /// Whether CTAPHID_WINK is supported, enabled by default
+    pub fn set_wink_enabled(&mut self, wink_enabled: bool) {
+        self.wink_enabled = wink_enabled;
+    }
+
+    /// The device version reported in response to CTAPHID_INIT, 0.0.0 by default
+    pub fn set_device_version(&mut self, device_version: DeviceVersion) {
+        self.device_version = device_version;
+    }
+
+    /// The capability flags reported in response to CTAPHID_INIT. `NMSG` is advertised
+    /// unless U2F messages (CTAPHID_MSG) are supported.
+    fn capabilities(&self) -> u8 {
+        let mut capabilities = 0;
+        if self.wink_enabled {
+            capabilities |= CAPABILITY_WINK;
         }
-        self.state = PacketProcessingState::Idle;
+        if !self.u2f_enabled {
+            capabilities |= CAPABILITY_NMSG;
+        }
+        capabilities
     }
 
-    pub fn begin_transaction(&mut self, init_packet: &InitializationPacket) -> HandlerResult {
-        assert!(!self.is_busy(), "Cannot begin transaction while busy");
-        let chan = init_packet.channel_identifier.get();
-        match ChannelParseState::new(init_packet) {
-            Ok(mut decoder) => {
-                if let Some(message) = decoder.try_finish() {
-                    self.state = PacketProcessingState::Busy { chan, decoder };
-                    let result = self.process_message(message);
-                    self.state = PacketProcessingState::Idle;
-                    result
-                } else {
-                    trace!("Got an initialization packet, waiting for more");
-                    assert!(
-                        chan != RESERVED_CHANNEL && chan != BROADCAST_CHANNEL,
-                        "Must not be broadcast"
-                    );
-                    self.state = PacketProcessingState::Busy { chan, decoder };
-                    Ok(PacketProcessingResult::WaitingForMorePackets)
-                }
-            }
-            Err(decode_err) => {
-                error!(
-                    ?decode_err,
-                    "Decode error while parsing initialization packet"
-                );
-                Err(decode_err.into())
+    /// Releases all allocated channels, aborting their transactions and releasing the
+    /// channel lock (if any). Should be invoked once the host closes the HID device.
+    pub fn release_all_channels(&mut self) {
+        self.reassembler.reset();
+        self.lock = None;
+        self.chan_alloc.release_all();
+    }
+
+    /// Returns the channel currently holding the lock, releasing the lock if it expired.
+    pub fn locked_channel(&mut self) -> Option<u32> {
+        if let Some(lock) = &self.lock {
+            if Instant::now() >= lock.expires_at {
+                debug!(chan = lock.chan, "Channel lock expired");
+                self.lock = None;
             }
         }
+        self.lock.as_ref().map(|lock| lock.chan)
+    }
+
+    /// Whether a message is being reassembled on the given channel
+    pub fn is_busy(&self, chan: u32) -> bool {
+        self.reassembler.is_reassembling(chan)
+    }
+
+    /// Aborts the transaction of the given channel, discarding any partially received message
+    pub fn abort_transaction(&mut self, chan: u32) {
+        if self.is_busy(chan) {
+            warn!(?chan, "Aborted transaction");
+        } else {
+            trace!(
+                ?chan,
+                "Tried to abort a transaction while channel is already idle"
+            )
+        }
+        self.reassembler.reset_channel(chan);
     }
 
     fn handle_init(&mut self, message: &Message) -> HandlerResult {
@@ -121,27 +176,78 @@ impl PacketProcessing {
             payload: Vec::new(),
         };
         if chan == BROADCAST_CHANNEL {
-            let new_cid = self.chan_alloc.allocate().ok_or_else(|| {
+            // evicting the channel holding the lock, or one in the middle of a transaction,
+            // would break its client
+            let locked = self.locked_channel();
+            let reassembler = &self.reassembler;
+            let can_evict = |chan| Some(chan) != locked && !reassembler.is_reassembling(chan);
+            let new_cid = self.chan_alloc.allocate(can_evict).ok_or_else(|| {
                 error!("Could not allocate a channel, server full");
                 ServerError::Other {
                     chan,
                     reason: "Could not allocate a channel".into(),
                 }
             })?;
-            ret_msg
-                .payload
-                .extend_from_slice(InitCommandResponse::new(msg.nonce, new_cid).as_bytes());
+            let response = InitCommandResponse::new(
+                msg.nonce,
+                new_cid,
+                self.device_version,
+                self.capabilities(),
+            );
+            ret_msg.payload.extend_from_slice(response.as_bytes());
             trace!(?new_cid, "Allocated new channel");
             Ok(PacketProcessingResult::ResponseReady(ret_msg))
         } else {
-            ret_msg
-                .payload
-                .extend_from_slice(InitCommandResponse::new(msg.nonce, chan).as_bytes());
-            self.abort_transaction();
+            let response =
+                InitCommandResponse::new(msg.nonce, chan, self.device_version, self.capabilities());
+            ret_msg.payload.extend_from_slice(response.as_bytes());
+            self.abort_transaction(chan);
             Ok(PacketProcessingResult::ResponseReady(ret_msg))
         }
     }
 
+    fn handle_lock(&mut self, message: &Message) -> HandlerResult {
+        let chan = message.channel_identifier;
+        if chan == BROADCAST_CHANNEL {
+            return Err(ServerError::InvalidChannel { chan });
+        }
+        let seconds = match message.payload.as_slice() {
+            [seconds] => *seconds,
+            _ => {
+                return Err(MessageDecodeError::InvalidPayloadLength {
+                    chan,
+                    invalid_len: message.payload.len() as u16,
+                }
+                .into())
+            }
+        };
+        if seconds > MAX_LOCK_SECONDS {
+            return Err(MessageDecodeError::InvalidParameter {
+                chan,
+                reason: format!(
+                    "Lock time of {} seconds exceeds maximum of {} seconds",
+                    seconds, MAX_LOCK_SECONDS
+                ),
+            }
+            .into());
+        }
+        if seconds == 0 {
+            debug!(?chan, "Releasing channel lock");
+            self.lock = None;
+        } else {
+            debug!(?chan, ?seconds, "Locking channel");
+            self.lock = Some(ChannelLock {
+                chan,
+                expires_at: Instant::now() + Duration::from_secs(seconds.into()),
+            });
+        }
+        Ok(PacketProcessingResult::ResponseReady(Message {
+            channel_identifier: chan,
+            command: message.command,
+            payload: Vec::new(),
+        }))
+    }
+
     #[instrument(skip(self, message), level = "debug")]
     pub fn process_message(&mut self, message: Message) -> HandlerResult {
         let chan = message.channel_identifier;
@@ -152,17 +258,28 @@ impl PacketProcessing {
         let _enter = span.enter();
         trace!(?command, "Processing message");
         match command {
-            CommandType::Msg => error!("TODO U2F message"),
+            CommandType::Msg if self.u2f_enabled => {
+                return Ok(PacketProcessingResult::CTAP2Request(message))
+            }
+            CommandType::Msg => error!("Received a U2F message while U2F is disabled"),
             CommandType::Cbor => return Ok(PacketProcessingResult::CTAP2Request(message)),
             CommandType::Init => return self.handle_init(&message),
             CommandType::Ping => return Ok(PacketProcessingResult::ResponseReady(message.clone())),
-            CommandType::Cancel => error!("TODO cancel"),
+            CommandType::Cancel => return Ok(PacketProcessingResult::Cancel(chan)),
             CommandType::Error => error!("Impossible - authenticator received an error message"),
             CommandType::Keepalive => {
                 error!("Impossible - authenticator received a keepalive message")
             }
-            CommandType::Wink => error!("TODO wink"),
-            CommandType::Lock => error!("LOCK unsupported"),
+            CommandType::Wink if self.wink_enabled => {
+                return Ok(PacketProcessingResult::Wink(Message {
+                    channel_identifier: chan,
+                    command: message.command,
+                    payload: Vec::new(),
+                }))
+            }
+            CommandType::Wink => error!("Received a wink while it is disabled"),
+            CommandType::Lock => return self.handle_lock(&message),
+            CommandType::Vendor(_) => return Ok(PacketProcessingResult::VendorRequest(message)),
         }
         Err(MessageDecodeError::InvalidCommand {
             chan,
@@ -185,77 +302,271 @@ impl PacketProcessing {
             return Err(ServerError::InvalidChannel { chan: new_chan });
         };
 
-        if new_chan != BROADCAST_CHANNEL && !self.chan_alloc.is_allocated(new_chan) {
+        if new_chan != BROADCAST_CHANNEL && !self.chan_alloc.touch(new_chan) {
             error!(?new_chan, "Received a non-allocated channel");
             return Err(ServerError::InvalidChannel { chan: new_chan });
         }
 
-        match (&mut self.state, packet) {
-            (PacketProcessingState::Busy { chan, .. }, _) if new_chan != *chan => {
+        if let Some(lock_chan) = self.locked_channel() {
+            if lock_chan != new_chan {
                 error!(
                     ?new_chan,
-                    cur_chan = chan,
-                    "Got packet from a conflicting channel"
+                    ?lock_chan,
+                    "Got packet while another channel holds the lock"
                 );
-                Err(ServerError::ChannelBusy {
-                    busy_chan: *chan,
+                return Err(ServerError::ChannelLocked {
+                    lock_chan,
                     new_chan,
-                })
+                });
             }
-            (PacketProcessingState::Busy { chan, decoder }, Packet::InitializationPacket(init)) => {
-                assert_eq!(new_chan, *chan, "Impossible");
-                if [Ok(CommandType::Init), Ok(CommandType::Cancel)]
-                    .contains(&init.get_command_type())
-                {
-                    // TODO: difference between abort and init
-                    self.abort_transaction();
-                    Ok(PacketProcessingResult::Aborted)
+        }
+
+        let fragment = Fragment::from(&packet);
+        if let Fragment::Initialization { command, .. } = fragment {
+            if self.is_busy(new_chan) {
+                if [Ok(CommandType::Init), Ok(CommandType::Cancel)].contains(&command) {
+                    // INIT re-synchronizes the channel, thus it is processed after aborting
+                    self.abort_transaction(new_chan);
+                    if command == Ok(CommandType::Cancel) {
+                        return Ok(PacketProcessingResult::Aborted);
+                    }
                 } else {
-                    error!(?decoder, "Received initialization packet that isn't INIT or CANCEL while busy, ignoring packet");
-                    Err(ServerError::ChannelBusy {
-                        busy_chan: *chan,
+                    error!("Received initialization packet that isn't INIT or CANCEL while busy, ignoring packet");
+                    return Err(ServerError::ChannelBusy {
+                        busy_chan: new_chan,
                         new_chan,
-                    })
+                    });
                 }
             }
-            (
-                PacketProcessingState::Busy {
-                    chan,
-                    ref mut decoder,
-                },
-                Packet::ContinuationPacket(cont),
-            ) => {
-                assert_eq!(new_chan, *chan, "Impossible");
-                match decoder.add_continuation_packet(&cont) {
-                    Ok(()) if decoder.is_finished() => {
-                        let message = decoder.try_finish().unwrap();
-                        self.process_message(message)
-                    }
-                    Ok(()) => {
-                        trace!(?decoder, "Got a continuation packet, waiting for more");
-                        Ok(PacketProcessingResult::WaitingForMorePackets)
-                    }
-                    Err(error) => {
-                        error!(
-                            ?error,
-                            ?decoder,
-                            "Error while processing continuation packet, aborting transaction"
-                        );
-                        self.abort_transaction();
-                        Err(error.into())
-                    }
-                }
+        }
+
+        match self.reassembler.add_fragment(fragment) {
+            Ok(Some(message)) => self.process_message(message),
+            Ok(None) if new_chan == BROADCAST_CHANNEL => {
+                error!("Received a multi-packet message on the broadcast channel");
+                self.abort_transaction(new_chan);
+                Err(ServerError::InvalidChannel { chan: new_chan })
             }
-            (PacketProcessingState::Idle, Packet::ContinuationPacket(_)) => {
-                error!(
-                    "Received a continuation packet while an initialization packet was expected"
-                );
-                Err(MessageDecodeError::UnexpectedCont { chan: new_chan }.into())
+            Ok(None) => {
+                trace!("Waiting for more packets");
+                Ok(PacketProcessingResult::WaitingForMorePackets)
             }
-            (PacketProcessingState::Idle, Packet::InitializationPacket(init)) => {
-                trace!("Received an initialization packet while idle, beginning new transaction");
-                self.begin_transaction(&*init)
+            Err(error) => {
+                error!(?error, "Error while reassembling a message");
+                Err(error.into())
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::hid::{
+        command::CAPABILITY_CBOR,
+        packet::{MessageEncoder, HID_REPORT_SIZE},
+    };
+
+    fn handle_message(logic: &mut PacketProcessing, message: Message) -> HandlerResult {
+        let mut buf = BytesMut::new();
+        MessageEncoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+        assert_eq!(buf.len(), HID_REPORT_SIZE as usize);
+        logic.handle_packet(Packet::from_report(&buf[..]))
+    }
+
+    fn encode_reports(message: Message) -> Vec<Vec<u8>> {
+        let mut buf = BytesMut::new();
+        MessageEncoder::new()
+            .encode_message(&message, &mut buf)
+            .unwrap();
+        buf.chunks_exact(HID_REPORT_SIZE as usize)
+            .map(|chunk| chunk.to_vec())
+            .collect()
+    }
+
+    fn init_message(chan: u32) -> Message {
+        Message {
+            channel_identifier: chan,
+            command: Ok(CommandType::Init),
+            payload: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        }
+    }
+
+    fn allocate_channel(logic: &mut PacketProcessing) -> u32 {
+        match handle_message(logic, init_message(BROADCAST_CHANNEL)) {
+            Ok(PacketProcessingResult::ResponseReady(res)) => {
+                let res =
+                    LayoutVerified::<_, InitCommandResponse>::new_unaligned(res.payload.as_ref())
+                        .unwrap();
+                res.channel_id.get()
+            }
+            other => panic!("Unexpected INIT result {:?}", other),
+        }
+    }
+
+    fn lock_message(chan: u32, seconds: u8) -> Message {
+        Message {
+            channel_identifier: chan,
+            command: Ok(CommandType::Lock),
+            payload: vec![seconds],
+        }
+    }
+
+    fn ping_message(chan: u32) -> Message {
+        Message {
+            channel_identifier: chan,
+            command: Ok(CommandType::Ping),
+            payload: vec![1, 3, 3, 7],
+        }
+    }
+
+    #[test]
+    fn test_lock_excludes_other_channels() {
+        let mut logic = PacketProcessing::new();
+        let chan_a = allocate_channel(&mut logic);
+        let chan_b = allocate_channel(&mut logic);
+
+        let res = handle_message(&mut logic, lock_message(chan_a, 5));
+        assert!(matches!(res, Ok(PacketProcessingResult::ResponseReady(_))));
+        assert_eq!(logic.locked_channel(), Some(chan_a));
+
+        let res = handle_message(&mut logic, ping_message(chan_b));
+        assert!(matches!(
+            res,
+            Err(ServerError::ChannelLocked { lock_chan, new_chan }) if lock_chan == chan_a && new_chan == chan_b
+        ));
+        let res = handle_message(&mut logic, ping_message(chan_a));
+        assert!(matches!(res, Ok(PacketProcessingResult::ResponseReady(_))));
+
+        let res = handle_message(&mut logic, lock_message(chan_a, 0));
+        assert!(matches!(res, Ok(PacketProcessingResult::ResponseReady(_))));
+        assert_eq!(logic.locked_channel(), None);
+        let res = handle_message(&mut logic, ping_message(chan_b));
+        assert!(matches!(res, Ok(PacketProcessingResult::ResponseReady(_))));
+    }
+
+    #[test]
+    fn test_lock_rejects_too_long_duration() {
+        let mut logic = PacketProcessing::new();
+        let chan = allocate_channel(&mut logic);
+        let res = handle_message(&mut logic, lock_message(chan, MAX_LOCK_SECONDS + 1));
+        assert!(matches!(
+            res,
+            Err(ServerError::MessageDecodeError(
+                MessageDecodeError::InvalidParameter { .. }
+            ))
+        ));
+        assert_eq!(logic.locked_channel(), None);
+    }
+
+    #[test]
+    fn test_interleaved_channels() {
+        let mut logic = PacketProcessing::new();
+        let chan_a = allocate_channel(&mut logic);
+        let chan_b = allocate_channel(&mut logic);
+        let long_ping = |chan| Message {
+            channel_identifier: chan,
+            command: Ok(CommandType::Ping),
+            payload: vec![chan as u8; 100],
+        };
+        let reports_a = encode_reports(long_ping(chan_a));
+        let reports_b = encode_reports(long_ping(chan_b));
+        assert_eq!(reports_a.len(), 2);
+
+        for report in [&reports_a[0], &reports_b[0]] {
+            let res = logic.handle_packet(Packet::from_report(&report[..]));
+            assert!(matches!(
+                res,
+                Ok(PacketProcessingResult::WaitingForMorePackets)
+            ));
+        }
+        assert!(logic.is_busy(chan_a) && logic.is_busy(chan_b));
+
+        for (chan, report) in [(chan_b, &reports_b[1]), (chan_a, &reports_a[1])] {
+            let res = logic.handle_packet(Packet::from_report(&report[..]));
+            assert!(
+                matches!(res, Ok(PacketProcessingResult::ResponseReady(res)) if res == long_ping(chan))
+            );
+            assert!(!logic.is_busy(chan));
+        }
+    }
+
+    #[test]
+    fn test_u2f_disabled() {
+        let mut logic = PacketProcessing::new();
+        let msg = |chan| Message {
+            channel_identifier: chan,
+            command: Ok(CommandType::Msg),
+            payload: vec![0, 3, 0, 0],
+        };
+        let chan = allocate_channel(&mut logic);
+        let res = handle_message(&mut logic, msg(chan));
+        assert!(matches!(res, Ok(PacketProcessingResult::CTAP2Request(_))));
+
+        logic.set_u2f_enabled(false);
+        let capabilities = match handle_message(&mut logic, init_message(chan)) {
+            Ok(PacketProcessingResult::ResponseReady(res)) => res.payload[16],
+            other => panic!("Unexpected INIT result {:?}", other),
+        };
+        assert_eq!(capabilities & CAPABILITY_NMSG, CAPABILITY_NMSG);
+        let res = handle_message(&mut logic, msg(chan));
+        assert!(matches!(
+            res,
+            Err(ServerError::MessageDecodeError(
+                MessageDecodeError::InvalidCommand { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_wink() {
+        let mut logic = PacketProcessing::new();
+        let chan = allocate_channel(&mut logic);
+        let wink = Message {
+            channel_identifier: chan,
+            command: Ok(CommandType::Wink),
+            payload: vec![],
+        };
+        let res = handle_message(&mut logic, wink.clone());
+        assert!(
+            matches!(res, Ok(PacketProcessingResult::Wink(res)) if res.channel_identifier == chan && res.payload.is_empty())
+        );
+
+        logic.set_wink_enabled(false);
+        let res = handle_message(&mut logic, wink);
+        assert!(matches!(
+            res,
+            Err(ServerError::MessageDecodeError(
+                MessageDecodeError::InvalidCommand { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_init_device_info() {
+        let mut logic = PacketProcessing::new();
+        let chan = allocate_channel(&mut logic);
+        let payload = |logic: &mut PacketProcessing| match handle_message(logic, init_message(chan))
+        {
+            Ok(PacketProcessingResult::ResponseReady(res)) => res.payload,
+            other => panic!("Unexpected INIT result {:?}", other),
+        };
+        let default = payload(&mut logic);
+        assert_eq!(&default[13..16], &[0, 0, 0]);
+        assert_eq!(default[16], CAPABILITY_WINK | CAPABILITY_CBOR);
+
+        logic.set_wink_enabled(false);
+        logic.set_device_version(DeviceVersion {
+            major: 5,
+            minor: 4,
+            build: 3,
+        });
+        let custom = payload(&mut logic);
+        assert_eq!(&custom[13..16], &[5, 4, 3]);
+        assert_eq!(custom[16], CAPABILITY_CBOR);
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use tracing::trace;
+use zerocopy::ByteSlice;
+
+use super::{
+    command::{CommandType, InvalidCommandType},
+    packet::{
+        ContinuationPacket, InitializationPacket, Message, MessageDecodeError, Packet,
+        MAX_MESSAGE_PAYLOAD_SIZE, MAX_SEQ_NUM,
+    },
+};
+
+/// Default maximal size(bytes) of a message the authenticator is willing to receive,
+/// advertised as `maxMsgSize` in authenticatorGetInfo.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1200;
+
+/// Default maximal number of channels whose messages may be reassembled in parallel.
+pub const DEFAULT_MAX_OPEN_CHANNELS: usize = 16;
+
+/// A fragment of a CTAP message, independent of the transport framing it arrived in
+/// (e.g, a CTAP-HID packet or a BLE fidoControlPoint write)
+#[derive(Debug, Clone, Copy)]
+pub enum Fragment<'a> {
+    /// The first fragment of a message
+    Initialization {
+        channel: u32,
+        command: Result<CommandType, InvalidCommandType>,
+        payload_length: usize,
+        data: &'a [u8],
+    },
+    /// Any further fragment of a message
+    Continuation {
+        channel: u32,
+        seq: u8,
+        data: &'a [u8],
+    },
+}
+
+impl<'a> From<&'a InitializationPacket> for Fragment<'a> {
+    fn from(init: &'a InitializationPacket) -> Self {
+        Fragment::Initialization {
+            channel: init.channel_identifier.get(),
+            command: init.get_command_type(),
+            payload_length: init.payload_length.get() as usize,
+            data: &init.data,
+        }
+    }
+}
+
+impl<'a> From<&'a ContinuationPacket> for Fragment<'a> {
+    fn from(cont: &'a ContinuationPacket) -> Self {
+        Fragment::Continuation {
+            channel: cont.channel_identifier.get(),
+            seq: cont.packet_sequence,
+            data: &cont.data,
+        }
+    }
+}
+
+impl<'a, B: ByteSlice> From<&'a Packet<B>> for Fragment<'a> {
+    fn from(packet: &'a Packet<B>) -> Self {
+        match packet {
+            Packet::InitializationPacket(init) => Fragment::from(&**init),
+            Packet::ContinuationPacket(cont) => Fragment::from(&**cont),
+        }
+    }
+}
+
+/// Limits imposed on message reassembly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReassemblyConfig {
+    /// Maximal payload size(bytes) of a reassembled message (`maxMsgSize`)
+    pub max_message_size: usize,
+
+    /// Maximal number of channels whose messages may be reassembled in parallel
+    pub max_open_channels: usize,
+
+    /// Maximal sequence number of a continuation fragment
+    pub max_seq: u8,
+
+    /// Whether the sequence number wraps around to 0 after `max_seq`, rather than limiting
+    /// the number of continuation fragments
+    pub wrap_seq: bool,
+}
+
+impl ReassemblyConfig {
+    /// Limits for CTAP-HID, where the message size is bounded by [MAX_MESSAGE_PAYLOAD_SIZE]
+    pub fn hid(max_message_size: usize) -> Self {
+        assert!(
+            max_message_size <= MAX_MESSAGE_PAYLOAD_SIZE,
+            "Maximal message size cannot exceed what is representable by CTAP-HID packets"
+        );
+        ReassemblyConfig {
+            max_message_size,
+            max_open_channels: DEFAULT_MAX_OPEN_CHANNELS,
+            max_seq: MAX_SEQ_NUM,
+            wrap_seq: false,
+        }
+    }
+
+    /// Limits for CTAP over BLE, which has no channels and whose sequence number wraps around
+    pub fn ble(max_message_size: usize) -> Self {
+        ReassemblyConfig {
+            max_message_size,
+            max_open_channels: 1,
+            max_seq: MAX_SEQ_NUM,
+            wrap_seq: true,
+        }
+    }
+}
+
+impl Default for ReassemblyConfig {
+    fn default() -> Self {
+        Self::hid(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+/// A message being reassembled on a particular channel
+#[derive(Debug)]
+struct PartialMessage {
+    message: Message,
+    remaining_payload_length: usize,
+    next_seq: u8,
+}
+
+impl PartialMessage {
+    fn is_finished(&self) -> bool {
+        self.remaining_payload_length == 0
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        let bytes_to_take = self.remaining_payload_length.min(data.len());
+        self.message
+            .payload
+            .extend_from_slice(&data[..bytes_to_take]);
+        self.remaining_payload_length -= bytes_to_take;
+    }
+}
+
+/// Reassembles messages from fragments. Fragments of different channels may be interleaved,
+/// in which case their messages are reassembled in parallel, but fragments are assumed to
+/// arrive in-order within each channel.
+///
+/// Once a fragment is rejected, the message being reassembled on its channel is discarded.
+#[derive(Debug)]
+pub struct MessageReassembler {
+    config: ReassemblyConfig,
+    channels: HashMap<u32, PartialMessage>,
+}
+
+impl MessageReassembler {
+    pub fn new(config: ReassemblyConfig) -> Self {
+        MessageReassembler {
+            config,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Whether a message is currently being reassembled on the given channel
+    pub fn is_reassembling(&self, chan: u32) -> bool {
+        self.channels.contains_key(&chan)
+    }
+
+    /// Discards the message being reassembled on the given channel (if any)
+    pub fn reset_channel(&mut self, chan: u32) {
+        self.channels.remove(&chan);
+    }
+
+    /// Discards all messages being reassembled
+    pub fn reset(&mut self) {
+        self.channels.clear();
+    }
+
+    /// Adds a fragment, returning the reassembled message if this was its last fragment.
+    pub fn add_fragment(
+        &mut self,
+        fragment: Fragment,
+    ) -> Result<Option<Message>, MessageDecodeError> {
+        match fragment {
+            Fragment::Initialization {
+                channel,
+                command,
+                payload_length,
+                data,
+            } => self.add_initialization(channel, command, payload_length, data),
+            Fragment::Continuation { channel, seq, data } => {
+                let res = self.add_continuation(channel, seq, data);
+                if res.is_err() {
+                    self.reset_channel(channel);
+                }
+                res
+            }
+        }
+    }
+
+    fn add_initialization(
+        &mut self,
+        chan: u32,
+        command: Result<CommandType, InvalidCommandType>,
+        payload_length: usize,
+        data: &[u8],
+    ) -> Result<Option<Message>, MessageDecodeError> {
+        if let Some(partial) = self.channels.get(&chan) {
+            return Err(MessageDecodeError::UnexpectedInit {
+                expected_seq: partial.next_seq,
+                chan,
+            });
+        }
+        if payload_length > self.config.max_message_size {
+            return Err(MessageDecodeError::InvalidPayloadLength {
+                chan,
+                invalid_len: payload_length.min(u16::MAX as usize) as u16,
+            });
+        }
+
+        let mut partial = PartialMessage {
+            message: Message {
+                channel_identifier: chan,
+                command,
+                payload: Vec::with_capacity(payload_length),
+            },
+            remaining_payload_length: payload_length,
+            next_seq: 0,
+        };
+        partial.append(data);
+        if partial.is_finished() {
+            return Ok(Some(partial.message));
+        }
+
+        if self.channels.len() >= self.config.max_open_channels {
+            return Err(MessageDecodeError::TooManyChannels { chan });
+        }
+        trace!(
+            ?chan,
+            remaining = partial.remaining_payload_length,
+            "Waiting for continuation fragments"
+        );
+        self.channels.insert(chan, partial);
+        Ok(None)
+    }
+
+    fn add_continuation(
+        &mut self,
+        chan: u32,
+        seq: u8,
+        data: &[u8],
+    ) -> Result<Option<Message>, MessageDecodeError> {
+        let mut entry = match self.channels.entry(chan) {
+            Entry::Occupied(entry) => entry,
+            Entry::Vacant(_) => return Err(MessageDecodeError::UnexpectedCont { chan }),
+        };
+        let partial = entry.get_mut();
+        if seq != partial.next_seq || seq > self.config.max_seq {
+            return Err(MessageDecodeError::UnexpectedSeq {
+                expected: partial.next_seq,
+                gotten: seq,
+                chan,
+            });
+        }
+        partial.append(data);
+        partial.next_seq = if partial.next_seq == self.config.max_seq && self.config.wrap_seq {
+            0
+        } else {
+            partial.next_seq.wrapping_add(1)
+        };
+        if partial.is_finished() {
+            return Ok(Some(entry.remove().message));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init(channel: u32, payload_length: usize, data: &[u8]) -> Fragment<'_> {
+        Fragment::Initialization {
+            channel,
+            command: Ok(CommandType::Ping),
+            payload_length,
+            data,
+        }
+    }
+
+    fn cont(channel: u32, seq: u8, data: &[u8]) -> Fragment<'_> {
+        Fragment::Continuation { channel, seq, data }
+    }
+
+    #[test]
+    fn test_single_fragment_message() {
+        let mut reassembler = MessageReassembler::new(ReassemblyConfig::default());
+        let res = reassembler.add_fragment(init(1, 2, &[1, 2, 0, 0])).unwrap();
+        assert_eq!(res.unwrap().payload, vec![1, 2]);
+        assert!(!reassembler.is_reassembling(1));
+    }
+
+    #[test]
+    fn test_interleaved_channels() {
+        let mut reassembler = MessageReassembler::new(ReassemblyConfig::default());
+        assert_eq!(reassembler.add_fragment(init(1, 4, &[1, 1])).unwrap(), None);
+        assert_eq!(reassembler.add_fragment(init(2, 4, &[2, 2])).unwrap(), None);
+        assert_eq!(reassembler.add_fragment(cont(2, 0, &[2])).unwrap(), None);
+        assert_eq!(reassembler.add_fragment(cont(1, 0, &[1])).unwrap(), None);
+
+        let first = reassembler
+            .add_fragment(cont(1, 1, &[1, 0]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.channel_identifier, 1);
+        assert_eq!(first.payload, vec![1, 1, 1, 1]);
+
+        let second = reassembler.add_fragment(cont(2, 1, &[2])).unwrap().unwrap();
+        assert_eq!(second.channel_identifier, 2);
+        assert_eq!(second.payload, vec![2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_unexpected_seq_discards_message() {
+        let mut reassembler = MessageReassembler::new(ReassemblyConfig::default());
+        reassembler.add_fragment(init(1, 4, &[1])).unwrap();
+        let res = reassembler.add_fragment(cont(1, 1, &[1]));
+        assert!(matches!(
+            res,
+            Err(MessageDecodeError::UnexpectedSeq {
+                expected: 0,
+                gotten: 1,
+                chan: 1
+            })
+        ));
+        assert!(!reassembler.is_reassembling(1));
+        assert!(matches!(
+            reassembler.add_fragment(cont(1, 0, &[1])),
+            Err(MessageDecodeError::UnexpectedCont { chan: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_unexpected_init() {
+        let mut reassembler = MessageReassembler::new(ReassemblyConfig::default());
+        reassembler.add_fragment(init(1, 4, &[1])).unwrap();
+        assert!(matches!(
+            reassembler.add_fragment(init(1, 4, &[1])),
+            Err(MessageDecodeError::UnexpectedInit { chan: 1, .. })
+        ));
+        assert!(reassembler.is_reassembling(1));
+    }
+
+    #[test]
+    fn test_limits() {
+        let config = ReassemblyConfig {
+            max_message_size: 8,
+            max_open_channels: 1,
+            max_seq: 1,
+            wrap_seq: false,
+        };
+        let mut reassembler = MessageReassembler::new(config);
+        assert!(matches!(
+            reassembler.add_fragment(init(1, 9, &[1])),
+            Err(MessageDecodeError::InvalidPayloadLength {
+                chan: 1,
+                invalid_len: 9
+            })
+        ));
+
+        reassembler.add_fragment(init(1, 8, &[1])).unwrap();
+        assert!(matches!(
+            reassembler.add_fragment(init(2, 8, &[1])),
+            Err(MessageDecodeError::TooManyChannels { chan: 2 })
+        ));
+        // a message fitting in a single fragment doesn't occupy a channel
+        assert!(reassembler
+            .add_fragment(init(2, 1, &[1]))
+            .unwrap()
+            .is_some());
+
+        reassembler.add_fragment(cont(1, 0, &[1])).unwrap();
+        reassembler.add_fragment(cont(1, 1, &[1])).unwrap();
+        assert!(matches!(
+            reassembler.add_fragment(cont(1, 2, &[1])),
+            Err(MessageDecodeError::UnexpectedSeq { gotten: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_wrapping_seq() {
+        let config = ReassemblyConfig {
+            max_message_size: 8,
+            max_open_channels: 1,
+            max_seq: 1,
+            wrap_seq: true,
+        };
+        let mut reassembler = MessageReassembler::new(config);
+        reassembler.add_fragment(init(1, 4, &[1])).unwrap();
+        reassembler.add_fragment(cont(1, 0, &[1])).unwrap();
+        reassembler.add_fragment(cont(1, 1, &[1])).unwrap();
+        let message = reassembler.add_fragment(cont(1, 0, &[1])).unwrap().unwrap();
+        assert_eq!(message.payload, vec![1; 4]);
+    }
+}
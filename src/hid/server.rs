@@ -1,21 +1,32 @@
 use super::packet_processing::{PacketProcessing, PacketProcessingResult};
-use anyhow::anyhow;
+use std::{
+    future::{poll_fn, Future},
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
 use bytes::BytesMut;
-use futures::{SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt, StreamExt};
 use thiserror::Error;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    time::Instant,
+};
 use tower::Service;
-use tracing::{debug_span, error, trace, warn};
+use tracing::{debug, debug_span, error, trace, warn};
 
 use crate::authenticator::{
-    api::{AuthServiceError, AuthenticatorError, CTAP2Request, CTAP2Response},
-    transport::CTAP2ServerTransport,
+    api::{AuthServiceError, CTAP2Request, CTAP2Response, CTAP2Service},
+    command::StatusCode,
+    presence::PresenceFrontend,
 };
 
 use super::{
-    command::ErrorCode,
+    command::{CommandType, DeviceVersion, ErrorCode, KeepaliveStatus},
     packet::{Message, MessageDecodeError, MessageEncoder, Packet, HID_REPORT_SIZE},
-    transport::HIDTransport,
+    transport::{HIDTransport, HIDTransportEvent},
 };
 
 /// An error that occurs during processing of a CTAP-HID packet/transaction.
@@ -28,6 +39,9 @@ pub enum ServerError {
     #[error("[chan {new_chan}] Server is busy on channel {busy_chan}")]
     ChannelBusy { busy_chan: u32, new_chan: u32 },
 
+    #[error("[chan {new_chan}] Device is locked by channel {lock_chan}")]
+    ChannelLocked { lock_chan: u32, new_chan: u32 },
+
     #[error("[chan {chan}] Invalid channel")]
     InvalidChannel { chan: u32 },
 
@@ -41,6 +55,7 @@ impl ServerError {
         match self {
             ServerError::MessageDecodeError(err) => err.get_channel(),
             ServerError::ChannelBusy { new_chan, .. } => *new_chan,
+            ServerError::ChannelLocked { new_chan, .. } => *new_chan,
             ServerError::InvalidChannel { chan } => *chan,
             ServerError::Other { chan, .. } => *chan,
         }
@@ -52,6 +67,7 @@ impl From<ServerError> for ErrorCode {
         match err {
             ServerError::MessageDecodeError(err) => err.into(),
             ServerError::ChannelBusy { .. } => ErrorCode::ChannelBusy,
+            ServerError::ChannelLocked { .. } => ErrorCode::ChannelBusy,
             ServerError::InvalidChannel { .. } => ErrorCode::InvalidChannel,
             ServerError::Other { .. } => ErrorCode::Other,
         }
@@ -65,88 +81,176 @@ impl From<ServerError> for Message {
             ServerError::ChannelBusy { new_chan, .. } => {
                 ErrorCode::ChannelBusy.to_message(new_chan)
             }
+            ServerError::ChannelLocked { new_chan, .. } => {
+                ErrorCode::ChannelBusy.to_message(new_chan)
+            }
             ServerError::InvalidChannel { chan } => ErrorCode::InvalidChannel.to_message(chan),
             ServerError::Other { chan, .. } => ErrorCode::Other.to_message(chan),
         }
     }
 }
 
+/// Settings of a [CTAPServer] which depend on the emulated device
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Whether U2F messages (CTAPHID_MSG) are accepted. Note that the authenticator
+    /// service must also have U2F enabled.
+    pub u2f_enabled: bool,
+
+    /// Whether CTAPHID_WINK is supported
+    pub wink_enabled: bool,
+
+    /// The device version reported in response to CTAPHID_INIT
+    pub device_version: DeviceVersion,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            u2f_enabled: true,
+            wink_enabled: true,
+            device_version: DeviceVersion::default(),
+        }
+    }
+}
+
+/// Interval between keepalives sent while a CTAP2 request is processed, see
+/// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#usb-hid-keepalive
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A CTAP2 or U2F request being processed by the authenticator
+struct PendingRequest {
+    channel: u32,
+    /// Whether it's a CTAP2 (rather than U2F) request, which is kept alive and responded to
+    /// when cancelled
+    is_ctap2: bool,
+    response: Pin<Box<dyn Future<Output = Result<CTAP2Response, AuthServiceError>>>>,
+}
+
+impl PendingRequest {
+    /// Waits for the response, failing if processing the request panicked
+    async fn response(&mut self) -> std::thread::Result<Result<CTAP2Response, AuthServiceError>> {
+        AssertUnwindSafe(&mut self.response).catch_unwind().await
+    }
+}
+
 /// Entry point to the authenticator daemon
 pub struct CTAPServer<T> {
     transport: T,
     logic: PacketProcessing,
     encoder: MessageEncoder,
+    authenticator: CTAP2Service,
+    frontend: Arc<dyn PresenceFrontend>,
 }
 
 impl<T> CTAPServer<T>
 where
     T: HIDTransport + Unpin,
 {
-    /// Creates a handler given a transport for CTAP-HID reports,
-    /// and an authenticator
-    pub fn new(transport: T) -> Self {
+    /// Creates a handler given a transport for CTAP-HID reports, and the authenticator, whose
+    /// frontend shows the user its cues, e.g, upon CTAPHID_WINK
+    pub fn new(transport: T, authenticator: CTAP2Service) -> Self {
         CTAPServer {
             transport,
             logic: PacketProcessing::new(),
             encoder: MessageEncoder::new(),
+            frontend: authenticator.frontend(),
+            authenticator,
         }
     }
 
-    /// Runs forever, processing CTAP-HID packets. May return early in case of a transport errors.
-    pub async fn run<A>(&mut self, service: A) -> anyhow::Result<()>
-    where
-        A: Service<CTAP2Request, Response = CTAP2Response, Error = AuthServiceError>
-            + Send
-            + 'static,
-        A::Future: 'static,
-    {
-        let (ctap2_transport, req_send, mut res_recv) = CTAP2ServerTransport::new();
-        let server = tokio_tower::pipeline::Server::new(ctap2_transport, service);
-        let ls = tokio::task::LocalSet::new();
-        let mut server_jh = ls.spawn_local(server);
+    /// Applies the given options, replacing the [default](ServerOptions::default) ones
+    pub fn configure(&mut self, options: &ServerOptions) {
+        self.logic.set_u2f_enabled(options.u2f_enabled);
+        self.logic.set_wink_enabled(options.wink_enabled);
+        self.logic.set_device_version(options.device_version);
+    }
+
+    /// Runs forever, processing CTAP-HID packets. CTAP2 and U2F requests are processed one at
+    /// a time, while packets of other channels are still handled, e.g, CTAPHID_CANCEL. Vendor
+    /// commands are handled by tasks of their own. A request whose processing panicked is
+    /// failed with ERR_OTHER. May return early in case of a transport errors.
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let mut pending: Option<PendingRequest> = None;
+        let (vendor_send, mut vendor_recv) = unbounded_channel();
+        let keepalive = tokio::time::sleep(KEEPALIVE_INTERVAL);
+        tokio::pin!(keepalive);
 
-        ls.run_until(async move {
-            loop {
+        loop {
+            let kept_alive = pending.as_ref().is_some_and(|pending| pending.is_ctap2);
             tokio::select! {
-                server_res = &mut server_jh => {
-                    match server_res {
-                        Ok(Ok(())) => return Ok(()),
-                        Ok(Err(e)) => {
-                            error!("There was an error in the CTAP2 server requiring it to shut down: {:?}", e);
-                            return Err(e.into());
-                        }
-                        Err(e) => {
-                            error!("There was a panic in the CTAP2 server requiring it to shut down: {:?}", e);
-                            return Err(e.into())
-                        },
-                    }
-                }
-                res = res_recv.recv() => {
+                result = async { pending.as_mut().unwrap().response().await }, if pending.is_some() => {
+                    let channel = pending.take().unwrap().channel;
                     let span = debug_span!("CTAP2 Response");
                     let _enter = span.enter();
-                    if let Some(res) = res {
-                        trace!(?res, "Writing CTAP2 Response message");
-                        self.write_message(res.into()).await?;
-                    } else {
-                        return Ok(())
-                    }
+                    let message = match result {
+                        Ok(Ok(res)) => Message::from(res),
+                        Ok(Err(e)) => Message::from(&e),
+                        Err(_) => {
+                            error!(channel, "Processing a CTAP2 request panicked");
+                            ErrorCode::Other.to_message(channel)
+                        }
+                    };
+                    trace!(?message, "Writing CTAP2 Response message");
+                    self.write_message(message).await?;
                 },
-                report = self.transport.next() => {
-                    if let Some(report) = report {
-                        let report = report?;
-                        self.handle_report(&req_send, report).await?;
+                _ = &mut keepalive, if kept_alive => {
+                    keepalive.as_mut().reset(Instant::now() + KEEPALIVE_INTERVAL);
+                    let status = if self.authenticator.awaiting_presence() {
+                        KeepaliveStatus::Upneeded
                     } else {
-                        return Ok(());
+                        KeepaliveStatus::Processing
+                    };
+                    let channel = pending.as_ref().unwrap().channel;
+                    trace!(?channel, ?status, "Sending a keepalive");
+                    self.write_message(Message {
+                        channel_identifier: channel,
+                        command: Ok(CommandType::Keepalive),
+                        payload: vec![status.into()],
+                    })
+                    .await?;
+                },
+                Some(response) = vendor_recv.recv() => {
+                    trace!(?response, "Writing a vendor command response message");
+                    self.write_message(response).await?;
+                },
+                event = self.transport.next() => {
+                    match event {
+                        Some(Ok(HIDTransportEvent::Report(report))) => {
+                            let started = pending.is_none();
+                            self.handle_report(&mut pending, &vendor_send, report).await?;
+                            if started && pending.is_some() {
+                                keepalive.as_mut().reset(Instant::now() + KEEPALIVE_INTERVAL);
+                            }
+                        }
+                        Some(Ok(HIDTransportEvent::Opened)) => {
+                            debug!("HID transport was opened by the host");
+                        }
+                        Some(Ok(HIDTransportEvent::Closed)) => {
+                            debug!("HID transport was closed by the host, releasing all channels");
+                            self.logic.release_all_channels();
+                            pending = None;
+                        }
+                        Some(Err(e)) => return Err(e.into()),
+                        None => return Ok(()),
                     }
                 },
-            };
             }
-        }).await
+        }
+    }
+
+    /// Closes the transport, e.g, destroying the HID device. Should be invoked once the
+    /// server is no longer run.
+    pub async fn shutdown(&mut self) -> anyhow::Result<()> {
+        debug!("Closing the HID transport");
+        self.transport.close().await?;
+        Ok(())
     }
 
     async fn handle_report(
         &mut self,
-        req_send: &UnboundedSender<CTAP2Request>,
+        pending: &mut Option<PendingRequest>,
+        vendor_send: &UnboundedSender<Message>,
         report: Vec<u8>,
     ) -> anyhow::Result<()> {
         let packet = Packet::from_report(report.as_ref());
@@ -157,26 +261,44 @@ where
         match self.logic.handle_packet(packet) {
             Ok(PacketProcessingResult::WaitingForMorePackets) => {}
             Ok(PacketProcessingResult::ResponseReady(message)) => {
+                // INIT re-synchronizes the channel, abandoning its request
+                if message.command == Ok(CommandType::Init)
+                    && pending.as_ref().map(|pending| pending.channel) == Some(channel)
+                {
+                    debug!("Abandoned the pending request");
+                    *pending = None;
+                }
                 trace!(?message, "Writing a CTAP HID response message");
                 self.write_message(message).await?;
             }
             Ok(PacketProcessingResult::CTAP2Request(message)) => {
+                if let Some(busy) = pending {
+                    error!(
+                        busy_chan = busy.channel,
+                        "Received a request while another is processed"
+                    );
+                    let error = ServerError::ChannelBusy {
+                        busy_chan: busy.channel,
+                        new_chan: channel,
+                    };
+                    self.write_message(error.into()).await?;
+                    return Ok(());
+                }
                 let ctap_req = CTAP2Request::try_from(&message);
                 match ctap_req {
                     Ok(req) => {
-                        req_send
-                            .send(req)
-                            .map_err(|_| anyhow!("CTAP2 service crashe,d can't send request"))?;
+                        poll_fn(|cx| self.authenticator.poll_ready(cx)).await?;
+                        *pending = Some(PendingRequest {
+                            channel,
+                            is_ctap2: message.command == Ok(CommandType::Cbor),
+                            response: self.authenticator.call(req),
+                        });
                     }
                     Err(auth_err) => {
-                        assert!(matches!(
-                            auth_err,
-                            AuthenticatorError::DeserializationError(_)
-                        ));
                         error!(
-                            "Error deserializing CBOR request: {:?}, bytes: {}",
+                            "Error parsing CBOR request: {:?}, bytes: {}",
                             auth_err,
-                            hex::encode(&message.payload[1..])
+                            hex::encode(&message.payload)
                         );
                         let err_msg = Message::from(&AuthServiceError::new(
                             auth_err,
@@ -189,6 +311,37 @@ where
             Ok(PacketProcessingResult::Aborted) => {
                 warn!("Aborted current CTAP-HID transaction");
             }
+            Ok(PacketProcessingResult::Cancel(chan)) => match pending.take() {
+                Some(cancelled) if cancelled.channel == chan => {
+                    debug!("Cancelled the pending request");
+                    if cancelled.is_ctap2 {
+                        self.write_message(Message {
+                            channel_identifier: chan,
+                            command: Ok(CommandType::Cbor),
+                            payload: vec![StatusCode::Ctap2ErrKeepaliveCancel as u8],
+                        })
+                        .await?;
+                    }
+                }
+                other => {
+                    trace!("No request to cancel on the channel");
+                    *pending = other;
+                }
+            },
+            Ok(PacketProcessingResult::Wink(message)) => {
+                self.frontend.wink();
+                self.write_message(message).await?;
+            }
+            Ok(PacketProcessingResult::VendorRequest(message)) => {
+                // handlers may take a while, e.g, waiting for the authenticator
+                let authenticator = self.authenticator.clone();
+                let vendor_send = vendor_send.clone();
+                tokio::spawn(async move {
+                    let response = authenticator.handle_vendor_command(message).await;
+                    // the server may have stopped meanwhile
+                    let _ = vendor_send.send(response);
+                });
+            }
             Err(error) => {
                 error!(?error, "Error while processing a CTAP-HID packet");
                 let error_message = Message::from(error);
@@ -209,3 +362,383 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ciborium::value::Value;
+    use futures::Future;
+    use zerocopy::LayoutVerified;
+
+    use crate::{
+        authenticator::{
+            api::CTAP2Service,
+            command::StatusCode,
+            presence::{PresenceToken, TestFrontend},
+        },
+        hid::{
+            channel::BROADCAST_CHANNEL,
+            command::{CommandType, InitCommandResponse},
+            loopback::{loopback, LoopbackHost},
+            vendor::{VendorCommandHandler, VendorContext},
+        },
+    };
+
+    use super::*;
+
+    /// Runs a test against a server driven by a loopback transport
+    async fn with_server<F, Fut>(test: F)
+    where
+        F: FnOnce(LoopbackHost) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        with_service(CTAP2Service::new(true), test).await
+    }
+
+    /// Runs a test against a server of the given authenticator, driven by a loopback transport
+    async fn with_service<F, Fut>(service: CTAP2Service, test: F)
+    where
+        F: FnOnce(LoopbackHost) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let (transport, host) = loopback();
+        let mut server = CTAPServer::new(transport, service);
+        tokio::select! {
+            res = server.run() => panic!("Server stopped early: {:?}", res),
+            _ = test(host) => {}
+        }
+    }
+
+    /// Runs a test against two servers sharing the same authenticator
+    async fn with_two_servers<F, Fut>(service: CTAP2Service, test: F)
+    where
+        F: FnOnce(LoopbackHost, LoopbackHost) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let (first_transport, first_host) = loopback();
+        let (second_transport, second_host) = loopback();
+        let mut first = CTAPServer::new(first_transport, service.for_transport("first"));
+        let mut second = CTAPServer::new(second_transport, service.for_transport("second"));
+        tokio::select! {
+            res = first.run() => panic!("Server stopped early: {:?}", res),
+            res = second.run() => panic!("Server stopped early: {:?}", res),
+            _ = test(first_host, second_host) => {}
+        }
+    }
+
+    fn message(chan: u32, command: CommandType, payload: Vec<u8>) -> Message {
+        Message {
+            channel_identifier: chan,
+            command: Ok(command),
+            payload,
+        }
+    }
+
+    /// Receives the next message other than a keepalive
+    async fn recv_response(host: &mut LoopbackHost) -> Message {
+        loop {
+            let message = host.recv_message().await;
+            if message.command != Ok(CommandType::Keepalive) {
+                return message;
+            }
+        }
+    }
+
+    async fn allocate_channel(host: &mut LoopbackHost) -> u32 {
+        let nonce = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        host.send_message(&message(
+            BROADCAST_CHANNEL,
+            CommandType::Init,
+            nonce.clone(),
+        ));
+        let res = recv_response(host).await;
+        assert_eq!(res.channel_identifier, BROADCAST_CHANNEL);
+        assert_eq!(res.command, Ok(CommandType::Init));
+        let init = LayoutVerified::<_, InitCommandResponse>::new_unaligned(res.payload.as_ref())
+            .expect("Invalid INIT response");
+        assert_eq!(&init.nonce[..], &nonce[..]);
+        init.channel_id.get()
+    }
+
+    #[tokio::test]
+    async fn test_ping_fragmented() {
+        with_server(|mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            // spans an initialization packet and several continuation packets, both ways
+            let payload = (0..500).map(|i| i as u8).collect::<Vec<_>>();
+            let ping = message(chan, CommandType::Ping, payload);
+            host.send_message(&ping);
+            assert_eq!(host.recv_message().await, ping);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_get_info() {
+        with_server(|mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            host.send_message(&message(chan, CommandType::Cbor, vec![0x04]));
+            let res = host.recv_message().await;
+            assert_eq!(res.command, Ok(CommandType::Cbor));
+            assert_eq!(res.payload[0], StatusCode::Ctap1ErrSuccess as u8);
+
+            let info: Value = ciborium::de::from_reader(&res.payload[1..]).unwrap();
+            let versions = info
+                .as_map()
+                .unwrap()
+                .iter()
+                .find(|(key, _)| key == &Value::from(1))
+                .map(|(_, versions)| versions.as_array().unwrap().clone())
+                .unwrap();
+            assert!(versions.contains(&Value::from("FIDO_2_0")));
+            assert!(versions.contains(&Value::from("U2F_V2")));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_invalid_cbor() {
+        with_server(|mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            host.send_message(&message(chan, CommandType::Cbor, vec![0x01, 0xff]));
+            let res = host.recv_message().await;
+            assert_eq!(res.channel_identifier, chan);
+            assert_eq!(res.payload, vec![StatusCode::Ctap2ErrInvalidCbor as u8]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_u2f_version() {
+        with_server(|mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            host.send_message(&message(chan, CommandType::Msg, vec![0, 3, 0, 0, 0]));
+            let res = host.recv_message().await;
+            assert_eq!(res.command, Ok(CommandType::Msg));
+            assert_eq!(res.payload, b"U2F_V2\x90\x00".to_vec());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_wink_reaches_frontend() {
+        let frontend = Arc::new(TestFrontend::default());
+        let service = CTAP2Service::with_frontend(true, frontend.clone());
+        with_service(service, |mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            host.send_message(&message(chan, CommandType::Wink, vec![]));
+            let res = host.recv_message().await;
+            assert_eq!(res, message(chan, CommandType::Wink, vec![]));
+            assert_eq!(frontend.winks(), 1);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_and_cancel() {
+        // a makeCredential request of Chromium
+        let make_credential = hex::decode("01a5015820a830e6419cd1e40a074b78365370c64a8796c2fe8cbb70903c8cf60dd534045b02a26269646b776562617574686e2e696f646e616d656b776562617574686e2e696f03a36269644a8a893e00000000000000646e616d656273666b646973706c61794e616d65627366048aa263616c672664747970656a7075626c69632d6b6579a263616c67382264747970656a7075626c69632d6b6579a263616c67382364747970656a7075626c69632d6b6579a263616c6739010064747970656a7075626c69632d6b6579a263616c6739010164747970656a7075626c69632d6b6579a263616c6739010264747970656a7075626c69632d6b6579a263616c67382464747970656a7075626c69632d6b6579a263616c67382564747970656a7075626c69632d6b6579a263616c67382664747970656a7075626c69632d6b6579a263616c672764747970656a7075626c69632d6b657907a1627576f5").unwrap();
+        let frontend = Arc::new(TestFrontend::default());
+        frontend.set_present(false);
+        let service = CTAP2Service::with_frontend(true, frontend.clone());
+        with_service(service, |mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            host.send_message(&message(chan, CommandType::Cbor, make_credential));
+            let upneeded = vec![KeepaliveStatus::Upneeded.into()];
+            assert_eq!(
+                host.recv_message().await,
+                message(chan, CommandType::Keepalive, upneeded)
+            );
+
+            // other channels are still served, but can't begin another request
+            let other = allocate_channel(&mut host).await;
+            host.send_message(&message(other, CommandType::Cbor, vec![0x04]));
+            let res = recv_response(&mut host).await;
+            assert_eq!(res.channel_identifier, other);
+            assert_eq!(res.payload, vec![ErrorCode::ChannelBusy as u8]);
+
+            host.send_message(&message(chan, CommandType::Cancel, vec![]));
+            let cancelled = vec![StatusCode::Ctap2ErrKeepaliveCancel as u8];
+            assert_eq!(
+                recv_response(&mut host).await,
+                message(chan, CommandType::Cbor, cancelled)
+            );
+
+            host.send_message(&message(other, CommandType::Cbor, vec![0x04]));
+            let res = host.recv_message().await;
+            assert_eq!(res.channel_identifier, other);
+            assert_eq!(res.payload[0], StatusCode::Ctap1ErrSuccess as u8);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_invalid_ctap2_requests() {
+        with_server(|mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            for (payload, status) in [
+                (vec![], StatusCode::Ctap1ErrInvalidLength),
+                (vec![0x55], StatusCode::Ctap1ErrInvalidCommand),
+                // authenticatorClientPIN isn't implemented
+                (vec![0x06, 0xa0], StatusCode::Ctap1ErrInvalidCommand),
+            ] {
+                host.send_message(&message(chan, CommandType::Cbor, payload));
+                assert_eq!(
+                    recv_response(&mut host).await,
+                    message(chan, CommandType::Cbor, vec![status as u8])
+                );
+            }
+        })
+        .await;
+    }
+
+    /// A frontend which fails whenever the user's presence is checked
+    struct PanickingFrontend;
+
+    impl PresenceFrontend for PanickingFrontend {
+        fn wink(&self) {}
+
+        fn check_presence(&self, _token: PresenceToken, _purpose: &str) -> bool {
+            panic!("The frontend is broken")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicking_request() {
+        let service = CTAP2Service::with_frontend(true, Arc::new(PanickingFrontend));
+        with_service(service, |mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            // a U2F registration
+            let mut register = vec![0, 0x01, 0, 0, 0, 0, 64];
+            register.extend_from_slice(&[0xcc; 64]);
+            register.extend_from_slice(&[0, 0]);
+            host.send_message(&message(chan, CommandType::Msg, register));
+            assert_eq!(
+                recv_response(&mut host).await,
+                ErrorCode::Other.to_message(chan)
+            );
+
+            // the server keeps serving
+            host.send_message(&message(chan, CommandType::Cbor, vec![0x04]));
+            let res = recv_response(&mut host).await;
+            assert_eq!(res.channel_identifier, chan);
+            assert_eq!(res.payload[0], StatusCode::Ctap1ErrSuccess as u8);
+        })
+        .await;
+    }
+
+    /// Responds with the number of stored credentials
+    struct CountCredentials;
+
+    #[async_trait::async_trait]
+    impl VendorCommandHandler for CountCredentials {
+        async fn handle(&self, context: &VendorContext, request: Message) -> Message {
+            let stats = context.authenticator.stats().await.unwrap();
+            Message {
+                payload: vec![stats.credentials as u8],
+                ..request
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_vendor_command_served_by_all_transports() {
+        let service = CTAP2Service::new(true);
+        service
+            .register_vendor_command(0x42, CountCredentials)
+            .unwrap();
+        with_two_servers(service, |mut first, mut second| async move {
+            for host in [&mut first, &mut second] {
+                let chan = allocate_channel(host).await;
+                host.send_message(&message(chan, CommandType::Vendor(0x42), vec![]));
+                let res = host.recv_message().await;
+                assert_eq!(res, message(chan, CommandType::Vendor(0x42), vec![0]));
+
+                host.send_message(&message(chan, CommandType::Vendor(0x43), vec![]));
+                let res = host.recv_message().await;
+                assert_eq!(res.payload, vec![ErrorCode::InvalidCmd as u8]);
+            }
+        })
+        .await;
+    }
+
+    /// Responds once notified
+    struct Blocking(Arc<tokio::sync::Notify>);
+
+    #[async_trait::async_trait]
+    impl VendorCommandHandler for Blocking {
+        async fn handle(&self, _context: &VendorContext, request: Message) -> Message {
+            self.0.notified().await;
+            request
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_vendor_command() {
+        let service = CTAP2Service::new(true);
+        let notify = Arc::new(tokio::sync::Notify::new());
+        service
+            .register_vendor_command(0x42, Blocking(notify.clone()))
+            .unwrap();
+        with_service(service, |mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            let vendor = message(chan, CommandType::Vendor(0x42), vec![1]);
+            host.send_message(&vendor);
+
+            // the transport is still served meanwhile
+            let other = allocate_channel(&mut host).await;
+            let ping = message(other, CommandType::Ping, vec![2]);
+            host.send_message(&ping);
+            assert_eq!(host.recv_message().await, ping);
+
+            notify.notify_one();
+            assert_eq!(host.recv_message().await, vendor);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_close_releases_channels() {
+        with_server(|mut host| async move {
+            let chan = allocate_channel(&mut host).await;
+            host.close();
+            host.send_message(&message(chan, CommandType::Ping, vec![1]));
+            let res = host.recv_message().await;
+            assert_eq!(res.channel_identifier, chan);
+            assert_eq!(res.command, Ok(CommandType::Error));
+            assert_eq!(res.payload, vec![ErrorCode::InvalidChannel as u8]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_transports_share_credentials() {
+        with_two_servers(
+            CTAP2Service::new(true),
+            |mut first, mut second| async move {
+                let app_param = [0x11u8; 32];
+                let mut register = vec![0, 1, 0, 0, 64];
+                register.extend_from_slice(&[0x22u8; 32]);
+                register.extend_from_slice(&app_param);
+                let chan = allocate_channel(&mut first).await;
+                first.send_message(&message(chan, CommandType::Msg, register));
+                let res = first.recv_message().await;
+                assert_eq!(&res.payload[res.payload.len() - 2..], &[0x90, 0x00]);
+                let key_handle_len = res.payload[66] as usize;
+                let key_handle = &res.payload[67..67 + key_handle_len];
+
+                // a check-only authentication of a known key handle is rejected with
+                // 'conditions not satisfied', rather than 'wrong data'
+                let mut authenticate = vec![0, 2, 7, 0, 65 + key_handle_len as u8];
+                authenticate.extend_from_slice(&[0x33u8; 32]);
+                authenticate.extend_from_slice(&app_param);
+                authenticate.push(key_handle_len as u8);
+                authenticate.extend_from_slice(key_handle);
+                let chan = allocate_channel(&mut second).await;
+                second.send_message(&message(chan, CommandType::Msg, authenticate));
+                let res = second.recv_message().await;
+                assert_eq!(res.payload, vec![0x69, 0x85]);
+            },
+        )
+        .await;
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    fs::{DirBuilder, Permissions},
+    io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, error, warn};
+
+use super::{
+    packet::HID_REPORT_SIZE,
+    transport::{HIDTransport, HIDTransportEvent, TransportError},
+};
+
+/// How many clients may be connected to a [UnixSocketTransport] at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketClientMode {
+    /// A single client is served at a time, further connections wait until it disconnects
+    Single,
+
+    /// Any number of clients are served at once, sharing the CTAP-HID channels. Input reports
+    /// are broadcast to all clients, each of which filters them by its channel as it would
+    /// on a real HID device.
+    Multi,
+}
+
+/// State shared between the transport and its connection tasks
+struct Shared {
+    /// Input report senders of the connected clients, by client number
+    clients: Mutex<HashMap<u64, UnboundedSender<Vec<u8>>>>,
+    events: UnboundedSender<Result<HIDTransportEvent, TransportError>>,
+}
+
+/// A HID transport exchanging raw CTAP-HID reports of [HID_REPORT_SIZE] bytes over a Unix
+/// domain stream socket, without any further framing. Unlike UHID, it needs neither root nor
+/// kernel support, which makes it useful for testing.
+///
+/// The host is considered to have opened the device once a client connects, and to have
+/// closed it once no client remains connected.
+pub struct UnixSocketTransport {
+    path: PathBuf,
+    shared: Arc<Shared>,
+    events: UnboundedReceiver<Result<HIDTransportEvent, TransportError>>,
+    /// Stops accepting and serving clients once the transport is closed or dropped
+    cancel: Option<DropGuard>,
+}
+
+impl UnixSocketTransport {
+    /// Listens on a Unix socket at the given path, replacing a stale socket left there
+    /// by a previous run. Only the user running the daemon may connect to the socket, as
+    /// clients may use the credentials. Must be called within a tokio runtime.
+    pub async fn new(path: impl AsRef<Path>, mode: SocketClientMode) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_owned();
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                debug!(?path, "Removing a stale socket");
+                std::fs::remove_file(&path)?;
+            }
+            Ok(_) => return Err(anyhow!("{:?} already exists and isn't a socket", path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let listener = bind_private(&path)?;
+
+        let (event_send, event_recv) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            clients: Mutex::new(HashMap::new()),
+            events: event_send,
+        });
+        let cancel = CancellationToken::new();
+        tokio::spawn(accept_clients(
+            listener,
+            mode,
+            shared.clone(),
+            cancel.clone(),
+        ));
+
+        Ok(Self {
+            path,
+            shared,
+            events: event_recv,
+            cancel: Some(cancel.drop_guard()),
+        })
+    }
+
+    /// Stops serving clients and removes the socket, can be invoked more than once
+    fn shutdown(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            drop(cancel);
+            self.shared.clients.lock().unwrap().clear();
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!(path = ?self.path, "Couldn't remove the socket: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for UnixSocketTransport {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+async fn accept_clients(
+    listener: UnixListener,
+    mode: SocketClientMode,
+    shared: Arc<Shared>,
+    cancel: CancellationToken,
+) {
+    let mut next_client = 0;
+    loop {
+        let stream = tokio::select! {
+            _ = cancel.cancelled() => return,
+            res = listener.accept() => match res {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Couldn't accept a client connection: {}", e);
+                    let _ = shared.events.send(Err(e.into()));
+                    return;
+                }
+            },
+        };
+        let client = next_client;
+        next_client += 1;
+        debug!(client, "Accepted a client connection");
+        let serve = {
+            let shared = shared.clone();
+            let cancel = cancel.clone();
+            async move {
+                tokio::select! {
+                    _ = cancel.cancelled() => {},
+                    _ = serve_client(stream, client, shared) => {},
+                }
+            }
+        };
+        match mode {
+            SocketClientMode::Single => serve.await,
+            SocketClientMode::Multi => {
+                tokio::spawn(serve);
+            }
+        }
+    }
+}
+
+async fn serve_client(stream: UnixStream, client: u64, shared: Arc<Shared>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (report_send, mut report_recv) = mpsc::unbounded_channel::<Vec<u8>>();
+    let first = {
+        let mut clients = shared.clients.lock().unwrap();
+        clients.insert(client, report_send);
+        clients.len() == 1
+    };
+    if first {
+        let _ = shared.events.send(Ok(HIDTransportEvent::Opened));
+    }
+
+    let write_reports = async {
+        while let Some(report) = report_recv.recv().await {
+            writer.write_all(&report).await?;
+        }
+        Ok::<(), io::Error>(())
+    };
+    let read_reports = async {
+        let mut report = [0u8; HID_REPORT_SIZE as usize];
+        loop {
+            reader.read_exact(&mut report).await?;
+            let event = HIDTransportEvent::Report(report.to_vec());
+            if shared.events.send(Ok(event)).is_err() {
+                break;
+            }
+        }
+        Ok::<(), io::Error>(())
+    };
+    let res = tokio::select! {
+        res = write_reports => res,
+        res = read_reports => res,
+    };
+    match res {
+        Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => {
+            warn!(client, "Client connection failed: {}", e)
+        }
+        _ => debug!(client, "Client disconnected"),
+    }
+
+    let last = {
+        let mut clients = shared.clients.lock().unwrap();
+        clients.remove(&client);
+        clients.is_empty()
+    };
+    if last {
+        let _ = shared.events.send(Ok(HIDTransportEvent::Closed));
+    }
+}
+
+impl futures::Stream for UnixSocketTransport {
+    type Item = Result<HIDTransportEvent, TransportError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl futures::Sink<Vec<u8>> for UnixSocketTransport {
+    type Error = TransportError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        if item.len() != HID_REPORT_SIZE as usize {
+            return Err(anyhow!(
+                "Input report of {} bytes doesn't fit the HID report size",
+                item.len()
+            )
+            .into());
+        }
+        let clients = self.shared.clients.lock().unwrap();
+        if clients.is_empty() {
+            warn!("Dropping an input report, as no client is connected");
+        }
+        for report_send in clients.values() {
+            // a client that disconnected meanwhile is removed by its connection task
+            let _ = report_send.send(item.clone());
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.shutdown();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl HIDTransport for UnixSocketTransport {}
+
+/// Binds a Unix socket at the given path, which only the owner may connect to. The socket
+/// is bound within a private directory next to the path, and moved into place once its
+/// permissions are restricted, as it would be accessible per the umask in between otherwise.
+pub(crate) fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} doesn't name a socket", path),
+        )
+    })?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let private_path = dir.join("socket");
+    let res = UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    if res.is_err() {
+        let _ = std::fs::remove_file(&private_path);
+    }
+    if let Err(e) = std::fs::remove_dir(&dir) {
+        warn!(
+            ?dir,
+            "Couldn't remove the directory the socket was bound in: {}", e
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+
+    use crate::test_util::temp_dir;
+
+    use super::*;
+
+    async fn next_event(transport: &mut UnixSocketTransport) -> HIDTransportEvent {
+        transport.next().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_single_client() {
+        let dir = temp_dir();
+        let path = dir.path().join("softauth.sock");
+        let mut transport = UnixSocketTransport::new(&path, SocketClientMode::Single)
+            .await
+            .unwrap();
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        assert_eq!(next_event(&mut transport).await, HIDTransportEvent::Opened);
+
+        let report = vec![0x42u8; HID_REPORT_SIZE as usize];
+        client.write_all(&report).await.unwrap();
+        assert_eq!(
+            next_event(&mut transport).await,
+            HIDTransportEvent::Report(report.clone())
+        );
+
+        transport.send(report.clone()).await.unwrap();
+        let mut received = [0u8; HID_REPORT_SIZE as usize];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received[..], &report[..]);
+
+        drop(client);
+        assert_eq!(next_event(&mut transport).await, HIDTransportEvent::Closed);
+
+        transport.close().await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_socket_is_private() {
+        let dir = temp_dir();
+        let path = dir.path().join("softauth.sock");
+        let mut transport = UnixSocketTransport::new(&path, SocketClientMode::Single)
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the directory the socket was bound in is gone
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        transport.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_multi_client() {
+        let dir = temp_dir();
+        let path = dir.path().join("softauth.sock");
+        let mut transport = UnixSocketTransport::new(&path, SocketClientMode::Multi)
+            .await
+            .unwrap();
+        let mut first = UnixStream::connect(&path).await.unwrap();
+        assert_eq!(next_event(&mut transport).await, HIDTransportEvent::Opened);
+        let mut second = UnixStream::connect(&path).await.unwrap();
+        let report = vec![0x17u8; HID_REPORT_SIZE as usize];
+        second.write_all(&report).await.unwrap();
+        assert_eq!(
+            next_event(&mut transport).await,
+            HIDTransportEvent::Report(report.clone())
+        );
+
+        transport.send(report.clone()).await.unwrap();
+        for client in [&mut first, &mut second] {
+            let mut received = [0u8; HID_REPORT_SIZE as usize];
+            client.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received[..], &report[..]);
+        }
+
+        // channels are only released once the last client disconnects
+        drop(first);
+        drop(second);
+        assert_eq!(next_event(&mut transport).await, HIDTransportEvent::Closed);
+    }
+}
@@ -0,0 +1,290 @@
+use std::{future::Future, time::Duration};
+
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::authenticator::api::CTAP2Service;
+
+use super::{
+    server::{CTAPServer, ServerOptions},
+    transport::HIDTransport,
+};
+
+/// A transport that stayed up for this long is considered healthy, so that its next
+/// failure is retried without waiting for the previous backoff delay
+const STABLE_RUN: Duration = Duration::from_secs(30);
+
+/// Exponentially growing delay between two attempts at recreating a transport
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt, doubling the following one
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Starts over from the initial delay
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+/// Runs the authenticator over the given transport until it fails or is stopped. The
+/// transport is closed either way, and the failure returned, rather than one to close it.
+pub async fn serve<T: HIDTransport + Unpin>(
+    transport: T,
+    service: CTAP2Service,
+    options: &ServerOptions,
+    stop: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut server = CTAPServer::new(transport, service);
+    server.configure(options);
+    let res = tokio::select! {
+        res = server.run() => res,
+        _ = stop.cancelled() => Ok(()),
+    };
+    if let Err(e) = server.shutdown().await {
+        warn!("Couldn't close the transport: {:?}", e);
+    }
+    res
+}
+
+/// Runs the authenticator over a transport obtained from `create`, tearing it down and
+/// creating a new one whenever it fails, e.g, when the UHID device goes away on
+/// suspend/resume. The authenticator state lives in `service` and is kept across restarts.
+///
+/// Only a failure to create the first transport is returned, as it usually stems from a
+/// configuration problem rather than a transient one. Returns once `stop` is cancelled.
+pub async fn supervise<T, F, Fut>(
+    name: &str,
+    mut create: F,
+    service: CTAP2Service,
+    options: &ServerOptions,
+    mut backoff: Backoff,
+    stop: CancellationToken,
+) -> anyhow::Result<()>
+where
+    T: HIDTransport + Unpin,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut transport = create().await?;
+    let mut restarts = 0u32;
+    loop {
+        let started = Instant::now();
+        let res = serve(transport, service.clone(), options, stop.clone()).await;
+        if stop.is_cancelled() {
+            return Ok(());
+        }
+        match res {
+            Ok(()) => warn!(transport = name, "Transport was closed unexpectedly"),
+            Err(e) => warn!(transport = name, "Transport failed: {:?}", e),
+        }
+        if started.elapsed() >= STABLE_RUN {
+            backoff.reset();
+        }
+
+        transport = loop {
+            let delay = backoff.next_delay();
+            restarts += 1;
+            info!(transport = name, restarts, ?delay, "Restarting transport");
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = stop.cancelled() => return Ok(()),
+            }
+            match create().await {
+                Ok(transport) => break transport,
+                Err(e) => warn!(transport = name, "Couldn't recreate transport: {:?}", e),
+            }
+        };
+        info!(transport = name, restarts, "Transport was recreated");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+    };
+
+    use anyhow::anyhow;
+
+    use crate::hid::{
+        channel::BROADCAST_CHANNEL,
+        command::CommandType,
+        loopback::{loopback, LoopbackHost, LoopbackTransport},
+        packet::Message,
+        transport::{HIDTransportEvent, TransportError},
+    };
+
+    use super::*;
+
+    /// Fails to close, like a device which went away
+    struct UnclosableTransport(LoopbackTransport);
+
+    impl futures::Stream for UnclosableTransport {
+        type Item = Result<HIDTransportEvent, TransportError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Pin::new(&mut self.0).poll_next(cx)
+        }
+    }
+
+    impl futures::Sink<Vec<u8>> for UnclosableTransport {
+        type Error = TransportError;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.0).poll_ready(cx)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+            Pin::new(&mut self.0).start_send(item)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Err(anyhow!("Device is gone").into()))
+        }
+    }
+
+    impl HIDTransport for UnclosableTransport {}
+
+    fn test_backoff() -> Backoff {
+        Backoff::new(Duration::from_millis(1), Duration::from_millis(4))
+    }
+
+    async fn init(host: &mut LoopbackHost) {
+        let init = Message {
+            channel_identifier: BROADCAST_CHANNEL,
+            command: Ok(CommandType::Init),
+            payload: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        host.send_message(&init);
+        let res = host.recv_message().await;
+        assert_eq!(res.command, Ok(CommandType::Init));
+        assert_eq!(&res.payload[..8], &init.payload[..]);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = test_backoff();
+        let delays = (0..4).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 4].map(Duration::from_millis));
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn test_recreates_failed_transport() {
+        let (first_transport, mut first_host) = loopback();
+        let (second_transport, mut second_host) = loopback();
+        let mut transports = vec![second_transport, first_transport];
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let create = {
+            let attempts = attempts.clone();
+            move || {
+                // the first attempt at recreating the transport fails as well
+                let transport = match attempts.fetch_add(1, Ordering::SeqCst) {
+                    1 => None,
+                    _ => transports.pop(),
+                };
+                async move { transport.ok_or_else(|| anyhow!("Device is not ready")) }
+            }
+        };
+        let stop = CancellationToken::new();
+        let options = ServerOptions::default();
+        let supervisor = supervise(
+            "loopback",
+            create,
+            CTAP2Service::new(true),
+            &options,
+            test_backoff(),
+            stop.clone(),
+        );
+        let test = async {
+            init(&mut first_host).await;
+            first_host.fail(TransportError::IoError(
+                std::io::ErrorKind::BrokenPipe.into(),
+            ));
+            init(&mut second_host).await;
+            stop.cancel();
+        };
+        let (res, ()) = tokio::join!(supervisor, test);
+        res.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_serve_returns_transport_failure() {
+        let (transport, host) = loopback();
+        host.fail(TransportError::IoError(
+            std::io::ErrorKind::BrokenPipe.into(),
+        ));
+        let res = serve(
+            UnclosableTransport(transport),
+            CTAP2Service::new(true),
+            &ServerOptions::default(),
+            CancellationToken::new(),
+        )
+        .await;
+        let e = res.unwrap_err();
+        assert!(
+            matches!(e.downcast_ref(), Some(TransportError::IoError(_))),
+            "{:?}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn test_initial_failure_is_fatal() {
+        let res = supervise(
+            "loopback",
+            || async { Err::<LoopbackTransport, _>(anyhow!("No device")) },
+            CTAP2Service::new(true),
+            &ServerOptions::default(),
+            test_backoff(),
+            CancellationToken::new(),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+}
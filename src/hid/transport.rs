@@ -12,9 +12,22 @@ pub enum TransportError {
     OtherError(#[from] anyhow::Error),
 }
 
-/// A HID transport is a sink and stream of CTAP HID input/output reports respectively, each
-/// with a fixed size of [HID_REPORT_SIZE] bytes.
+/// An event received from a HID transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HIDTransportEvent {
+    /// An output report sent by the host, of [HID_REPORT_SIZE] bytes
+    Report(Vec<u8>),
+
+    /// The host opened the device, i.e, a client attached to it.
+    Opened,
+
+    /// The host closed the device, thus any channels allocated so far are no longer in use.
+    Closed,
+}
+
+/// A HID transport is a sink of CTAP HID input reports, each with a fixed size of
+/// [HID_REPORT_SIZE] bytes, and a stream of [HIDTransportEvent]s (mostly output reports).
 pub trait HIDTransport:
-    Sink<Vec<u8>, Error = TransportError> + Stream<Item = Result<Vec<u8>, TransportError>>
+    Sink<Vec<u8>, Error = TransportError> + Stream<Item = Result<HIDTransportEvent, TransportError>>
 {
 }
@@ -0,0 +1,311 @@
+//! Descriptors and standard/HID control requests of the exported USB HID device, see the
+//! [USB 2.0](https://www.usb.org/document-library/usb-20-specification) spec (chapter 9) and
+//! the [HID 1.11](https://www.usb.org/document-library/device-class-definition-hid-111) spec.
+
+use tracing::{debug, warn};
+
+use crate::hid::{
+    descriptor::{HidIdentity, CTAP_REPORT_DESCRIPTOR},
+    packet::HID_REPORT_SIZE,
+};
+
+use super::protocol::{UsbDevice, UsbInterface, BUS_ID_SIZE, USB_SPEED_FULL};
+
+/// Bus ID under which the device is exported
+pub const BUS_ID: &str = "1-1";
+const BUS_NUM: u32 = 1;
+const DEV_NUM: u32 = 2;
+
+/// Address of the interrupt IN endpoint, carrying input reports
+pub const EP_IN: u8 = 0x81;
+/// Address of the interrupt OUT endpoint, carrying output reports
+pub const EP_OUT: u8 = 0x01;
+/// Polling interval of the interrupt endpoints, in milliseconds
+const EP_INTERVAL: u8 = 5;
+
+const USB_CLASS_HID: u8 = 0x03;
+const BCD_DEVICE: u16 = 0x0100;
+
+const DESCRIPTOR_DEVICE: u8 = 0x01;
+const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_STRING: u8 = 0x03;
+const DESCRIPTOR_INTERFACE: u8 = 0x04;
+const DESCRIPTOR_ENDPOINT: u8 = 0x05;
+const DESCRIPTOR_HID: u8 = 0x21;
+const DESCRIPTOR_REPORT: u8 = 0x22;
+
+const REQUEST_GET_STATUS: u8 = 0x00;
+const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+const REQUEST_SET_FEATURE: u8 = 0x03;
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+const REQUEST_GET_CONFIGURATION: u8 = 0x08;
+const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+const REQUEST_GET_INTERFACE: u8 = 0x0A;
+const REQUEST_SET_INTERFACE: u8 = 0x0B;
+const HID_REQUEST_GET_IDLE: u8 = 0x02;
+const HID_REQUEST_SET_IDLE: u8 = 0x0A;
+const HID_REQUEST_SET_PROTOCOL: u8 = 0x0B;
+
+/// bmRequestType values, i.e, direction | type | recipient
+const IN_STANDARD_DEVICE: u8 = 0x80;
+const IN_STANDARD_INTERFACE: u8 = 0x81;
+const IN_STANDARD_ENDPOINT: u8 = 0x82;
+const IN_CLASS_INTERFACE: u8 = 0xA1;
+const OUT_STANDARD_DEVICE: u8 = 0x00;
+const OUT_STANDARD_INTERFACE: u8 = 0x01;
+const OUT_STANDARD_ENDPOINT: u8 = 0x02;
+const OUT_CLASS_INTERFACE: u8 = 0x21;
+
+const STRING_MANUFACTURER: u8 = 1;
+const STRING_PRODUCT: u8 = 2;
+const STRING_SERIAL: u8 = 3;
+/// The language ID of US English, the only language of the string descriptors
+const LANGID_EN_US: u16 = 0x0409;
+
+/// A setup packet, starting a control transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl From<[u8; 8]> for SetupPacket {
+    fn from(bytes: [u8; 8]) -> Self {
+        SetupPacket {
+            request_type: bytes[0],
+            request: bytes[1],
+            value: u16::from_le_bytes([bytes[2], bytes[3]]),
+            index: u16::from_le_bytes([bytes[4], bytes[5]]),
+            length: u16::from_le_bytes([bytes[6], bytes[7]]),
+        }
+    }
+}
+
+/// Handles a control request to the default endpoint, returning the data stage of IN
+/// requests (empty for OUT requests), or `None` if the endpoint should stall.
+pub fn handle_control_request(setup: &SetupPacket, identity: &HidIdentity) -> Option<Vec<u8>> {
+    let [descriptor_type, descriptor_index] = setup.value.to_be_bytes();
+    let mut response = match (setup.request_type, setup.request) {
+        (IN_STANDARD_DEVICE, REQUEST_GET_DESCRIPTOR) => match descriptor_type {
+            DESCRIPTOR_DEVICE => device_descriptor(identity),
+            DESCRIPTOR_CONFIGURATION => configuration_descriptor(),
+            DESCRIPTOR_STRING => match string_descriptor(descriptor_index, identity) {
+                Some(descriptor) => descriptor,
+                None => return stall(setup),
+            },
+            _ => return stall(setup),
+        },
+        (IN_STANDARD_INTERFACE, REQUEST_GET_DESCRIPTOR) => match descriptor_type {
+            DESCRIPTOR_HID => hid_descriptor(),
+            DESCRIPTOR_REPORT => CTAP_REPORT_DESCRIPTOR.to_vec(),
+            _ => return stall(setup),
+        },
+        (IN_STANDARD_DEVICE | IN_STANDARD_INTERFACE | IN_STANDARD_ENDPOINT, REQUEST_GET_STATUS) => {
+            vec![0, 0]
+        }
+        (IN_STANDARD_DEVICE, REQUEST_GET_CONFIGURATION) => vec![1],
+        (IN_STANDARD_INTERFACE, REQUEST_GET_INTERFACE) => vec![0],
+        (IN_CLASS_INTERFACE, HID_REQUEST_GET_IDLE) => vec![0],
+        (OUT_STANDARD_DEVICE, REQUEST_SET_CONFIGURATION) => {
+            debug!(configuration = setup.value, "Configuration was set");
+            vec![]
+        }
+        (OUT_STANDARD_INTERFACE, REQUEST_SET_INTERFACE)
+        | (OUT_STANDARD_DEVICE, REQUEST_CLEAR_FEATURE)
+        | (OUT_STANDARD_DEVICE, REQUEST_SET_FEATURE)
+        | (OUT_STANDARD_ENDPOINT, REQUEST_CLEAR_FEATURE)
+        | (OUT_CLASS_INTERFACE, HID_REQUEST_SET_IDLE)
+        | (OUT_CLASS_INTERFACE, HID_REQUEST_SET_PROTOCOL) => vec![],
+        _ => return stall(setup),
+    };
+    response.truncate(setup.length as usize);
+    Some(response)
+}
+
+fn stall(setup: &SetupPacket) -> Option<Vec<u8>> {
+    warn!(?setup, "Stalling an unsupported control request");
+    None
+}
+
+fn device_descriptor(identity: &HidIdentity) -> Vec<u8> {
+    let mut descriptor = vec![18, DESCRIPTOR_DEVICE];
+    // bcdUSB
+    descriptor.extend_from_slice(&0x0200u16.to_le_bytes());
+    // class, subclass and protocol are defined by the interface, bMaxPacketSize0
+    descriptor.extend_from_slice(&[0, 0, 0, HID_REPORT_SIZE]);
+    descriptor.extend_from_slice(&identity.vendor_id.to_le_bytes());
+    descriptor.extend_from_slice(&identity.product_id.to_le_bytes());
+    descriptor.extend_from_slice(&BCD_DEVICE.to_le_bytes());
+    descriptor.extend_from_slice(&[STRING_MANUFACTURER, STRING_PRODUCT, STRING_SERIAL]);
+    // bNumConfigurations
+    descriptor.push(1);
+    descriptor
+}
+
+/// The HID descriptor, which is also part of the configuration descriptor
+fn hid_descriptor() -> Vec<u8> {
+    let mut descriptor = vec![9, DESCRIPTOR_HID];
+    // bcdHID
+    descriptor.extend_from_slice(&0x0111u16.to_le_bytes());
+    // bCountryCode, bNumDescriptors
+    descriptor.extend_from_slice(&[0, 1, DESCRIPTOR_REPORT]);
+    descriptor.extend_from_slice(&(CTAP_REPORT_DESCRIPTOR.len() as u16).to_le_bytes());
+    descriptor
+}
+
+fn endpoint_descriptor(address: u8) -> [u8; 7] {
+    let [size_low, size_high] = (HID_REPORT_SIZE as u16).to_le_bytes();
+    // bmAttributes = interrupt transfers
+    [
+        7,
+        DESCRIPTOR_ENDPOINT,
+        address,
+        0x03,
+        size_low,
+        size_high,
+        EP_INTERVAL,
+    ]
+}
+
+/// The configuration descriptor, followed by the descriptors of the single interface
+/// and its endpoints
+fn configuration_descriptor() -> Vec<u8> {
+    let interface = [9, DESCRIPTOR_INTERFACE, 0, 0, 2, USB_CLASS_HID, 0, 0, 0];
+    let mut rest = interface.to_vec();
+    rest.extend(hid_descriptor());
+    rest.extend(endpoint_descriptor(EP_IN));
+    rest.extend(endpoint_descriptor(EP_OUT));
+
+    let mut descriptor = vec![9, DESCRIPTOR_CONFIGURATION];
+    descriptor.extend_from_slice(&(9 + rest.len() as u16).to_le_bytes());
+    // bNumInterfaces, bConfigurationValue, iConfiguration, bmAttributes (bus powered),
+    // bMaxPower (in 2mA units)
+    descriptor.extend_from_slice(&[1, 1, 0, 0x80, 50]);
+    descriptor.extend(rest);
+    descriptor
+}
+
+fn string_descriptor(index: u8, identity: &HidIdentity) -> Option<Vec<u8>> {
+    let string = match index {
+        0 => {
+            let [low, high] = LANGID_EN_US.to_le_bytes();
+            return Some(vec![4, DESCRIPTOR_STRING, low, high]);
+        }
+        STRING_MANUFACTURER => "softauth",
+        STRING_PRODUCT => &identity.name,
+        STRING_SERIAL => env!("CARGO_PKG_VERSION"),
+        _ => return None,
+    };
+    let mut descriptor = vec![0, DESCRIPTOR_STRING];
+    descriptor.extend(string.encode_utf16().flat_map(u16::to_le_bytes));
+    descriptor[0] = descriptor.len() as u8;
+    Some(descriptor)
+}
+
+/// Describes the device in OP_REP_DEVLIST and OP_REP_IMPORT
+pub fn usbip_device(identity: &HidIdentity) -> UsbDevice {
+    let mut path = [0u8; 256];
+    let sys_path = format!("/sys/devices/softauth/usb{}/{}", BUS_NUM, BUS_ID);
+    path[..sys_path.len()].copy_from_slice(sys_path.as_bytes());
+    let mut busid = [0u8; BUS_ID_SIZE];
+    busid[..BUS_ID.len()].copy_from_slice(BUS_ID.as_bytes());
+    UsbDevice {
+        path,
+        busid,
+        busnum: BUS_NUM.into(),
+        devnum: DEV_NUM.into(),
+        speed: USB_SPEED_FULL.into(),
+        id_vendor: identity.vendor_id.into(),
+        id_product: identity.product_id.into(),
+        bcd_device: BCD_DEVICE.into(),
+        device_class: 0,
+        device_subclass: 0,
+        device_protocol: 0,
+        configuration_value: 1,
+        num_configurations: 1,
+        num_interfaces: 1,
+    }
+}
+
+/// Describes the HID interface in OP_REP_DEVLIST
+pub fn usbip_interface() -> UsbInterface {
+    UsbInterface {
+        interface_class: USB_CLASS_HID,
+        interface_subclass: 0,
+        interface_protocol: 0,
+        padding: 0,
+    }
+}
+
+/// The devid of URBs targeting the device
+pub fn usbip_devid() -> u32 {
+    BUS_NUM << 16 | DEV_NUM
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_descriptor(request_type: u8, descriptor_type: u8, index: u8) -> Option<Vec<u8>> {
+        let setup = SetupPacket {
+            request_type,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: u16::from_be_bytes([descriptor_type, index]),
+            index: 0,
+            length: 0xff,
+        };
+        handle_control_request(&setup, &HidIdentity::default())
+    }
+
+    #[test]
+    fn test_setup_packet() {
+        let setup = SetupPacket::from([0x81, 0x06, 0x00, 0x22, 0x00, 0x00, 0x22, 0x00]);
+        assert_eq!(setup.request_type, 0x81);
+        assert_eq!(setup.value, 0x2200);
+        assert_eq!(setup.length, 0x22);
+    }
+
+    #[test]
+    fn test_descriptors() {
+        let device = get_descriptor(0x80, DESCRIPTOR_DEVICE, 0).unwrap();
+        assert_eq!(device.len(), 18);
+        assert_eq!(&device[8..12], &[0x39, 0x05, 0x39, 0x05]);
+
+        let configuration = get_descriptor(0x80, DESCRIPTOR_CONFIGURATION, 0).unwrap();
+        assert_eq!(configuration.len(), 41);
+        assert_eq!(&configuration[2..4], &[41, 0]);
+        assert_eq!(&configuration[27..30], &[7, DESCRIPTOR_ENDPOINT, EP_IN]);
+
+        let report = get_descriptor(0x81, DESCRIPTOR_REPORT, 0).unwrap();
+        assert_eq!(report, CTAP_REPORT_DESCRIPTOR);
+
+        let product = get_descriptor(0x80, DESCRIPTOR_STRING, STRING_PRODUCT).unwrap();
+        assert_eq!(
+            product[0] as usize,
+            2 + 2 * HidIdentity::default().name.len()
+        );
+        assert_eq!(&product[2..4], &[b'S', 0]);
+
+        assert_eq!(get_descriptor(0x80, DESCRIPTOR_STRING, 9), None);
+    }
+
+    #[test]
+    fn test_truncated_descriptor() {
+        let setup = SetupPacket::from([0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0x09, 0x00]);
+        let configuration = handle_control_request(&setup, &HidIdentity::default()).unwrap();
+        assert_eq!(configuration.len(), 9);
+    }
+
+    #[test]
+    fn test_unsupported_request() {
+        // GET_REPORT isn't supported, reports only use the interrupt endpoints
+        let setup = SetupPacket::from([0xA1, 0x01, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00]);
+        assert_eq!(
+            handle_control_request(&setup, &HidIdentity::default()),
+            None
+        );
+    }
+}
@@ -0,0 +1,8 @@
+//! A [USB/IP](https://docs.kernel.org/usb/usbip_protocol.html) server exporting the
+//! authenticator as a full-speed USB HID device, which the `vhci-hcd` driver attaches as if
+//! it were plugged in. Unlike UHID, the device is enumerated by the kernel's USB stack, and
+//! the server may run in a container or on another machine.
+
+pub(crate) mod device;
+pub(crate) mod protocol;
+pub(crate) mod transport;
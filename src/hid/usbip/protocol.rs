@@ -0,0 +1,207 @@
+//! Messages of the USB/IP protocol, all of which are big endian, see
+//! https://docs.kernel.org/usb/usbip_protocol.html
+
+use zerocopy::{AsBytes, BigEndian, FromBytes, Unaligned, I32, U16, U32};
+
+/// The protocol version sent in operation headers
+pub const USBIP_VERSION: u16 = 0x0111;
+
+/// Requests the list of exported devices
+pub const OP_REQ_DEVLIST: u16 = 0x8005;
+pub const OP_REP_DEVLIST: u16 = 0x0005;
+
+/// Requests to attach an exported device, after which the connection carries URBs
+pub const OP_REQ_IMPORT: u16 = 0x8003;
+pub const OP_REP_IMPORT: u16 = 0x0003;
+
+pub const USBIP_CMD_SUBMIT: u32 = 1;
+pub const USBIP_CMD_UNLINK: u32 = 2;
+pub const USBIP_RET_SUBMIT: u32 = 3;
+pub const USBIP_RET_UNLINK: u32 = 4;
+
+/// Direction of a URB, from the host's point of view
+pub const USBIP_DIR_OUT: u32 = 0;
+pub const USBIP_DIR_IN: u32 = 1;
+
+/// Status of an operation reply
+pub const ST_OK: u32 = 0;
+pub const ST_NA: u32 = 1;
+
+/// `enum usb_device_speed` value of a full-speed device
+pub const USB_SPEED_FULL: u32 = 2;
+
+/// Size of a bus ID, NUL padded
+pub const BUS_ID_SIZE: usize = 32;
+
+/// Header of the operations exchanged before a device is imported
+#[repr(C)]
+#[derive(FromBytes, AsBytes, Unaligned, Debug, Clone, Copy)]
+pub struct OpHeader {
+    pub version: U16<BigEndian>,
+    pub code: U16<BigEndian>,
+    pub status: U32<BigEndian>,
+}
+
+impl OpHeader {
+    pub fn new(code: u16, status: u32) -> Self {
+        OpHeader {
+            version: USBIP_VERSION.into(),
+            code: code.into(),
+            status: status.into(),
+        }
+    }
+}
+
+/// Description of an exported device, in OP_REP_DEVLIST and OP_REP_IMPORT
+#[repr(C)]
+#[derive(FromBytes, AsBytes, Unaligned, Debug, Clone, Copy)]
+pub struct UsbDevice {
+    pub path: [u8; 256],
+    pub busid: [u8; BUS_ID_SIZE],
+    pub busnum: U32<BigEndian>,
+    pub devnum: U32<BigEndian>,
+    pub speed: U32<BigEndian>,
+    pub id_vendor: U16<BigEndian>,
+    pub id_product: U16<BigEndian>,
+    pub bcd_device: U16<BigEndian>,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8,
+}
+
+/// Description of an interface of an exported device, only part of OP_REP_DEVLIST
+#[repr(C)]
+#[derive(FromBytes, AsBytes, Unaligned, Debug, Clone, Copy)]
+pub struct UsbInterface {
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+    pub padding: u8,
+}
+
+/// Header common to all URB commands and replies
+#[repr(C)]
+#[derive(FromBytes, AsBytes, Unaligned, Debug, Clone, Copy)]
+pub struct HeaderBasic {
+    pub command: U32<BigEndian>,
+    pub seqnum: U32<BigEndian>,
+    pub devid: U32<BigEndian>,
+    pub direction: U32<BigEndian>,
+    pub ep: U32<BigEndian>,
+}
+
+impl HeaderBasic {
+    /// The header of a reply, whose devid, direction and ep are left as 0
+    pub fn reply(command: u32, seqnum: u32) -> Self {
+        HeaderBasic {
+            command: command.into(),
+            seqnum: seqnum.into(),
+            devid: 0.into(),
+            direction: 0.into(),
+            ep: 0.into(),
+        }
+    }
+}
+
+/// Follows a [HeaderBasic] of USBIP_CMD_SUBMIT, and precedes the transfer buffer of OUT URBs
+#[repr(C)]
+#[derive(FromBytes, AsBytes, Unaligned, Debug, Clone, Copy)]
+pub struct CmdSubmit {
+    pub transfer_flags: U32<BigEndian>,
+    pub transfer_buffer_length: I32<BigEndian>,
+    pub start_frame: I32<BigEndian>,
+    pub number_of_packets: I32<BigEndian>,
+    pub interval: I32<BigEndian>,
+    /// The setup packet of control transfers, whose fields are little endian
+    pub setup: [u8; 8],
+}
+
+/// Follows a [HeaderBasic] of USBIP_RET_SUBMIT, and precedes the transfer buffer of IN URBs
+#[repr(C)]
+#[derive(FromBytes, AsBytes, Unaligned, Debug, Clone, Copy)]
+pub struct RetSubmit {
+    pub status: I32<BigEndian>,
+    pub actual_length: I32<BigEndian>,
+    pub start_frame: I32<BigEndian>,
+    pub number_of_packets: I32<BigEndian>,
+    pub error_count: I32<BigEndian>,
+    pub padding: [u8; 8],
+}
+
+impl RetSubmit {
+    pub fn new(status: i32, actual_length: usize) -> Self {
+        RetSubmit {
+            status: status.into(),
+            actual_length: (actual_length as i32).into(),
+            start_frame: 0.into(),
+            // not an isochronous transfer
+            number_of_packets: (-1).into(),
+            error_count: 0.into(),
+            padding: [0; 8],
+        }
+    }
+}
+
+/// Follows a [HeaderBasic] of USBIP_CMD_UNLINK
+#[repr(C)]
+#[derive(FromBytes, AsBytes, Unaligned, Debug, Clone, Copy)]
+pub struct CmdUnlink {
+    pub unlink_seqnum: U32<BigEndian>,
+    pub padding: [u8; 24],
+}
+
+/// Follows a [HeaderBasic] of USBIP_RET_UNLINK
+#[repr(C)]
+#[derive(FromBytes, AsBytes, Unaligned, Debug, Clone, Copy)]
+pub struct RetUnlink {
+    pub status: I32<BigEndian>,
+    pub padding: [u8; 24],
+}
+
+impl RetUnlink {
+    pub fn new(status: i32) -> Self {
+        RetUnlink {
+            status: status.into(),
+            padding: [0; 24],
+        }
+    }
+}
+
+/// Parses a NUL padded bus ID
+pub fn parse_bus_id(bytes: &[u8; BUS_ID_SIZE]) -> &[u8] {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(BUS_ID_SIZE);
+    &bytes[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_sizes() {
+        assert_eq!(std::mem::size_of::<OpHeader>(), 8);
+        assert_eq!(std::mem::size_of::<UsbDevice>(), 312);
+        assert_eq!(std::mem::size_of::<UsbInterface>(), 4);
+        // every URB command and reply has a 48 bytes header
+        assert_eq!(std::mem::size_of::<HeaderBasic>(), 20);
+        assert_eq!(std::mem::size_of::<CmdSubmit>(), 28);
+        assert_eq!(std::mem::size_of::<RetSubmit>(), 28);
+        assert_eq!(std::mem::size_of::<CmdUnlink>(), 28);
+        assert_eq!(std::mem::size_of::<RetUnlink>(), 28);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let header = OpHeader::new(OP_REP_IMPORT, ST_NA);
+        assert_eq!(header.as_bytes(), &[0x01, 0x11, 0x00, 0x03, 0, 0, 0, 1]);
+        let ret = RetUnlink::new(-104);
+        assert_eq!(&ret.as_bytes()[..4], &[0xff, 0xff, 0xff, 0x98]);
+
+        let mut bus_id = [0u8; BUS_ID_SIZE];
+        bus_id[..3].copy_from_slice(b"1-1");
+        assert_eq!(parse_bus_id(&bus_id), b"1-1");
+    }
+}
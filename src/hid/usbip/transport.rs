@@ -0,0 +1,604 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream, ToSocketAddrs,
+    },
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, error, trace, warn};
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::hid::{
+    descriptor::HidIdentity,
+    packet::HID_REPORT_SIZE,
+    transport::{HIDTransport, HIDTransportEvent, TransportError},
+};
+
+use super::{
+    device::{
+        handle_control_request, usbip_device, usbip_devid, usbip_interface, SetupPacket, BUS_ID,
+        EP_IN, EP_OUT,
+    },
+    protocol::*,
+};
+
+/// Bounds the transfer buffer of OUT URBs, which only carry setup data or a single report
+const MAX_TRANSFER_LENGTH: usize = 4096;
+
+/// URB statuses, as negated errno values
+const EPIPE: i32 = 32;
+const ECONNRESET: i32 = 104;
+
+/// State shared between the transport and its connection tasks
+struct Shared {
+    /// Input report sender of the connection which imported the device, if any
+    input: Mutex<Option<UnboundedSender<Vec<u8>>>>,
+    events: UnboundedSender<Result<HIDTransportEvent, TransportError>>,
+    identity: HidIdentity,
+}
+
+/// A HID transport exporting the authenticator as a USB HID device over USB/IP, such that
+/// it can be attached with `usbip attach -r <host> -b 1-1`.
+///
+/// The host is considered to have opened the device once a client imports it, and to have
+/// closed it once that client disconnects. Only one client may import the device at a time.
+pub struct UsbIpTransport {
+    local_addr: SocketAddr,
+    shared: Arc<Shared>,
+    events: UnboundedReceiver<Result<HIDTransportEvent, TransportError>>,
+    /// Stops accepting and serving clients once the transport is closed or dropped
+    cancel: Option<DropGuard>,
+}
+
+impl UsbIpTransport {
+    /// Listens for USB/IP clients on the given address, exporting a device with the given
+    /// identity. Must be called within a tokio runtime.
+    pub async fn new(address: impl ToSocketAddrs, identity: HidIdentity) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+
+        let (event_send, event_recv) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            input: Mutex::new(None),
+            events: event_send,
+            identity,
+        });
+        let cancel = CancellationToken::new();
+        tokio::spawn(accept_clients(listener, shared.clone(), cancel.clone()));
+
+        Ok(Self {
+            local_addr,
+            shared,
+            events: event_recv,
+            cancel: Some(cancel.drop_guard()),
+        })
+    }
+
+    /// The address the server listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops serving clients, can be invoked more than once
+    fn shutdown(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            drop(cancel);
+            self.shared.input.lock().unwrap().take();
+        }
+    }
+}
+
+impl Drop for UsbIpTransport {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+async fn accept_clients(listener: TcpListener, shared: Arc<Shared>, cancel: CancellationToken) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = cancel.cancelled() => return,
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Couldn't accept a USB/IP connection: {}", e);
+                    let _ = shared.events.send(Err(e.into()));
+                    return;
+                }
+            },
+        };
+        debug!(%peer, "Accepted a USB/IP connection");
+        let shared = shared.clone();
+        let cancel = cancel.clone();
+        // `usbip list` connects while another client may have imported the device
+        tokio::spawn(async move {
+            let res = tokio::select! {
+                _ = cancel.cancelled() => Ok(()),
+                res = serve_client(stream, &shared) => res,
+            };
+            match res {
+                Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                    warn!(%peer, "USB/IP connection failed: {}", e)
+                }
+                _ => debug!(%peer, "USB/IP client disconnected"),
+            }
+        });
+    }
+}
+
+/// Serves the operations preceding an import, then the URBs of the imported device
+async fn serve_client(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    loop {
+        let mut header = OpHeader::new_zeroed();
+        stream.read_exact(header.as_bytes_mut()).await?;
+        match header.code.get() {
+            OP_REQ_DEVLIST => {
+                let mut reply = OpHeader::new(OP_REP_DEVLIST, ST_OK).as_bytes().to_vec();
+                reply.extend_from_slice(&1u32.to_be_bytes());
+                reply.extend_from_slice(usbip_device(&shared.identity).as_bytes());
+                reply.extend_from_slice(usbip_interface().as_bytes());
+                stream.write_all(&reply).await?;
+            }
+            OP_REQ_IMPORT => {
+                let mut bus_id = [0u8; BUS_ID_SIZE];
+                stream.read_exact(&mut bus_id).await?;
+                let (report_send, report_recv) = mpsc::unbounded_channel();
+                let imported = parse_bus_id(&bus_id) == BUS_ID.as_bytes() && {
+                    let mut input = shared.input.lock().unwrap();
+                    input.is_none() && input.replace(report_send).is_none()
+                };
+                if !imported {
+                    warn!(bus_id = ?parse_bus_id(&bus_id), "Refusing to import a device");
+                    let reply = OpHeader::new(OP_REP_IMPORT, ST_NA);
+                    stream.write_all(reply.as_bytes()).await?;
+                    continue;
+                }
+
+                let mut reply = OpHeader::new(OP_REP_IMPORT, ST_OK).as_bytes().to_vec();
+                reply.extend_from_slice(usbip_device(&shared.identity).as_bytes());
+                stream.write_all(&reply).await?;
+                debug!("The device was imported");
+                let _ = shared.events.send(Ok(HIDTransportEvent::Opened));
+
+                let res = serve_urbs(stream, report_recv, shared).await;
+                shared.input.lock().unwrap().take();
+                let _ = shared.events.send(Ok(HIDTransportEvent::Closed));
+                return res;
+            }
+            code => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown USB/IP operation {:#06x}", code),
+                ))
+            }
+        }
+    }
+}
+
+/// A URB command sent by the client
+#[derive(Debug)]
+enum UrbCommand {
+    Submit {
+        header: HeaderBasic,
+        submit: CmdSubmit,
+        /// The transfer buffer of OUT URBs
+        data: Vec<u8>,
+    },
+    Unlink {
+        header: HeaderBasic,
+        unlink: CmdUnlink,
+    },
+}
+
+async fn read_urb_command(reader: &mut OwnedReadHalf) -> io::Result<UrbCommand> {
+    let mut header = HeaderBasic::new_zeroed();
+    reader.read_exact(header.as_bytes_mut()).await?;
+    match header.command.get() {
+        USBIP_CMD_SUBMIT => {
+            let mut submit = CmdSubmit::new_zeroed();
+            reader.read_exact(submit.as_bytes_mut()).await?;
+            let mut data = Vec::new();
+            if header.direction.get() == USBIP_DIR_OUT {
+                let length = usize::try_from(submit.transfer_buffer_length.get())
+                    .ok()
+                    .filter(|&length| length <= MAX_TRANSFER_LENGTH)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Invalid transfer length")
+                    })?;
+                data.resize(length, 0);
+                reader.read_exact(&mut data).await?;
+            }
+            Ok(UrbCommand::Submit {
+                header,
+                submit,
+                data,
+            })
+        }
+        USBIP_CMD_UNLINK => {
+            let mut unlink = CmdUnlink::new_zeroed();
+            reader.read_exact(unlink.as_bytes_mut()).await?;
+            Ok(UrbCommand::Unlink { header, unlink })
+        }
+        command => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown URB command {}", command),
+        )),
+    }
+}
+
+/// Completes URBs until the client disconnects. Interrupt IN URBs are held until an input
+/// report is available, input reports are queued until an IN URB is submitted.
+async fn serve_urbs(
+    stream: TcpStream,
+    mut report_recv: UnboundedReceiver<Vec<u8>>,
+    shared: &Shared,
+) -> io::Result<()> {
+    let (mut reader, writer) = stream.into_split();
+    // reading isn't cancel safe, thus URBs are read apart from processing them
+    let (urb_send, mut urb_recv) = mpsc::unbounded_channel();
+    let read_urbs = async move {
+        loop {
+            let urb = read_urb_command(&mut reader).await?;
+            if urb_send.send(urb).is_err() {
+                return Ok::<(), io::Error>(());
+            }
+        }
+    };
+
+    let process_urbs = async {
+        let mut device = ImportedDevice {
+            writer,
+            shared,
+            pending_in: VecDeque::new(),
+            queued_reports: VecDeque::new(),
+        };
+        loop {
+            tokio::select! {
+                urb = urb_recv.recv() => match urb {
+                    Some(urb) => device.handle_urb(urb).await?,
+                    None => return Ok(()),
+                },
+                report = report_recv.recv() => match report {
+                    Some(report) => device.handle_input_report(report).await?,
+                    // the transport was shut down
+                    None => return Ok(()),
+                },
+            }
+        }
+    };
+
+    tokio::select! {
+        res = read_urbs => res,
+        res = process_urbs => res,
+    }
+}
+
+/// The state of the device while imported by a client
+struct ImportedDevice<'a> {
+    writer: OwnedWriteHalf,
+    shared: &'a Shared,
+    /// Sequence numbers of the interrupt IN URBs waiting for an input report
+    pending_in: VecDeque<u32>,
+    /// Input reports waiting for an interrupt IN URB
+    queued_reports: VecDeque<Vec<u8>>,
+}
+
+impl ImportedDevice<'_> {
+    async fn handle_urb(&mut self, urb: UrbCommand) -> io::Result<()> {
+        let (header, submit, data) = match urb {
+            UrbCommand::Submit {
+                header,
+                submit,
+                data,
+            } => (header, submit, data),
+            UrbCommand::Unlink { header, unlink } => {
+                return self.handle_unlink(header, unlink).await
+            }
+        };
+        trace!(?header, ?submit, "Received a URB");
+        if header.devid.get() != usbip_devid() {
+            warn!(
+                devid = header.devid.get(),
+                "Received a URB for another device"
+            );
+        }
+        let seqnum = header.seqnum.get();
+        let ep = header.ep.get();
+        match header.direction.get() {
+            direction if ep == 0 => {
+                let setup = SetupPacket::from(submit.setup);
+                match handle_control_request(&setup, &self.shared.identity) {
+                    Some(response) if direction == USBIP_DIR_IN => {
+                        self.write_ret_submit(seqnum, 0, &response, response.len())
+                            .await
+                    }
+                    Some(_) => self.write_ret_submit(seqnum, 0, &[], data.len()).await,
+                    None => self.write_ret_submit(seqnum, -EPIPE, &[], 0).await,
+                }
+            }
+            USBIP_DIR_OUT if ep == (EP_OUT & 0x7f) as u32 => {
+                let length = data.len();
+                if length == HID_REPORT_SIZE as usize {
+                    let event = HIDTransportEvent::Report(data);
+                    let _ = self.shared.events.send(Ok(event));
+                } else {
+                    warn!(length, "Dropping an output report of unexpected size");
+                }
+                self.write_ret_submit(seqnum, 0, &[], length).await
+            }
+            USBIP_DIR_IN if ep == (EP_IN & 0x7f) as u32 => match self.queued_reports.pop_front() {
+                Some(report) => {
+                    self.write_ret_submit(seqnum, 0, &report, report.len())
+                        .await
+                }
+                None => {
+                    self.pending_in.push_back(seqnum);
+                    Ok(())
+                }
+            },
+            _ => {
+                warn!(ep, "Stalling a URB for an unknown endpoint");
+                self.write_ret_submit(seqnum, -EPIPE, &[], 0).await
+            }
+        }
+    }
+
+    async fn handle_unlink(&mut self, header: HeaderBasic, unlink: CmdUnlink) -> io::Result<()> {
+        let unlink_seqnum = unlink.unlink_seqnum.get();
+        let pending = self
+            .pending_in
+            .iter()
+            .position(|&seqnum| seqnum == unlink_seqnum);
+        // a URB which was already completed can't be unlinked anymore
+        let status = match pending {
+            Some(position) => {
+                self.pending_in.remove(position);
+                -ECONNRESET
+            }
+            None => 0,
+        };
+        trace!(unlink_seqnum, status, "Unlinked a URB");
+        let mut reply = HeaderBasic::reply(USBIP_RET_UNLINK, header.seqnum.get())
+            .as_bytes()
+            .to_vec();
+        reply.extend_from_slice(RetUnlink::new(status).as_bytes());
+        self.writer.write_all(&reply).await
+    }
+
+    async fn handle_input_report(&mut self, report: Vec<u8>) -> io::Result<()> {
+        match self.pending_in.pop_front() {
+            Some(seqnum) => {
+                self.write_ret_submit(seqnum, 0, &report, report.len())
+                    .await
+            }
+            None => {
+                self.queued_reports.push_back(report);
+                Ok(())
+            }
+        }
+    }
+
+    async fn write_ret_submit(
+        &mut self,
+        seqnum: u32,
+        status: i32,
+        data: &[u8],
+        actual_length: usize,
+    ) -> io::Result<()> {
+        let mut reply = HeaderBasic::reply(USBIP_RET_SUBMIT, seqnum)
+            .as_bytes()
+            .to_vec();
+        reply.extend_from_slice(RetSubmit::new(status, actual_length).as_bytes());
+        reply.extend_from_slice(data);
+        self.writer.write_all(&reply).await
+    }
+}
+
+impl futures::Stream for UsbIpTransport {
+    type Item = Result<HIDTransportEvent, TransportError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl futures::Sink<Vec<u8>> for UsbIpTransport {
+    type Error = TransportError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        if item.len() != HID_REPORT_SIZE as usize {
+            return Err(anyhow!(
+                "Input report of {} bytes doesn't fit the HID report size",
+                item.len()
+            )
+            .into());
+        }
+        match &*self.shared.input.lock().unwrap() {
+            // a client that disconnected meanwhile is removed by its connection task
+            Some(report_send) => {
+                let _ = report_send.send(item);
+            }
+            None => warn!("Dropping an input report, as the device isn't imported"),
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.shutdown();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl HIDTransport for UsbIpTransport {}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+
+    use super::*;
+
+    async fn next_event(transport: &mut UsbIpTransport) -> HIDTransportEvent {
+        transport.next().await.unwrap().unwrap()
+    }
+
+    async fn import(transport: &UsbIpTransport) -> (TcpStream, OpHeader) {
+        let mut client = TcpStream::connect(transport.local_addr()).await.unwrap();
+        let mut request = OpHeader::new(OP_REQ_IMPORT, 0).as_bytes().to_vec();
+        let mut bus_id = [0u8; BUS_ID_SIZE];
+        bus_id[..BUS_ID.len()].copy_from_slice(BUS_ID.as_bytes());
+        request.extend_from_slice(&bus_id);
+        client.write_all(&request).await.unwrap();
+        let mut reply = OpHeader::new_zeroed();
+        client.read_exact(reply.as_bytes_mut()).await.unwrap();
+        if reply.status.get() == ST_OK {
+            let mut device = UsbDevice::new_zeroed();
+            client.read_exact(device.as_bytes_mut()).await.unwrap();
+            assert_eq!(device.busid, bus_id);
+        }
+        (client, reply)
+    }
+
+    fn urb_header(command: u32, seqnum: u32, direction: u32, ep: u32) -> HeaderBasic {
+        HeaderBasic {
+            command: command.into(),
+            seqnum: seqnum.into(),
+            devid: usbip_devid().into(),
+            direction: direction.into(),
+            ep: ep.into(),
+        }
+    }
+
+    async fn submit(client: &mut TcpStream, seqnum: u32, ep: u32, direction: u32, data: &[u8]) {
+        let header = urb_header(USBIP_CMD_SUBMIT, seqnum, direction, ep);
+        let mut submit = CmdSubmit::new_zeroed();
+        submit.transfer_buffer_length = (HID_REPORT_SIZE as i32).into();
+        let mut request = header.as_bytes().to_vec();
+        request.extend_from_slice(submit.as_bytes());
+        request.extend_from_slice(data);
+        client.write_all(&request).await.unwrap();
+    }
+
+    async fn read_reply<T: FromBytes + AsBytes>(client: &mut TcpStream) -> (HeaderBasic, T) {
+        let mut header = HeaderBasic::new_zeroed();
+        client.read_exact(header.as_bytes_mut()).await.unwrap();
+        let mut reply = T::new_zeroed();
+        client.read_exact(reply.as_bytes_mut()).await.unwrap();
+        (header, reply)
+    }
+
+    #[tokio::test]
+    async fn test_devlist() {
+        let transport = UsbIpTransport::new("127.0.0.1:0", HidIdentity::default())
+            .await
+            .unwrap();
+        let mut client = TcpStream::connect(transport.local_addr()).await.unwrap();
+        let request = OpHeader::new(OP_REQ_DEVLIST, 0);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut reply = OpHeader::new_zeroed();
+        client.read_exact(reply.as_bytes_mut()).await.unwrap();
+        assert_eq!(reply.code.get(), OP_REP_DEVLIST);
+        assert_eq!(client.read_u32().await.unwrap(), 1);
+        let mut device = UsbDevice::new_zeroed();
+        client.read_exact(device.as_bytes_mut()).await.unwrap();
+        assert_eq!(parse_bus_id(&device.busid), BUS_ID.as_bytes());
+        assert_eq!(device.num_interfaces, 1);
+    }
+
+    #[tokio::test]
+    async fn test_import_and_urbs() {
+        let mut transport = UsbIpTransport::new("127.0.0.1:0", HidIdentity::default())
+            .await
+            .unwrap();
+        let (mut client, reply) = import(&transport).await;
+        assert_eq!(reply.status.get(), ST_OK);
+        assert_eq!(next_event(&mut transport).await, HIDTransportEvent::Opened);
+        // only a single client may import the device
+        let (_, reply) = import(&transport).await;
+        assert_eq!(reply.status.get(), ST_NA);
+
+        let report = vec![0x42u8; HID_REPORT_SIZE as usize];
+        submit(&mut client, 1, EP_OUT as u32, USBIP_DIR_OUT, &report).await;
+        let (header, ret) = read_reply::<RetSubmit>(&mut client).await;
+        assert_eq!(header.seqnum.get(), 1);
+        assert_eq!(ret.status.get(), 0);
+        assert_eq!(
+            next_event(&mut transport).await,
+            HIDTransportEvent::Report(report.clone())
+        );
+
+        // the IN URB is completed once the authenticator sends an input report
+        submit(&mut client, 2, (EP_IN & 0x7f) as u32, USBIP_DIR_IN, &[]).await;
+        transport.send(report.clone()).await.unwrap();
+        let (header, ret) = read_reply::<RetSubmit>(&mut client).await;
+        assert_eq!(header.seqnum.get(), 2);
+        assert_eq!(ret.actual_length.get(), HID_REPORT_SIZE as i32);
+        let mut received = vec![0u8; HID_REPORT_SIZE as usize];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, report);
+
+        submit(&mut client, 3, (EP_IN & 0x7f) as u32, USBIP_DIR_IN, &[]).await;
+        let mut unlink = CmdUnlink::new_zeroed();
+        unlink.unlink_seqnum = 3.into();
+        let mut request = urb_header(USBIP_CMD_UNLINK, 4, USBIP_DIR_OUT, 0)
+            .as_bytes()
+            .to_vec();
+        request.extend_from_slice(unlink.as_bytes());
+        client.write_all(&request).await.unwrap();
+        let (header, ret) = read_reply::<RetUnlink>(&mut client).await;
+        assert_eq!(header.command.get(), USBIP_RET_UNLINK);
+        assert_eq!(header.seqnum.get(), 4);
+        assert_eq!(ret.status.get(), -ECONNRESET);
+
+        drop(client);
+        assert_eq!(next_event(&mut transport).await, HIDTransportEvent::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_control_request() {
+        let mut transport = UsbIpTransport::new("127.0.0.1:0", HidIdentity::default())
+            .await
+            .unwrap();
+        let (mut client, _) = import(&transport).await;
+        assert_eq!(next_event(&mut transport).await, HIDTransportEvent::Opened);
+
+        let mut submit = CmdSubmit::new_zeroed();
+        submit.transfer_buffer_length = 18.into();
+        // GET_DESCRIPTOR(device)
+        submit.setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let mut request = urb_header(USBIP_CMD_SUBMIT, 1, USBIP_DIR_IN, 0)
+            .as_bytes()
+            .to_vec();
+        request.extend_from_slice(submit.as_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let (_, ret) = read_reply::<RetSubmit>(&mut client).await;
+        assert_eq!(ret.status.get(), 0);
+        assert_eq!(ret.actual_length.get(), 18);
+        let mut descriptor = [0u8; 18];
+        client.read_exact(&mut descriptor).await.unwrap();
+        assert_eq!(&descriptor[..2], &[18, 0x01]);
+    }
+}
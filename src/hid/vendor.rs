@@ -0,0 +1,231 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tracing::{debug, error};
+
+use crate::authenticator::api::CTAP2Service;
+
+use super::{
+    command::{CommandType, ErrorCode, CTAPHID_VENDOR_FIRST, CTAPHID_VENDOR_LAST},
+    packet::Message,
+};
+
+/// Vendor command returning the daemon's version as a UTF-8 string, registered by the daemon
+/// rather than built in, as embedders have versions of their own
+pub const VENDOR_COMMAND_VERSION: u8 = CTAPHID_VENDOR_FIRST;
+
+/// Vendor command returning the [statistics](crate::authenticator::auth_impl::AuthenticatorStats)
+/// of the authenticator as a CBOR map
+pub const VENDOR_COMMAND_STATS: u8 = CTAPHID_VENDOR_FIRST + 1;
+
+/// Handler for [VENDOR_COMMAND_VERSION]
+pub fn version_command(_context: &VendorContext, request: Message) -> Message {
+    Message {
+        payload: env!("CARGO_PKG_VERSION").as_bytes().to_vec(),
+        ..request
+    }
+}
+
+/// Handler for [VENDOR_COMMAND_STATS]
+pub struct StatsCommand;
+
+#[async_trait]
+impl VendorCommandHandler for StatsCommand {
+    async fn handle(&self, context: &VendorContext, request: Message) -> Message {
+        match context.authenticator.stats().await {
+            Ok(stats) => {
+                let mut payload = Vec::new();
+                ciborium::ser::into_writer(&stats, &mut payload)
+                    .expect("Serializing the stats into a vector cannot fail");
+                Message { payload, ..request }
+            }
+            Err(e) => {
+                error!(?e, "Couldn't gather the authenticator stats");
+                ErrorCode::Other.to_message(request.channel_identifier)
+            }
+        }
+    }
+}
+
+/// What a vendor command handler may act upon
+pub struct VendorContext {
+    /// The authenticator the command was sent to, on behalf of the transport it was
+    /// received on
+    pub authenticator: CTAP2Service,
+}
+
+/// Handles a vendor specific CTAP-HID command, allowing management operations to be
+/// performed over the HID channel itself.
+#[async_trait]
+pub trait VendorCommandHandler: Send + Sync {
+    /// Handles a request message, returning a response message. The response is usually
+    /// sent with the same command and channel as the request, or is an error message
+    /// (see [ErrorCode::to_message])
+    async fn handle(&self, context: &VendorContext, request: Message) -> Message;
+}
+
+#[async_trait]
+impl<F> VendorCommandHandler for F
+where
+    F: Fn(&VendorContext, Message) -> Message + Send + Sync,
+{
+    async fn handle(&self, context: &VendorContext, request: Message) -> Message {
+        self(context, request)
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VendorCommandError {
+    #[error("'{0}' is not within the CTAP-HID vendor command range")]
+    NotVendorCommand(u8),
+
+    #[error("Vendor command '{0}' already has a handler")]
+    AlreadyRegistered(u8),
+}
+
+/// A registry of vendor command handlers, which is cheap to clone as handlers are shared
+#[derive(Clone)]
+pub struct VendorCommands {
+    handlers: HashMap<u8, Arc<dyn VendorCommandHandler>>,
+}
+
+impl VendorCommands {
+    pub fn new() -> Self {
+        VendorCommands {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// The registry of the built-in vendor commands
+    pub fn builtin() -> Self {
+        let mut commands = Self::new();
+        commands
+            .register(VENDOR_COMMAND_STATS, StatsCommand)
+            .expect("Built-in vendor commands must not conflict");
+        commands
+    }
+
+    /// Registers a handler for a vendor command identifier (whose MSB isn't set)
+    pub fn register(
+        &mut self,
+        command: u8,
+        handler: impl VendorCommandHandler + 'static,
+    ) -> Result<(), VendorCommandError> {
+        if !(CTAPHID_VENDOR_FIRST..=CTAPHID_VENDOR_LAST).contains(&command) {
+            return Err(VendorCommandError::NotVendorCommand(command));
+        }
+        if self.handlers.contains_key(&command) {
+            return Err(VendorCommandError::AlreadyRegistered(command));
+        }
+        self.handlers.insert(command, Arc::new(handler));
+        Ok(())
+    }
+
+    /// Handles a vendor command message, responding with an invalid command error if there's
+    /// no handler for it.
+    pub async fn handle(&self, context: &VendorContext, request: Message) -> Message {
+        let chan = request.channel_identifier;
+        let handler = match request.command {
+            Ok(CommandType::Vendor(command)) => self.handlers.get(&command),
+            _ => panic!("Message passed must be a vendor command message"),
+        };
+        match handler {
+            Some(handler) => handler.handle(context, request).await,
+            None => {
+                debug!(command = ?request.command, "No handler for vendor command");
+                ErrorCode::InvalidCmd.to_message(chan)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    use super::*;
+
+    fn vendor_message(command: u8, payload: Vec<u8>) -> Message {
+        Message {
+            channel_identifier: 1337,
+            command: Ok(CommandType::Vendor(command)),
+            payload,
+        }
+    }
+
+    fn context() -> VendorContext {
+        VendorContext {
+            authenticator: CTAP2Service::new(true),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatches_to_registered_handler() {
+        let mut commands = VendorCommands::builtin();
+        commands
+            .register(VENDOR_COMMAND_VERSION, version_command)
+            .unwrap();
+        let counter = AtomicU8::new(0);
+        commands
+            .register(0x42, move |_: &VendorContext, request: Message| Message {
+                payload: vec![counter.fetch_add(1, Ordering::SeqCst) + 1],
+                ..request
+            })
+            .unwrap();
+
+        let context = context();
+        let res = commands
+            .handle(&context, vendor_message(0x42, vec![]))
+            .await;
+        assert_eq!(res, vendor_message(0x42, vec![1]));
+        let res = commands
+            .handle(&context, vendor_message(0x42, vec![]))
+            .await;
+        assert_eq!(res, vendor_message(0x42, vec![2]));
+
+        let res = commands
+            .handle(&context, vendor_message(VENDOR_COMMAND_VERSION, vec![]))
+            .await;
+        assert_eq!(res.payload, env!("CARGO_PKG_VERSION").as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let commands = VendorCommands::builtin();
+        let res = commands
+            .handle(&context(), vendor_message(VENDOR_COMMAND_STATS, vec![]))
+            .await;
+        let stats: ciborium::value::Value = ciborium::de::from_reader(&res.payload[..]).unwrap();
+        assert_eq!(
+            stats,
+            ciborium::value::Value::Map(vec![
+                ("credentials".into(), 0.into()),
+                ("globalSignCount".into(), 0.into()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unregistered_command_is_invalid() {
+        let commands = VendorCommands::new();
+        let res = commands
+            .handle(&context(), vendor_message(0x7F, vec![1, 2, 3]))
+            .await;
+        assert_eq!(res, ErrorCode::InvalidCmd.to_message(1337));
+    }
+
+    #[test]
+    fn test_register_validates_command() {
+        let mut commands = VendorCommands::builtin();
+        let handler = |_: &VendorContext, request: Message| request;
+        assert_eq!(
+            commands.register(0x10, handler),
+            Err(VendorCommandError::NotVendorCommand(0x10))
+        );
+        assert_eq!(
+            commands.register(VENDOR_COMMAND_STATS, handler),
+            Err(VendorCommandError::AlreadyRegistered(VENDOR_COMMAND_STATS))
+        );
+    }
+}
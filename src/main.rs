@@ -1,24 +1,584 @@
 mod authenticator;
+mod ble;
+mod cable;
 mod cbor;
 mod hid;
+mod nfc;
+mod profile;
+#[cfg(test)]
+mod test_util;
 
-use tracing::{debug, info};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Parser, Subcommand,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+use uhid_virt::AsyncUHIDDevice;
+use zeroize::Zeroizing;
 
 use crate::{
-    authenticator::api::CTAP2Service,
-    hid::{linux::uhid_transport::LinuxUHIDTransport, server::CTAPServer},
+    authenticator::{
+        crypto::{recovery_phrase, seed_from_recovery_phrase, SEED_LENGTH},
+        presence::{AssumedPresenceFrontend, PresenceFrontend, TerminalFrontend},
+        storage::{
+            encrypted::{EncryptedFileStorage, KdfParams},
+            file::FileStateStore,
+            keyring::{KeyringCache, KeyringKind, DEFAULT_KEY_TIMEOUT},
+            memory::{InMemoryStateStore, InMemoryStorage},
+            passphrase::PassphraseSource,
+            secret_service::SecretServiceStorage,
+            sqlite::SqliteStorage,
+            state::{PersistentState, SecretBytes, StateError, StateStore},
+            store::Storage,
+        },
+    },
+    hid::{
+        linux::{
+            privsep::{receive_uhid_device, run_helper, DEFAULT_HELPER_SOCKET},
+            uhid_transport::LinuxUHIDTransport,
+        },
+        socket_transport::{SocketClientMode, UnixSocketTransport},
+        supervisor::{serve, supervise, Backoff},
+        usbip::transport::UsbIpTransport,
+        vendor::{version_command, VENDOR_COMMAND_VERSION},
+    },
+    nfc::vpcd::{VpcdCard, DEFAULT_VPCD_ADDRESS},
+    profile::{Profile, ProfileError, BUILTIN_PROFILES, DEFAULT_PROFILE},
 };
 
+/// A software FIDO2/U2F authenticator
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    profile: ProfileArgs,
+
+    #[command(flatten)]
+    storage: StorageArgs,
+
+    /// Disable U2F (CTAP1), only supporting CTAP2
+    #[arg(long)]
+    disable_u2f: bool,
+
+    /// Don't create a UHID device, e.g, when only serving a Unix socket
+    #[arg(long)]
+    no_uhid: bool,
+
+    /// Obtain the UHID device from `softauth uhid-helper` listening on this socket, rather
+    /// than opening /dev/uhid, which requires running as root. The HID identity of the
+    /// device is then chosen by the profile of the helper.
+    #[arg(long, value_name = "SOCKET", num_args = 0..=1, default_missing_value = DEFAULT_HELPER_SOCKET, conflicts_with = "no_uhid")]
+    uhid_helper: Option<PathBuf>,
+
+    /// Also exchange raw CTAP-HID reports over a Unix socket at this path
+    #[arg(long, value_name = "PATH")]
+    unix_socket: Option<PathBuf>,
+
+    /// Serve several clients of the Unix socket at once, rather than one at a time
+    #[arg(long, requires = "unix_socket")]
+    multi_client: bool,
+
+    /// Also act as a contactless smartcard, connected to the vsmartcard vpcd reader driver
+    #[arg(long, value_name = "ADDRESS", num_args = 0..=1, default_missing_value = DEFAULT_VPCD_ADDRESS)]
+    vpcd: Option<String>,
+
+    /// Also export a USB HID device over USB/IP, to be attached with `usbip attach`. USB/IP
+    /// has no authentication: anyone able to connect to this address, e.g, any local user for
+    /// 127.0.0.1:3240, may attach the device and ask for the user's presence.
+    #[arg(long, value_name = "ADDRESS")]
+    usbip: Option<String>,
+
+    /// Don't ask for the user's presence on the terminal, considering them always present.
+    /// Any process able to reach the authenticator may then use its credentials, thus this is
+    /// only meant for automated tests.
+    #[arg(long)]
+    assume_presence: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run as root, creating UHID devices on behalf of a daemon running as an unprivileged user
+    UhidHelper {
+        /// The socket to listen on
+        #[arg(long, value_name = "SOCKET", default_value = DEFAULT_HELPER_SOCKET)]
+        socket: PathBuf,
+
+        /// The user allowed to obtain devices, by default the one who invoked sudo
+        #[arg(long, env = "SUDO_UID")]
+        uid: u32,
+
+        #[command(flatten)]
+        profile: ProfileArgs,
+    },
+
+    /// Change the passphrase of an encrypted credential store
+    ChangePassphrase {
+        /// The encrypted credential store
+        #[arg(long, value_name = "PATH")]
+        encrypted_store: PathBuf,
+
+        #[command(flatten)]
+        passphrase: PassphraseArgs,
+    },
+
+    /// Unlock an encrypted credential store with its passphrase, keeping its key in the kernel
+    /// keyring, so that the daemon can start without asking for it until the key expires
+    Unlock {
+        /// The encrypted credential store
+        #[arg(long, value_name = "PATH")]
+        encrypted_store: PathBuf,
+
+        #[command(flatten)]
+        keyring: KeyringArgs,
+
+        #[command(flatten)]
+        passphrase: PassphraseArgs,
+    },
+
+    /// Lock an encrypted credential store by removing its key from the kernel keyring, which
+    /// also locks a running daemon
+    Lock {
+        /// The encrypted credential store
+        #[arg(long, value_name = "PATH")]
+        encrypted_store: PathBuf,
+
+        /// The kernel keyring keeping the key
+        #[arg(long, value_name = "KEYRING", default_value = "user", value_parser = keyring_parser())]
+        keyring: KeyringKind,
+    },
+
+    /// Manage the seed from which the keys of non-discoverable credentials are derived
+    Seed {
+        /// The state file keeping the seed
+        #[arg(long, value_name = "PATH")]
+        state_file: PathBuf,
+
+        #[command(subcommand)]
+        command: SeedCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SeedCommand {
+    /// Set up a new seed, printing its recovery phrase
+    Generate,
+
+    /// Print the recovery phrase of the seed
+    Show,
+
+    /// Set up the seed from its recovery phrase, e.g, on a new machine, which restores the
+    /// credentials created from it
+    Restore,
+}
+
+/// Selects the device to emulate
+#[derive(clap::Args, Debug)]
+struct ProfileArgs {
+    /// Emulate a built-in device profile
+    #[arg(long, value_name = "NAME", default_value = DEFAULT_PROFILE, value_parser = PossibleValuesParser::new(BUILTIN_PROFILES))]
+    profile: String,
+
+    /// Emulate the device described by a JSON profile
+    #[arg(long, value_name = "PATH", conflicts_with = "profile")]
+    profile_file: Option<PathBuf>,
+}
+
+impl ProfileArgs {
+    fn load(&self) -> Result<Profile, ProfileError> {
+        match &self.profile_file {
+            Some(path) => Profile::from_file(path),
+            None => Profile::builtin(&self.profile),
+        }
+    }
+}
+
+/// Selects where the authenticator keeps its credentials and state, by default in memory only
+#[derive(clap::Args, Debug)]
+struct StorageArgs {
+    /// Keep credentials in an SQLite database at this path
+    #[arg(long, value_name = "PATH")]
+    sqlite: Option<PathBuf>,
+
+    /// Keep credentials in a file encrypted with a passphrase, which is created if needed
+    #[arg(long, value_name = "PATH", conflicts_with = "sqlite")]
+    encrypted_store: Option<PathBuf>,
+
+    /// Keep credentials in the default collection of the Secret Service, e.g, gnome-keyring
+    #[arg(long, conflicts_with_all = ["sqlite", "encrypted_store"])]
+    secret_service: bool,
+
+    #[command(flatten)]
+    passphrase: PassphraseArgs,
+
+    /// Once unlocked, keep the key of the encrypted store in this kernel keyring rather than in
+    /// memory, so that restarting doesn't ask for the passphrase until the key expires
+    #[arg(long, value_name = "KEYRING", requires = "encrypted_store", value_parser = keyring_parser())]
+    keyring: Option<KeyringKind>,
+
+    /// Seconds after which the key expires from the kernel keyring, locking the store
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_KEY_TIMEOUT.as_secs(), requires = "keyring")]
+    keyring_timeout: u64,
+
+    /// Keep the authenticator state, e.g, its PIN and counters, in this file
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<PathBuf>,
+}
+
+impl StorageArgs {
+    async fn credentials(&self) -> anyhow::Result<Box<dyn Storage>> {
+        if let Some(path) = &self.encrypted_store {
+            let source = self.passphrase.source();
+            let keyring = match self.keyring {
+                Some(kind) => Some(
+                    KeyringCache::new(kind, path)?
+                        .with_timeout(Duration::from_secs(self.keyring_timeout)),
+                ),
+                None => None,
+            };
+            let storage =
+                EncryptedFileStorage::open(path, &source, KdfParams::default(), keyring).await?;
+            return Ok(Box::new(storage));
+        }
+        if self.secret_service {
+            return Ok(Box::new(SecretServiceStorage::connect().await?));
+        }
+        Ok(match &self.sqlite {
+            Some(path) => Box::new(SqliteStorage::open(path)?),
+            None => Box::new(InMemoryStorage::new()),
+        })
+    }
+
+    async fn state(&self) -> Result<PersistentState, StateError> {
+        let store: Box<dyn StateStore> = match &self.state_file {
+            Some(path) => Box::new(FileStateStore::open(path)?),
+            None => Box::new(InMemoryStateStore::new()),
+        };
+        PersistentState::load(store).await
+    }
+}
+
+/// Selects how the passphrase of an encrypted store is obtained, by default by prompting on
+/// the terminal
+#[derive(clap::Args, Debug)]
+struct PassphraseArgs {
+    /// Read the passphrase from SOFTAUTH_PASSPHRASE in this environment file
+    #[arg(long, value_name = "PATH", requires = "encrypted_store")]
+    passphrase_env_file: Option<PathBuf>,
+
+    /// Prompt for the passphrase via systemd-ask-password
+    #[arg(
+        long,
+        requires = "encrypted_store",
+        conflicts_with = "passphrase_env_file"
+    )]
+    systemd_ask_password: bool,
+}
+
+impl PassphraseArgs {
+    fn source(&self) -> PassphraseSource {
+        match &self.passphrase_env_file {
+            Some(path) => PassphraseSource::EnvFile(path.clone()),
+            None if self.systemd_ask_password => PassphraseSource::SystemdAskPassword,
+            None => PassphraseSource::Terminal,
+        }
+    }
+}
+
+fn keyring_parser() -> impl TypedValueParser<Value = KeyringKind> {
+    PossibleValuesParser::new(["user", "session"]).map(|kind| match kind.as_str() {
+        "session" => KeyringKind::Session,
+        _ => KeyringKind::User,
+    })
+}
+
+/// Selects the kernel keyring keeping the key of an unlocked store
+#[derive(clap::Args, Debug)]
+struct KeyringArgs {
+    /// The kernel keyring keeping the key, the session one being cleared upon logout
+    #[arg(long, value_name = "KEYRING", default_value = "user", value_parser = keyring_parser())]
+    keyring: KeyringKind,
+
+    /// Seconds after which the key expires, locking the store
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_KEY_TIMEOUT.as_secs())]
+    keyring_timeout: u64,
+}
+
+impl KeyringArgs {
+    fn cache(&self, path: &Path) -> std::io::Result<KeyringCache> {
+        Ok(KeyringCache::new(self.keyring, path)?
+            .with_timeout(Duration::from_secs(self.keyring_timeout)))
+    }
+}
+
+/// Unlocks the encrypted store with its passphrase, caching its key in the kernel keyring
+async fn unlock(
+    path: &Path,
+    keyring: &KeyringArgs,
+    passphrase: &PassphraseArgs,
+) -> anyhow::Result<()> {
+    let passphrase = passphrase
+        .source()
+        .read("Passphrase of the credential store")
+        .await?;
+    let mut store = EncryptedFileStorage::unlock(path, &passphrase).await?;
+    store.keep_key_in(keyring.cache(path)?)?;
+    info!(
+        ?path,
+        timeout = keyring.keyring_timeout,
+        "Unlocked the store"
+    );
+    Ok(())
+}
+
+/// Removes the key of the encrypted store from the kernel keyring
+fn lock(path: &Path, keyring: KeyringKind) -> anyhow::Result<()> {
+    if KeyringCache::new(keyring, path)?.clear()? {
+        info!(?path, "Locked the store");
+    } else {
+        info!(?path, "The store wasn't unlocked");
+    }
+    Ok(())
+}
+
+/// Unlocks the encrypted store with its current passphrase, then protects it with a new one
+/// read from the terminal, or systemd-ask-password
+async fn change_passphrase(path: &Path, passphrase: &PassphraseArgs) -> anyhow::Result<()> {
+    let current = passphrase.source().read("Current passphrase").await?;
+    let mut store = EncryptedFileStorage::unlock(path, &current).await?;
+    // fail before asking for the new passphrase, if a daemon uses the store
+    store.lock_file().await?;
+    let new_source = match passphrase.source() {
+        PassphraseSource::SystemdAskPassword => PassphraseSource::SystemdAskPassword,
+        _ => PassphraseSource::Terminal,
+    };
+    let new = new_source.read_new("New passphrase").await?;
+    store.change_passphrase(&new).await?;
+    info!(?path, "Changed the passphrase");
+    Ok(())
+}
+
+/// Sets up the seed kept in the state file, or prints its recovery phrase. An existing seed
+/// is never replaced, as that would invalidate the credentials created from it. Fails while
+/// a daemon uses the state file, which would overwrite the changes.
+async fn seed(state_file: &Path, command: &SeedCommand) -> anyhow::Result<()> {
+    let mut state = PersistentState::load(Box::new(FileStateStore::open(state_file)?)).await?;
+    let current = state.get().seed.clone();
+    match (command, current) {
+        (SeedCommand::Show, Some(seed)) => {
+            let seed: &[u8; SEED_LENGTH] = seed[..]
+                .try_into()
+                .map_err(|_| anyhow!("The seed has an invalid length"))?;
+            println!("{}", *recovery_phrase(seed));
+        }
+        (SeedCommand::Show, None) => return Err(anyhow!("{:?} has no seed", state_file)),
+        (_, Some(_)) => return Err(anyhow!("{:?} already has a seed", state_file)),
+        (SeedCommand::Generate, None) => {
+            let mut seed = Zeroizing::new([0; SEED_LENGTH]);
+            SystemRandom::new()
+                .fill(seed.as_mut())
+                .map_err(|_| anyhow!("Couldn't generate a seed"))?;
+            state
+                .update(|state| state.seed = Some(SecretBytes::from(&seed[..])))
+                .await?;
+            println!("Write down the recovery phrase of the seed, and keep it safe:");
+            println!("{}", *recovery_phrase(&seed));
+        }
+        (SeedCommand::Restore, None) => {
+            let phrase = PassphraseSource::Terminal.read("Recovery phrase").await?;
+            let seed = seed_from_recovery_phrase(&phrase)?;
+            // RPs may reject signature counters going backwards. The counter of the previous
+            // machine is unknown, but as it only counted assertions, the current time in
+            // seconds is ahead of it.
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let now = u32::try_from(now).unwrap_or(u32::MAX);
+            state
+                .update(|state| {
+                    state.seed = Some(SecretBytes::from(&seed[..]));
+                    state.global_sign_count = state.global_sign_count.max(now);
+                })
+                .await?;
+            info!(?state_file, "Restored the seed");
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
     tracing_subscriber::fmt::init();
 
-    info!("Creating UHID transport");
-    let transport = LinuxUHIDTransport::new().await?;
-    debug!("Created UHID transport");
-    let authenticator = CTAP2Service::new();
-    let mut server = CTAPServer::new(transport);
-    server.run(authenticator).await?;
-    info!("Daemon is stopping");
-    Ok(())
+    if let Some(Command::UhidHelper {
+        socket,
+        uid,
+        profile,
+    }) = &args.command
+    {
+        return run_helper(socket, *uid, &profile.load()?.hid, None).await;
+    }
+    if let Some(Command::ChangePassphrase {
+        encrypted_store,
+        passphrase,
+    }) = &args.command
+    {
+        return change_passphrase(encrypted_store, passphrase).await;
+    }
+    if let Some(Command::Unlock {
+        encrypted_store,
+        keyring,
+        passphrase,
+    }) = &args.command
+    {
+        return unlock(encrypted_store, keyring, passphrase).await;
+    }
+    if let Some(Command::Lock {
+        encrypted_store,
+        keyring,
+    }) = &args.command
+    {
+        return lock(encrypted_store, *keyring);
+    }
+    if let Some(Command::Seed {
+        state_file,
+        command,
+    }) = &args.command
+    {
+        return seed(state_file, command).await;
+    }
+
+    if args.no_uhid && args.unix_socket.is_none() && args.vpcd.is_none() && args.usbip.is_none() {
+        return Err(anyhow!("No transport is enabled"));
+    }
+    let u2f_enabled = !args.disable_u2f;
+    let profile = args.profile.load()?;
+    let frontend: Arc<dyn PresenceFrontend> = if args.assume_presence {
+        Arc::new(AssumedPresenceFrontend)
+    } else {
+        Arc::new(TerminalFrontend::new())
+    };
+    // all transports share the same authenticator
+    let authenticator = profile.service(
+        u2f_enabled,
+        args.storage.credentials().await?,
+        args.storage.state().await?,
+        frontend,
+    )?;
+    authenticator.register_vendor_command(VENDOR_COMMAND_VERSION, version_command)?;
+    let options = profile.server_options(u2f_enabled);
+    let stop = CancellationToken::new();
+
+    let uhid = async {
+        if args.no_uhid {
+            return Ok(());
+        }
+        // the UHID device may go away, e.g, on suspend/resume, in which case it is recreated
+        let create = || async {
+            let transport = match &args.uhid_helper {
+                Some(socket) => {
+                    info!(?socket, "Obtaining UHID device from the helper");
+                    let device = receive_uhid_device(socket).await?;
+                    LinuxUHIDTransport::from_device(AsyncUHIDDevice::from_file(device)?)
+                }
+                None => {
+                    info!("Creating UHID transport");
+                    LinuxUHIDTransport::new(&profile.hid).await?
+                }
+            };
+            debug!("Created UHID transport");
+            Ok(transport)
+        };
+        let service = authenticator.for_transport("uhid");
+        supervise(
+            "uhid",
+            create,
+            service,
+            &options,
+            Backoff::default(),
+            stop.clone(),
+        )
+        .await
+    };
+    let unix_socket = async {
+        let path = match &args.unix_socket {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        info!(?path, "Creating Unix socket transport");
+        let mode = if args.multi_client {
+            SocketClientMode::Multi
+        } else {
+            SocketClientMode::Single
+        };
+        let transport = UnixSocketTransport::new(path, mode).await?;
+        debug!("Created Unix socket transport");
+        let service = authenticator.for_transport("unix-socket");
+        serve(transport, service, &options, stop.clone()).await
+    };
+    let vpcd = async {
+        let address = match &args.vpcd {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+        info!(%address, "Connecting to vpcd");
+        let mut card = VpcdCard::connect(address.as_str(), u2f_enabled).await?;
+        let service = authenticator.for_transport("vpcd");
+        tokio::select! {
+            res = card.run(service) => res,
+            _ = stop.cancelled() => Ok(()),
+        }
+    };
+    let usbip = async {
+        let address = match &args.usbip {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+        info!(%address, "Creating USB/IP transport");
+        let transport = UsbIpTransport::new(address.as_str(), profile.hid.clone()).await?;
+        debug!(address = %transport.local_addr(), "Created USB/IP transport");
+        let service = authenticator.for_transport("usbip");
+        serve(transport, service, &options, stop.clone()).await
+    };
+    let ctrl_c = async {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C"),
+            _ = stop.cancelled() => {},
+        }
+        stop.cancel();
+    };
+
+    let (uhid_res, unix_socket_res, vpcd_res, usbip_res, ()) = tokio::join!(
+        stop_on_error(uhid, &stop),
+        stop_on_error(unix_socket, &stop),
+        stop_on_error(vpcd, &stop),
+        stop_on_error(usbip, &stop),
+        ctrl_c
+    );
+    info!("Daemon has stopped");
+    uhid_res.and(unix_socket_res).and(vpcd_res).and(usbip_res)
+}
+
+/// Stops the other transports in case one of them can't be created or fails
+async fn stop_on_error(
+    transport: impl Future<Output = anyhow::Result<()>>,
+    stop: &CancellationToken,
+) -> anyhow::Result<()> {
+    let res = transport.await;
+    if let Err(e) = &res {
+        error!("Transport failed: {:?}", e);
+        stop.cancel();
+    }
+    res
 }
@@ -0,0 +1,438 @@
+use tracing::{debug, trace, warn};
+
+use crate::{
+    authenticator::{
+        apdu::{CommandApdu, ResponseApdu, StatusWord},
+        types::U2F_VERSION,
+    },
+    hid::{
+        command::{CommandType, KeepaliveStatus},
+        packet::Message,
+        reassembly::DEFAULT_MAX_MESSAGE_SIZE,
+    },
+};
+
+/// The AID of the FIDO applet, see
+/// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#nfc-applet-selection
+pub const FIDO_AID: [u8; 8] = [0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01];
+
+/// The version returned upon selecting the applet, when U2F isn't supported
+const FIDO_2_VERSION: &str = "FIDO_2_0";
+
+/// The channel identifier of the messages passed to the authenticator, as NFC has no channels
+pub const NFC_CHANNEL: u32 = 0;
+
+const CLA_ISO: u8 = 0x00;
+const CLA_PROPRIETARY: u8 = 0x80;
+/// The CLA bit marking a command APDU which is followed by more parts of the same command
+const CLA_CHAINING: u8 = 0x10;
+
+const INS_SELECT: u8 = 0xA4;
+const INS_GET_RESPONSE: u8 = 0xC0;
+const INS_NFCCTAP_MSG: u8 = 0x10;
+const INS_NFCCTAP_GETRESPONSE: u8 = 0x11;
+
+/// The P1 of SELECT, when selecting by name (i.e, AID)
+const P1_SELECT_BY_NAME: u8 = 0x04;
+/// The P1 bit of NFCCTAP_MSG, set by clients which support NFCCTAP_GETRESPONSE
+const P1_GETRESPONSE_SUPPORTED: u8 = 0x80;
+
+/// The status word of an NFCCTAP_GETRESPONSE answered while the request is still processed
+const SW_STATUS_UPDATE: u16 = 0x9100;
+
+/// The number of response bytes sent when the command APDU doesn't specify `Ne`
+const DEFAULT_NE: usize = 256;
+
+/// Handles the ISO 7816-4 framing of CTAP over NFC in a synchronous manner:
+/// - Selection of the FIDO applet
+/// - Command chaining, and response chaining via GET RESPONSE
+/// - NFCCTAP_MSG and U2F requests, and polling for their responses via NFCCTAP_GETRESPONSE
+///
+/// Does not handle IO, timeouts or the actual logic of CTAP commands.
+/// [See more](https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#nfc)
+pub struct ApduProcessing {
+    u2f_enabled: bool,
+    selected: bool,
+    /// The parts of a chained command received so far
+    command_chain: Option<CommandApdu>,
+    /// The rest of a chained response, yet to be fetched via GET RESPONSE
+    response_chain: Option<ResponseApdu>,
+    /// A request which was passed to the authenticator, and whose response is awaited
+    pending: Option<PendingRequest>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingRequest {
+    command: CommandType,
+    /// Maximal number of response bytes of the first response APDU
+    ne: usize,
+}
+
+/// The result of processing a command APDU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApduProcessingResult {
+    /// An immediate response is available
+    Response(ResponseApdu),
+
+    /// A CBOR or U2F request has been received, its handling should be delegated to the
+    /// authenticator, whose response is passed to [ApduProcessing::complete_request].
+    Request {
+        message: Message,
+        /// Whether the client polls for the response via NFCCTAP_GETRESPONSE, in which case it
+        /// may be answered with [ApduProcessing::status_update] until the response is ready.
+        polling: bool,
+    },
+
+    /// The client polls for the response of the pending request, which is to be passed to
+    /// [ApduProcessing::complete_request] (or answered with [ApduProcessing::status_update])
+    Polling,
+}
+
+impl ApduProcessing {
+    pub fn new(u2f_enabled: bool) -> Self {
+        ApduProcessing {
+            u2f_enabled,
+            selected: false,
+            command_chain: None,
+            response_chain: None,
+            pending: None,
+        }
+    }
+
+    /// Resets all state, e.g, when the card is powered off or removed from the field
+    pub fn reset(&mut self) {
+        self.selected = false;
+        self.command_chain = None;
+        self.response_chain = None;
+        self.pending = None;
+    }
+
+    /// Handles a command APDU
+    pub fn handle_apdu(&mut self, bytes: &[u8]) -> ApduProcessingResult {
+        let apdu = match CommandApdu::parse(bytes) {
+            Ok(apdu) => apdu,
+            Err(e) => {
+                warn!(?e, "Received a malformed APDU");
+                return self.respond(StatusWord::from(e));
+            }
+        };
+        trace!(?apdu, "Received APDU");
+
+        let apdu = match self.chain_command(apdu) {
+            Ok(Some(apdu)) => apdu,
+            Ok(None) => return self.respond(StatusWord::NoError),
+            Err(status) => return self.respond(status),
+        };
+
+        if self.pending.is_some()
+            && (apdu.cla, apdu.ins) != (CLA_PROPRIETARY, INS_NFCCTAP_GETRESPONSE)
+        {
+            warn!("Received an APDU while a request is processed");
+            return self.respond(StatusWord::ConditionsNotSatisfied);
+        }
+        if apdu.ins != INS_GET_RESPONSE {
+            self.response_chain = None;
+        }
+
+        match (apdu.cla, apdu.ins) {
+            (CLA_ISO, INS_SELECT) => self.select(&apdu),
+            _ if !self.selected => {
+                warn!("Received an APDU before the FIDO applet was selected");
+                self.respond(StatusWord::ConditionsNotSatisfied)
+            }
+            (CLA_ISO, INS_GET_RESPONSE) => match self.response_chain.take() {
+                Some(rest) => {
+                    let ne = if apdu.ne == 0 { DEFAULT_NE } else { apdu.ne };
+                    let response = self.chain_response(rest, ne);
+                    self.respond(response)
+                }
+                None => self.respond(StatusWord::ConditionsNotSatisfied),
+            },
+            (CLA_PROPRIETARY, INS_NFCCTAP_MSG) if apdu.data.is_empty() => {
+                self.respond(StatusWord::WrongLength)
+            }
+            (CLA_PROPRIETARY, INS_NFCCTAP_MSG) => {
+                let polling = apdu.p1 & P1_GETRESPONSE_SUPPORTED != 0;
+                self.request(CommandType::Cbor, apdu.data, apdu.ne, polling)
+            }
+            (CLA_PROPRIETARY, INS_NFCCTAP_GETRESPONSE) if self.pending.is_some() => {
+                ApduProcessingResult::Polling
+            }
+            (CLA_PROPRIETARY, INS_NFCCTAP_GETRESPONSE) => {
+                self.respond(StatusWord::ConditionsNotSatisfied)
+            }
+            (CLA_ISO, _) if self.u2f_enabled => {
+                // parsed again by the authenticator, so that it can validate the U2F framing
+                let ne = apdu.ne;
+                self.request(CommandType::Msg, apdu.into(), ne, false)
+            }
+            (CLA_ISO | CLA_PROPRIETARY, _) => self.respond(StatusWord::InsNotSupported),
+            _ => self.respond(StatusWord::ClaNotSupported),
+        }
+    }
+
+    /// Completes the pending request given the payload of the authenticator's response
+    /// message, returning the (first) response APDU.
+    pub fn complete_request(&mut self, payload: Vec<u8>) -> ResponseApdu {
+        let pending = self
+            .pending
+            .take()
+            .expect("complete_request must follow a request");
+        let response = match pending.command {
+            CommandType::Msg => ResponseApdu::parse(&payload)
+                .unwrap_or_else(|| StatusWord::NoPreciseDiagnosis.into()),
+            _ => ResponseApdu::success(payload),
+        };
+        let ne = if pending.ne == 0 {
+            DEFAULT_NE
+        } else {
+            pending.ne
+        };
+        self.chain_response(response, ne)
+    }
+
+    /// A response to NFCCTAP_GETRESPONSE while the pending request is still processed
+    pub fn status_update(&self) -> ResponseApdu {
+        ResponseApdu {
+            data: vec![KeepaliveStatus::Processing.into()],
+            status: SW_STATUS_UPDATE,
+        }
+    }
+
+    fn respond(&self, response: impl Into<ResponseApdu>) -> ApduProcessingResult {
+        ApduProcessingResult::Response(response.into())
+    }
+
+    fn select(&mut self, apdu: &CommandApdu) -> ApduProcessingResult {
+        if apdu.p1 != P1_SELECT_BY_NAME || apdu.data != FIDO_AID {
+            debug!(aid = %hex::encode(&apdu.data), "Selected an unknown applet");
+            self.selected = false;
+            return self.respond(StatusWord::FileNotFound);
+        }
+        debug!("Selected the FIDO applet");
+        self.selected = true;
+        let version = if self.u2f_enabled {
+            U2F_VERSION
+        } else {
+            FIDO_2_VERSION
+        };
+        self.respond(ResponseApdu::success(version.as_bytes().to_owned()))
+    }
+
+    fn request(
+        &mut self,
+        command: CommandType,
+        payload: Vec<u8>,
+        ne: usize,
+        polling: bool,
+    ) -> ApduProcessingResult {
+        self.pending = Some(PendingRequest { command, ne });
+        ApduProcessingResult::Request {
+            message: Message {
+                channel_identifier: NFC_CHANNEL,
+                command: Ok(command),
+                payload,
+            },
+            polling,
+        }
+    }
+
+    /// Accumulates the parts of a chained command, returning the whole command once its
+    /// last part is received. Commands longer than the maximal message size are rejected.
+    fn chain_command(&mut self, mut apdu: CommandApdu) -> Result<Option<CommandApdu>, StatusWord> {
+        let chained = apdu.cla & CLA_CHAINING != 0;
+        apdu.cla &= !CLA_CHAINING;
+        let apdu = match self.command_chain.take() {
+            Some(mut chain) => {
+                if (chain.cla, chain.ins, chain.p1, chain.p2)
+                    != (apdu.cla, apdu.ins, apdu.p1, apdu.p2)
+                {
+                    warn!("Received a different command in the middle of a command chain");
+                    return Err(StatusWord::ConditionsNotSatisfied);
+                }
+                chain.data.extend_from_slice(&apdu.data);
+                chain.ne = apdu.ne;
+                chain
+            }
+            None => apdu,
+        };
+        if apdu.data.len() > DEFAULT_MAX_MESSAGE_SIZE {
+            warn!(
+                len = apdu.data.len(),
+                "Received a command which is too long"
+            );
+            return Err(StatusWord::WrongLength);
+        }
+        if chained {
+            self.command_chain = Some(apdu);
+            Ok(None)
+        } else {
+            Ok(Some(apdu))
+        }
+    }
+
+    /// Returns the first `ne` bytes of a response, keeping the rest for GET RESPONSE
+    fn chain_response(&mut self, mut response: ResponseApdu, ne: usize) -> ResponseApdu {
+        if response.data.len() <= ne {
+            return response;
+        }
+        let rest = response.data.split_off(ne);
+        let remaining = rest.len();
+        self.response_chain = Some(ResponseApdu {
+            data: rest,
+            status: response.status,
+        });
+        ResponseApdu::bytes_remaining(response.data, remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(processing: &mut ApduProcessing) -> ApduProcessingResult {
+        let mut apdu = vec![0x00, 0xA4, 0x04, 0x00, FIDO_AID.len() as u8];
+        apdu.extend_from_slice(&FIDO_AID);
+        apdu.push(0x00);
+        processing.handle_apdu(&apdu)
+    }
+
+    fn response(bytes: &[u8]) -> ApduProcessingResult {
+        ApduProcessingResult::Response(ResponseApdu::parse(bytes).unwrap())
+    }
+
+    #[test]
+    fn test_select() {
+        let mut processing = ApduProcessing::new(true);
+        let get_info = [0x80, 0x10, 0x00, 0x00, 0x01, 0x04, 0x00];
+        assert_eq!(processing.handle_apdu(&get_info), response(&[0x69, 0x85]));
+        assert_eq!(select(&mut processing), response(b"U2F_V2\x90\x00"));
+
+        let other_aid = [0x00, 0xA4, 0x04, 0x00, 0x02, 0xAA, 0xBB, 0x00];
+        assert_eq!(processing.handle_apdu(&other_aid), response(&[0x6A, 0x82]));
+        assert_eq!(processing.handle_apdu(&get_info), response(&[0x69, 0x85]));
+
+        let mut processing = ApduProcessing::new(false);
+        assert_eq!(select(&mut processing), response(b"FIDO_2_0\x90\x00"));
+        let u2f_version = [0x00, 0x03, 0x00, 0x00, 0x00];
+        assert_eq!(
+            processing.handle_apdu(&u2f_version),
+            response(&[0x6D, 0x00])
+        );
+    }
+
+    #[test]
+    fn test_nfcctap_msg_with_response_chaining() {
+        let mut processing = ApduProcessing::new(true);
+        select(&mut processing);
+        let get_info = [0x80, 0x10, 0x00, 0x00, 0x01, 0x04, 0x00];
+        match processing.handle_apdu(&get_info) {
+            ApduProcessingResult::Request { message, polling } => {
+                assert_eq!(message.command, Ok(CommandType::Cbor));
+                assert_eq!(message.payload, vec![0x04]);
+                assert!(!polling);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let payload = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+        let first = processing.complete_request(payload.clone());
+        assert_eq!(
+            first,
+            ResponseApdu::bytes_remaining(payload[..256].to_vec(), 44)
+        );
+        let get_response = [0x00, 0xC0, 0x00, 0x00, 0x00];
+        assert_eq!(
+            processing.handle_apdu(&get_response),
+            ApduProcessingResult::Response(ResponseApdu::success(payload[256..].to_vec()))
+        );
+        assert_eq!(
+            processing.handle_apdu(&get_response),
+            response(&[0x69, 0x85])
+        );
+    }
+
+    #[test]
+    fn test_nfcctap_getresponse() {
+        let mut processing = ApduProcessing::new(true);
+        select(&mut processing);
+        let get_info = [0x80, 0x10, 0x80, 0x00, 0x01, 0x04, 0x00];
+        assert!(matches!(
+            processing.handle_apdu(&get_info),
+            ApduProcessingResult::Request { polling: true, .. }
+        ));
+        assert_eq!(
+            Vec::from(processing.status_update()),
+            vec![0x01, 0x91, 0x00]
+        );
+
+        let get_response = [0x80, 0x11, 0x00, 0x00, 0x00];
+        assert_eq!(
+            processing.handle_apdu(&get_response),
+            ApduProcessingResult::Polling
+        );
+        // other commands are rejected until the request completes
+        assert_eq!(processing.handle_apdu(&get_info), response(&[0x69, 0x85]));
+        assert_eq!(
+            processing.complete_request(vec![0x00]),
+            ResponseApdu::success(vec![0x00])
+        );
+        assert_eq!(
+            processing.handle_apdu(&get_response),
+            response(&[0x69, 0x85])
+        );
+    }
+
+    #[test]
+    fn test_command_chaining() {
+        let mut processing = ApduProcessing::new(true);
+        select(&mut processing);
+        let first = [0x90, 0x10, 0x00, 0x00, 0x02, 0x01, 0xA1];
+        assert_eq!(processing.handle_apdu(&first), response(&[0x90, 0x00]));
+        let last = [0x80, 0x10, 0x00, 0x00, 0x02, 0x01, 0x02, 0x00];
+        match processing.handle_apdu(&last) {
+            ApduProcessingResult::Request { message, .. } => {
+                assert_eq!(message.payload, vec![0x01, 0xA1, 0x01, 0x02]);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_command_chain_too_long() {
+        let mut processing = ApduProcessing::new(true);
+        select(&mut processing);
+        let mut part = vec![0x90, 0x10, 0x00, 0x00, 0xFF];
+        part.extend_from_slice(&[0xA1; 0xFF]);
+        for _ in 0..DEFAULT_MAX_MESSAGE_SIZE / 0xFF {
+            assert_eq!(processing.handle_apdu(&part), response(&[0x90, 0x00]));
+        }
+        assert_eq!(processing.handle_apdu(&part), response(&[0x67, 0x00]));
+
+        // the chain was dropped
+        let get_info = [0x80, 0x10, 0x00, 0x00, 0x01, 0x04, 0x00];
+        match processing.handle_apdu(&get_info) {
+            ApduProcessingResult::Request { message, .. } => {
+                assert_eq!(message.payload, vec![0x04]);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_u2f_request() {
+        let mut processing = ApduProcessing::new(true);
+        select(&mut processing);
+        match processing.handle_apdu(&[0x00, 0x03, 0x00, 0x00, 0x00]) {
+            ApduProcessingResult::Request { message, .. } => {
+                assert_eq!(message.command, Ok(CommandType::Msg));
+                assert_eq!(CommandApdu::parse(&message.payload).unwrap().ins, 0x03);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(
+            processing.complete_request(b"U2F_V2\x90\x00".to_vec()),
+            ResponseApdu::success(b"U2F_V2".to_vec())
+        );
+    }
+}
@@ -0,0 +1,2 @@
+pub(crate) mod apdu_processing;
+pub(crate) mod vpcd;
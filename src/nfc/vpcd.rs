@@ -0,0 +1,155 @@
+//! A virtual smartcard connected to the `vpcd` reader driver of
+//! [vsmartcard](https://frankmorgner.github.io/vsmartcard/virtualsmartcard/README.html),
+//! so that PC/SC clients can use the authenticator as a contactless card.
+
+use std::{future::poll_fn, time::Duration};
+
+use num_enum::TryFromPrimitive;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+};
+use tower::Service;
+use tracing::{debug, error, trace, warn};
+
+use crate::{
+    authenticator::{
+        apdu::ResponseApdu,
+        api::{AuthServiceError, CTAP2Request, CTAP2Response},
+    },
+    hid::packet::Message,
+};
+
+use super::apdu_processing::{ApduProcessing, ApduProcessingResult, NFC_CHANNEL};
+
+/// The address `vpcd` listens on for virtual cards by default
+pub const DEFAULT_VPCD_ADDRESS: &str = "localhost:35963";
+
+/// How long an NFCCTAP_GETRESPONSE poll waits for the response before sending a status update
+const STATUS_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The ATR of a contactless card without historical bytes, per PC/SC part 3
+const ATR: [u8; 5] = [0x3B, 0x80, 0x80, 0x01, 0x01];
+
+/// A single byte message sent by `vpcd` instead of an APDU
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+enum VpcdControl {
+    PowerOff = 0,
+    PowerOn = 1,
+    Reset = 2,
+    GetAtr = 4,
+}
+
+/// A virtual card connected to `vpcd`. Messages in both directions are prefixed by their
+/// length, as a big endian u16.
+pub struct VpcdCard {
+    stream: TcpStream,
+    processing: ApduProcessing,
+}
+
+impl VpcdCard {
+    pub async fn connect(address: impl ToSocketAddrs, u2f_enabled: bool) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(address).await?;
+        debug!(peer = ?stream.peer_addr()?, "Connected to vpcd");
+        Ok(VpcdCard {
+            stream,
+            processing: ApduProcessing::new(u2f_enabled),
+        })
+    }
+
+    /// Processes APDUs until `vpcd` disconnects. May return early in case of IO errors.
+    pub async fn run<A>(&mut self, mut service: A) -> anyhow::Result<()>
+    where
+        A: Service<CTAP2Request, Response = CTAP2Response, Error = AuthServiceError>,
+        A::Future: Unpin,
+    {
+        let mut pending = None;
+        loop {
+            let message = match self.read_message().await? {
+                Some(message) => message,
+                None => {
+                    debug!("vpcd has disconnected");
+                    return Ok(());
+                }
+            };
+            if let [control] = message[..] {
+                match VpcdControl::try_from(control) {
+                    Ok(VpcdControl::GetAtr) => self.write_message(&ATR).await?,
+                    Ok(VpcdControl::PowerOn) => debug!("Card was powered on"),
+                    Ok(control) => {
+                        debug!(?control, "Card was powered off, resetting");
+                        self.processing.reset();
+                        pending = None;
+                    }
+                    Err(_) => warn!(control, "Received an unknown vpcd control message"),
+                }
+                continue;
+            }
+
+            let response = match self.processing.handle_apdu(&message) {
+                ApduProcessingResult::Response(response) => response,
+                ApduProcessingResult::Request { message, polling } => {
+                    match CTAP2Request::try_from(&message) {
+                        Ok(request) => {
+                            poll_fn(|cx| service.poll_ready(cx)).await?;
+                            pending = Some(service.call(request));
+                            self.await_response(&mut pending, polling).await
+                        }
+                        Err(e) => {
+                            error!(?e, "Couldn't parse a request");
+                            let error = AuthServiceError::new(e, NFC_CHANNEL);
+                            self.processing
+                                .complete_request(Message::from(&error).payload)
+                        }
+                    }
+                }
+                ApduProcessingResult::Polling => self.await_response(&mut pending, true).await,
+            };
+            trace!(?response, "Sending response APDU");
+            self.write_message(&Vec::from(response)).await?;
+        }
+    }
+
+    /// Waits for the response to the pending request, or only until the next status update if
+    /// the client polls for it.
+    async fn await_response<F>(&mut self, pending: &mut Option<F>, polling: bool) -> ResponseApdu
+    where
+        F: std::future::Future<Output = Result<CTAP2Response, AuthServiceError>> + Unpin,
+    {
+        let request = pending
+            .as_mut()
+            .expect("A request must be pending while the client polls for its response");
+        let result = if polling {
+            match tokio::time::timeout(STATUS_UPDATE_INTERVAL, request).await {
+                Ok(result) => result,
+                Err(_) => return self.processing.status_update(),
+            }
+        } else {
+            request.await
+        };
+        *pending = None;
+        let message = match result {
+            Ok(response) => Message::from(response),
+            Err(error) => Message::from(&error),
+        };
+        self.processing.complete_request(message.payload)
+    }
+
+    async fn read_message(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let len = match self.stream.read_u16().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut message = vec![0u8; len as usize];
+        self.stream.read_exact(&mut message).await?;
+        Ok(Some(message))
+    }
+
+    async fn write_message(&mut self, message: &[u8]) -> anyhow::Result<()> {
+        self.stream.write_u16(message.len() as u16).await?;
+        self.stream.write_all(message).await?;
+        Ok(())
+    }
+}
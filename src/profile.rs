@@ -0,0 +1,395 @@
+//! Emulation profiles, describing how the authenticator identifies itself: its HID identity,
+//! AAGUID, authenticatorGetInfo contents, attestation certificate chain and firmware version,
+//! along with behaviour quirks of the emulated model.
+//!
+//! Besides the built-in profiles, a profile may be loaded from a JSON file, in which every
+//! field is optional and defaults to the `softauth` profile, e.g:
+//!
+//! ```json
+//! {
+//!     "hid": { "name": "Example Key", "vendor_id": 4176, "product_id": 1031, "bus": "usb" },
+//!     "aaguid": "cb69481e-8ff7-4039-93ec-0a2729a154a8",
+//!     "versions": ["FIDO_2_0", "FIDO_2_1"],
+//!     "firmware_version": { "major": 5, "minor": 4, "build": 3 },
+//!     "attestation": { "key": "key.der", "certificates": ["cert.der", "ca.der"] },
+//!     "quirks": { "no_wink": true }
+//! }
+//! ```
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::{
+    authenticator::{
+        api::CTAP2Service,
+        crypto::AttestationKey,
+        presence::PresenceFrontend,
+        storage::{state::PersistentState, store::Storage},
+        types::{Aaguid, AuthenticatorGetInfoOptions, AuthenticatorGetInfoResponse, APP_AAGUID},
+    },
+    hid::{command::DeviceVersion, descriptor::HidIdentity, server::ServerOptions},
+};
+
+/// The profile used unless another one is chosen
+pub const DEFAULT_PROFILE: &str = "softauth";
+
+/// Names of the built-in profiles
+pub const BUILTIN_PROFILES: &[&str] = &[DEFAULT_PROFILE, "ctap2.0-minimal", "ctap2.1"];
+
+/// The authenticatorGetInfo version string of CTAP 2.1, which introduced `firmwareVersion`
+const FIDO_2_1: &str = "FIDO_2_1";
+
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    #[error("Unknown profile {0:?}, expected one of {BUILTIN_PROFILES:?}")]
+    UnknownProfile(String),
+
+    #[error("Couldn't read {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Invalid profile: {0}")]
+    InvalidProfile(#[from] serde_json::Error),
+
+    #[error("{0:?} isn't a PKCS#8 encoded P-256 private key")]
+    InvalidAttestationKey(PathBuf),
+
+    #[error("The certificate of the attestation key is missing")]
+    MissingAttestationCertificate,
+}
+
+/// Files holding an attestation key and its certificate chain, all DER encoded
+#[derive(Debug, Clone, Deserialize)]
+pub struct AttestationFiles {
+    /// The PKCS#8 encoded P-256 private key
+    pub key: PathBuf,
+
+    /// The certificate of the key, followed by the certificates of its issuers
+    pub certificates: Vec<PathBuf>,
+}
+
+/// Deviations from the default behaviour, as exhibited by some authenticators
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Quirks {
+    /// CTAPHID_WINK is neither advertised nor supported
+    pub no_wink: bool,
+
+    /// U2F_V2 is missing from the authenticatorGetInfo versions, even though U2F is supported
+    pub hide_u2f_version: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub hid: HidIdentity,
+
+    #[serde(deserialize_with = "deserialize_aaguid")]
+    pub aaguid: Aaguid,
+
+    /// The CTAP versions advertised by authenticatorGetInfo, U2F_V2 is added if U2F is enabled
+    pub versions: Vec<String>,
+
+    pub extensions: Vec<String>,
+
+    pub options: AuthenticatorGetInfoOptions,
+
+    /// Reported in response to CTAPHID_INIT, and by authenticatorGetInfo as of CTAP 2.1
+    pub firmware_version: DeviceVersion,
+
+    /// The built-in attestation key is used unless specified
+    pub attestation: Option<AttestationFiles>,
+
+    pub quirks: Quirks,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            hid: HidIdentity::default(),
+            aaguid: APP_AAGUID,
+            versions: vec!["FIDO_2_0".into()],
+            extensions: Vec::new(),
+            options: AuthenticatorGetInfoOptions::default(),
+            firmware_version: DeviceVersion::default(),
+            attestation: None,
+            quirks: Quirks::default(),
+        }
+    }
+}
+
+impl Profile {
+    /// Returns the built-in profile with the given name, see [BUILTIN_PROFILES]
+    pub fn builtin(name: &str) -> Result<Self, ProfileError> {
+        let default = Profile::default();
+        match name {
+            DEFAULT_PROFILE => Ok(default),
+            // only what CTAP 2.0 mandates, e.g, for testing how RPs cope with basic keys
+            "ctap2.0-minimal" => Ok(Profile {
+                hid: HidIdentity {
+                    name: "Software CTAP2.0".into(),
+                    product_id: 1338,
+                    ..default.hid
+                },
+                aaguid: Aaguid([1, 3, 3, 7, 1, 1, 2, 3, 5, 8, 13, 21, 2, 0, 0, 0]),
+                options: AuthenticatorGetInfoOptions {
+                    rk: false,
+                    uv: false,
+                    ..default.options
+                },
+                quirks: Quirks {
+                    no_wink: true,
+                    ..default.quirks
+                },
+                ..default
+            }),
+            // only authenticatorGetInfo is that of CTAP 2.1, e.g. for testing how RPs read its
+            // firmwareVersion, as none of the commands it introduced are implemented
+            "ctap2.1" => Ok(Profile {
+                hid: HidIdentity {
+                    name: "Software CTAP2.1".into(),
+                    product_id: 1339,
+                    ..default.hid
+                },
+                aaguid: Aaguid([1, 3, 3, 7, 1, 1, 2, 3, 5, 8, 13, 21, 2, 1, 0, 0]),
+                versions: vec!["FIDO_2_0".into(), FIDO_2_1.into()],
+                firmware_version: DeviceVersion {
+                    major: 1,
+                    minor: 0,
+                    build: 0,
+                },
+                ..default
+            }),
+            _ => Err(ProfileError::UnknownProfile(name.to_owned())),
+        }
+    }
+
+    /// Loads a profile from a JSON file. Relative attestation file paths are resolved
+    /// against the directory of the profile.
+    pub fn from_file(path: &Path) -> Result<Self, ProfileError> {
+        let json = read(path)?;
+        let mut profile: Profile = serde_json::from_slice(&json)?;
+        if let (Some(attestation), Some(dir)) = (&mut profile.attestation, path.parent()) {
+            attestation.key = dir.join(&attestation.key);
+            for certificate in &mut attestation.certificates {
+                *certificate = dir.join(&*certificate);
+            }
+        }
+        Ok(profile)
+    }
+
+    /// The authenticatorGetInfo response of the emulated device
+    pub fn get_info(&self, u2f_enabled: bool) -> AuthenticatorGetInfoResponse {
+        let mut info = AuthenticatorGetInfoResponse::new(
+            self.versions.clone(),
+            self.extensions.clone(),
+            self.aaguid.clone(),
+            self.options.clone(),
+        );
+        if u2f_enabled && !self.quirks.hide_u2f_version {
+            info = info.with_u2f();
+        }
+        if self.versions.iter().any(|version| version == FIDO_2_1) {
+            let DeviceVersion {
+                major,
+                minor,
+                build,
+            } = self.firmware_version;
+            info = info
+                .with_firmware_version(u64::from_be_bytes([0, 0, 0, 0, 0, major, minor, build]));
+        }
+        info
+    }
+
+    /// Loads the attestation key of the emulated device, along with its certificate chain
+    pub fn attestation_key(&self) -> Result<AttestationKey, ProfileError> {
+        let files = match &self.attestation {
+            Some(files) => files,
+            None => return Ok(AttestationKey::builtin()),
+        };
+        let mut certificates = files
+            .certificates
+            .iter()
+            .map(|path| read(path))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter();
+        let certificate = certificates
+            .next()
+            .ok_or(ProfileError::MissingAttestationCertificate)?;
+        AttestationKey::from_pkcs8(&read(&files.key)?, certificate, certificates.collect())
+            .map_err(|_| ProfileError::InvalidAttestationKey(files.key.clone()))
+    }
+
+    /// Creates an authenticator identifying itself as the emulated device, keeping its
+    /// credentials in `storage` and the rest of its `state` alongside, and interacting with
+    /// its user via `frontend`
+    pub fn service(
+        &self,
+        u2f_enabled: bool,
+        storage: Box<dyn Storage>,
+        state: PersistentState,
+        frontend: Arc<dyn PresenceFrontend>,
+    ) -> Result<CTAP2Service, ProfileError> {
+        Ok(CTAP2Service::with_identity(
+            u2f_enabled,
+            self.get_info(u2f_enabled),
+            self.attestation_key()?,
+            storage,
+            state,
+            frontend,
+        ))
+    }
+
+    /// Options of the CTAP-HID servers of the emulated device
+    pub fn server_options(&self, u2f_enabled: bool) -> ServerOptions {
+        ServerOptions {
+            u2f_enabled,
+            wink_enabled: !self.quirks.no_wink,
+            device_version: self.firmware_version,
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, ProfileError> {
+    fs::read(path).map_err(|source| ProfileError::Io {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// Parses an AAGUID in UUID notation, dashes are optional
+fn deserialize_aaguid<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Aaguid, D::Error> {
+    let uuid = String::deserialize(deserializer)?;
+    let bytes = hex::decode(uuid.replace('-', "")).map_err(de::Error::custom)?;
+    let aaguid = bytes
+        .try_into()
+        .map_err(|_| de::Error::custom("an AAGUID must be 16 bytes long"))?;
+    Ok(Aaguid(aaguid))
+}
+
+#[cfg(test)]
+mod tests {
+    use ciborium::value::Value;
+
+    use crate::{
+        authenticator::{
+            api::CTAP2ResponseData, presence::TestFrontend, storage::memory::InMemoryStorage,
+        },
+        hid::descriptor::{HidBus, CTAPHID_VENDOR_ID},
+        test_util::temp_dir,
+    };
+
+    use super::*;
+
+    /// Returns the authenticatorGetInfo fields as encoded in a response
+    fn encoded_get_info(profile: &Profile, u2f_enabled: bool) -> Vec<(Value, Value)> {
+        let res = Vec::<u8>::from(CTAP2ResponseData::GetInfo(profile.get_info(u2f_enabled)));
+        let info: Value = ciborium::de::from_reader(&res[1..]).unwrap();
+        info.as_map().unwrap().clone()
+    }
+
+    fn field(info: &[(Value, Value)], key: u8) -> Option<&Value> {
+        info.iter()
+            .find(|(k, _)| k == &Value::from(key))
+            .map(|(_, v)| v)
+    }
+
+    #[test]
+    fn test_builtin_profiles() {
+        for name in BUILTIN_PROFILES {
+            let profile = Profile::builtin(name).unwrap();
+            profile
+                .service(
+                    true,
+                    Box::new(InMemoryStorage::new()),
+                    PersistentState::in_memory(),
+                    Arc::new(TestFrontend::default()),
+                )
+                .unwrap();
+        }
+        assert!(matches!(
+            Profile::builtin("yubikey"),
+            Err(ProfileError::UnknownProfile(_))
+        ));
+    }
+
+    #[test]
+    fn test_get_info() {
+        let default = encoded_get_info(&Profile::default(), true);
+        let versions = field(&default, 0x01).unwrap().as_array().unwrap();
+        assert_eq!(versions, &[Value::from("U2F_V2"), Value::from("FIDO_2_0")]);
+        assert_eq!(field(&default, 0x0E), None);
+
+        let ctap21 = encoded_get_info(&Profile::builtin("ctap2.1").unwrap(), false);
+        let versions = field(&ctap21, 0x01).unwrap().as_array().unwrap();
+        assert_eq!(
+            versions,
+            &[Value::from("FIDO_2_0"), Value::from("FIDO_2_1")]
+        );
+        assert_eq!(field(&ctap21, 0x0E), Some(&Value::from(0x010000)));
+
+        let mut hidden = Profile::default();
+        hidden.quirks.hide_u2f_version = true;
+        let hidden = encoded_get_info(&hidden, true);
+        let versions = field(&hidden, 0x01).unwrap().as_array().unwrap();
+        assert_eq!(versions, &[Value::from("FIDO_2_0")]);
+    }
+
+    #[test]
+    fn test_profile_file() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        fs::write(
+            dir.join("key.der"),
+            include_bytes!("authenticator/crypto/attestation/key.der"),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("cert.der"),
+            include_bytes!("authenticator/crypto/attestation/cert.der"),
+        )
+        .unwrap();
+        fs::write(dir.join("ca.der"), [0xca; 16]).unwrap();
+        let path = dir.join("profile.json");
+        fs::write(
+            &path,
+            r#"{
+                "hid": { "name": "Example Key", "bus": "usb" },
+                "aaguid": "cb69481e-8ff7-4039-93ec-0a2729a154a8",
+                "quirks": { "no_wink": true },
+                "attestation": { "key": "key.der", "certificates": ["cert.der", "ca.der"] }
+            }"#,
+        )
+        .unwrap();
+
+        let profile = Profile::from_file(&path).unwrap();
+        assert_eq!(profile.hid.name, "Example Key");
+        assert_eq!(profile.hid.bus, HidBus::Usb);
+        assert_eq!(profile.hid.vendor_id, CTAPHID_VENDOR_ID);
+        assert_eq!(profile.aaguid.0[..2], [0xcb, 0x69]);
+        assert_eq!(profile.versions, ["FIDO_2_0"]);
+        assert!(!profile.server_options(true).wink_enabled);
+        let key = profile.attestation_key().unwrap();
+        assert_eq!(
+            key.certificate(),
+            include_bytes!("authenticator/crypto/attestation/cert.der")
+        );
+        assert_eq!(key.chain().len(), 2);
+        assert_eq!(key.chain()[1], [0xca; 16]);
+
+        fs::write(&path, r#"{ "aaguid": "cb69481e" }"#).unwrap();
+        assert!(matches!(
+            Profile::from_file(&path),
+            Err(ProfileError::InvalidProfile(_))
+        ));
+    }
+}
@@ -0,0 +1,11 @@
+//! Helpers shared by the tests of several modules
+
+use tempfile::TempDir;
+
+/// A fresh directory for the files of a test, removed along with them once dropped
+pub fn temp_dir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("softauth-")
+        .tempdir()
+        .expect("Couldn't create a temporary directory")
+}
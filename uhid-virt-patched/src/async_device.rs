@@ -0,0 +1,106 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::future::poll_fn;
+use std::io::{self, prelude::*};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::unix::AsyncFd;
+
+use crate::codec::*;
+use crate::uhid_device::{open_uhid_device_file, CreateParams};
+
+/// A UHID device driven by the tokio reactor, performing non-blocking reads and writes on
+/// the UHID character device instead of occupying a thread per direction.
+///
+/// Dropping the device closes the character device, which destroys the HID device.
+#[derive(Debug)]
+pub struct AsyncUHIDDevice {
+    fd: AsyncFd<File>,
+}
+
+impl AsyncUHIDDevice {
+    /// Opens a UHID character device at given path (or /dev/uhid if no path is provided) in
+    /// non-blocking mode, and creates a HID device. Must be called within a tokio runtime.
+    pub fn create(params: CreateParams, path: Option<&Path>) -> io::Result<Self> {
+        let file = open_uhid_device_file(params, path, libc::O_NONBLOCK)?;
+        Ok(AsyncUHIDDevice {
+            fd: AsyncFd::new(file)?,
+        })
+    }
+
+    /// Drives a UHID character device on which a HID device was already created, e.g, by a
+    /// privileged process which passed the file descriptor. The file is switched to
+    /// non-blocking mode. Must be called within a tokio runtime.
+    pub fn from_file(file: File) -> io::Result<Self> {
+        let fd = file.as_raw_fd();
+        // SAFETY: fcntl only reads and sets the status flags of a valid, owned descriptor
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(AsyncUHIDDevice {
+            fd: AsyncFd::new(file)?,
+        })
+    }
+
+    /// Attempts to read a queued output event, registering the current task for wakeup
+    /// once an event is available.
+    pub fn poll_read_output_event(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<OutputEvent, StreamError>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let mut event = [0u8; UHID_EVENT_SIZE];
+            match guard.try_io(|fd| fd.get_ref().read(&mut event)) {
+                Ok(Ok(n)) if n == UHID_EVENT_SIZE => {
+                    return Poll::Ready(OutputEvent::try_from(event))
+                }
+                Ok(Ok(n)) => {
+                    return Poll::Ready(Err(StreamError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("Read a partial UHID event of {} bytes", n),
+                    ))))
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(StreamError::Io(e))),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Attempts to write an encoded input event, registering the current task for wakeup
+    /// once the device is writable.
+    pub fn poll_write_event(
+        &self,
+        cx: &mut Context<'_>,
+        event: &[u8; UHID_EVENT_SIZE],
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            match guard.try_io(|fd| fd.get_ref().write(event)) {
+                Ok(Ok(n)) if n == UHID_EVENT_SIZE => return Poll::Ready(Ok(())),
+                Ok(Ok(n)) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        format!("Wrote a partial UHID event of {} bytes", n),
+                    )))
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Reads a queued output event, see [crate::UHIDRead::read_output_event]
+    pub async fn read_output_event(&self) -> Result<OutputEvent, StreamError> {
+        poll_fn(|cx| self.poll_read_output_event(cx)).await
+    }
+
+    /// Writes an input event, see [crate::UHIDWrite]
+    pub async fn write_event(&self, event: InputEvent<'_>) -> io::Result<()> {
+        let event: [u8; UHID_EVENT_SIZE] = event.into();
+        poll_fn(|cx| self.poll_write_event(cx, &event)).await
+    }
+}
@@ -132,18 +132,20 @@ impl<'a> Into<sys::uhid_event> for InputEvent<'a> {
                     .for_each(|(i, x)| payload.data[i] = *x);
                 payload.size = data.len() as u16;
             }
-            InputEvent::GetReportReply { err, data, .. } => {
+            InputEvent::GetReportReply { id, err, data } => {
                 event.type_ = sys::uhid_event_type_UHID_GET_REPORT_REPLY as u32;
                 let payload = unsafe { &mut event.u.get_report_reply };
+                payload.id = id;
                 payload.err = err;
                 data.iter()
                     .enumerate()
                     .for_each(|(i, x)| payload.data[i] = *x);
                 payload.size = data.len() as u16;
             }
-            InputEvent::SetReportReply { err, .. } => {
+            InputEvent::SetReportReply { id, err } => {
                 event.type_ = sys::uhid_event_type_UHID_SET_REPORT_REPLY as u32;
                 let payload = unsafe { &mut event.u.set_report_reply };
+                payload.id = id;
                 payload.err = err;
             }
         };
@@ -455,4 +457,29 @@ mod tests {
         let result: [u8; UHID_EVENT_SIZE] = InputEvent::Destroy.into();
         assert_bytes_eq(&result[..], &expected);
     }
+
+    #[test]
+    fn encode_report_replies() {
+        let result: [u8; UHID_EVENT_SIZE] = InputEvent::GetReportReply {
+            id: 0x01020304,
+            err: 5,
+            data: vec![0xaa, 0xbb],
+        }
+        .into();
+        assert_bytes_eq(
+            &result[..12],
+            &[0x0a, 0, 0, 0, 0x04, 0x03, 0x02, 0x01, 5, 0, 2, 0],
+        );
+        assert_bytes_eq(&result[12..14], &[0xaa, 0xbb]);
+
+        let result: [u8; UHID_EVENT_SIZE] = InputEvent::SetReportReply {
+            id: 0x01020304,
+            err: 5,
+        }
+        .into();
+        assert_bytes_eq(
+            &result[..10],
+            &[0x0e, 0, 0, 0, 0x04, 0x03, 0x02, 0x01, 5, 0],
+        );
+    }
 }
@@ -1,5 +1,9 @@
+#[cfg(feature = "tokio")]
+mod async_device;
 mod codec;
 mod uhid_device;
 
+#[cfg(feature = "tokio")]
+pub use async_device::*;
 pub use codec::*;
-pub use uhid_device::*;
\ No newline at end of file
+pub use uhid_device::*;
@@ -58,11 +58,19 @@ pub struct CreateParams {
 
 /// Opens a UHID character device at given path, or /dev/uhid if no path is provided.
 pub fn create_uhid_device_file(params: CreateParams, path: Option<&Path>) -> io::Result<File> {
+    open_uhid_device_file(params, path, 0)
+}
+
+pub(crate) fn open_uhid_device_file(
+    params: CreateParams,
+    path: Option<&Path>,
+    extra_flags: i32,
+) -> io::Result<File> {
     let mut options = OpenOptions::new();
     options.read(true);
     options.write(true);
     if cfg!(unix) {
-        options.custom_flags(libc::O_RDWR | libc::O_CLOEXEC);
+        options.custom_flags(libc::O_RDWR | libc::O_CLOEXEC | extra_flags);
     }
     let path = path.unwrap_or(Path::new("/dev/uhid"));
     let mut handle = options.open(path)?;